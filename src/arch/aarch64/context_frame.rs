@@ -4,8 +4,8 @@ use core::fmt::Formatter;
 
 use cortex_a::registers::*;
 
-use crate::{msr, mrs};
 use crate::arch::gic::GicState;
+use crate::{mrs, msr, HyperResult, SnapshotReader, SnapshotWriter};
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
@@ -68,16 +68,36 @@ impl crate::traits::ContextFrameTrait for Aarch64ContextFrame {
         self.gpr[0] = arg as u64;
     }
 
+    // index 31 is `xzr` wherever it isn't `sp`, which has its own accessors
     fn set_gpr(&mut self, index: usize, val: usize) {
-        self.gpr[index] = val as u64;
+        if let Some(reg) = self.gpr.get_mut(index) {
+            *reg = val as u64;
+        }
     }
 
     fn gpr(&self, index: usize) -> usize {
-        self.gpr[index] as usize
+        self.gpr.get(index).map_or(0, |&reg| reg as usize)
     }
 }
 
 impl Aarch64ContextFrame {
+    pub fn write_snapshot(&self, w: &mut SnapshotWriter) {
+        self.gpr.iter().for_each(|&reg| w.put_u64(reg));
+        w.put_u64(self.sp);
+        w.put_u64(self.elr);
+        w.put_u64(self.spsr);
+    }
+
+    pub fn read_snapshot(&mut self, r: &mut SnapshotReader) -> HyperResult {
+        for reg in self.gpr.iter_mut() {
+            *reg = r.get_u64()?;
+        }
+        self.sp = r.get_u64()?;
+        self.elr = r.get_u64()?;
+        self.spsr = r.get_u64()?;
+        Ok(())
+    }
+
     pub fn default() -> Aarch64ContextFrame {
         Aarch64ContextFrame {
             gpr: [0; 31],
@@ -93,6 +113,52 @@ impl Aarch64ContextFrame {
     }
 }
 
+/// Calls `$m` with every register of a [`VmContext`], in snapshot order.
+macro_rules! vm_context_regs {
+    ($m:ident) => {
+        $m!(
+            cntvoff_el2,
+            cntp_cval_el0,
+            cntv_cval_el0,
+            cntkctl_el1,
+            cntvct_el0,
+            cntp_ctl_el0,
+            cntv_ctl_el0,
+            cntp_tval_el0,
+            cntv_tval_el0,
+            vpidr_el2,
+            vmpidr_el2,
+            sp_el0,
+            sp_el1,
+            elr_el1,
+            spsr_el1,
+            sctlr_el1,
+            actlr_el1,
+            cpacr_el1,
+            ttbr0_el1,
+            ttbr1_el1,
+            tcr_el1,
+            esr_el1,
+            far_el1,
+            par_el1,
+            mair_el1,
+            amair_el1,
+            vbar_el1,
+            contextidr_el1,
+            tpidr_el0,
+            tpidr_el1,
+            tpidrro_el0,
+            hcr_el2,
+            cptr_el2,
+            hstr_el2,
+            pmcr_el0,
+            vtcr_el2,
+            far_el2,
+            hpfar_el2
+        )
+    };
+}
+
 #[repr(C)]
 #[repr(align(16))]
 #[derive(Debug, Clone)]
@@ -115,20 +181,20 @@ pub struct VmContext {
     // 64bit EL1/EL0 register
     sp_el0: u64,
     sp_el1: u64,
-    elr_el1: u64,
-    spsr_el1: u32,
+    pub elr_el1: u64,
+    pub spsr_el1: u32,
     pub sctlr_el1: u32,
     actlr_el1: u64,
     cpacr_el1: u32,
-    ttbr0_el1: u64,
-    ttbr1_el1: u64,
-    tcr_el1: u64,
-    esr_el1: u32,
-    far_el1: u64,
+    pub ttbr0_el1: u64,
+    pub ttbr1_el1: u64,
+    pub tcr_el1: u64,
+    pub esr_el1: u32,
+    pub far_el1: u64,
     par_el1: u64,
     mair_el1: u64,
     amair_el1: u64,
-    vbar_el1: u64,
+    pub vbar_el1: u64,
     contextidr_el1: u32,
     tpidr_el0: u64,
     tpidr_el1: u64,
@@ -328,6 +394,23 @@ impl VmContext {
         msr!(CNTVOFF_EL2, self.cntvoff_el2);
     }
 
+    /// Writes every register, 32-bit ones widened to 64 bits, and the GIC state.
+    pub fn write_snapshot(&self, w: &mut SnapshotWriter) {
+        macro_rules! put {
+            ($($reg:ident),*) => { $(w.put_u64(self.$reg as u64);)* };
+        }
+        vm_context_regs!(put);
+        self.gic_state.write_snapshot(w);
+    }
+
+    pub fn read_snapshot(&mut self, r: &mut SnapshotReader) -> HyperResult {
+        macro_rules! get {
+            ($($reg:ident),*) => { $(self.$reg = r.get_u64()? as _;)* };
+        }
+        vm_context_regs!(get);
+        self.gic_state.read_snapshot(r)
+    }
+
     pub fn gic_save_state(&mut self) {
         self.gic_state.save_state();
     }
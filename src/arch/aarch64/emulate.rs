@@ -0,0 +1,265 @@
+//! Emulation of the guest loads and stores that abort on emulated MMIO.
+
+use alloc::vec::Vec;
+
+use crate::{HyperError, HyperResult, PendingRead};
+
+/// One register transfer of a guest access to emulated MMIO.
+#[derive(Debug, Clone, Copy)]
+pub struct EmuContext {
+    /// Intermediate physical address accessed.
+    pub address: usize,
+    /// Access width in bytes.
+    pub width: usize,
+    /// Whether the access is a store.
+    pub write: bool,
+    /// Whether a load sign-extends the data.
+    pub sign_ext: bool,
+    /// Transfer register, 31 being the zero register.
+    pub reg: usize,
+    /// Width of the transfer register in bytes, 4 for `Wt` and 8 for `Xt`.
+    pub reg_width: usize,
+}
+
+impl EmuContext {
+    /// Where the data of a load goes.
+    pub(crate) fn pending_read(&self) -> PendingRead {
+        PendingRead {
+            sign_extend: self.sign_ext,
+            reg_size: self.reg_width as u8,
+            ..PendingRead::new(self.reg, self.width as u8)
+        }
+    }
+
+    /// The data of a store, given the value of the transfer register.
+    pub(crate) fn write_data(&self, reg_val: usize) -> u64 {
+        let reg_val = if self.reg == 31 { 0 } else { reg_val };
+        reg_val as u64 & (u64::MAX >> (64 - 8 * self.width as u32))
+    }
+}
+
+/// A load or store decoded from its encoding, for aborts whose syndrome isn't valid.
+pub(crate) struct LoadStore {
+    /// Base register, 31 being SP.
+    pub rn: usize,
+    /// Offset of the first accessed address from the base register.
+    pub offset: isize,
+    /// Amount the base register is updated by, for pre- and post-indexed forms.
+    pub writeback: Option<isize>,
+    /// The transfers in address order, with addresses relative to the first one.
+    pub transfers: Vec<EmuContext>,
+}
+
+/// Decodes the general-purpose register loads and stores with an immediate offset, including
+/// the pre- and post-indexed forms and pairs that never report a valid syndrome.
+pub(crate) fn decode_load_store(inst: u32) -> HyperResult<LoadStore> {
+    let bits = |lo: u32, len: u32| ((inst >> lo) & ((1 << len) - 1)) as usize;
+    let signed = |lo: u32, len: u32| ((inst << (32 - lo - len)) as i32 >> (32 - len)) as isize;
+    let rt = bits(0, 5);
+    let rn = bits(5, 5);
+
+    if inst & 0x3f00_0000 == 0x3800_0000 || inst & 0x3f00_0000 == 0x3900_0000 {
+        // load/store register, unsigned offset or 9-bit signed offset
+        let size = bits(30, 2);
+        let width = 1 << size;
+        let (write, sign_ext, reg_width) = match (bits(22, 2), size) {
+            (0b00, _) => (true, false, if size == 3 { 8 } else { 4 }),
+            (0b01, _) => (false, false, if size == 3 { 8 } else { 4 }),
+            (0b10, 0..=2) => (false, true, 8),
+            (0b11, 0..=1) => (false, true, 4),
+            // PRFM and unallocated encodings
+            _ => return Err(HyperError::InvalidInstruction),
+        };
+        let (offset, writeback) = if inst & (1 << 24) != 0 {
+            ((bits(10, 12) << size) as isize, None)
+        } else {
+            // bit 21 set: register offset and atomic memory operations
+            if inst & (1 << 21) != 0 {
+                return Err(HyperError::NotSupported);
+            }
+            let imm = signed(12, 9);
+            match bits(10, 2) {
+                // unscaled and unprivileged
+                0b00 | 0b10 => (imm, None),
+                0b01 => (0, Some(imm)),
+                _ => (imm, Some(imm)),
+            }
+        };
+        let transfer = EmuContext {
+            address: 0,
+            width,
+            write,
+            sign_ext,
+            reg: rt,
+            reg_width,
+        };
+        return Ok(LoadStore {
+            rn,
+            offset,
+            writeback,
+            transfers: [transfer].into(),
+        });
+    }
+
+    if inst & 0x3c00_0000 == 0x2800_0000 {
+        // load/store pair
+        let write = inst & (1 << 22) == 0;
+        let (width, sign_ext, reg_width) = match bits(30, 2) {
+            0b00 => (4, false, 4),
+            0b01 if !write => (4, true, 8),
+            0b10 => (8, false, 8),
+            // STGP and SIMD&FP pairs
+            _ => return Err(HyperError::NotSupported),
+        };
+        let imm = signed(15, 7) * width as isize;
+        let (offset, writeback) = match bits(23, 2) {
+            0b01 => (0, Some(imm)),
+            0b11 => (imm, Some(imm)),
+            // signed offset and no-allocate
+            _ => (imm, None),
+        };
+        let transfers = [rt, bits(10, 5)]
+            .into_iter()
+            .enumerate()
+            .map(|(i, reg)| EmuContext {
+                address: i * width,
+                width,
+                write,
+                sign_ext,
+                reg,
+                reg_width,
+            })
+            .collect();
+        return Ok(LoadStore {
+            rn,
+            offset,
+            writeback,
+            transfers,
+        });
+    }
+
+    Err(HyperError::InvalidInstruction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `(address, width, write, sign_ext, reg, reg_width)` of a transfer.
+    type Transfer = (usize, usize, bool, bool, usize, usize);
+
+    /// The base register, offset and writeback of `inst`, and its transfers.
+    fn decode(inst: u32) -> (usize, isize, Option<isize>, Vec<Transfer>) {
+        let ls = decode_load_store(inst).unwrap();
+        let transfers = ls
+            .transfers
+            .iter()
+            .map(|t| (t.address, t.width, t.write, t.sign_ext, t.reg, t.reg_width))
+            .collect();
+        (ls.rn, ls.offset, ls.writeback, transfers)
+    }
+
+    #[test]
+    fn single_register_forms() {
+        // ldr x3, [sp, #24]
+        assert_eq!(
+            decode(0xf940_0fe3),
+            (31, 24, None, [(0, 8, false, false, 3, 8)].into())
+        );
+        // ldur w2, [x1, #-4]
+        assert_eq!(
+            decode(0xb85f_c022),
+            (1, -4, None, [(0, 4, false, false, 2, 4)].into())
+        );
+        // ldr w1, [x0], #4
+        assert_eq!(
+            decode(0xb840_4401),
+            (0, 0, Some(4), [(0, 4, false, false, 1, 4)].into())
+        );
+        // strb w5, [x2, #1]!
+        assert_eq!(
+            decode(0x3800_1c45),
+            (2, 1, Some(1), [(0, 1, true, false, 5, 4)].into())
+        );
+        // str x0, [x1], #-8
+        assert_eq!(
+            decode(0xf81f_8420),
+            (1, 0, Some(-8), [(0, 8, true, false, 0, 8)].into())
+        );
+    }
+
+    #[test]
+    fn sign_extending_loads() {
+        // ldrsw x1, [x0, #8]
+        assert_eq!(
+            decode(0xb980_0801),
+            (0, 8, None, [(0, 4, false, true, 1, 8)].into())
+        );
+        // ldrsh w3, [x4]
+        assert_eq!(
+            decode(0x79c0_0083),
+            (4, 0, None, [(0, 2, false, true, 3, 4)].into())
+        );
+        // ldrsb x0, [x1, #3]
+        assert_eq!(
+            decode(0x3980_0c20),
+            (1, 3, None, [(0, 1, false, true, 0, 8)].into())
+        );
+    }
+
+    #[test]
+    fn pairs() {
+        // ldp x1, x2, [x0, #16]!
+        assert_eq!(
+            decode(0xa9c1_0801),
+            (
+                0,
+                16,
+                Some(16),
+                [(0, 8, false, false, 1, 8), (8, 8, false, false, 2, 8)].into()
+            )
+        );
+        // stp x29, x30, [sp, #-16]!
+        assert_eq!(
+            decode(0xa9bf_7bfd),
+            (
+                31,
+                -16,
+                Some(-16),
+                [(0, 8, true, false, 29, 8), (8, 8, true, false, 30, 8)].into()
+            )
+        );
+        // stp w1, w2, [x3, #-8]
+        assert_eq!(
+            decode(0x293f_0861),
+            (
+                3,
+                -8,
+                None,
+                [(0, 4, true, false, 1, 4), (4, 4, true, false, 2, 4)].into()
+            )
+        );
+        // ldpsw x1, x2, [x0], #8
+        assert_eq!(
+            decode(0x68c1_0801),
+            (
+                0,
+                0,
+                Some(8),
+                [(0, 4, false, true, 1, 8), (4, 4, false, true, 2, 8)].into()
+            )
+        );
+    }
+
+    #[test]
+    fn unsupported_encodings_are_rejected() {
+        let rejected = |inst| decode_load_store(inst).is_err();
+        assert!(rejected(0xf980_0000)); // prfm pldl1keep, [x0]
+        assert!(rejected(0x3dc0_0000)); // ldr q0, [x0]
+        assert!(rejected(0xfd40_0000)); // ldr d0, [x0]
+        assert!(rejected(0x6d40_0400)); // ldp d0, d1, [x0]
+        assert!(rejected(0xf862_6820)); // ldr x0, [x1, x2]
+        assert!(rejected(0xf820_0041)); // ldadd x0, x1, [x2]
+        assert!(rejected(0xc85f_7c20)); // ldxr x0, [x1]
+    }
+}
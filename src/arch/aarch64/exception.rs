@@ -2,9 +2,13 @@ use core::arch::global_asm;
 
 use tock_registers::interfaces::*;
 
-use crate::mrs;
+use crate::arch::sync::{
+    data_abort_emu_context, data_abort_handler, exit_to_host, guest_running, hvc_handler,
+};
+use crate::arch::vcpu::VmCpuRegisters;
 use crate::arch::ContextFrame;
-use crate::arch::sync::{data_abort_handler, hvc_handler};
+use crate::arch::VmExitInfo;
+use crate::mrs;
 use crate::traits::ContextFrameTrait;
 
 //global_asm!(include_str!("exception.S"));
@@ -79,14 +83,15 @@ fn translate_far_to_hpfar(far: usize) -> Result<usize, ()> {
 #[inline(always)]
 pub fn exception_fault_addr() -> usize {
     let far = exception_far();
-    let hpfar = if (exception_esr() & ESR_ELx_S1PTW) == 0 && exception_data_abort_is_permission_fault() {
-        translate_far_to_hpfar(far).unwrap_or_else(|_| {
-            info!("error happen in translate_far_to_hpfar");
-            0
-        })
-    } else {
-        exception_hpfar()
-    };
+    let hpfar =
+        if (exception_esr() & ESR_ELx_S1PTW) == 0 && exception_data_abort_is_permission_fault() {
+            translate_far_to_hpfar(far).unwrap_or_else(|_| {
+                info!("error happen in translate_far_to_hpfar");
+                0
+            })
+        } else {
+            exception_hpfar()
+        };
     (far & 0xfff) | (hpfar << 8)
 }
 
@@ -151,6 +156,33 @@ pub fn exception_data_abort_access_is_sign_ext() -> bool {
     ((exception_iss() >> 21) & 1) != 0
 }
 
+/// Record the exception being handled as the last exit of the vcpu running on this cpu.
+/// TPIDR_EL2 holds the address of its `VmCpuRegisters`, set when the vcpu is booted and zeroed
+/// by [`init_el2`](crate::arch::sync::init_el2) and when it exits.
+pub fn record_vm_exit(ctx: &ContextFrame) {
+    let regs_addr: usize;
+    mrs!(regs_addr, TPIDR_EL2);
+    if regs_addr == 0 {
+        return;
+    }
+    let (fault_addr, far) = match exception_class() {
+        0x20 | 0x24 => (exception_fault_addr(), exception_far()),
+        _ => (0, 0),
+    };
+    let emu_ctx = match exception_class() {
+        0x24 => data_abort_emu_context(),
+        _ => None,
+    };
+    let regs = unsafe { &mut *(regs_addr as *mut VmCpuRegisters) };
+    regs.exit_info = Some(VmExitInfo {
+        esr: exception_esr(),
+        fault_addr,
+        far,
+        pc: ctx.exception_pc(),
+        emu_ctx,
+    });
+}
+
 /// deal with lower aarch64 synchronous exception
 #[no_mangle]
 pub extern "C" fn lower_aarch64_synchronous(ctx: &mut ContextFrame) {
@@ -158,6 +190,13 @@ pub extern "C" fn lower_aarch64_synchronous(ctx: &mut ContextFrame) {
     // current_cpu().set_context_addr(ctx);
 
     match exception_class() {
+        // WFI/WFE from the guest is handed to the host, resume after it
+        0x01 if guest_running() => {
+            record_vm_exit(ctx);
+            let val = ctx.exception_pc() + exception_next_instruction_step();
+            ctx.set_exception_pc(val);
+            exit_to_host(ctx);
+        }
         0x24 => {
             // info!("Core[{}] data_abort_handler", cpu_id());
             data_abort_handler(ctx);
@@ -166,7 +205,12 @@ pub extern "C" fn lower_aarch64_synchronous(ctx: &mut ContextFrame) {
             hvc_handler(ctx);
         }
         // 0x18 todo？
-        _ => {   
+        // anything else from the guest is up to the host as well
+        _ if guest_running() => {
+            record_vm_exit(ctx);
+            exit_to_host(ctx);
+        }
+        _ => {
             panic!(
                 "handler not presents for EC_{} @ipa 0x{:x}, @pc 0x{:x}, @esr 0x{:x}, @sctlr_el1 0x{:x}, @vttbr_el2 0x{:x}, ",
                 exception_class(),
@@ -176,6 +220,6 @@ pub extern "C" fn lower_aarch64_synchronous(ctx: &mut ContextFrame) {
                 cortex_a::registers::SCTLR_EL1.get() as usize,
                 cortex_a::registers::VTTBR_EL2.get() as usize,
             );
-        },
+        }
     }
 }
@@ -1,10 +1,11 @@
 use spin::Mutex;
 use spinlock::SpinNoIrq;
 
-use arm_gic::gic_v2::{GicDistributor, GicHypervisorInterface, GicCpuInterface};
+use arm_gic::gic_v2::{GicCpuInterface, GicDistributor, GicHypervisorInterface};
 use arm_gic::GIC_LIST_REGS_NUM;
 
 use crate::arch::utils::bit_extract;
+use crate::{HyperResult, SnapshotReader, SnapshotWriter};
 
 pub static GICD: Option<&SpinNoIrq<GicDistributor>> = None;
 pub static GICC: Option<&GicCpuInterface> = None;
@@ -15,14 +16,12 @@ pub const GICC_BASE: usize = 0x08010000;
 pub const GICH_BASE: usize = 0x08030000;
 pub const GICV_BASE: usize = 0x08040000;
 
-
 // GICC BITS
 pub const GICC_CTLR_EN_BIT: usize = 0x1;
 pub const GICC_CTLR_EOIMODENS_BIT: usize = 1 << 9;
 
 pub static GIC_LRS_NUM: Mutex<usize> = Mutex::new(0);
 
-
 #[repr(C)]
 #[derive(Debug, Clone)]
 pub struct GicState {
@@ -45,7 +44,7 @@ impl GicState {
         }
     }
 
-    pub fn save_state(&mut self) { 
+    pub fn save_state(&mut self) {
         if let Some(gich) = GICH {
             self.saved_hcr = gich.get_hcr();
             self.saved_apr = gich.get_apr();
@@ -65,11 +64,37 @@ impl GicState {
         }
         if let Some(gicc) = GICC {
             self.saved_ctlr = gicc.get_ctlr();
-        }else {
+        } else {
             warn!("No available gicc in save_state!")
         }
     }
 
+    pub fn write_snapshot(&self, w: &mut SnapshotWriter) {
+        w.put_u32(self.saved_hcr);
+        self.saved_eisr.iter().for_each(|&reg| w.put_u32(reg));
+        self.saved_elrsr.iter().for_each(|&reg| w.put_u32(reg));
+        w.put_u32(self.saved_apr);
+        self.saved_lr.iter().for_each(|&reg| w.put_u32(reg));
+        w.put_u32(self.saved_ctlr);
+    }
+
+    pub fn read_snapshot(&mut self, r: &mut SnapshotReader) -> HyperResult {
+        self.saved_hcr = r.get_u32()?;
+        for reg in self
+            .saved_eisr
+            .iter_mut()
+            .chain(self.saved_elrsr.iter_mut())
+        {
+            *reg = r.get_u32()?;
+        }
+        self.saved_apr = r.get_u32()?;
+        for reg in self.saved_lr.iter_mut() {
+            *reg = r.get_u32()?;
+        }
+        self.saved_ctlr = r.get_u32()?;
+        Ok(())
+    }
+
     pub fn restore_state(&self) {
         if let Some(gich) = GICH {
             gich.set_hcr(self.saved_hcr);
@@ -82,14 +107,13 @@ impl GicState {
         }
         if let Some(gicc) = GICC {
             gicc.set_ctlr(self.saved_ctlr);
-        }else {
+        } else {
             warn!("No available gicc in restore_state!")
         }
     }
-
 }
 
-/* 
+/*
 pub fn gicc_get_current_irq() -> (usize, usize) {
     if let Some(gicc) = GICC {
         let iar = gicc.get_iar();
@@ -1,4 +1,3 @@
-
 use aarch64_cpu::{asm, asm::barrier, registers::*};
 use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
 
@@ -10,6 +9,9 @@ pub const HVC_SYS: usize = 0;
 /// HVC SYS event
 pub const HVC_SYS_BOOT: usize = 0;
 
+/// Returned to the host by the `HVC_SYS_BOOT` call once the guest exits.
+pub const HVC_VM_EXIT: usize = 1;
+
 #[repr(C)]
 pub struct HvcDefaultMsg {
     pub fid: usize,
@@ -31,7 +33,10 @@ pub fn hvc_guest_handler(
     match hvc_type {
         HVC_SYS => hvc_sys_handler(event, x0, x1),
         _ => {
-            info!("hvc_guest_handler: unknown hvc type {} event {}", hvc_type, event);
+            info!(
+                "hvc_guest_handler: unknown hvc type {} event {}",
+                hvc_type, event
+            );
             Err(())
         }
     }
@@ -58,28 +63,33 @@ fn hvc_sys_handler(event: usize, root_paddr: usize, vm_ctx_addr: usize) -> Resul
 /// hvc handler for initial hv
 /// x0: root_paddr, x1: vm regs context addr
 fn init_hv(root_paddr: usize, vm_ctx_addr: usize) {
-    // cptr_el2: Condtrols trapping to EL2 for accesses to the CPACR, Trace functionality 
+    // cptr_el2: Condtrols trapping to EL2 for accesses to the CPACR, Trace functionality
     //           an registers associated with floating-point and Advanced SIMD execution.
 
     // ldr x2, =(0x30c51835)  // do not set sctlr_el2 as this value, some fields have no use.
     unsafe {
-        core::arch::asm!("
+        core::arch::asm!(
+            "
             mov x3, xzr           // Trap nothing from EL1 to El2.
             msr cptr_el2, x3"
         );
     }
-        // init_page_table(root_paddr);
+    // init_page_table(root_paddr);
     msr!(VTTBR_EL2, root_paddr);
-        // init_sysregs();
+    // init_sysregs();
     unsafe {
-        core::arch::asm!("
+        core::arch::asm!(
+            "
             tlbi	alle2         // Flush tlb
+            tlbi	vmalls12e1    // and the guest's, whose stage-2 permissions may have changed
             dsb	nsh
             isb"
         );
     }
-    
-    let regs: &VmCpuRegisters = unsafe{core::mem::transmute(vm_ctx_addr)};
+
+    let regs: &mut VmCpuRegisters = unsafe { core::mem::transmute(vm_ctx_addr) };
+    // save arceos system related register, restored when the guest exits
+    regs.host_system_regs.ext_regs_store();
     // set vm system related register
     regs.vm_system_regs.ext_regs_restore();
 }
@@ -89,19 +99,14 @@ fn init_sysregs() {
         asm::barrier,
         registers::{HCR_EL2, SCTLR_EL2},
     };
-    HCR_EL2.write(    
-        HCR_EL2::VM::Enable
-            + HCR_EL2::RW::EL1IsAarch64,
-    );  // Make irq and fiq do not route to el2
-    SCTLR_EL2.modify(SCTLR_EL2::M::Enable 
-                    + SCTLR_EL2::C::Cacheable 
-                    + SCTLR_EL2::I::Cacheable); // other fields need? EIS, EOS?
+    HCR_EL2.write(HCR_EL2::VM::Enable + HCR_EL2::RW::EL1IsAarch64); // Make irq and fiq do not route to el2
+    SCTLR_EL2.modify(SCTLR_EL2::M::Enable + SCTLR_EL2::C::Cacheable + SCTLR_EL2::I::Cacheable); // other fields need? EIS, EOS?
     barrier::isb(barrier::SY);
 }
 
 fn init_page_table(vttbr: usize) {
     use aarch64_cpu::registers::{VTCR_EL2, VTTBR_EL2};
-    /* 
+    /*
     VTCR_EL2.write(
         VTCR_EL2::PS::PA_36B_64GB   //0b001 36 bits, 64GB.
             + VTCR_EL2::TG0::Granule4KB
@@ -115,13 +120,13 @@ fn init_page_table(vttbr: usize) {
     msr!(VTTBR_EL2, vttbr);
 }
 
-/* 
+/*
 // really need init MAIR_EL2 and TCR_EL2 ??
-// MAIR_EL2: Provides the memory attribute encodings corresponding to the possible 
-//           AttrIndx values in a Long-descriptor format translation table entry for 
+// MAIR_EL2: Provides the memory attribute encodings corresponding to the possible
+//           AttrIndx values in a Long-descriptor format translation table entry for
 //           stage 1 translations at EL2.
-// TCR_EL2: When the Effective value of HCR_EL2.E2H is 0, this register controls stage 1 
-//          of the EL2 translation regime, that supports a single VA range, translated 
+// TCR_EL2: When the Effective value of HCR_EL2.E2H is 0, this register controls stage 1
+//          of the EL2 translation regime, that supports a single VA range, translated
 //          using TTBR0_EL2.
 unsafe fn init_hv_mmu(token: usize) {
     MAIR_EL2.write(
@@ -150,10 +155,10 @@ unsafe fn init_hv_mmu(token: usize) {
 
 #[inline(never)]
 fn hvc_call(
-    x0: usize, 
-    x1: usize, 
-    x2: usize, 
-    x3: usize, 
+    x0: usize,
+    x1: usize,
+    x2: usize,
+    x3: usize,
     x4: usize,
     x5: usize,
     x6: usize,
@@ -0,0 +1,510 @@
+use crate::{
+    GuestAccess, GuestMemory, GuestPageFault, GuestPageTableTrait, GuestPageWalk, GuestPhysAddr,
+    GuestVirtAddr, HyperCraftHal, HyperError, HyperResult,
+};
+
+/// `TCR_EL1` fields.
+const TCR_T0SZ_SHIFT: u64 = 0;
+const TCR_EPD0: u64 = 1 << 7;
+const TCR_TG0_SHIFT: u64 = 14;
+const TCR_T1SZ_SHIFT: u64 = 16;
+const TCR_EPD1: u64 = 1 << 23;
+const TCR_TG1_SHIFT: u64 = 30;
+const TCR_TBI0: u64 = 1 << 37;
+const TCR_TBI1: u64 = 1 << 38;
+const TCR_HA: u64 = 1 << 39;
+const TCR_HPD0: u64 = 1 << 41;
+const TCR_HPD1: u64 = 1 << 42;
+
+const SCTLR_M: u64 = 1 << 0;
+const SCTLR_WXN: u64 = 1 << 19;
+
+/// Translation table descriptor bits.
+const DESC_VALID: u64 = 1 << 0;
+const DESC_TABLE: u64 = 1 << 1;
+const DESC_AP_EL0: u64 = 1 << 6;
+const DESC_AP_RO: u64 = 1 << 7;
+const DESC_AF: u64 = 1 << 10;
+const DESC_PXN: u64 = 1 << 53;
+const DESC_UXN: u64 = 1 << 54;
+const DESC_PXN_TABLE: u64 = 1 << 59;
+const DESC_UXN_TABLE: u64 = 1 << 60;
+const DESC_AP_TABLE_NO_EL0: u64 = 1 << 61;
+const DESC_AP_TABLE_RO: u64 = 1 << 62;
+/// Bits 12..48 of a descriptor or `TTBRn_EL1`, the output address.
+const DESC_ADDR_MASK: u64 = 0x0000_ffff_ffff_f000;
+const TTBR_BADDR_MASK: u64 = 0x0000_ffff_ffff_fffe;
+
+/// Fault status codes, to which the level of the fault is added.
+const FSC_TRANSLATION: u32 = 0b000100;
+const FSC_ACCESS_FLAG: u32 = 0b001000;
+const FSC_PERMISSION: u32 = 0b001100;
+
+#[derive(Debug)]
+/// The information of guest page walk: the stage-1 registers of the vCPU and its exception level.
+pub struct GuestPageWalkInfo {
+    /// `TTBR0_EL1`, the tables of the lower VA range.
+    pub ttbr0: u64,
+    /// `TTBR1_EL1`, the tables of the upper VA range.
+    pub ttbr1: u64,
+    /// `TCR_EL1`, which sets the granule and size of both ranges.
+    pub tcr: u64,
+    /// `SCTLR_EL1`, for whether the MMU is on and `WXN`.
+    pub sctlr: u64,
+    /// The access is made from EL0.
+    pub is_user_mode_access: bool,
+    /// `PSTATE.PAN`, which keeps EL1 from reading and writing EL0 pages.
+    pub pan: bool,
+    /// Set the access flag of the leaf descriptor when `TCR_EL1.HA` asks the MMU to. Otherwise
+    /// the descriptors are left alone, as inspecting guest memory should.
+    pub set_accessed_dirty: bool,
+}
+
+/// Permissions collected from the table descriptors of a walk.
+struct TablePermissions {
+    no_el0: bool,
+    read_only: bool,
+    pxn: bool,
+    uxn: bool,
+}
+
+impl GuestPageWalkInfo {
+    /// Whether the leaf descriptor `desc`, under tables granting `table`, denies `access`.
+    fn denies(&self, access: GuestAccess, desc: u64, table: &TablePermissions) -> bool {
+        let el0_page = desc & DESC_AP_EL0 != 0 && !table.no_el0;
+        let writable = desc & DESC_AP_RO == 0 && !table.read_only;
+        let wxn = self.sctlr & SCTLR_WXN != 0 && writable;
+        if self.is_user_mode_access {
+            return match access {
+                GuestAccess::Read => !el0_page,
+                GuestAccess::Write => !el0_page || !writable,
+                GuestAccess::Fetch => desc & DESC_UXN != 0 || table.uxn || wxn,
+            };
+        }
+        match access {
+            GuestAccess::Read => self.pan && el0_page,
+            GuestAccess::Write => (self.pan && el0_page) || !writable,
+            // EL1 never executes from memory EL0 can write
+            GuestAccess::Fetch => {
+                desc & DESC_PXN != 0 || table.pxn || wxn || (el0_page && writable)
+            }
+        }
+    }
+}
+
+impl GuestPageWalk for GuestPageWalkInfo {
+    /// Walks the EL1&0 stage-1 tables with 4K, 16K or 64K granules and a VA size of up to
+    /// 48 bits. A failed walk reports the fault status code of the abort the guest would take.
+    fn translate<H: HyperCraftHal, G: GuestPageTableTrait>(
+        &self,
+        memory: &GuestMemory<H>,
+        gpt: &G,
+        gva: GuestVirtAddr,
+        access: GuestAccess,
+    ) -> HyperResult<GuestPhysAddr> {
+        if self.sctlr & SCTLR_M == 0 {
+            return Ok(gva as GuestPhysAddr);
+        }
+        let fault = |fsc: u32, level: usize| {
+            HyperError::GuestPageFault(GuestPageFault {
+                addr: gva,
+                physical: false,
+                access,
+                error_code: fsc + level as u32,
+            })
+        };
+        let va = gva as u64;
+        let upper = va & (1 << 55) != 0;
+        // TG0 and TG1 encode the granules differently
+        let (txsz, granule_bits, disabled, tbi, hpd, ttbr) = if upper {
+            let tg = match (self.tcr >> TCR_TG1_SHIFT) & 0b11 {
+                0b01 => 14,
+                0b11 => 16,
+                _ => 12,
+            };
+            let txsz = (self.tcr >> TCR_T1SZ_SHIFT) & 0x3f;
+            let hpd = self.tcr & TCR_HPD1 != 0;
+            (
+                txsz,
+                tg,
+                self.tcr & TCR_EPD1 != 0,
+                self.tcr & TCR_TBI1 != 0,
+                hpd,
+                self.ttbr1,
+            )
+        } else {
+            let tg = match (self.tcr >> TCR_TG0_SHIFT) & 0b11 {
+                0b01 => 16,
+                0b10 => 14,
+                _ => 12,
+            };
+            let txsz = (self.tcr >> TCR_T0SZ_SHIFT) & 0x3f;
+            let hpd = self.tcr & TCR_HPD0 != 0;
+            (
+                txsz,
+                tg,
+                self.tcr & TCR_EPD0 != 0,
+                self.tcr & TCR_TBI0 != 0,
+                hpd,
+                self.ttbr0,
+            )
+        };
+        let va_bits = 64 - txsz.clamp(16, 39) as usize;
+        // the bits above the VA size select the range and must all be equal
+        let top = if tbi { 56 } else { 64 };
+        let range = (va & (u64::MAX >> (64 - top))) >> va_bits;
+        let expected = if upper { (1 << (top - va_bits)) - 1 } else { 0 };
+        if disabled || range != expected {
+            return Err(fault(FSC_TRANSLATION, 0));
+        }
+
+        // each level resolves `stride` bits, the first one what is left
+        let stride = granule_bits - 3;
+        let mut level = 4 - (va_bits - granule_bits).div_ceil(stride);
+        let mut table = ttbr & TTBR_BADDR_MASK;
+        let mut perms = TablePermissions {
+            no_el0: false,
+            read_only: false,
+            pxn: false,
+            uxn: false,
+        };
+        loop {
+            let shift = granule_bits + stride * (3 - level);
+            let bits = (va_bits - shift).min(stride);
+            let desc_addr = table as usize + ((va >> shift) & ((1 << bits) - 1)) as usize * 8;
+            let desc = memory.read_obj::<u64, G>(gpt, desc_addr)?;
+            if desc & DESC_VALID == 0 {
+                return Err(fault(FSC_TRANSLATION, level));
+            }
+            if level < 3 && desc & DESC_TABLE != 0 {
+                if !hpd {
+                    perms.no_el0 |= desc & DESC_AP_TABLE_NO_EL0 != 0;
+                    perms.read_only |= desc & DESC_AP_TABLE_RO != 0;
+                    perms.pxn |= desc & DESC_PXN_TABLE != 0;
+                    perms.uxn |= desc & DESC_UXN_TABLE != 0;
+                }
+                table = desc & DESC_ADDR_MASK & !((1 << granule_bits) - 1);
+                level += 1;
+                continue;
+            }
+            // blocks: 1G and 2M with 4K granules, 32M with 16K and 512M with 64K
+            let block_ok = match level {
+                1 => granule_bits == 12,
+                2 => true,
+                _ => false,
+            };
+            if (level < 3 && !block_ok) || (level == 3 && desc & DESC_TABLE == 0) {
+                return Err(fault(FSC_TRANSLATION, level));
+            }
+
+            // without hardware management, a clear access flag faults before any permission
+            let update_af = desc & DESC_AF == 0;
+            if update_af && !(self.set_accessed_dirty && self.tcr & TCR_HA != 0) {
+                return Err(fault(FSC_ACCESS_FLAG, level));
+            }
+            if self.denies(access, desc, &perms) {
+                return Err(fault(FSC_PERMISSION, level));
+            }
+            if update_af {
+                memory.fetch_or_u32(gpt, desc_addr, DESC_AF as u32)?;
+            }
+            let page_mask = (1u64 << shift) - 1;
+            let base = desc & DESC_ADDR_MASK & !page_mask;
+            return Ok((base | (va & page_mask)) as GuestPhysAddr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::guest_memory::tests::{ram, TestGpt, TestHal};
+
+    const L0: u64 = 0x4000;
+    const L1: u64 = 0x1000;
+    const L2: u64 = 0x2000;
+    const L3: u64 = 0x3000;
+    const PAGE: u64 = 0x8000;
+
+    const TABLE: u64 = DESC_VALID | DESC_TABLE;
+    const BLOCK: u64 = DESC_VALID | DESC_AF;
+    const PAGE_DESC: u64 = DESC_VALID | DESC_TABLE | DESC_AF;
+
+    /// A 39-bit VA range with 4K granules for both halves, as seen from EL1 with the MMU on.
+    fn info() -> GuestPageWalkInfo {
+        GuestPageWalkInfo {
+            ttbr0: L1,
+            ttbr1: L1,
+            tcr: 25 << TCR_T0SZ_SHIFT | 25 << TCR_T1SZ_SHIFT | 0b10 << TCR_TG1_SHIFT,
+            sctlr: SCTLR_M,
+            is_user_mode_access: false,
+            pan: false,
+            set_accessed_dirty: false,
+        }
+    }
+
+    /// 4K-granule tables mapping the page at virtual address 0x1000 to [`PAGE`] with `attrs`,
+    /// and a level 0 table pointing to them for a 48-bit VA range.
+    fn tables(attrs: u64) -> (GuestMemory<TestHal>, TestGpt) {
+        let (memory, gpt) = ram(0x10000);
+        memory.write_obj(&gpt, L0 as usize, &(L1 | TABLE)).unwrap();
+        memory.write_obj(&gpt, L1 as usize, &(L2 | TABLE)).unwrap();
+        memory.write_obj(&gpt, L2 as usize, &(L3 | TABLE)).unwrap();
+        let desc = PAGE | PAGE_DESC | attrs;
+        memory.write_obj(&gpt, L3 as usize + 8, &desc).unwrap();
+        (memory, gpt)
+    }
+
+    fn translate(
+        info: &GuestPageWalkInfo,
+        (memory, gpt): &(GuestMemory<TestHal>, TestGpt),
+        gva: GuestVirtAddr,
+        access: GuestAccess,
+    ) -> HyperResult<GuestPhysAddr> {
+        info.translate(memory, gpt, gva, access)
+    }
+
+    fn fault(gva: GuestVirtAddr, access: GuestAccess, fsc: u32, level: u32) -> HyperResult<usize> {
+        Err(HyperError::GuestPageFault(GuestPageFault {
+            addr: gva,
+            physical: false,
+            access,
+            error_code: fsc + level,
+        }))
+    }
+
+    #[test]
+    fn va_size_and_ranges() {
+        use GuestAccess::*;
+        let mem = tables(0);
+        assert_eq!(translate(&info(), &mem, 0x1234, Read), Ok(0x8234));
+        // the upper range, here with the same tables
+        let upper = 0xffff_ff80_0000_1234;
+        assert_eq!(translate(&info(), &mem, upper, Read), Ok(0x8234));
+        // above the 39-bit VA size of either range
+        for gva in [1 << 39 | 0x1234, 0xffff_ff00_0000_1234] {
+            assert_eq!(
+                translate(&info(), &mem, gva, Read),
+                fault(gva, Read, FSC_TRANSLATION, 0)
+            );
+        }
+        // unless the top byte is ignored
+        let tbi = GuestPageWalkInfo {
+            tcr: info().tcr | TCR_TBI0,
+            ..info()
+        };
+        let tagged = 0xab00_0000_0000_1234;
+        assert_eq!(translate(&tbi, &mem, tagged, Read), Ok(0x8234));
+        // EPD0 disables the lower range
+        let epd = GuestPageWalkInfo {
+            tcr: info().tcr | TCR_EPD0,
+            ..info()
+        };
+        assert_eq!(
+            translate(&epd, &mem, 0x1234, Read),
+            fault(0x1234, Read, FSC_TRANSLATION, 0)
+        );
+
+        // a 48-bit VA starts at level 0, and T0SZ below 16 is taken as 16
+        for t0sz in [16, 12] {
+            let va48 = GuestPageWalkInfo {
+                ttbr0: L0,
+                tcr: t0sz << TCR_T0SZ_SHIFT,
+                ..info()
+            };
+            assert_eq!(translate(&va48, &mem, 0x1234, Read), Ok(0x8234));
+            let gva = 1 << 39 | 0x1234;
+            assert_eq!(
+                translate(&va48, &mem, gva, Read),
+                fault(gva, Read, FSC_TRANSLATION, 0)
+            );
+        }
+
+        // the MMU off doesn't translate
+        let off = GuestPageWalkInfo { sctlr: 0, ..info() };
+        assert_eq!(translate(&off, &mem, 0x1234, Read), Ok(0x1234));
+    }
+
+    #[test]
+    fn granules() {
+        use GuestAccess::*;
+        // 16K: a 36-bit VA starts at level 2, each table holding 2048 entries
+        let (memory, gpt) = ram(0x40000);
+        memory.write_obj(&gpt, 0x4000, &(0x8000 | TABLE)).unwrap();
+        memory
+            .write_obj(&gpt, 0x8000 + 8, &(0xc000 | PAGE_DESC))
+            .unwrap();
+        let mem = (memory, gpt);
+        let tg16 = GuestPageWalkInfo {
+            ttbr0: 0x4000,
+            tcr: 28 << TCR_T0SZ_SHIFT | 0b10 << TCR_TG0_SHIFT,
+            ..info()
+        };
+        assert_eq!(translate(&tg16, &mem, 0x5678, Read), Ok(0xd678));
+
+        // 64K: a 39-bit VA starts at level 2 too
+        let (memory, gpt) = ram(0x40000);
+        memory.write_obj(&gpt, 0x10000, &(0x20000 | TABLE)).unwrap();
+        memory
+            .write_obj(&gpt, 0x20000 + 8, &(0x30000 | PAGE_DESC))
+            .unwrap();
+        let mem = (memory, gpt);
+        let tg64 = GuestPageWalkInfo {
+            ttbr0: 0x10000,
+            tcr: 25 << TCR_T0SZ_SHIFT | 0b01 << TCR_TG0_SHIFT,
+            ..info()
+        };
+        assert_eq!(translate(&tg64, &mem, 0x1_2345, Read), Ok(0x3_2345));
+        // TG1 encodes 64K as 0b11
+        let tg64_upper = GuestPageWalkInfo {
+            ttbr1: 0x10000,
+            tcr: 25 << TCR_T1SZ_SHIFT | 0b11 << TCR_TG1_SHIFT,
+            ..info()
+        };
+        let gva = 0xffff_ff80_0001_2345;
+        assert_eq!(translate(&tg64_upper, &mem, gva, Read), Ok(0x3_2345));
+    }
+
+    #[test]
+    fn blocks() {
+        use GuestAccess::*;
+        let (memory, gpt) = tables(0);
+        memory
+            .write_obj(&gpt, L2 as usize + 8, &(0x60_0000 | BLOCK))
+            .unwrap();
+        memory
+            .write_obj(&gpt, L1 as usize + 8, &(0x8000_0000 | BLOCK))
+            .unwrap();
+        // a block descriptor at level 3 is reserved
+        memory
+            .write_obj(&gpt, L3 as usize + 16, &(PAGE | BLOCK))
+            .unwrap();
+        let mem = (memory, gpt);
+        assert_eq!(translate(&info(), &mem, 0x21_2345, Read), Ok(0x61_2345));
+        assert_eq!(translate(&info(), &mem, 0x4012_3456, Read), Ok(0x8012_3456));
+        assert_eq!(
+            translate(&info(), &mem, 0x2000, Read),
+            fault(0x2000, Read, FSC_TRANSLATION, 3)
+        );
+        // not valid
+        assert_eq!(
+            translate(&info(), &mem, 0x3000, Read),
+            fault(0x3000, Read, FSC_TRANSLATION, 3)
+        );
+    }
+
+    #[test]
+    fn access_permissions() {
+        use GuestAccess::*;
+        let user = GuestPageWalkInfo {
+            is_user_mode_access: true,
+            ..info()
+        };
+        let perm = |gva, access| fault(gva, access, FSC_PERMISSION, 3);
+
+        // an EL1 read-write page
+        let mem = tables(0);
+        assert_eq!(translate(&info(), &mem, 0x1000, Write), Ok(0x8000));
+        assert_eq!(translate(&user, &mem, 0x1000, Read), perm(0x1000, Read));
+        // read-only
+        let mem = tables(DESC_AP_RO);
+        assert_eq!(translate(&info(), &mem, 0x1000, Read), Ok(0x8000));
+        assert_eq!(translate(&info(), &mem, 0x1000, Write), perm(0x1000, Write));
+
+        // an EL0 page, closed to EL1 loads and stores with PAN
+        let mem = tables(DESC_AP_EL0 | DESC_PXN);
+        let pan = GuestPageWalkInfo {
+            pan: true,
+            ..info()
+        };
+        assert_eq!(translate(&user, &mem, 0x1000, Write), Ok(0x8000));
+        assert_eq!(translate(&info(), &mem, 0x1000, Read), Ok(0x8000));
+        assert_eq!(translate(&pan, &mem, 0x1000, Read), perm(0x1000, Read));
+        assert_eq!(translate(&user, &mem, 0x1000, Fetch), Ok(0x8000));
+        assert_eq!(translate(&info(), &mem, 0x1000, Fetch), perm(0x1000, Fetch));
+        let mem = tables(DESC_AP_EL0 | DESC_AP_RO | DESC_UXN);
+        assert_eq!(translate(&user, &mem, 0x1000, Write), perm(0x1000, Write));
+        assert_eq!(translate(&user, &mem, 0x1000, Fetch), perm(0x1000, Fetch));
+        // EL1 executes from an EL0 page only if EL0 can't write it
+        assert_eq!(translate(&info(), &mem, 0x1000, Fetch), Ok(0x8000));
+
+        // WXN makes writable pages execute-never
+        let wxn = GuestPageWalkInfo {
+            sctlr: SCTLR_M | SCTLR_WXN,
+            ..info()
+        };
+        let mem = tables(0);
+        assert_eq!(translate(&info(), &mem, 0x1000, Fetch), Ok(0x8000));
+        assert_eq!(translate(&wxn, &mem, 0x1000, Fetch), perm(0x1000, Fetch));
+    }
+
+    #[test]
+    fn table_permissions() {
+        use GuestAccess::*;
+        let user = GuestPageWalkInfo {
+            is_user_mode_access: true,
+            ..info()
+        };
+        let perm = |gva, access| fault(gva, access, FSC_PERMISSION, 3);
+        let (memory, gpt) = tables(DESC_AP_EL0);
+        let limits = DESC_AP_TABLE_NO_EL0 | DESC_AP_TABLE_RO | DESC_UXN_TABLE;
+        memory
+            .write_obj(&gpt, L1 as usize, &(L2 | TABLE | limits))
+            .unwrap();
+        let mem = (memory, gpt);
+        assert_eq!(translate(&user, &mem, 0x1000, Read), perm(0x1000, Read));
+        assert_eq!(translate(&info(), &mem, 0x1000, Write), perm(0x1000, Write));
+        // the page is no longer writable by EL0, so EL1 may run it
+        assert_eq!(translate(&info(), &mem, 0x1000, Fetch), Ok(0x8000));
+
+        // hierarchical permissions disabled
+        let hpd = GuestPageWalkInfo {
+            tcr: info().tcr | TCR_HPD0,
+            ..user
+        };
+        assert_eq!(translate(&hpd, &mem, 0x1000, Write), Ok(0x8000));
+        assert_eq!(translate(&hpd, &mem, 0x1000, Fetch), Ok(0x8000));
+    }
+
+    #[test]
+    fn access_flag() {
+        use GuestAccess::*;
+        let mem = tables(0);
+        let (memory, gpt) = &mem;
+        memory
+            .write_obj(gpt, L3 as usize + 8, &(PAGE | TABLE | DESC_AP_RO))
+            .unwrap();
+        // without hardware management, the access flag fault comes before the permission one
+        assert_eq!(
+            translate(&info(), &mem, 0x1000, Write),
+            fault(0x1000, Write, FSC_ACCESS_FLAG, 3)
+        );
+        let ha = GuestPageWalkInfo {
+            tcr: info().tcr | TCR_HA,
+            ..info()
+        };
+        assert_eq!(
+            translate(&ha, &mem, 0x1000, Read),
+            fault(0x1000, Read, FSC_ACCESS_FLAG, 3)
+        );
+
+        // with it, only on an allowed access the flag is set
+        let set = GuestPageWalkInfo {
+            set_accessed_dirty: true,
+            ..ha
+        };
+        assert_eq!(
+            translate(&set, &mem, 0x1000, Write),
+            fault(0x1000, Write, FSC_PERMISSION, 3)
+        );
+        assert_eq!(
+            memory.read_obj::<u64, _>(gpt, L3 as usize + 8),
+            Ok(PAGE | TABLE | DESC_AP_RO)
+        );
+        assert_eq!(translate(&set, &mem, 0x1000, Read), Ok(0x8000));
+        assert_eq!(
+            memory.read_obj::<u64, _>(gpt, L3 as usize + 8),
+            Ok(PAGE | PAGE_DESC | DESC_AP_RO)
+        );
+    }
+}
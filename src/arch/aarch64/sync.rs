@@ -1,66 +1,98 @@
 use crate::arch::exception::*;
 use crate::arch::hvc::hvc_guest_handler;
-use crate::arch::ContextFrame;
-use crate::traits::ContextFrameTrait;
+use crate::arch::hvc::{HVC_SYS, HVC_SYS_BOOT, HVC_VM_EXIT};
 use crate::arch::vcpu::VmCpuRegisters;
-use crate::arch::hvc::{HVC_SYS, HVC_SYS_BOOT};
+use crate::arch::{ContextFrame, EmuContext};
+use crate::traits::ContextFrameTrait;
+use crate::{mrs, msr};
 
 pub const HVC_RETURN_REG: usize = 0;
 
-pub fn data_abort_handler(ctx: &mut ContextFrame) {
-    /* 
-    let emu_ctx = EmuContext {
+/// Builds the emulation context of the data abort being handled from its syndrome, if the
+/// syndrome is valid and the abort is a translation fault. Loads and stores with writeback or
+/// of register pairs don't report a syndrome, the host decodes those itself.
+pub fn data_abort_emu_context() -> Option<EmuContext> {
+    if exception_iss() & (1 << 24) == 0 || !exception_data_abort_is_translate_fault() {
+        return None;
+    }
+    Some(EmuContext {
         address: exception_fault_addr(),
         width: exception_data_abort_access_width(),
         write: exception_data_abort_access_is_write(),
         sign_ext: exception_data_abort_access_is_sign_ext(),
         reg: exception_data_abort_access_reg(),
         reg_width: exception_data_abort_access_reg_width(),
-    };
-    */
-    let elr = ctx.exception_pc();
-
-    if !exception_data_abort_handleable() {
-        panic!(
-            "Data abort not handleable 0x{:x}, esr 0x{:x}",
-            exception_fault_addr(),
-            exception_esr()
-        );
-    }
+    })
+}
 
-    if !exception_data_abort_is_translate_fault() {
-        // No migrate need
-        panic!(
-            "Data abort is not translate fault 0x{:x}\n ctx: {}",
-            exception_fault_addr(), ctx
-        );           
-    }
-    /* 
-    if !emu_handler(&emu_ctx) {
-        active_vm().unwrap().show_pagetable(emu_ctx.address);
-        info!(
-            "write {}, width {}, reg width {}, addr {:x}, iss {:x}, reg idx {}, reg val 0x{:x}, esr 0x{:x}",
-            exception_data_abort_access_is_write(),
-            emu_ctx.width,
-            emu_ctx.reg_width,
-            emu_ctx.address,
-            exception_iss(),
-            emu_ctx.reg,
-            ctx.get_gpr(emu_ctx.reg),
-            exception_esr()
-        );
-        panic!(
-            "data_abort_handler: Failed to handler emul device request, ipa 0x{:x} elr 0x{:x}",
-            emu_ctx.address, elr
-        );
+pub fn data_abort_handler(ctx: &mut ContextFrame) {
+    if guest_running() {
+        // the host dispatches the access to its MMIO devices
+        record_vm_exit(ctx);
+        if data_abort_emu_context().is_some() {
+            let val = ctx.exception_pc() + exception_next_instruction_step();
+            ctx.set_exception_pc(val);
+        }
+        exit_to_host(ctx);
+        return;
     }
-    */
-    let val = elr + exception_next_instruction_step();
-    ctx.set_exception_pc(val);
+
+    // a stage-2 abort of the host itself, which has no device to emulate behind the address
+    warn!(
+        "Data abort of the host at 0x{:x}, esr 0x{:x}, elr 0x{:x}",
+        exception_fault_addr(),
+        exception_esr(),
+        ctx.exception_pc()
+    );
+    inject_host_abort(ctx);
+}
+
+/// Makes the host take the data abort being handled as a synchronous external abort at its own
+/// exception vector, as if stage 2 didn't exist.
+fn inject_host_abort(ctx: &mut ContextFrame) {
+    use cortex_a::registers::{ELR_EL1, FAR_EL1, FAR_EL2, SPSR_EL1, VBAR_EL1};
+    use tock_registers::interfaces::{Readable, Writeable};
+
+    const ESR_IL: u64 = 1 << 25;
+    const ESR_EC_SHIFT: u64 = 26;
+    const EC_DABT_LOWER: u64 = 0x24;
+    const EC_DABT_CUR: u64 = 0x25;
+    const ISS_WNR: u64 = 1 << 6;
+    const DFSC_SYNC_EXTERNAL: u64 = 0b010000;
+    const SPSR_MODE_MASK: u64 = 0b1111;
+    const SPSR_EL0T: u64 = 0b0000;
+    const SPSR_EL1T: u64 = 0b0100;
+
+    // the exception class and the offset of the synchronous exception vector
+    let (ec, vector) = match ctx.spsr & SPSR_MODE_MASK {
+        SPSR_EL0T => (EC_DABT_LOWER, 0x400),
+        SPSR_EL1T => (EC_DABT_CUR, 0x0),
+        _ => (EC_DABT_CUR, 0x200),
+    };
+    let wnr = exception_iss() as u64 & ISS_WNR;
+    let esr = (ec << ESR_EC_SHIFT) | ESR_IL | wnr | DFSC_SYNC_EXTERNAL;
+    msr!(ESR_EL1, esr);
+    FAR_EL1.set(FAR_EL2.get());
+    ELR_EL1.set(ctx.elr);
+    SPSR_EL1.set(ctx.spsr);
+    ctx.elr = VBAR_EL1.get() + vector;
+    ctx.spsr = (SPSR_EL1::M::EL1h
+        + SPSR_EL1::I::Masked
+        + SPSR_EL1::F::Masked
+        + SPSR_EL1::A::Masked
+        + SPSR_EL1::D::Masked)
+        .value;
 }
 
 #[inline(never)]
 pub fn hvc_handler(ctx: &mut ContextFrame) {
+    if guest_running() {
+        // hvc from the guest, the host decides what it means
+        record_vm_exit(ctx);
+        exit_to_host(ctx);
+        return;
+    }
+
     let x0 = ctx.gpr(0);
     let x1 = ctx.gpr(1);
     let x2 = ctx.gpr(2);
@@ -78,14 +110,17 @@ pub fn hvc_handler(ctx: &mut ContextFrame) {
             ctx.set_gpr(HVC_RETURN_REG, val);
         }
         Err(_) => {
-            warn!("Failed to handle hvc request fid 0x{:x} event 0x{:x}", hvc_type, event);
+            warn!(
+                "Failed to handle hvc request fid 0x{:x} event 0x{:x}",
+                hvc_type, event
+            );
             ctx.set_gpr(HVC_RETURN_REG, usize::MAX);
         }
     }
-    if hvc_type==HVC_SYS && event== HVC_SYS_BOOT {
+    if hvc_type == HVC_SYS && event == HVC_SYS_BOOT {
         unsafe {
-            let regs: &mut VmCpuRegisters = core::mem::transmute(x1);   // x1 is the vm regs context
-            // save arceos context
+            let regs: &mut VmCpuRegisters = core::mem::transmute(x1); // x1 is the vm regs context
+                                                                      // save arceos context
             regs.save_for_os_context_regs.gpr = ctx.gpr;
             regs.save_for_os_context_regs.sp = ctx.sp;
             regs.save_for_os_context_regs.elr = ctx.elr;
@@ -96,5 +131,45 @@ pub fn hvc_handler(ctx: &mut ContextFrame) {
             ctx.elr = regs.guest_trap_context_regs.elr;
             ctx.spsr = regs.guest_trap_context_regs.spsr;
         }
+        // remember which vcpu runs on this cpu, so that its exits can be recorded
+        msr!(TPIDR_EL2, x1);
     }
 }
+
+/// Initializes the EL2 state of this cpu. It must run at EL2 on every cpu before the host can
+/// take an HVC or an abort to EL2: TPIDR_EL2 says which vcpu runs on the cpu, and is UNKNOWN at
+/// reset.
+pub fn init_el2() {
+    msr!(TPIDR_EL2, 0usize);
+}
+
+/// Whether the exception was taken from a guest, i.e. a vcpu has been booted on this cpu
+/// and has not exited to the host yet.
+pub fn guest_running() -> bool {
+    let regs_addr: usize;
+    mrs!(regs_addr, TPIDR_EL2);
+    regs_addr != 0
+}
+
+/// Leave the guest and return to the host which booted it: the guest context is saved to its
+/// `VmCpuRegisters`, and the `HVC_SYS_BOOT` call of the host returns `HVC_VM_EXIT`.
+pub fn exit_to_host(ctx: &mut ContextFrame) {
+    let regs_addr: usize;
+    mrs!(regs_addr, TPIDR_EL2);
+    let regs = unsafe { &mut *(regs_addr as *mut VmCpuRegisters) };
+    // save guest context
+    regs.guest_trap_context_regs.gpr = ctx.gpr;
+    regs.guest_trap_context_regs.sp = ctx.sp;
+    regs.guest_trap_context_regs.elr = ctx.elr;
+    regs.guest_trap_context_regs.spsr = ctx.spsr;
+    regs.vm_system_regs.ext_regs_store();
+
+    // back to arceos
+    regs.host_system_regs.ext_regs_restore();
+    ctx.gpr = regs.save_for_os_context_regs.gpr;
+    ctx.sp = regs.save_for_os_context_regs.sp;
+    ctx.elr = regs.save_for_os_context_regs.elr;
+    ctx.spsr = regs.save_for_os_context_regs.spsr;
+    ctx.set_gpr(HVC_RETURN_REG, HVC_VM_EXIT);
+    msr!(TPIDR_EL2, 0usize);
+}
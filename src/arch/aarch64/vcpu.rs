@@ -1,19 +1,23 @@
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-use core::mem::size_of;
 use core::arch::global_asm;
-use spin::Mutex;
 use core::marker::PhantomData;
+use core::mem::size_of;
+use spin::Mutex;
 
 // type ContextFrame = crate::arch::contextFrame::Aarch64ContextFrame;
 use cortex_a::registers::*;
 use tock_registers::interfaces::*;
- 
-use crate::arch::ContextFrame;
+
 use crate::arch::context_frame::VmContext;
+use crate::arch::hvc::{run_guest_by_trap2el2, HVC_VM_EXIT};
+use crate::arch::ContextFrame;
+use crate::arch::GuestPageWalkInfo;
 use crate::traits::ContextFrameTrait;
-use crate::HyperCraftHal;
-use crate::arch::hvc::run_guest_by_trap2el2;
+use crate::{
+    GuestAccess, GuestFault, GuestPhysAddr, GuestVirtAddr, HyperCraftHal, HyperError, HyperResult,
+    PendingRead, SnapshotReader, SnapshotWriter, VCpuTrait, VmExitInfo,
+};
 
 /// (v)CPU register state that must be saved or restored when entering/exiting a VM or switching
 /// between VMs.
@@ -26,6 +30,10 @@ pub struct VmCpuRegisters {
     pub save_for_os_context_regs: ContextFrame,
     /// virtual machine system regs setting
     pub vm_system_regs: VmContext,
+    /// arceos system regs, saved while the guest runs
+    pub host_system_regs: VmContext,
+    /// the last exception taken from the guest, recorded by the EL2 handler
+    pub exit_info: Option<VmExitInfo>,
 }
 
 impl VmCpuRegisters {
@@ -35,17 +43,22 @@ impl VmCpuRegisters {
             guest_trap_context_regs: ContextFrame::default(),
             save_for_os_context_regs: ContextFrame::default(),
             vm_system_regs: VmContext::default(),
+            host_system_regs: VmContext::default(),
+            exit_info: None,
         }
     }
 }
 
 /// A virtual CPU within a guest
 #[derive(Clone)]
-pub struct VCpu<H:HyperCraftHal> {
+pub struct VCpu<H: HyperCraftHal> {
     /// Vcpu id
     pub vcpu_id: usize,
     /// Vcpu context
     pub regs: VmCpuRegisters,
+    pending_read: Option<PendingRead>,
+    entry: GuestPhysAddr,
+    boot_arg: usize,
     // pub vcpu_ctx: ContextFrame,
     // pub vm_ctx: VmContext,
     // pub vm: Option<Vm>,
@@ -53,12 +66,15 @@ pub struct VCpu<H:HyperCraftHal> {
     marker: PhantomData<H>,
 }
 
-impl <H:HyperCraftHal> VCpu<H> {
+impl<H: HyperCraftHal> VCpu<H> {
     /// Create a new vCPU
     pub fn new(id: usize) -> Self {
         Self {
             vcpu_id: id,
             regs: VmCpuRegisters::default(),
+            pending_read: None,
+            entry: 0,
+            boot_arg: 0,
             // vcpu_ctx: ContextFrame::default(),
             // vm_ctx: VmContext::default(),
             // vm: None,
@@ -67,48 +83,63 @@ impl <H:HyperCraftHal> VCpu<H> {
         }
     }
 
-    /// Init Vcpu registers
-    pub fn init(&mut self, kernel_entry_point: usize, device_tree_ipa: usize) {
-        self.vcpu_arch_init(kernel_entry_point, device_tree_ipa);
-        self.init_vm_context();
+    /// Run this vcpu until the guest traps to EL2 with an exception EL2 does not handle itself.
+    pub fn run(&mut self, vttbr_token: usize) -> HyperResult<VmExitInfo> {
+        self.regs.exit_info = None;
+        match run_guest_by_trap2el2(vttbr_token, self.vcpu_ctx_addr()) {
+            HVC_VM_EXIT => self.regs.exit_info.ok_or(HyperError::BadState),
+            _ => Err(HyperError::Internal),
+        }
     }
 
-    /// Get vcpu id
-    pub fn vcpu_id(&self) -> usize {
-        self.vcpu_id
+    /// Remember where the data of an emulated read goes once the VMM supplies it.
+    pub(crate) fn set_pending_read(&mut self, pending: PendingRead) {
+        self.pending_read = Some(pending);
     }
 
-    /// Run this vcpu
-    pub fn run(&self, vttbr_token: usize) -> ! {
-        loop {  // because of elr_el2, it will not return to this?
-            _ = run_guest_by_trap2el2(vttbr_token, self.vcpu_ctx_addr());
-        }
+    /// Whether an emulated read is waiting for [`VCpuTrait::complete_read`].
+    pub(crate) fn has_pending_read(&self) -> bool {
+        self.pending_read.is_some()
     }
-    
+
     /// Get vcpu whole context address
     pub fn vcpu_ctx_addr(&self) -> usize {
         &(self.regs) as *const _ as usize
     }
-    
+
     /// Get vcpu trap context for guest or arceos
     pub fn vcpu_trap_ctx_addr(&self, if_guest: bool) -> usize {
         if if_guest {
             &(self.regs.guest_trap_context_regs) as *const _ as usize
-        }else {
+        } else {
             &(self.regs.save_for_os_context_regs) as *const _ as usize
         }
     }
 
+    /// Gets what a walk of the guest stage-1 tables needs. It works from the saved EL1 state,
+    /// so the vCPU need not be loaded on this CPU.
+    pub fn get_ptw_info(&self) -> GuestPageWalkInfo {
+        const SPSR_MODE_MASK: u64 = 0b1111;
+        const SPSR_PAN: u64 = 1 << 22;
+
+        let sys = &self.regs.vm_system_regs;
+        let spsr = self.regs.guest_trap_context_regs.spsr;
+        GuestPageWalkInfo {
+            ttbr0: sys.ttbr0_el1,
+            ttbr1: sys.ttbr1_el1,
+            tcr: sys.tcr_el1,
+            sctlr: sys.sctlr_el1 as u64,
+            is_user_mode_access: spsr & SPSR_MODE_MASK == 0,
+            pan: spsr & SPSR_PAN != 0,
+            set_accessed_dirty: false,
+        }
+    }
+
     /// Set exception return pc
     pub fn set_elr(&mut self, elr: usize) {
         self.regs.guest_trap_context_regs.set_exception_pc(elr);
     }
 
-    /// Set general purpose register
-    pub fn set_gpr(&mut self, idx: usize, val: usize) {
-        self.regs.guest_trap_context_regs.set_gpr(idx, val);
-    }
-
     /// Init guest context. Also set some el2 register value.
     fn init_vm_context(&mut self) {
         self.regs.vm_system_regs.cntvoff_el2 = 0;
@@ -116,21 +147,22 @@ impl <H:HyperCraftHal> VCpu<H> {
         self.regs.vm_system_regs.cntkctl_el1 = 0;
         self.regs.vm_system_regs.pmcr_el0 = 0;
         // self.regs.vm_system_regs.vtcr_el2 = 0x8001355c;
-        self.regs.vm_system_regs.vtcr_el2 = (VTCR_EL2::PS::PA_36B_64GB   //0b001 36 bits, 64GB.
+        self.regs.vm_system_regs.vtcr_el2 =
+            (VTCR_EL2::PS::PA_36B_64GB   //0b001 36 bits, 64GB.
                                           + VTCR_EL2::TG0::Granule4KB
                                           + VTCR_EL2::SH0::Inner
                                           + VTCR_EL2::ORGN0::NormalWBRAWA
                                           + VTCR_EL2::IRGN0::NormalWBRAWA
                                           + VTCR_EL2::SL0.val(0b01)
-                                          + VTCR_EL2::T0SZ.val(64 - 36)).into();
+                                          + VTCR_EL2::T0SZ.val(64 - 36))
+            .into();
         //self.regs.vm_system_regs.hcr_el2 = 0x80000001;  // Maybe we do not need smc setting? passthrough gic.
-        self.regs.vm_system_regs.hcr_el2 = (HCR_EL2::VM::Enable
-                                         + HCR_EL2::RW::EL1IsAarch64).into();
+        self.regs.vm_system_regs.hcr_el2 = (HCR_EL2::VM::Enable + HCR_EL2::RW::EL1IsAarch64).into();
         let mut vmpidr = 0;
         vmpidr |= 1 << 31;
         vmpidr |= self.vcpu_id;
         self.regs.vm_system_regs.vmpidr_el2 = vmpidr as u64;
-        
+
         // self.gic_ctx_reset(); // because of passthrough gic, do not need gic context anymore?
     }
 
@@ -138,12 +170,169 @@ impl <H:HyperCraftHal> VCpu<H> {
     fn vcpu_arch_init(&mut self, kernel_entry_point: usize, device_tree_ipa: usize) {
         self.set_gpr(0, device_tree_ipa);
         self.set_elr(kernel_entry_point);
-        self.regs.guest_trap_context_regs.spsr =( SPSR_EL1::M::EL1h + 
-                                            SPSR_EL1::I::Masked + 
-                                            SPSR_EL1::F::Masked + 
-                                            SPSR_EL1::A::Masked + 
-                                            SPSR_EL1::D::Masked )
-                                            .value;
+        self.regs.guest_trap_context_regs.spsr = (SPSR_EL1::M::EL1h
+            + SPSR_EL1::I::Masked
+            + SPSR_EL1::F::Masked
+            + SPSR_EL1::A::Masked
+            + SPSR_EL1::D::Masked)
+            .value;
+    }
+}
+
+impl<H: HyperCraftHal> VCpuTrait for VCpu<H> {
+    fn vcpu_id(&self) -> usize {
+        self.vcpu_id
     }
 
+    /// Init Vcpu registers. `boot_arg` is the device tree ipa, passed in x0.
+    fn init(&mut self, entry: GuestPhysAddr, boot_arg: usize) -> HyperResult {
+        self.vcpu_arch_init(entry, boot_arg);
+        self.init_vm_context();
+        self.entry = entry;
+        self.boot_arg = boot_arg;
+        Ok(())
+    }
+
+    fn pc(&self) -> GuestVirtAddr {
+        self.regs.guest_trap_context_regs.exception_pc()
+    }
+
+    fn set_pc(&mut self, pc: GuestVirtAddr) {
+        self.set_elr(pc);
+    }
+
+    fn gpr(&self, index: usize) -> usize {
+        self.regs.guest_trap_context_regs.gpr(index)
+    }
+
+    fn set_gpr(&mut self, index: usize, val: usize) {
+        self.regs.guest_trap_context_regs.set_gpr(index, val);
+    }
+
+    fn exit_info(&self) -> HyperResult<VmExitInfo> {
+        self.regs.exit_info.ok_or(HyperError::BadState)
+    }
+
+    fn complete_read(&mut self, data: u64) -> HyperResult {
+        let pending = self.pending_read.take().ok_or(HyperError::BadState)?;
+        // x31 is the zero register here
+        if pending.reg != 31 {
+            let old = self.gpr(pending.reg);
+            self.set_gpr(pending.reg, pending.apply(old, data));
+        }
+        Ok(())
+    }
+    /// Take a synchronous exception to the guest's EL1: an unknown-reason exception for illegal
+    /// instructions, a synchronous external abort for access faults and a data or instruction
+    /// abort carrying the fault status code of the walk for page faults.
+    fn inject_fault(&mut self, fault: GuestFault) -> HyperResult {
+        const ESR_IL: u32 = 1 << 25;
+        const ESR_EC_SHIFT: u32 = 26;
+        const EC_DABT_LOWER: u32 = 0x24;
+        const EC_DABT_CUR: u32 = 0x25;
+        const EC_IABT_LOWER: u32 = 0x20;
+        const EC_IABT_CUR: u32 = 0x21;
+        const ISS_FSC_MASK: u32 = 0x3f;
+        const ISS_WNR: u32 = 1 << 6;
+        const DFSC_SYNC_EXTERNAL: u32 = 0b010000;
+        const SPSR_MODE_MASK: u64 = 0b1111;
+        const SPSR_EL0T: u64 = 0b0000;
+        const SPSR_EL1T: u64 = 0b0100;
+
+        let far = self.regs.exit_info.map_or(0, |info| info.far);
+        let ctx = &mut self.regs.guest_trap_context_regs;
+        let sys = &mut self.regs.vm_system_regs;
+        let from_el0 = ctx.spsr & SPSR_MODE_MASK == SPSR_EL0T;
+        sys.esr_el1 = match fault {
+            GuestFault::IllegalInstruction { .. } | GuestFault::GeneralProtection { .. } => ESR_IL,
+            GuestFault::AccessFault { access, .. } => {
+                sys.far_el1 = far as u64;
+                let ec = match (access, from_el0) {
+                    (GuestAccess::Fetch, true) => EC_IABT_LOWER,
+                    (GuestAccess::Fetch, false) => EC_IABT_CUR,
+                    (_, true) => EC_DABT_LOWER,
+                    (_, false) => EC_DABT_CUR,
+                };
+                let wnr = if access == GuestAccess::Write {
+                    ISS_WNR
+                } else {
+                    0
+                };
+                // also the instruction fault status code of a synchronous external abort
+                (ec << ESR_EC_SHIFT) | ESR_IL | wnr | DFSC_SYNC_EXTERNAL
+            }
+            GuestFault::PageFault { fault, .. } => {
+                sys.far_el1 = fault.addr as u64;
+                let ec = match (fault.access, from_el0) {
+                    (GuestAccess::Fetch, true) => EC_IABT_LOWER,
+                    (GuestAccess::Fetch, false) => EC_IABT_CUR,
+                    (_, true) => EC_DABT_LOWER,
+                    (_, false) => EC_DABT_CUR,
+                };
+                let wnr = if fault.access == GuestAccess::Write {
+                    ISS_WNR
+                } else {
+                    0
+                };
+                (ec << ESR_EC_SHIFT) | ESR_IL | wnr | (fault.error_code & ISS_FSC_MASK)
+            }
+        };
+        // offset of the synchronous exception vector
+        let vector = match ctx.spsr & SPSR_MODE_MASK {
+            SPSR_EL0T => 0x400,
+            SPSR_EL1T => 0x0,
+            _ => 0x200,
+        };
+        sys.elr_el1 = ctx.elr;
+        sys.spsr_el1 = ctx.spsr as u32;
+        ctx.elr = sys.vbar_el1 + vector;
+        ctx.spsr = (SPSR_EL1::M::EL1h
+            + SPSR_EL1::I::Masked
+            + SPSR_EL1::F::Masked
+            + SPSR_EL1::A::Masked
+            + SPSR_EL1::D::Masked)
+            .value;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> HyperResult {
+        self.regs.guest_trap_context_regs = ContextFrame::default();
+        self.regs.vm_system_regs = VmContext::default();
+        self.regs.exit_info = None;
+        self.pending_read = None;
+        self.init(self.entry, self.boot_arg)
+    }
+
+    /// Save the guest trap context and the EL1/EL2 system registers, which already hold the
+    /// guest values since the last exit.
+    fn save_state(&self, w: &mut SnapshotWriter) -> HyperResult {
+        if self.has_pending_read() {
+            return Err(HyperError::BadState);
+        }
+        self.regs.guest_trap_context_regs.write_snapshot(w);
+        self.regs.vm_system_regs.write_snapshot(w);
+        Ok(())
+    }
+
+    fn load_state(&mut self, r: &mut SnapshotReader) -> HyperResult {
+        (self.regs.guest_trap_context_regs, self.regs.vm_system_regs) = self.read_state(r)?;
+        self.regs.exit_info = None;
+        self.pending_read = None;
+        Ok(())
+    }
+}
+
+impl<H: HyperCraftHal> VCpu<H> {
+    /// Reads the guest trap context and system registers written by [`VCpuTrait::save_state`]
+    /// without loading them.
+    pub(crate) fn read_state(
+        &self,
+        r: &mut SnapshotReader,
+    ) -> HyperResult<(ContextFrame, VmContext)> {
+        let mut trap_context = self.regs.guest_trap_context_regs;
+        trap_context.read_snapshot(r)?;
+        let mut system_regs = self.regs.vm_system_regs.clone();
+        system_regs.read_snapshot(r)?;
+        Ok((trap_context, system_regs))
+    }
 }
@@ -1,38 +1,428 @@
-use crate::{HyperCraftHal, GuestPageTableTrait, VmCpus, HyperResult};
+use super::emulate::{decode_load_store, EmuContext};
+use crate::guest_memory::SavedRegions;
+use crate::snapshot::{SECTION_MEMORY, SECTION_MMIO_DEVICES, SECTION_VCPU};
+use crate::{
+    handle_guest_fault, AtomicVmState, GuestAccess, GuestFault, GuestFaultPolicy, GuestMemory,
+    GuestMemoryRegion, GuestPageTableTrait, GuestPhysAddr, GuestVirtAddr, HostVirtAddr,
+    HyperCraftHal, HyperError, HyperResult, MmioBus, MmioOps, SnapshotReader, SnapshotWriter, VCpu,
+    VCpuGuard, VCpuTrait, VmCpus, VmExit, VmExitInfo, VmState, VmTrait,
+};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use page_table_entry::MappingFlags;
+use spin::Mutex;
+
+/// PSCI `SYSTEM_OFF` function ID.
+const PSCI_SYSTEM_OFF: usize = 0x8400_0008;
+/// PSCI `SYSTEM_RESET` function ID.
+const PSCI_SYSTEM_RESET: usize = 0x8400_0009;
 
 /// The guest VM
 #[repr(align(4096))]
 pub struct VM<H: HyperCraftHal, G: GuestPageTableTrait> {
     /// The vcpus belong to VM
     vcpus: VmCpus<H>,
-    /// The guest page table of VM
-    gpt: G,
+    /// The guest page table of VM, locked to unprotect pages written while dirty logging
+    gpt: Mutex<G>,
     /// VM id
     vm_id: usize,
+    /// Emulated MMIO devices
+    mmio_bus: MmioBus,
+    /// How guest-triggered faults are handled
+    fault_policy: GuestFaultPolicy,
+    /// Lifecycle state
+    state: AtomicVmState,
+    /// Guest RAM owned by the VM
+    memory: GuestMemory<H>,
 }
 
-impl <H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
+impl<H: HyperCraftHal, G: GuestPageTableTrait> VmTrait<H, G> for VM<H, G> {
     /// Create a new VM
-    pub fn new(vcpus: VmCpus<H>, gpt: G, id: usize)-> HyperResult<Self> {
-        Ok(Self { 
-                vcpus: vcpus, 
-                gpt: gpt, 
-                vm_id: id
-            }
-        )
+    fn new(vcpus: VmCpus<H>, gpt: G, vm_id: usize) -> HyperResult<Self> {
+        Ok(Self {
+            vcpus: vcpus,
+            gpt: Mutex::new(gpt),
+            vm_id: vm_id,
+            mmio_bus: MmioBus::new(),
+            fault_policy: GuestFaultPolicy::default(),
+            state: AtomicVmState::new(VmState::Created),
+            memory: GuestMemory::new(),
+        })
     }
 
-    /// Init VM vcpu by vcpu id. Set kernel entry point.
-    pub fn init_vm_vcpu(&mut self, vcpu_id:usize, kernel_entry_point: usize, device_tree_ipa: usize) {
-        let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
-        vcpu.init(kernel_entry_point, device_tree_ipa);
+    fn vm_id(&self) -> usize {
+        self.vm_id
+    }
+
+    /// Init VM vcpu by vcpu id. Set kernel entry point and the device tree ipa.
+    fn init_vcpu(
+        &self,
+        vcpu_id: usize,
+        kernel_entry_point: GuestPhysAddr,
+        device_tree_ipa: usize,
+    ) -> HyperResult {
+        if self.state.is_destroyed() {
+            return Err(HyperError::BadState);
+        }
+        let mut vcpu = self.vcpus.lock_vcpu(vcpu_id)?;
+        vcpu.init(kernel_entry_point, device_tree_ipa)
     }
 
     /// Run this VM.
-    pub fn run(&mut self, vcpu_id: usize) {
-        let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
-        let vttbr_token = (self.vm_id << 48) | self.gpt.token();
-        debug!("vttbr_token: 0x{:X}", self.gpt.token());
-        vcpu.run(vttbr_token);
+    fn run_vcpu(&self, vcpu_id: usize) -> HyperResult<VmExit> {
+        let mut guard = self.vcpus.lock_vcpu(vcpu_id)?;
+        let vcpu = &mut *guard;
+        if vcpu.has_pending_read() {
+            return Err(HyperError::BadState);
+        }
+        self.state.enter_guest()?;
+        let token = self.gpt.lock().token();
+        let vttbr_token = (self.vm_id << 48) | token;
+        debug!("vttbr_token: 0x{:X}", token);
+        loop {
+            if let Some(exit) = self.state.stop_exit() {
+                return Ok(exit);
+            }
+            let exit_info = vcpu.run(vttbr_token)?;
+            // a write to a page protected for dirty logging, EL2 flushes the stale entry on the
+            // next entry and the guest retries the write
+            if exit_info.is_permission_fault()
+                && exit_info.is_write()
+                && self
+                    .memory
+                    .handle_write_fault(&mut *self.gpt.lock(), exit_info.fault_addr)?
+            {
+                continue;
+            }
+            // a load or store without a syndrome, decode it here
+            let exit = if exit_info.is_translation_fault() && exit_info.emu_ctx.is_none() {
+                match self.emulate_load_store(vcpu, &exit_info)? {
+                    Some(exit) => exit,
+                    None => continue,
+                }
+            } else {
+                translate_exit(vcpu, exit_info)
+            };
+            match exit {
+                VmExit::GuestFault(fault) => {
+                    if let Some(exit) = handle_guest_fault(self.fault_policy, vcpu, fault)? {
+                        return Ok(exit);
+                    }
+                }
+                VmExit::Shutdown => {
+                    self.state.store(VmState::Shutdown);
+                    return Ok(VmExit::Shutdown);
+                }
+                exit => {
+                    if let Some(exit) = self.mmio_bus.dispatch(vcpu, exit) {
+                        return Ok(exit);
+                    }
+                }
+            }
+        }
+    }
+
+    fn vcpu(&mut self, vcpu_id: usize) -> HyperResult<&mut VCpu<H>> {
+        self.vcpus.get_vcpu(vcpu_id)
+    }
+
+    fn lock_vcpu(&self, vcpu_id: usize) -> HyperResult<VCpuGuard<'_, VCpu<H>>> {
+        self.vcpus.lock_vcpu(vcpu_id)
+    }
+
+    fn add_vcpu(&mut self, vcpu: VCpu<H>) -> HyperResult {
+        if self.state.is_destroyed() {
+            return Err(HyperError::BadState);
+        }
+        self.vcpus.add_vcpu(vcpu)
+    }
+
+    fn remove_vcpu(&mut self, vcpu_id: usize) -> HyperResult<VCpu<H>> {
+        self.vcpus.remove_vcpu(vcpu_id)
+    }
+
+    fn register_mmio_device(&mut self, device: Arc<Mutex<dyn MmioOps>>) -> HyperResult {
+        self.mmio_bus.register(device)
+    }
+
+    fn unregister_mmio_device(&mut self, base: u64) -> HyperResult<Arc<Mutex<dyn MmioOps>>> {
+        self.mmio_bus.unregister(base)
+    }
+
+    fn set_fault_policy(&mut self, policy: GuestFaultPolicy) {
+        self.fault_policy = policy;
+    }
+
+    fn state(&self) -> VmState {
+        self.state.load()
+    }
+
+    fn pause(&self) -> HyperResult {
+        self.state.pause()
+    }
+
+    fn resume(&self) -> HyperResult {
+        self.state.resume()
+    }
+
+    fn reset(&mut self) -> HyperResult {
+        self.state.reset()?;
+        for vcpu_id in 0..self.vcpus.capacity() {
+            if let Ok(vcpu) = self.vcpus.get_vcpu(vcpu_id) {
+                vcpu.reset()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn destroy(&mut self) -> HyperResult {
+        if self.state.is_destroyed() {
+            return Err(HyperError::BadState);
+        }
+        self.state.store(VmState::Destroyed);
+        self.vcpus.clear();
+        self.memory.release(self.gpt.get_mut());
+        Ok(())
+    }
+
+    fn alloc_memory(
+        &mut self,
+        gpa: GuestPhysAddr,
+        size: usize,
+        flags: MappingFlags,
+    ) -> HyperResult<HostVirtAddr> {
+        if self.state.is_destroyed() {
+            return Err(HyperError::BadState);
+        }
+        self.memory
+            .alloc_region(self.gpt.get_mut(), gpa, size, flags)
+    }
+
+    fn save_snapshot(&mut self) -> HyperResult<Vec<u8>> {
+        let mut w = SnapshotWriter::new();
+        self.save_vcpu_state(&mut w)?;
+        self.save_device_state(&mut w)?;
+        self.memory.save(&mut w)?;
+        Ok(w.into_bytes())
+    }
+
+    fn restore_snapshot(&mut self, data: &[u8]) -> HyperResult {
+        self.state.ensure_stopped()?;
+        // check the snapshot before the guest memory is gone
+        self.check_snapshot(data)?;
+        self.memory.release(self.gpt.get_mut());
+        self.load_state(data)
+    }
+
+    fn save_vcpu_state(&mut self, w: &mut SnapshotWriter) -> HyperResult {
+        self.state.ensure_stopped()?;
+        for vcpu_id in 0..self.vcpus.capacity() {
+            if let Ok(vcpu) = self.vcpus.get_vcpu(vcpu_id) {
+                w.section(SECTION_VCPU, |w| {
+                    w.put_usize(vcpu_id);
+                    vcpu.save_state(w)
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The virtual GIC state is part of each vCPU, the other devices are those on the MMIO
+    /// bus.
+    fn save_device_state(&mut self, w: &mut SnapshotWriter) -> HyperResult {
+        self.state.ensure_stopped()?;
+        w.section(SECTION_MMIO_DEVICES, |w| self.mmio_bus.save_state(w))
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> HyperResult {
+        self.state.ensure_stopped()?;
+        let mut r = SnapshotReader::new(data)?;
+        while let Some((tag, mut section)) = r.next_section()? {
+            match tag {
+                SECTION_VCPU => {
+                    let vcpu_id = section.get_usize()?;
+                    self.vcpus.get_vcpu(vcpu_id)?.load_state(&mut section)?;
+                }
+                SECTION_MMIO_DEVICES => self.mmio_bus.load_state(&mut section)?,
+                SECTION_MEMORY => self.memory.load_region(self.gpt.get_mut(), &mut section)?,
+                // written by a later version, skip it
+                _ => {}
+            }
+        }
+        self.state.store(VmState::Paused);
+        Ok(())
+    }
+
+    fn memory_regions(&self) -> &[GuestMemoryRegion] {
+        self.memory.regions()
+    }
+
+    /// Maps guest RAM read-only at stage 2 until the guest writes each page. Every entry to
+    /// the guest flushes its stage-2 TLB entries, so no flush is needed here.
+    fn enable_dirty_log(&mut self) -> HyperResult {
+        if self.state.is_destroyed() {
+            return Err(HyperError::BadState);
+        }
+        self.memory.start_dirty_log(self.gpt.get_mut(), true)
+    }
+
+    fn disable_dirty_log(&mut self) -> HyperResult {
+        self.memory.stop_dirty_log(self.gpt.get_mut())
+    }
+
+    fn get_dirty_log(&mut self, gpa: GuestPhysAddr, size: usize) -> HyperResult<Vec<u64>> {
+        self.memory.take_dirty_log(self.gpt.get_mut(), gpa, size)
+    }
+}
+
+// Private methods implementation
+impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
+    /// Checks that the vCPU, MMIO device and memory sections of `data` are well-formed and can
+    /// be loaded into this VM.
+    fn check_snapshot(&mut self, data: &[u8]) -> HyperResult {
+        let mut r = SnapshotReader::new(data)?;
+        let mut regions = SavedRegions::default();
+        while let Some((tag, mut section)) = r.next_section()? {
+            match tag {
+                SECTION_VCPU => {
+                    let vcpu_id = section.get_usize()?;
+                    self.vcpus.get_vcpu(vcpu_id)?.read_state(&mut section)?;
+                }
+                SECTION_MMIO_DEVICES => self.mmio_bus.check_state(&mut section)?,
+                SECTION_MEMORY => regions.check(&mut section)?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Decodes the load or store at the pc of a data abort without a syndrome, and steps over
+    /// it. A single transfer becomes an MMIO exit, the transfers of a pair are dispatched to
+    /// the MMIO bus here. Returns `None` if there is nothing left to do.
+    fn emulate_load_store(
+        &self,
+        vcpu: &mut VCpu<H>,
+        exit_info: &VmExitInfo,
+    ) -> HyperResult<Option<VmExit>> {
+        let fault = VmExit::GuestFault(GuestFault::AccessFault {
+            pc: exit_info.pc,
+            addr: exit_info.fault_addr,
+            access: exit_info.data_access(),
+        });
+        let Ok(decoded) = self
+            .fetch_instruction(vcpu, exit_info.pc)
+            .and_then(decode_load_store)
+        else {
+            return Ok(Some(fault));
+        };
+        let rn = decoded.rn;
+        let mut transfers = decoded.transfers;
+        // SP is not among the saved general purpose registers
+        if rn == 31 && (transfers.len() > 1 || decoded.writeback.is_some()) {
+            return Ok(Some(fault));
+        }
+        // the abort may be on either transfer of a pair, find the address of the first one
+        let base = if transfers.len() > 1 {
+            let va = vcpu.gpr(rn).wrapping_add_signed(decoded.offset);
+            exit_info
+                .fault_addr
+                .wrapping_sub(exit_info.far.wrapping_sub(va))
+        } else {
+            exit_info.fault_addr
+        };
+        for transfer in transfers.iter_mut() {
+            transfer.address = transfer.address.wrapping_add(base);
+        }
+        let exit = match transfers.as_slice() {
+            [transfer] => Some(mmio_exit(vcpu, transfer)),
+            pair => {
+                if !pair
+                    .iter()
+                    .all(|t| self.mmio_bus.contains(t.address as u64))
+                {
+                    return Ok(Some(fault));
+                }
+                for transfer in pair {
+                    let exit = mmio_exit(vcpu, transfer);
+                    if let Some(exit) = self.mmio_bus.dispatch(vcpu, exit) {
+                        return Ok(Some(exit));
+                    }
+                }
+                None
+            }
+        };
+        if let Some(imm) = decoded.writeback {
+            vcpu.set_gpr(rn, vcpu.gpr(rn).wrapping_add_signed(imm));
+        }
+        vcpu.set_pc(exit_info.pc + 4);
+        Ok(exit)
+    }
+
+    /// Fetches the instruction at `pc` by walking the guest stage-1 tables.
+    fn fetch_instruction(&self, vcpu: &VCpu<H>, pc: GuestVirtAddr) -> HyperResult<u32> {
+        let mut inst = [0u8; 4];
+        self.memory
+            .fetch_gva(&*self.gpt.lock(), &vcpu.get_ptw_info(), pc, &mut inst)?;
+        Ok(u32::from_le_bytes(inst))
+    }
+}
+
+/// The MMIO exit of one transfer of a load or store, remembering where the data of a load goes.
+fn mmio_exit<H: HyperCraftHal>(vcpu: &mut VCpu<H>, emu_ctx: &EmuContext) -> VmExit {
+    let addr = emu_ctx.address;
+    let size = emu_ctx.width as u8;
+    if emu_ctx.write {
+        VmExit::MmioWrite {
+            addr,
+            size,
+            data: emu_ctx.write_data(vcpu.gpr(emu_ctx.reg)),
+        }
+    } else {
+        vcpu.set_pending_read(emu_ctx.pending_read());
+        VmExit::MmioRead { addr, size }
+    }
+}
+
+/// Translate a [`VmExitInfo`] into an arch-independent [`VmExit`], remembering where the data
+/// of a load goes. EL2 has already stepped over WFI and decodable data aborts.
+fn translate_exit<H: HyperCraftHal>(vcpu: &mut VCpu<H>, exit_info: VmExitInfo) -> VmExit {
+    let gpr = |index: usize| if index == 31 { 0 } else { vcpu.gpr(index) };
+    match exit_info.exception_class() {
+        // Unknown reason, e.g. an undefined instruction
+        0x00 => VmExit::GuestFault(GuestFault::IllegalInstruction { pc: exit_info.pc }),
+        // WFI/WFE
+        0x01 => VmExit::Halt,
+        // HVC from aarch64: PSCI calls, otherwise a hypercall with its number in x7.
+        0x16 => match gpr(0) {
+            PSCI_SYSTEM_OFF => VmExit::Shutdown,
+            PSCI_SYSTEM_RESET => VmExit::Reset,
+            func if func & 0xff00_0000 == 0x8400_0000 || func & 0xff00_0000 == 0xc400_0000 => {
+                VmExit::SystemEvent {
+                    event: func as u32,
+                    data: gpr(1) as u64,
+                }
+            }
+            _ => VmExit::Hypercall {
+                nr: gpr(7),
+                args: core::array::from_fn(gpr),
+            },
+        },
+        // Data abort from a lower EL, with the access recorded by EL2 from the syndrome
+        0x24 => match exit_info.emu_ctx {
+            Some(emu_ctx) => mmio_exit(vcpu, &emu_ctx),
+            // not a translation fault
+            None => VmExit::GuestFault(GuestFault::AccessFault {
+                pc: exit_info.pc,
+                addr: exit_info.fault_addr,
+                access: exit_info.data_access(),
+            }),
+        },
+        // Instruction abort from a lower EL, outside of guest memory
+        0x20 => VmExit::GuestFault(GuestFault::AccessFault {
+            pc: exit_info.pc,
+            addr: exit_info.fault_addr,
+            access: GuestAccess::Fetch,
+        }),
+        _ => VmExit::ArchSpecific(exit_info),
     }
 }
@@ -0,0 +1,57 @@
+use super::EmuContext;
+use crate::{GuestAccess, GuestPhysAddr, GuestVirtAddr};
+
+/// Information about a synchronous exception taken from the guest to EL2.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct VmExitInfo {
+    /// Exception syndrome (`ESR_EL2`).
+    pub esr: usize,
+    /// Faulting intermediate physical address. Only valid for stage-2 aborts.
+    pub fault_addr: GuestPhysAddr,
+    /// Faulting virtual address (`FAR_EL2`). Only valid for aborts.
+    pub far: GuestVirtAddr,
+    /// Guest pc where the exception was taken.
+    pub pc: GuestVirtAddr,
+    /// The access of a data abort on a stage-2 translation fault, built from the syndrome when
+    /// it is valid.
+    pub emu_ctx: Option<EmuContext>,
+}
+
+impl VmExitInfo {
+    /// Exception class, `ESR_EL2.EC`.
+    pub fn exception_class(&self) -> usize {
+        (self.esr >> 26) & 0b111111
+    }
+
+    /// Instruction specific syndrome, `ESR_EL2.ISS`.
+    pub fn iss(&self) -> usize {
+        self.esr & ((1 << 25) - 1)
+    }
+
+    /// Whether this is a data abort caused by a stage-2 permission fault, e.g. a write to a
+    /// page write-protected for dirty logging.
+    pub fn is_permission_fault(&self) -> bool {
+        self.exception_class() == 0x24 && self.iss() & 0b111100 == 0b001100
+    }
+
+    /// Whether this is a data abort caused by a stage-2 translation fault, e.g. an access to
+    /// emulated MMIO.
+    pub fn is_translation_fault(&self) -> bool {
+        self.exception_class() == 0x24 && self.iss() & 0b111100 == 0b000100
+    }
+
+    /// Whether a data abort was caused by a write, `ISS.WnR`.
+    pub fn is_write(&self) -> bool {
+        self.iss() & (1 << 6) != 0
+    }
+
+    /// The kind of access of a data abort.
+    pub(crate) fn data_access(&self) -> GuestAccess {
+        if self.is_write() {
+            GuestAccess::Write
+        } else {
+            GuestAccess::Read
+        }
+    }
+}
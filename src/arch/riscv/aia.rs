@@ -0,0 +1,235 @@
+//! Support for the Advanced Interrupt Architecture: guest interrupt files of the host IMSICs,
+//! selected with `hstatus.VGEIN`, and emulation of the IMSIC CSRs for guests without one.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::csrs::{RiscvCsrTrait, CSR};
+use super::detect::detect_guest_interrupt_files;
+use super::devices::aplic::AplicState;
+use super::devices::imsic::{ImsicFile, SoftImsic, IMSIC_PAGE_SIZE};
+use crate::{GuestPhysAddr, HostPhysAddr, HyperError, HyperResult};
+
+/// Where a VM's AIA interrupt controllers live, passed to [`VM::enable_aia`](super::VM::enable_aia).
+#[derive(Debug, Clone, Copy)]
+pub struct AiaConfig {
+    /// Guest physical address of the emulated APLIC domain.
+    pub aplic_base: GuestPhysAddr,
+    /// Guest physical address of the S-level interrupt file of vCPU 0, those of the other vCPUs
+    /// following one page apart.
+    pub imsic_base: GuestPhysAddr,
+    /// The host IMSICs, to give vCPUs guest interrupt files of their own. Without them, or if
+    /// the harts have no guest interrupt files, the interrupt files are emulated.
+    pub host_imsic: Option<HostImsic>,
+}
+
+/// Layout of the S-level IMSICs of the host.
+#[derive(Debug, Clone, Copy)]
+pub struct HostImsic {
+    /// Host physical address of the S-level interrupt file of hart 0.
+    pub base: HostPhysAddr,
+    /// Distance between the interrupt files of consecutive harts. The guest interrupt files of
+    /// a hart are the pages right after its S-level interrupt file.
+    pub hart_stride: usize,
+}
+
+/// The AIA interrupt controllers of a VM.
+pub(crate) struct AiaState {
+    pub config: AiaConfig,
+    pub aplic: Arc<Mutex<AplicState>>,
+    /// The emulated interrupt files. With guest interrupt files, they only hold the MSIs sent
+    /// to a vCPU before it got one.
+    pub imsic: Arc<Mutex<SoftImsic>>,
+    /// Layout of the host IMSICs if the vCPUs get guest interrupt files.
+    pub host_imsic: Option<HostImsic>,
+    /// The guest interrupt file of each vCPU, bound when it first runs.
+    pub files: Mutex<Vec<Option<GuestFile>>>,
+}
+
+impl AiaState {
+    pub fn new(config: AiaConfig, num_vcpus: usize) -> Self {
+        let host_imsic = config
+            .host_imsic
+            .filter(|_| detect_guest_interrupt_files() > 0);
+        Self {
+            config,
+            aplic: Arc::new(Mutex::new(AplicState::new(config.aplic_base))),
+            imsic: Arc::new(Mutex::new(SoftImsic::new(config.imsic_base, num_vcpus))),
+            host_imsic,
+            files: Mutex::new(vec![None; num_vcpus]),
+        }
+    }
+
+    /// Sends MSI `eiid` to the S-level interrupt file of vCPU `vcpu_id`.
+    pub fn send_msi(&self, vcpu_id: usize, eiid: u32) -> HyperResult {
+        let file = *self
+            .files
+            .lock()
+            .get(vcpu_id)
+            .ok_or(HyperError::InvalidParam)?;
+        match (file, self.host_imsic) {
+            (Some(file), Some(host_imsic)) => {
+                // host physical addresses are identity-mapped, as for the host PLIC
+                let page = file.host_page(&host_imsic) as *mut u32;
+                unsafe { core::ptr::write_volatile(page, eiid) };
+            }
+            _ => self.imsic.lock().files[vcpu_id].set_pending(eiid),
+        }
+        Ok(())
+    }
+}
+
+/// A guest interrupt file of a host hart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct GuestFile {
+    /// The host hart.
+    pub hart: usize,
+    /// Number of the file, the value of `hstatus.VGEIN` selecting it, starting from 1.
+    pub index: usize,
+}
+
+impl GuestFile {
+    /// Host physical address of the MMIO page of the file.
+    pub fn host_page(&self, imsic: &HostImsic) -> HostPhysAddr {
+        imsic.base + self.hart * imsic.hart_stride + self.index * IMSIC_PAGE_SIZE
+    }
+
+    /// Clears the registers of the file, which keeps whatever its previous guest left. Must run on
+    /// `self.hart`.
+    pub fn clear(&self) {
+        // with hstatus.VGEIN selecting the file, vsiselect and vsireg access it from HS-mode
+        let hstatus = CSR.hstatus.get_value();
+        CSR.hstatus
+            .write_value(hstatus & !(0x3f << 12) | self.index << 12);
+        let iselects = [0x70, 0x72].into_iter().chain((0x80..0x100).step_by(2));
+        for iselect in iselects {
+            unsafe {
+                // vsiselect and vsireg, unknown to assemblers without Smaia
+                core::arch::asm!(
+                    "csrw 0x250, {iselect}",
+                    "csrw 0x251, zero",
+                    iselect = in(reg) iselect,
+                );
+            }
+        }
+        CSR.hstatus.write_value(hstatus);
+    }
+
+    /// Whether the file has an interrupt for its guest, from `hgeip`. Must run on `self.hart`.
+    pub fn is_pending(&self) -> bool {
+        CSR.hgeip.get_value() & (1 << self.index) != 0
+    }
+}
+
+/// The guest interrupt files in use, as a bitmap per host hart.
+static GUEST_FILES: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+/// Allocates a guest interrupt file of `hart`, which must be the current hart.
+pub(crate) fn alloc_guest_file(hart: usize) -> HyperResult<GuestFile> {
+    let num_files = detect_guest_interrupt_files();
+    let mut files = GUEST_FILES.lock();
+    let used = files.entry(hart).or_insert(0);
+    let index = (1..=num_files)
+        .find(|&index| *used & (1 << index) == 0)
+        .ok_or(HyperError::NoMemory)?;
+    *used |= 1 << index;
+    Ok(GuestFile { hart, index })
+}
+
+/// Frees a file allocated by [`alloc_guest_file`].
+pub(crate) fn free_guest_file(file: GuestFile) {
+    if let Some(used) = GUEST_FILES.lock().get_mut(&file.hart) {
+        *used &= !(1 << file.index);
+    }
+}
+
+/// `sireg`, accessing the register of the IMSIC selected by `siselect`.
+pub(crate) const CSR_SIREG: u16 = 0x151;
+/// `stopei`, the top external interrupt of the IMSIC.
+pub(crate) const CSR_STOPEI: u16 = 0x15c;
+
+const OPCODE_SYSTEM: u32 = 0b111_0011;
+
+/// How a CSR instruction updates the CSR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CsrOp {
+    Write,
+    Set,
+    Clear,
+}
+
+/// A decoded `csrrw`, `csrrs`, `csrrc` or one of their immediate forms.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CsrAccess {
+    pub csr: u16,
+    /// Destination of the old value, 0 if it is discarded.
+    pub rd: usize,
+    pub op: CsrOp,
+    /// Source register, or the immediate itself for the immediate forms.
+    pub rs1: usize,
+    pub imm: bool,
+}
+
+impl CsrAccess {
+    /// Whether the instruction writes the CSR: `csrrs` and `csrrc` with `x0` or a zero
+    /// immediate only read it.
+    pub fn writes(&self) -> bool {
+        self.op == CsrOp::Write || self.rs1 != 0
+    }
+
+    /// The value written, given the old value of the CSR and the operand.
+    pub fn new_value(&self, old: u64, operand: u64) -> u64 {
+        match self.op {
+            CsrOp::Write => operand,
+            CsrOp::Set => old | operand,
+            CsrOp::Clear => old & !operand,
+        }
+    }
+}
+
+/// Decodes the CSR instruction `inst`.
+pub(crate) fn decode_csr_access(inst: u32) -> HyperResult<CsrAccess> {
+    if inst & 0x7f != OPCODE_SYSTEM {
+        return Err(HyperError::InvalidInstruction);
+    }
+    let funct3 = (inst >> 12) & 0b111;
+    let op = match funct3 & 0b11 {
+        0b01 => CsrOp::Write,
+        0b10 => CsrOp::Set,
+        0b11 => CsrOp::Clear,
+        // ecall and friends, and the hypervisor loads and stores
+        _ => return Err(HyperError::InvalidInstruction),
+    };
+    Ok(CsrAccess {
+        csr: (inst >> 20) as u16,
+        rd: ((inst >> 7) & 0x1f) as usize,
+        op,
+        rs1: ((inst >> 15) & 0x1f) as usize,
+        imm: funct3 & 0b100 != 0,
+    })
+}
+
+/// Emulates `access` to `sireg` or `stopei` on the emulated interrupt file `file`, with
+/// `siselect` holding `iselect`. Returns the old value of the CSR.
+pub(crate) fn emulate_imsic_csr(
+    file: &mut ImsicFile,
+    access: &CsrAccess,
+    iselect: usize,
+    operand: u64,
+) -> HyperResult<u64> {
+    match access.csr {
+        CSR_SIREG => {
+            let old = file.read_reg(iselect)?;
+            if access.writes() {
+                file.write_reg(iselect, access.new_value(old, operand))?;
+            }
+            Ok(old)
+        }
+        // any write claims the interrupt, whatever the value
+        CSR_STOPEI if access.writes() => Ok(file.claim_topei() as u64),
+        CSR_STOPEI => Ok(file.topei() as u64),
+        _ => Err(HyperError::NotSupported),
+    }
+}
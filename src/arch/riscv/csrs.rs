@@ -11,6 +11,8 @@ pub struct CSR {
     pub hideleg: ReadWriteCsr<hideleg::Register, CSR_HIDELEG>,
     pub hcounteren: ReadWriteCsr<hcounteren::Register, CSR_HCOUNTEREN>,
     pub hvip: ReadWriteCsr<hvip::Register, CSR_HVIP>,
+    pub hgeie: ReadWriteCsr<hgeie::Register, CSR_HGEIE>,
+    pub hgeip: ReadWriteCsr<hgeip::Register, CSR_HGEIP>,
 }
 
 #[allow(clippy::identity_op, clippy::erasing_op)]
@@ -21,6 +23,8 @@ pub const CSR: &CSR = &CSR {
     hideleg: ReadWriteCsr::new(),
     hcounteren: ReadWriteCsr::new(),
     hvip: ReadWriteCsr::new(),
+    hgeie: ReadWriteCsr::new(),
+    hgeip: ReadWriteCsr::new(),
 };
 
 /// Trait defining the possible operations on a RISC-V CSR.
@@ -268,6 +272,21 @@ pub mod defs {
         vsext OFFSET(10) NUMBITS(1) [],
     ]
     ];
+
+    // Hypervisor guest external interrupt enable register.
+    register_bitfields![usize,
+    pub hgeie [
+        // One bit per guest interrupt file, bit 0 is read-only zero.
+        files OFFSET(1) NUMBITS(63) [],
+    ]
+    ];
+
+    // Hypervisor guest external interrupt pending register, read-only.
+    register_bitfields![usize,
+    pub hgeip [
+        files OFFSET(1) NUMBITS(63) [],
+    ]
+    ];
 }
 
 pub mod traps {
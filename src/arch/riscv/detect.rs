@@ -1,5 +1,6 @@
 //! ref: https://github.com/luojia65/zihai/blob/main/zihai/src/detect.rs
 
+use super::csrs::{RiscvCsrTrait, CSR};
 use core::arch::asm;
 use riscv::register::{
     scause::{Exception, Scause, Trap},
@@ -19,6 +20,16 @@ pub fn detect_h_extension() -> bool {
     ans != 2
 }
 
+// Counts the guest interrupt files of the current hart, GEILEN in the privileged spec.
+//
+// Bits of hgeie for files that don't exist are read-only zero, so all ones are written to it
+// and the bits that stuck are counted.
+pub fn detect_guest_interrupt_files() -> usize {
+    let hgeie = CSR.hgeie.atomic_replace(usize::MAX);
+    let files = CSR.hgeie.atomic_replace(hgeie);
+    files.count_ones() as usize
+}
+
 // Tries to execute all instructions defined in clojure `f`.
 // If resulted in an exception, this function returns its exception id.
 //
@@ -0,0 +1,291 @@
+//! Core-local interruptor emulated for guests, with the register layout of the SiFive CLINT
+//! extended by an ACLINT SSWI device: the MSWI `msip` registers at offset 0, the MTIMER
+//! `mtimecmp` registers at 0x4000 and `mtime` at 0xbff8, and the SSWI `setssip` registers at
+//! 0xc000, one of each per vCPU.
+//!
+//! Guests run in VS-mode, so the timer of a vCPU raises its supervisor timer interrupt, and
+//! `msip` and `setssip` its supervisor software interrupt.
+
+use alloc::vec::Vec;
+
+use crate::{HyperError, HyperResult, MmioOps, SnapshotReader, SnapshotWriter};
+
+/// Size of the register window.
+const ACLINT_SIZE: usize = 0x10000;
+
+const MSWI_BASE: usize = 0x0000;
+const MTIMECMP_BASE: usize = 0x4000;
+const MTIME: usize = 0xbff8;
+const SSWI_BASE: usize = 0xc000;
+
+pub struct AclintState {
+    base: usize,
+    /// Reads the host time.
+    clock: fn() -> u64,
+    /// What is added to the host time to get `mtime`, the value of `htimedelta`.
+    time_delta: u64,
+    /// Per vCPU, in guest time.
+    mtimecmp: Vec<u64>,
+    msip: Vec<bool>,
+    /// Supervisor software interrupts raised through the SSWI or by SBI IPIs, until the guest
+    /// clears them in `sip`.
+    ssip: Vec<bool>,
+}
+
+impl AclintState {
+    pub fn new(base: usize, num_vcpus: usize, clock: fn() -> u64) -> Self {
+        Self {
+            base,
+            clock,
+            time_delta: 0,
+            mtimecmp: vec![u64::MAX; num_vcpus],
+            msip: vec![false; num_vcpus],
+            ssip: vec![false; num_vcpus],
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new(self.base, self.mtimecmp.len(), self.clock);
+    }
+
+    /// The guest time, `mtime`.
+    pub fn time(&self) -> u64 {
+        (self.clock)().wrapping_add(self.time_delta)
+    }
+
+    /// The value of `htimedelta` for the vCPUs.
+    pub fn time_delta(&self) -> u64 {
+        self.time_delta
+    }
+
+    /// Sets the timer of vCPU `vcpu_id` to fire at guest time `deadline`, as the SBI `set_timer`
+    /// call does.
+    pub fn set_timer(&mut self, vcpu_id: usize, deadline: u64) -> HyperResult {
+        *self
+            .mtimecmp
+            .get_mut(vcpu_id)
+            .ok_or(HyperError::InvalidParam)? = deadline;
+        Ok(())
+    }
+
+    /// Whether the timer of vCPU `vcpu_id` has fired.
+    pub fn timer_pending(&self, vcpu_id: usize) -> bool {
+        self.mtimecmp
+            .get(vcpu_id)
+            .is_some_and(|&mtimecmp| self.time() >= mtimecmp)
+    }
+
+    /// When the timer of vCPU `vcpu_id` fires in host time, `None` if it is off.
+    pub fn deadline(&self, vcpu_id: usize) -> Option<u64> {
+        match self.mtimecmp.get(vcpu_id) {
+            Some(&u64::MAX) | None => None,
+            Some(&mtimecmp) => Some(mtimecmp.wrapping_sub(self.time_delta)),
+        }
+    }
+
+    /// Whether vCPU `vcpu_id` has a supervisor software interrupt.
+    pub fn soft_pending(&self, vcpu_id: usize) -> bool {
+        self.msip.get(vcpu_id) == Some(&true) || self.ssip.get(vcpu_id) == Some(&true)
+    }
+
+    /// Raises a supervisor software interrupt on vCPU `vcpu_id`.
+    pub fn set_ssip(&mut self, vcpu_id: usize) -> HyperResult {
+        *self.ssip.get_mut(vcpu_id).ok_or(HyperError::InvalidParam)? = true;
+        Ok(())
+    }
+
+    /// Clears the supervisor software interrupt of vCPU `vcpu_id` raised by
+    /// [`AclintState::set_ssip`], once the guest cleared it.
+    pub fn clear_ssip(&mut self, vcpu_id: usize) {
+        if let Some(ssip) = self.ssip.get_mut(vcpu_id) {
+            *ssip = false;
+        }
+    }
+
+    /// Writes the emulated registers into a VM snapshot. `mtime` is saved rather than its
+    /// offset from the host time, which differs on another host.
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        w.put_u64(self.time());
+        w.put_usize(self.mtimecmp.len());
+        for vcpu_id in 0..self.mtimecmp.len() {
+            w.put_u64(self.mtimecmp[vcpu_id]);
+            w.put_u8(self.msip[vcpu_id] as u8);
+            w.put_u8(self.ssip[vcpu_id] as u8);
+        }
+    }
+
+    /// Loads the registers written by [`AclintState::save_state`], which must come from a VM
+    /// with as many vCPUs.
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> HyperResult {
+        let time = r.get_u64()?;
+        if r.get_usize()? != self.mtimecmp.len() {
+            return Err(HyperError::DecodeError);
+        }
+        for vcpu_id in 0..self.mtimecmp.len() {
+            self.mtimecmp[vcpu_id] = r.get_u64()?;
+            self.msip[vcpu_id] = r.get_u8()? != 0;
+            self.ssip[vcpu_id] = r.get_u8()? != 0;
+        }
+        self.time_delta = time.wrapping_sub((self.clock)());
+        Ok(())
+    }
+
+    /// The vCPU whose register at `offset` from `base` is `stride` bytes wide.
+    fn vcpu_of(&self, offset: usize, base: usize, stride: usize) -> Option<usize> {
+        let vcpu_id = (offset - base) / stride;
+        (vcpu_id < self.mtimecmp.len()).then_some(vcpu_id)
+    }
+}
+
+/// Reads `size` bytes at byte `shift` of the 64-bit register `reg`.
+fn read_part(reg: u64, shift: usize, size: u8) -> u64 {
+    let val = reg >> (8 * shift);
+    if size == 8 {
+        val
+    } else {
+        val & 0xffff_ffff
+    }
+}
+
+/// Writes the low `size` bytes of `val` at byte `shift` of the 64-bit register `reg`.
+fn write_part(reg: u64, shift: usize, size: u8, val: u64) -> u64 {
+    if size == 8 {
+        return val;
+    }
+    let mask = 0xffff_ffffu64 << (8 * shift);
+    reg & !mask | (val << (8 * shift)) & mask
+}
+
+impl MmioOps for AclintState {
+    fn mmio_range(&self) -> core::ops::Range<u64> {
+        self.base as u64..(self.base + ACLINT_SIZE) as u64
+    }
+
+    /// The 64-bit registers may be accessed in two halves.
+    fn read(&mut self, addr: u64, access_size: u8) -> HyperResult<u64> {
+        let offset = addr as usize - self.base;
+        if !matches!(access_size, 4 | 8) || offset & (access_size as usize - 1) != 0 {
+            return Err(HyperError::InValidMmioRead);
+        }
+        let val = match offset {
+            MSWI_BASE..MTIMECMP_BASE if access_size == 4 => self
+                .vcpu_of(offset, MSWI_BASE, 4)
+                .map_or(0, |vcpu_id| self.msip[vcpu_id] as u64),
+            MTIMECMP_BASE..MTIME => self.vcpu_of(offset, MTIMECMP_BASE, 8).map_or(0, |vcpu_id| {
+                read_part(self.mtimecmp[vcpu_id], offset & 7, access_size)
+            }),
+            MTIME..SSWI_BASE => read_part(self.time(), offset & 7, access_size),
+            // setssip always reads as zero
+            SSWI_BASE.. if access_size == 4 => 0,
+            _ => return Err(HyperError::InValidMmioRead),
+        };
+        Ok(val)
+    }
+
+    fn write(&mut self, addr: u64, access_size: u8, value: u64) -> HyperResult {
+        let offset = addr as usize - self.base;
+        if !matches!(access_size, 4 | 8) || offset & (access_size as usize - 1) != 0 {
+            return Err(HyperError::InValidMmioWrite);
+        }
+        match offset {
+            MSWI_BASE..MTIMECMP_BASE if access_size == 4 => {
+                if let Some(vcpu_id) = self.vcpu_of(offset, MSWI_BASE, 4) {
+                    self.msip[vcpu_id] = value & 1 != 0;
+                }
+            }
+            MTIMECMP_BASE..MTIME => {
+                if let Some(vcpu_id) = self.vcpu_of(offset, MTIMECMP_BASE, 8) {
+                    let mtimecmp = &mut self.mtimecmp[vcpu_id];
+                    *mtimecmp = write_part(*mtimecmp, offset & 7, access_size, value);
+                }
+            }
+            MTIME..SSWI_BASE => {
+                let time = write_part(self.time(), offset & 7, access_size, value);
+                self.time_delta = time.wrapping_sub((self.clock)());
+            }
+            SSWI_BASE.. if access_size == 4 => {
+                if let Some(vcpu_id) = self.vcpu_of(offset, SSWI_BASE, 4) {
+                    // writing 0 has no effect
+                    self.ssip[vcpu_id] |= value & 1 != 0;
+                }
+            }
+            _ => return Err(HyperError::InValidMmioWrite),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+
+    const BASE: u64 = 0x200_0000;
+
+    /// An ACLINT for two vCPUs. Tests run in parallel, so each one that moves the host time
+    /// passes a clock of its own.
+    fn aclint(clock: fn() -> u64) -> AclintState {
+        AclintState::new(BASE as usize, 2, clock)
+    }
+
+    #[test]
+    fn mtime_follows_host_time_with_delta() {
+        static HOST_TIME: AtomicU64 = AtomicU64::new(0);
+        fn host_time() -> u64 {
+            HOST_TIME.load(Ordering::SeqCst)
+        }
+
+        let mut aclint = aclint(host_time);
+        HOST_TIME.fetch_add(1000, Ordering::SeqCst);
+        aclint.write(BASE + MTIME as u64, 8, 50).unwrap();
+        HOST_TIME.fetch_add(10, Ordering::SeqCst);
+        assert_eq!(aclint.read(BASE + MTIME as u64, 8).unwrap(), 60);
+        // write the high half only
+        aclint.write(BASE + MTIME as u64 + 4, 4, 1).unwrap();
+        assert_eq!(aclint.time(), 1 << 32 | 60);
+        assert_eq!(aclint.time_delta(), aclint.time().wrapping_sub(host_time()));
+    }
+
+    #[test]
+    fn per_vcpu_timers() {
+        let mut aclint = aclint(|| 1000);
+        let now = aclint.time();
+        aclint
+            .write(BASE + MTIMECMP_BASE as u64 + 8, 8, now + 100)
+            .unwrap();
+        assert_eq!(aclint.deadline(0), None);
+        assert_eq!(
+            aclint.deadline(1),
+            Some((now + 100).wrapping_sub(aclint.time_delta()))
+        );
+        assert!(!aclint.timer_pending(1));
+        aclint.set_timer(0, now).unwrap();
+        assert!(aclint.timer_pending(0));
+        assert_eq!(
+            aclint.read(BASE + MTIMECMP_BASE as u64 + 12, 4).unwrap(),
+            (now + 100) >> 32
+        );
+        assert!(aclint.set_timer(2, 0).is_err());
+    }
+
+    #[test]
+    fn software_interrupts() {
+        let mut aclint = aclint(|| 0);
+        aclint.write(BASE + 4, 4, 1).unwrap();
+        assert!(aclint.soft_pending(1) && !aclint.soft_pending(0));
+        assert_eq!(aclint.read(BASE + 4, 4).unwrap(), 1);
+        aclint.write(BASE + 4, 4, 0).unwrap();
+        assert!(!aclint.soft_pending(1));
+
+        aclint.write(BASE + SSWI_BASE as u64, 4, 1).unwrap();
+        assert_eq!(aclint.read(BASE + SSWI_BASE as u64, 4).unwrap(), 0);
+        aclint.write(BASE + SSWI_BASE as u64, 4, 0).unwrap();
+        assert!(aclint.soft_pending(0));
+        aclint.clear_ssip(0);
+        assert!(!aclint.soft_pending(0));
+        // registers of missing vCPUs are reserved
+        assert_eq!(aclint.read(BASE + 8, 4).unwrap(), 0);
+        assert!(aclint.read(BASE + 2, 4).is_err());
+    }
+}
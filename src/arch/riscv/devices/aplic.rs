@@ -0,0 +1,476 @@
+//! Advanced platform-level interrupt controller emulated for guests, following the RISC-V
+//! Advanced Interrupt Architecture. The guest sees a single S-level interrupt domain in MSI
+//! delivery mode: interrupts that become pending and enabled are forwarded as MSIs, which the
+//! VM collects with [`AplicState::take_msis`] and writes to the interrupt files of its vCPUs.
+
+use alloc::vec::Vec;
+
+use crate::{HyperError, HyperResult, MmioOps, SnapshotReader, SnapshotWriter};
+
+/// Number of interrupt sources, including source 0 which doesn't exist.
+pub const APLIC_NUM_SOURCES: usize = 1024;
+/// Number of 32-bit words of a bitmap of all sources.
+const WORDS: usize = APLIC_NUM_SOURCES / 32;
+/// Size of the register window of an interrupt domain.
+const APLIC_SIZE: usize = 0x4000;
+
+const DOMAINCFG: usize = 0x0000;
+const SOURCECFG_BASE: usize = 0x0004;
+const SMSIADDRCFG: usize = 0x1bc8;
+const SMSIADDRCFGH: usize = 0x1bcc;
+const SETIP_BASE: usize = 0x1c00;
+const SETIPNUM: usize = 0x1cdc;
+const IN_CLRIP_BASE: usize = 0x1d00;
+const CLRIPNUM: usize = 0x1ddc;
+const SETIE_BASE: usize = 0x1e00;
+const SETIENUM: usize = 0x1edc;
+const CLRIE_BASE: usize = 0x1f00;
+const CLRIENUM: usize = 0x1fdc;
+const SETIPNUM_LE: usize = 0x2000;
+const SETIPNUM_BE: usize = 0x2004;
+const GENMSI: usize = 0x3000;
+const TARGET_BASE: usize = 0x3004;
+
+/// `domaincfg` always reads with bit 31 set.
+const DOMAINCFG_RO80: u32 = 1 << 31;
+const DOMAINCFG_IE: u32 = 1 << 8;
+/// MSI delivery mode, the only one supported.
+const DOMAINCFG_DM: u32 = 1 << 2;
+
+/// Hart index in `target` and `genmsi`.
+const TARGET_HART_SHIFT: u32 = 18;
+/// Interrupt identity in `target` and `genmsi`.
+const TARGET_EIID_MASK: u32 = 0x7ff;
+
+/// Source modes of `sourcecfg`.
+const SM_INACTIVE: u32 = 0;
+const SM_DETACHED: u32 = 1;
+const SM_EDGE1: u32 = 4;
+const SM_EDGE0: u32 = 5;
+const SM_LEVEL1: u32 = 6;
+const SM_LEVEL0: u32 = 7;
+
+/// An MSI sent by the APLIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Msi {
+    /// Hart index of the target, the ID of the vCPU.
+    pub hart_index: usize,
+    /// Interrupt identity written to the S-level interrupt file of the hart.
+    pub eiid: u32,
+}
+
+pub struct AplicState {
+    base: usize,
+    domaincfg: u32,
+    sourcecfg: [u32; APLIC_NUM_SOURCES],
+    target: [u32; APLIC_NUM_SOURCES],
+    msiaddrcfg: [u32; 2],
+    pending: [u32; WORDS],
+    enabled: [u32; WORDS],
+    /// Levels of the interrupt lines.
+    level: [u32; WORDS],
+    /// MSIs sent and not collected yet.
+    msis: Vec<Msi>,
+}
+
+fn test_bit(bitmap: &[u32; WORDS], source: usize) -> bool {
+    bitmap[source / 32] & (1 << (source % 32)) != 0
+}
+
+fn assign_bit(bitmap: &mut [u32; WORDS], source: usize, val: bool) {
+    if val {
+        bitmap[source / 32] |= 1 << (source % 32);
+    } else {
+        bitmap[source / 32] &= !(1 << (source % 32));
+    }
+}
+
+impl AplicState {
+    pub fn new(base: usize) -> Self {
+        Self {
+            base,
+            domaincfg: DOMAINCFG_DM,
+            sourcecfg: [SM_INACTIVE; APLIC_NUM_SOURCES],
+            target: [0; APLIC_NUM_SOURCES],
+            msiaddrcfg: [0; 2],
+            pending: [0; WORDS],
+            enabled: [0; WORDS],
+            level: [0; WORDS],
+            msis: Vec::new(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new(self.base);
+    }
+
+    /// The source mode of `source`.
+    fn mode(&self, source: usize) -> u32 {
+        self.sourcecfg[source]
+    }
+
+    /// The input of `source` after inversion for the active-low modes.
+    fn rectified(&self, source: usize) -> bool {
+        let level = test_bit(&self.level, source);
+        match self.mode(source) {
+            SM_EDGE1 | SM_LEVEL1 => level,
+            SM_EDGE0 | SM_LEVEL0 => !level,
+            _ => false,
+        }
+    }
+
+    /// Sets the level of interrupt line `source`. Both edge- and level-sensitive sources
+    /// become pending when their rectified input rises, as in MSI delivery mode the pending bit
+    /// is cleared when the MSI is sent; level-sensitive ones stop being pending when it falls.
+    pub fn set_irq(&mut self, source: u32, level: bool) -> HyperResult {
+        let source = source as usize;
+        if source == 0 || source >= APLIC_NUM_SOURCES {
+            return Err(HyperError::InvalidParam);
+        }
+        let was = self.rectified(source);
+        assign_bit(&mut self.level, source, level);
+        let now = self.rectified(source);
+        match self.mode(source) {
+            SM_EDGE1 | SM_EDGE0 | SM_LEVEL1 | SM_LEVEL0 if !was && now => {
+                assign_bit(&mut self.pending, source, true)
+            }
+            SM_LEVEL1 | SM_LEVEL0 if !now => assign_bit(&mut self.pending, source, false),
+            _ => {}
+        }
+        self.forward();
+        Ok(())
+    }
+
+    /// Sets `source` pending by a write of software. Level-sensitive sources only become
+    /// pending while their rectified input is high.
+    fn set_pending(&mut self, source: usize) {
+        if source == 0 || source >= APLIC_NUM_SOURCES {
+            return;
+        }
+        let allowed = match self.mode(source) {
+            SM_DETACHED | SM_EDGE1 | SM_EDGE0 => true,
+            SM_LEVEL1 | SM_LEVEL0 => self.rectified(source),
+            _ => false,
+        };
+        if allowed {
+            assign_bit(&mut self.pending, source, true);
+        }
+    }
+
+    fn clear_pending(&mut self, source: usize) {
+        if source != 0 && source < APLIC_NUM_SOURCES {
+            assign_bit(&mut self.pending, source, false);
+        }
+    }
+
+    fn set_enabled(&mut self, source: usize, enabled: bool) {
+        if source != 0 && source < APLIC_NUM_SOURCES && self.mode(source) != SM_INACTIVE {
+            assign_bit(&mut self.enabled, source, enabled);
+        }
+    }
+
+    /// Sends an MSI for every pending and enabled source if interrupts are enabled for the
+    /// domain.
+    fn forward(&mut self) {
+        if self.domaincfg & DOMAINCFG_IE == 0 {
+            return;
+        }
+        for word in 0..WORDS {
+            let mut bits = self.pending[word] & self.enabled[word];
+            self.pending[word] &= !bits;
+            while bits != 0 {
+                let source = word * 32 + bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                self.send_msi(self.target[source]);
+            }
+        }
+    }
+
+    fn send_msi(&mut self, target: u32) {
+        let eiid = target & TARGET_EIID_MASK;
+        // identity 0 is never signaled
+        if eiid != 0 {
+            self.msis.push(Msi {
+                hart_index: (target >> TARGET_HART_SHIFT) as usize,
+                eiid,
+            });
+        }
+    }
+
+    /// Takes the MSIs sent since the last call.
+    pub fn take_msis(&mut self) -> Vec<Msi> {
+        core::mem::take(&mut self.msis)
+    }
+
+    /// Writes the emulated registers into a VM snapshot.
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        w.put_u32(self.domaincfg);
+        for val in self.sourcecfg.iter().chain(self.target.iter()) {
+            w.put_u32(*val);
+        }
+        self.msiaddrcfg.iter().for_each(|&val| w.put_u32(val));
+        for bitmap in [&self.pending, &self.enabled, &self.level] {
+            bitmap.iter().for_each(|&val| w.put_u32(val));
+        }
+    }
+
+    /// Loads the registers written by [`AplicState::save_state`].
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> HyperResult {
+        self.domaincfg = r.get_u32()?;
+        for val in self.sourcecfg.iter_mut().chain(self.target.iter_mut()) {
+            *val = r.get_u32()?;
+        }
+        for val in self.msiaddrcfg.iter_mut() {
+            *val = r.get_u32()?;
+        }
+        for bitmap in [&mut self.pending, &mut self.enabled, &mut self.level] {
+            for val in bitmap.iter_mut() {
+                *val = r.get_u32()?;
+            }
+        }
+        self.msis.clear();
+        Ok(())
+    }
+
+    /// Reads the register at `addr`. Reserved registers read as zero.
+    pub fn read_u32(&mut self, addr: usize) -> u32 {
+        let offset = addr.wrapping_sub(self.base);
+        let source = |base: usize| (offset - base) / 4 + 1;
+        let word = |base: usize| (offset - base) / 4;
+        match offset {
+            DOMAINCFG => DOMAINCFG_RO80 | self.domaincfg,
+            SOURCECFG_BASE..SMSIADDRCFG if source(SOURCECFG_BASE) < APLIC_NUM_SOURCES => {
+                self.sourcecfg[source(SOURCECFG_BASE)]
+            }
+            SMSIADDRCFG => self.msiaddrcfg[0],
+            SMSIADDRCFGH => self.msiaddrcfg[1],
+            SETIP_BASE..SETIPNUM if word(SETIP_BASE) < WORDS => self.pending[word(SETIP_BASE)],
+            IN_CLRIP_BASE..CLRIPNUM if word(IN_CLRIP_BASE) < WORDS => {
+                let base = word(IN_CLRIP_BASE) * 32;
+                (0..32)
+                    .filter(|&bit| base + bit != 0 && self.rectified(base + bit))
+                    .fold(0, |val, bit| val | 1 << bit)
+            }
+            SETIE_BASE..SETIENUM if word(SETIE_BASE) < WORDS => self.enabled[word(SETIE_BASE)],
+            TARGET_BASE.. if offset < TARGET_BASE + 4 * (APLIC_NUM_SOURCES - 1) => {
+                self.target[source(TARGET_BASE)]
+            }
+            _ => 0,
+        }
+    }
+
+    /// Writes `val` to the register at `addr`. Writes to read-only and reserved registers are
+    /// ignored.
+    pub fn write_u32(&mut self, addr: usize, val: u32) {
+        let offset = addr.wrapping_sub(self.base);
+        let source = |base: usize| (offset - base) / 4 + 1;
+        let word = |base: usize| (offset - base) / 4;
+        match offset {
+            // MSI delivery mode and little-endian are fixed
+            DOMAINCFG => self.domaincfg = DOMAINCFG_DM | (val & DOMAINCFG_IE),
+            SOURCECFG_BASE..SMSIADDRCFG if source(SOURCECFG_BASE) < APLIC_NUM_SOURCES => {
+                let source = source(SOURCECFG_BASE);
+                // there are no child domains to delegate to, and modes 2 and 3 are reserved
+                let mode = match val & 0x7 {
+                    mode @ (SM_DETACHED | SM_EDGE1..=SM_LEVEL0) if val & (1 << 10) == 0 => mode,
+                    _ => SM_INACTIVE,
+                };
+                self.sourcecfg[source] = mode;
+                if mode == SM_INACTIVE {
+                    assign_bit(&mut self.pending, source, false);
+                    assign_bit(&mut self.enabled, source, false);
+                } else if matches!(mode, SM_LEVEL1 | SM_LEVEL0) {
+                    let rectified = self.rectified(source);
+                    if !rectified {
+                        assign_bit(&mut self.pending, source, false);
+                    }
+                }
+            }
+            SMSIADDRCFG => self.msiaddrcfg[0] = val,
+            SMSIADDRCFGH => self.msiaddrcfg[1] = val,
+            SETIP_BASE..SETIPNUM => {
+                let base = word(SETIP_BASE) * 32;
+                (0..32)
+                    .filter(|bit| val & (1 << bit) != 0)
+                    .for_each(|bit| self.set_pending(base + bit));
+            }
+            SETIPNUM | SETIPNUM_LE => self.set_pending(val as usize),
+            SETIPNUM_BE => self.set_pending(val.swap_bytes() as usize),
+            IN_CLRIP_BASE..CLRIPNUM => {
+                let base = word(IN_CLRIP_BASE) * 32;
+                (0..32)
+                    .filter(|bit| val & (1 << bit) != 0)
+                    .for_each(|bit| self.clear_pending(base + bit));
+            }
+            CLRIPNUM => self.clear_pending(val as usize),
+            SETIE_BASE..SETIENUM => {
+                let base = word(SETIE_BASE) * 32;
+                (0..32)
+                    .filter(|bit| val & (1 << bit) != 0)
+                    .for_each(|bit| self.set_enabled(base + bit, true));
+            }
+            SETIENUM => self.set_enabled(val as usize, true),
+            CLRIE_BASE..CLRIENUM => {
+                let base = word(CLRIE_BASE) * 32;
+                (0..32)
+                    .filter(|bit| val & (1 << bit) != 0)
+                    .for_each(|bit| self.set_enabled(base + bit, false));
+            }
+            CLRIENUM => self.set_enabled(val as usize, false),
+            // the MSI is sent at once, so `Busy` always reads as zero
+            GENMSI => self.send_msi(val & !(0x3f << 12 | 1 << 11)),
+            TARGET_BASE.. if offset < TARGET_BASE + 4 * (APLIC_NUM_SOURCES - 1) => {
+                // guests have no guest interrupt files, the guest index is zero
+                self.target[source(TARGET_BASE)] =
+                    val & (u32::MAX << TARGET_HART_SHIFT | TARGET_EIID_MASK);
+            }
+            _ => return,
+        }
+        self.forward();
+    }
+}
+
+impl MmioOps for AplicState {
+    fn mmio_range(&self) -> core::ops::Range<u64> {
+        self.base as u64..(self.base + APLIC_SIZE) as u64
+    }
+
+    fn read(&mut self, addr: u64, access_size: u8) -> HyperResult<u64> {
+        // all APLIC registers are 32 bits wide
+        if access_size != 4 || addr & 3 != 0 {
+            return Err(HyperError::InValidMmioRead);
+        }
+        Ok(self.read_u32(addr as usize) as u64)
+    }
+
+    fn write(&mut self, addr: u64, access_size: u8, value: u64) -> HyperResult {
+        if access_size != 4 || addr & 3 != 0 {
+            return Err(HyperError::InValidMmioWrite);
+        }
+        self.write_u32(addr as usize, value as u32);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: usize = 0xd00_0000;
+
+    fn sourcecfg(source: usize) -> usize {
+        BASE + SOURCECFG_BASE + 4 * (source - 1)
+    }
+
+    fn target(source: usize) -> usize {
+        BASE + TARGET_BASE + 4 * (source - 1)
+    }
+
+    /// An APLIC with `source` in `mode`, enabled and targeting hart 1 with identity 33.
+    fn aplic_with(source: usize, mode: u32) -> AplicState {
+        let mut aplic = AplicState::new(BASE);
+        aplic.write_u32(sourcecfg(source), mode);
+        aplic.write_u32(target(source), 1 << TARGET_HART_SHIFT | 33);
+        aplic.write_u32(BASE + SETIENUM, source as u32);
+        aplic.write_u32(BASE + DOMAINCFG, DOMAINCFG_IE);
+        aplic
+    }
+
+    const MSI: Msi = Msi {
+        hart_index: 1,
+        eiid: 33,
+    };
+
+    #[test]
+    fn edge_source_sends_one_msi_per_rising_edge() {
+        let mut aplic = aplic_with(5, SM_EDGE1);
+        aplic.set_irq(5, true).unwrap();
+        aplic.set_irq(5, true).unwrap();
+        assert_eq!(aplic.take_msis(), [MSI]);
+        aplic.set_irq(5, false).unwrap();
+        assert!(aplic.take_msis().is_empty());
+        aplic.set_irq(5, true).unwrap();
+        assert_eq!(aplic.take_msis(), [MSI]);
+        // software may trigger edge sources at any time
+        aplic.write_u32(BASE + SETIPNUM_LE, 5);
+        assert_eq!(aplic.take_msis(), [MSI]);
+    }
+
+    #[test]
+    fn level_low_source_retriggers_only_while_asserted() {
+        let mut aplic = aplic_with(9, SM_LEVEL0);
+        // active low: the line at rest is high
+        aplic.set_irq(9, true).unwrap();
+        assert!(aplic.take_msis().is_empty());
+        aplic.set_irq(9, false).unwrap();
+        assert_eq!(aplic.take_msis(), [MSI]);
+        assert_eq!(aplic.read_u32(BASE + IN_CLRIP_BASE), 1 << 9);
+        aplic.write_u32(BASE + SETIPNUM, 9);
+        assert_eq!(aplic.take_msis(), [MSI]);
+        aplic.set_irq(9, true).unwrap();
+        aplic.write_u32(BASE + SETIPNUM, 9);
+        assert!(aplic.take_msis().is_empty());
+    }
+
+    #[test]
+    fn pending_interrupts_wait_for_enables() {
+        let mut aplic = aplic_with(40, SM_EDGE1);
+        aplic.write_u32(BASE + DOMAINCFG, 0);
+        aplic.set_irq(40, true).unwrap();
+        assert!(aplic.take_msis().is_empty());
+        assert_eq!(aplic.read_u32(BASE + SETIP_BASE + 4), 1 << 8);
+        aplic.write_u32(BASE + CLRIENUM, 40);
+        aplic.write_u32(BASE + DOMAINCFG, DOMAINCFG_IE);
+        assert!(aplic.take_msis().is_empty());
+        aplic.write_u32(BASE + SETIE_BASE + 4, 1 << 8);
+        assert_eq!(aplic.take_msis(), [MSI]);
+        assert_eq!(aplic.read_u32(BASE + SETIP_BASE + 4), 0);
+    }
+
+    #[test]
+    fn inactive_and_reserved_modes() {
+        let mut aplic = aplic_with(3, 2);
+        assert_eq!(aplic.read_u32(sourcecfg(3)), SM_INACTIVE);
+        assert_eq!(aplic.read_u32(BASE + SETIE_BASE), 0);
+        aplic.write_u32(sourcecfg(3), 1 << 10 | SM_EDGE1);
+        assert_eq!(aplic.read_u32(sourcecfg(3)), SM_INACTIVE);
+        aplic.write_u32(BASE + SETIPNUM, 3);
+        assert_eq!(aplic.read_u32(BASE + SETIP_BASE), 0);
+        assert!(aplic.set_irq(0, true).is_err());
+        assert_eq!(
+            aplic.read_u32(BASE + DOMAINCFG),
+            DOMAINCFG_RO80 | DOMAINCFG_IE | DOMAINCFG_DM
+        );
+    }
+
+    #[test]
+    fn genmsi_and_target_fields() {
+        let mut aplic = AplicState::new(BASE);
+        aplic.write_u32(BASE + GENMSI, 2 << TARGET_HART_SHIFT | 1 << 12 | 7);
+        assert_eq!(
+            aplic.take_msis(),
+            [Msi {
+                hart_index: 2,
+                eiid: 7
+            }]
+        );
+        aplic.write_u32(target(1), u32::MAX);
+        assert_eq!(
+            aplic.read_u32(target(1)),
+            u32::MAX << TARGET_HART_SHIFT | TARGET_EIID_MASK
+        );
+    }
+
+    #[test]
+    fn reserved_bitmap_words_read_as_zero() {
+        let mut aplic = aplic_with(1, SM_EDGE1);
+        aplic.write_u32(BASE + SETIE_BASE, u32::MAX);
+        aplic.write_u32(BASE + SETIP_BASE, u32::MAX);
+        for offset in [SETIP_BASE, IN_CLRIP_BASE, SETIE_BASE] {
+            assert_eq!(aplic.read_u32(BASE + offset + 4 * WORDS), 0);
+        }
+        // past the last source, writes are ignored
+        aplic.write_u32(BASE + SETIP_BASE + 4 * WORDS, u32::MAX);
+        aplic.write_u32(BASE + SETIE_BASE + 4 * WORDS, u32::MAX);
+    }
+}
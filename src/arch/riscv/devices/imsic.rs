@@ -0,0 +1,314 @@
+//! Incoming MSI controller emulated in software, for hosts without guest interrupt files. It
+//! follows the RISC-V Advanced Interrupt Architecture: each vCPU has one S-level interrupt file,
+//! written through its MMIO page and accessed by the guest through `sireg`/`stopei`, which trap
+//! when `hstatus.VGEIN` selects no guest interrupt file.
+
+use alloc::vec::Vec;
+
+use crate::{HyperError, HyperResult, MmioOps, SnapshotReader, SnapshotWriter};
+
+/// Number of interrupt identities of a file, including identity 0 which is never valid.
+pub const IMSIC_NUM_IDS: usize = 256;
+/// Number of 64-bit words of a bitmap of all identities.
+const WORDS: usize = IMSIC_NUM_IDS / 64;
+/// Size of the MMIO page of one interrupt file.
+pub const IMSIC_PAGE_SIZE: usize = 0x1000;
+
+const SETEIPNUM_LE: usize = 0x0;
+const SETEIPNUM_BE: usize = 0x4;
+
+const ISELECT_EIDELIVERY: usize = 0x70;
+const ISELECT_EITHRESHOLD: usize = 0x72;
+const ISELECT_EIP0: usize = 0x80;
+const ISELECT_EIE0: usize = 0xc0;
+const ISELECT_EIE63: usize = 0xff;
+
+/// One interrupt file: the pending and enabled identities and the delivery registers.
+#[derive(Clone)]
+pub struct ImsicFile {
+    eidelivery: u64,
+    eithreshold: u64,
+    eip: [u64; WORDS],
+    eie: [u64; WORDS],
+}
+
+impl Default for ImsicFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ImsicFile {
+    pub fn new() -> Self {
+        Self {
+            eidelivery: 0,
+            eithreshold: 0,
+            eip: [0; WORDS],
+            eie: [0; WORDS],
+        }
+    }
+
+    /// Marks identity `id` pending, as an MSI written to the file does. Invalid identities are
+    /// dropped.
+    pub fn set_pending(&mut self, id: u32) {
+        let id = id as usize;
+        if id != 0 && id < IMSIC_NUM_IDS {
+            self.eip[id / 64] |= 1 << (id % 64);
+        }
+    }
+
+    /// The pending identities, for moving them to another file.
+    pub fn pending_ids(&self) -> impl Iterator<Item = u32> + '_ {
+        (1..IMSIC_NUM_IDS as u32).filter(|&id| self.eip[id as usize / 64] & 1 << (id % 64) != 0)
+    }
+
+    /// The value of `topei`: the lowest pending and enabled identity below the threshold, in
+    /// both the identity and the priority field, or 0 if there is none.
+    pub fn topei(&self) -> u32 {
+        for (word, (&eip, &eie)) in self.eip.iter().zip(self.eie.iter()).enumerate() {
+            let bits = eip & eie;
+            if bits != 0 {
+                let id = (word * 64) as u64 + bits.trailing_zeros() as u64;
+                if self.eithreshold != 0 && id >= self.eithreshold {
+                    return 0;
+                }
+                return (id << 16 | id) as u32;
+            }
+        }
+        0
+    }
+
+    /// Claims the interrupt reported by `topei`, as a write to `stopei` does, and returns the
+    /// value read.
+    pub fn claim_topei(&mut self) -> u32 {
+        let topei = self.topei();
+        let id = (topei & 0x7ff) as usize;
+        if id != 0 {
+            self.eip[id / 64] &= !(1 << (id % 64));
+        }
+        topei
+    }
+
+    /// Whether the file signals an interrupt to its hart.
+    pub fn has_irq(&self) -> bool {
+        self.eidelivery == 1 && self.topei() != 0
+    }
+
+    /// Reads the register selected by `iselect` through `sireg`. Fails with
+    /// [`HyperError::InvalidParam`] for registers that don't exist, which are illegal to access.
+    pub fn read_reg(&self, iselect: usize) -> HyperResult<u64> {
+        Ok(match iselect {
+            ISELECT_EIDELIVERY => self.eidelivery,
+            ISELECT_EITHRESHOLD => self.eithreshold,
+            ISELECT_EIP0..=ISELECT_EIE63 => *self.bitmap_word(iselect)?.unwrap_or(&0),
+            _ => return Err(HyperError::InvalidParam),
+        })
+    }
+
+    /// Writes the register selected by `iselect` through `sireg`.
+    pub fn write_reg(&mut self, iselect: usize, val: u64) -> HyperResult {
+        match iselect {
+            // only MSI delivery is supported
+            ISELECT_EIDELIVERY => self.eidelivery = val & 1,
+            ISELECT_EITHRESHOLD => {
+                self.eithreshold = if val < IMSIC_NUM_IDS as u64 {
+                    val
+                } else {
+                    self.eithreshold
+                }
+            }
+            ISELECT_EIP0..=ISELECT_EIE63 => {
+                if let Some(word) = self.bitmap_word_mut(iselect)? {
+                    // identity 0 doesn't exist
+                    *word = if iselect == ISELECT_EIP0 || iselect == ISELECT_EIE0 {
+                        val & !1
+                    } else {
+                        val
+                    };
+                }
+            }
+            _ => return Err(HyperError::InvalidParam),
+        }
+        Ok(())
+    }
+
+    /// The word of `eip` or `eie` that `iselect` selects, `None` for identities beyond the
+    /// implemented ones. Odd registers don't exist on RV64.
+    fn bitmap_word(&self, iselect: usize) -> HyperResult<Option<&u64>> {
+        if iselect & 1 != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        let (bitmap, index) = if iselect >= ISELECT_EIE0 {
+            (&self.eie, (iselect - ISELECT_EIE0) / 2)
+        } else {
+            (&self.eip, (iselect - ISELECT_EIP0) / 2)
+        };
+        Ok(bitmap.get(index))
+    }
+
+    fn bitmap_word_mut(&mut self, iselect: usize) -> HyperResult<Option<&mut u64>> {
+        if iselect & 1 != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        let (bitmap, index) = if iselect >= ISELECT_EIE0 {
+            (&mut self.eie, (iselect - ISELECT_EIE0) / 2)
+        } else {
+            (&mut self.eip, (iselect - ISELECT_EIP0) / 2)
+        };
+        Ok(bitmap.get_mut(index))
+    }
+
+    fn save_state(&self, w: &mut SnapshotWriter) {
+        w.put_u64(self.eidelivery);
+        w.put_u64(self.eithreshold);
+        self.eip
+            .iter()
+            .chain(self.eie.iter())
+            .for_each(|&val| w.put_u64(val));
+    }
+
+    fn load_state(&mut self, r: &mut SnapshotReader) -> HyperResult {
+        self.eidelivery = r.get_u64()?;
+        self.eithreshold = r.get_u64()?;
+        for val in self.eip.iter_mut().chain(self.eie.iter_mut()) {
+            *val = r.get_u64()?;
+        }
+        Ok(())
+    }
+}
+
+/// The S-level interrupt files of all vCPUs of a VM, with one page each starting at `base`.
+pub struct SoftImsic {
+    base: usize,
+    pub files: Vec<ImsicFile>,
+}
+
+impl SoftImsic {
+    pub fn new(base: usize, num_vcpus: usize) -> Self {
+        Self {
+            base,
+            files: vec![ImsicFile::new(); num_vcpus],
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.files
+            .iter_mut()
+            .for_each(|file| *file = ImsicFile::new());
+    }
+
+    /// Writes the interrupt files into a VM snapshot.
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        w.put_usize(self.files.len());
+        self.files.iter().for_each(|file| file.save_state(w));
+    }
+
+    /// Loads the interrupt files written by [`SoftImsic::save_state`], which must come from a
+    /// VM with as many vCPUs.
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> HyperResult {
+        if r.get_usize()? != self.files.len() {
+            return Err(HyperError::DecodeError);
+        }
+        self.files
+            .iter_mut()
+            .try_for_each(|file| file.load_state(r))
+    }
+}
+
+impl MmioOps for SoftImsic {
+    fn mmio_range(&self) -> core::ops::Range<u64> {
+        self.base as u64..(self.base + IMSIC_PAGE_SIZE * self.files.len()) as u64
+    }
+
+    /// The MMIO registers are write-only.
+    fn read(&mut self, _addr: u64, access_size: u8) -> HyperResult<u64> {
+        if access_size != 4 {
+            return Err(HyperError::InValidMmioRead);
+        }
+        Ok(0)
+    }
+
+    fn write(&mut self, addr: u64, access_size: u8, value: u64) -> HyperResult {
+        if access_size != 4 {
+            return Err(HyperError::InValidMmioWrite);
+        }
+        let offset = addr as usize - self.base;
+        let file = &mut self.files[offset / IMSIC_PAGE_SIZE];
+        match offset % IMSIC_PAGE_SIZE {
+            SETEIPNUM_LE => file.set_pending(value as u32),
+            SETEIPNUM_BE => file.set_pending((value as u32).swap_bytes()),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled_file(ids: &[u32]) -> ImsicFile {
+        let mut file = ImsicFile::new();
+        file.write_reg(ISELECT_EIDELIVERY, 1).unwrap();
+        for &id in ids {
+            let iselect = ISELECT_EIE0 + id as usize / 64 * 2;
+            let val = file.read_reg(iselect).unwrap() | 1 << (id % 64);
+            file.write_reg(iselect, val).unwrap();
+        }
+        file
+    }
+
+    #[test]
+    fn topei_reports_lowest_enabled_identity() {
+        let mut file = enabled_file(&[3, 70]);
+        file.set_pending(70);
+        file.set_pending(5);
+        assert_eq!(file.topei(), 70 << 16 | 70);
+        file.set_pending(3);
+        assert!(file.has_irq());
+        assert_eq!(file.claim_topei(), 3 << 16 | 3);
+        assert_eq!(file.claim_topei(), 70 << 16 | 70);
+        assert_eq!(file.topei(), 0);
+        // 5 is still pending, but not enabled
+        assert_eq!(file.read_reg(ISELECT_EIP0).unwrap(), 1 << 5);
+        assert!(!file.has_irq());
+    }
+
+    #[test]
+    fn threshold_and_delivery_gate_interrupts() {
+        let mut file = enabled_file(&[10]);
+        file.set_pending(10);
+        file.write_reg(ISELECT_EITHRESHOLD, 10).unwrap();
+        assert_eq!(file.topei(), 0);
+        file.write_reg(ISELECT_EITHRESHOLD, 11).unwrap();
+        assert!(file.has_irq());
+        file.write_reg(ISELECT_EIDELIVERY, 0).unwrap();
+        assert!(!file.has_irq());
+    }
+
+    #[test]
+    fn rv64_register_file_layout() {
+        let mut file = ImsicFile::new();
+        assert!(file.read_reg(ISELECT_EIP0 + 1).is_err());
+        assert!(file.write_reg(0x71, 1).is_err());
+        file.write_reg(ISELECT_EIE0, u64::MAX).unwrap();
+        assert_eq!(file.read_reg(ISELECT_EIE0).unwrap(), u64::MAX - 1);
+        // identities beyond the implemented ones read as zero
+        file.write_reg(ISELECT_EIE63 - 1, u64::MAX).unwrap();
+        assert_eq!(file.read_reg(ISELECT_EIE63 - 1).unwrap(), 0);
+        file.set_pending(IMSIC_NUM_IDS as u32);
+        assert_eq!(file.pending_ids().count(), 0);
+    }
+
+    #[test]
+    fn mmio_writes_set_pending_in_the_right_file() {
+        let mut imsic = SoftImsic::new(0x2800_0000, 2);
+        imsic.write(0x2800_1000, 4, 9).unwrap();
+        imsic
+            .write(0x2800_0004, 4, 12u32.swap_bytes() as u64)
+            .unwrap();
+        assert_eq!(imsic.files[0].pending_ids().collect::<Vec<_>>(), [12]);
+        assert_eq!(imsic.files[1].pending_ids().collect::<Vec<_>>(), [9]);
+        assert_eq!(imsic.read(0x2800_0000, 4).unwrap(), 0);
+    }
+}
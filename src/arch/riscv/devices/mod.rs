@@ -1 +1,4 @@
+pub mod aclint;
+pub mod aplic;
+pub mod imsic;
 pub mod plic;
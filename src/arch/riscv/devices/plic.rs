@@ -1,30 +1,71 @@
-use crate::{
-    arch::csrs::{traps, RiscvCsrTrait, CSR},
-    vcpus::MAX_CPUS,
-};
+//! Platform-level interrupt controller emulated for guests, following the RISC-V PLIC
+//! specification. The register model doesn't touch any hart state, so that it can be tested on
+//! the host; the VM turns [`PlicState::has_irq`] into `hvip.VSEIP` of its vCPUs.
 
-/// Number of contexts for the PLIC. Value is twice the max number of harts because each hart will
-/// have one M-mode context and one S-mode context.
-pub const MAX_CONTEXTS: usize = 2 * MAX_CPUS;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::{HyperError, HyperResult, MmioOps, SnapshotReader, SnapshotWriter};
+
+/// Number of interrupt sources, including source 0 which means "no interrupt".
+pub const PLIC_NUM_SOURCES: usize = 1024;
+/// Number of 32-bit words of a bitmap of all sources.
+const WORDS: usize = PLIC_NUM_SOURCES / 32;
+/// Priorities are 3-bit WARL fields, 0 meaning "never interrupt".
+const MAX_PRIORITY: u32 = 7;
+
+/// Size of the PLIC register window.
+const PLIC_SIZE: usize = 0x0400_0000;
+const PENDING_BASE: usize = 0x1000;
+const ENABLE_BASE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_BASE: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
 
 pub struct PlicState {
     base: usize,
-    source_priority: [u32; 512],
-    pending: [u32; 16],
-    enable: [[u32; 32]; MAX_CONTEXTS],
-    thresholds: [u32; MAX_CONTEXTS],
-    pub claim_complete: [u32; MAX_CONTEXTS],
+    priority: [u32; PLIC_NUM_SOURCES],
+    /// Interrupts forwarded by the gateways and not claimed yet.
+    pending: [u32; WORDS],
+    /// Interrupts claimed by a context and not completed yet. The gateway forwards no new
+    /// request for them until then.
+    claimed: [u32; WORDS],
+    /// Levels of the interrupt lines.
+    level: [u32; WORDS],
+    enable: Vec<[u32; WORDS]>,
+    thresholds: Vec<u32>,
+    /// Sources raised for interrupts claimed at the host PLIC, with the address of the host
+    /// claim/complete register to complete them at.
+    host_claims: BTreeMap<u32, usize>,
+}
+
+fn test_bit(bitmap: &[u32; WORDS], source: usize) -> bool {
+    bitmap[source / 32] & (1 << (source % 32)) != 0
+}
+
+fn assign_bit(bitmap: &mut [u32; WORDS], source: usize, val: bool) {
+    if val {
+        bitmap[source / 32] |= 1 << (source % 32);
+    } else {
+        bitmap[source / 32] &= !(1 << (source % 32));
+    }
 }
 
 impl PlicState {
-    pub fn new(base: usize) -> Self {
+    /// Creates the PLIC of a VM with `num_harts` harts. There are twice as many contexts because
+    /// each hart has one M-mode context and one S-mode context.
+    pub fn new(base: usize, num_harts: usize) -> Self {
+        let num_contexts = 2 * num_harts;
         Self {
             base,
-            source_priority: [0; 512],
-            pending: [0; 16],
-            enable: [[0; 32]; MAX_CONTEXTS],
-            thresholds: [0; MAX_CONTEXTS],
-            claim_complete: [0; MAX_CONTEXTS],
+            priority: [0; PLIC_NUM_SOURCES],
+            pending: [0; WORDS],
+            claimed: [0; WORDS],
+            level: [0; WORDS],
+            enable: vec![[0; WORDS]; num_contexts],
+            thresholds: vec![0; num_contexts],
+            host_claims: BTreeMap::new(),
         }
     }
 
@@ -32,46 +73,332 @@ impl PlicState {
         self.base
     }
 
+    pub fn num_contexts(&self) -> usize {
+        self.thresholds.len()
+    }
+
+    /// Returns to the state after [`PlicState::new`], completing the interrupts still claimed
+    /// at the host so that it delivers them again.
+    pub fn reset(&mut self) {
+        for (&source, &addr) in self.host_claims.iter() {
+            unsafe { core::ptr::write_volatile(addr as *mut u32, source) };
+        }
+        *self = Self::new(self.base, self.num_contexts() / 2);
+    }
+
+    /// Sets the level of interrupt line `source`. The line is level-triggered: while it is
+    /// high the source is pending unless it is being serviced, lowering it withdraws the
+    /// request if it hasn't been claimed yet.
+    pub fn set_irq(&mut self, source: u32, level: bool) -> HyperResult {
+        let source = source as usize;
+        if source == 0 || source >= PLIC_NUM_SOURCES {
+            return Err(HyperError::InvalidParam);
+        }
+        assign_bit(&mut self.level, source, level);
+        if !test_bit(&self.claimed, source) {
+            assign_bit(&mut self.pending, source, level);
+        }
+        Ok(())
+    }
+
+    /// Raises `source` for an interrupt claimed at the host PLIC through the claim/complete
+    /// register at `host_claim_addr`. It is completed there once the guest completes it.
+    pub fn forward_host_irq(&mut self, source: u32, host_claim_addr: usize) -> HyperResult {
+        self.set_irq(source, true)?;
+        self.host_claims.insert(source, host_claim_addr);
+        Ok(())
+    }
+
+    /// The interrupt `context` would claim: the pending and enabled source with the highest
+    /// priority above the threshold of the context, the lowest ID winning ties. 0 if none.
+    fn best_irq(&self, context: usize) -> u32 {
+        let mut best = 0;
+        let mut best_priority = self.thresholds[context];
+        for (word, bits) in self.enable[context].iter().enumerate() {
+            let mut bits = bits & self.pending[word];
+            while bits != 0 {
+                let source = word * 32 + bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                if self.priority[source] > best_priority {
+                    best = source;
+                    best_priority = self.priority[source];
+                }
+            }
+        }
+        best as u32
+    }
+
+    /// Whether `context` has an interrupt to take, i.e. its external interrupt pending bit is
+    /// set.
+    pub fn has_irq(&self, context: usize) -> bool {
+        context < self.num_contexts() && self.best_irq(context) != 0
+    }
+
+    /// Claims the interrupt for `context`, returning its source or 0 if there is none.
+    pub fn claim(&mut self, context: usize) -> u32 {
+        let source = self.best_irq(context);
+        if source != 0 {
+            assign_bit(&mut self.pending, source as usize, false);
+            assign_bit(&mut self.claimed, source as usize, true);
+        }
+        source
+    }
+
+    /// Signals that `context` finished servicing `source`. Completions of sources the context
+    /// has not enabled are ignored.
+    pub fn complete(&mut self, context: usize, source: u32) {
+        let source = source as usize;
+        if source == 0 || source >= PLIC_NUM_SOURCES || !test_bit(&self.enable[context], source) {
+            return;
+        }
+        assign_bit(&mut self.claimed, source, false);
+        if let Some(addr) = self.host_claims.remove(&(source as u32)) {
+            // the host device asserts the line again if it still needs service
+            assign_bit(&mut self.level, source, false);
+            unsafe { core::ptr::write_volatile(addr as *mut u32, source as u32) };
+        }
+        if test_bit(&self.level, source) {
+            assign_bit(&mut self.pending, source, true);
+        }
+    }
+
+    /// Writes the emulated registers into a VM snapshot. Interrupts claimed at the host are
+    /// not part of it.
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        w.put_usize(PLIC_NUM_SOURCES);
+        w.put_usize(self.num_contexts());
+        self.priority.iter().for_each(|&val| w.put_u32(val));
+        for bitmap in [&self.pending, &self.claimed, &self.level] {
+            bitmap.iter().for_each(|&val| w.put_u32(val));
+        }
+        for context in 0..self.num_contexts() {
+            self.enable[context].iter().for_each(|&val| w.put_u32(val));
+            w.put_u32(self.thresholds[context]);
+        }
+    }
+
+    /// Loads the registers written by [`PlicState::save_state`], which must come from a PLIC
+    /// with as many sources and contexts.
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> HyperResult {
+        if r.get_usize()? != PLIC_NUM_SOURCES || r.get_usize()? != self.num_contexts() {
+            return Err(HyperError::DecodeError);
+        }
+        for val in self.priority.iter_mut() {
+            *val = r.get_u32()?;
+        }
+        for bitmap in [&mut self.pending, &mut self.claimed, &mut self.level] {
+            for val in bitmap.iter_mut() {
+                *val = r.get_u32()?;
+            }
+        }
+        for context in 0..self.num_contexts() {
+            for val in self.enable[context].iter_mut() {
+                *val = r.get_u32()?;
+            }
+            self.thresholds[context] = r.get_u32()?;
+        }
+        self.host_claims.clear();
+        Ok(())
+    }
+
+    /// Reads the register at `addr`. Reserved registers read as zero.
     pub fn read_u32(&mut self, addr: usize) -> u32 {
         let offset = addr.wrapping_sub(self.base);
-        if (0x20_0000..0x20_0000 + 0x1000 * MAX_CONTEXTS).contains(&offset) {
-            // threshold/claim/complete
-            let hart = (offset - 0x200000) / 0x1000;
-            let index = ((offset - 0x200000) & 0xfff) >> 2;
-            if index == 1 {
-                // debug!("PLIC read@{:#x} -> {:#x}", addr, self.claim_complete[hart]);
-                return self.claim_complete[hart];
+        let enable_end = ENABLE_BASE + ENABLE_STRIDE * self.num_contexts();
+        let context_end = CONTEXT_BASE + CONTEXT_STRIDE * self.num_contexts();
+        match offset {
+            0..PENDING_BASE => self.priority[offset / 4],
+            PENDING_BASE..ENABLE_BASE if offset < PENDING_BASE + 4 * WORDS => {
+                self.pending[(offset - PENDING_BASE) / 4]
+            }
+            ENABLE_BASE.. if offset < enable_end => {
+                let context = (offset - ENABLE_BASE) / ENABLE_STRIDE;
+                let word = (offset - ENABLE_BASE) % ENABLE_STRIDE / 4;
+                self.enable[context][word]
+            }
+            CONTEXT_BASE.. if offset < context_end => {
+                let context = (offset - CONTEXT_BASE) / CONTEXT_STRIDE;
+                match (offset - CONTEXT_BASE) % CONTEXT_STRIDE {
+                    0 => self.thresholds[context],
+                    4 => self.claim(context),
+                    _ => 0,
+                }
             }
-            todo!()
+            _ => 0,
         }
-        todo!()
     }
 
+    /// Writes `val` to the register at `addr`. Writes to read-only and reserved registers are
+    /// ignored.
     pub fn write_u32(&mut self, addr: usize, val: u32) {
-        // debug!("PLIC write@{:#x} -> {:#x}", addr, val);
         let offset = addr.wrapping_sub(self.base);
-        // threshold/claim/complete
-        if (0x200000..0x200000 + 0x1000 * MAX_CONTEXTS).contains(&offset) {
-            let hart = (offset - 0x200000) / 0x1000;
-            let index = ((offset - 0x200000) & 0xfff) >> 2;
-            if index == 0 {
-                // threshold
-                self.thresholds[hart] = val;
-                unsafe {
-                    core::ptr::write_volatile(addr as *mut u32, val);
-                }
-            } else if index == 1 {
-                // claim
-                unsafe {
-                    core::ptr::write_volatile(addr as *mut u32, val);
+        let enable_end = ENABLE_BASE + ENABLE_STRIDE * self.num_contexts();
+        let context_end = CONTEXT_BASE + CONTEXT_STRIDE * self.num_contexts();
+        match offset {
+            // source 0 doesn't exist
+            4..PENDING_BASE => self.priority[offset / 4] = val.min(MAX_PRIORITY),
+            ENABLE_BASE.. if offset < enable_end => {
+                let context = (offset - ENABLE_BASE) / ENABLE_STRIDE;
+                let word = (offset - ENABLE_BASE) % ENABLE_STRIDE / 4;
+                self.enable[context][word] = if word == 0 { val & !1 } else { val };
+            }
+            CONTEXT_BASE.. if offset < context_end => {
+                let context = (offset - CONTEXT_BASE) / CONTEXT_STRIDE;
+                match (offset - CONTEXT_BASE) % CONTEXT_STRIDE {
+                    0 => self.thresholds[context] = val.min(MAX_PRIORITY),
+                    4 => self.complete(context, val),
+                    _ => {}
                 }
-                self.claim_complete[hart] = 0;
-                // Send Interrupt to the hart
-                CSR.hvip
-                    .read_and_clear_bits(traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL);
             }
-        } else {
-            todo!()
+            _ => {}
+        }
+    }
+}
+
+impl MmioOps for PlicState {
+    fn mmio_range(&self) -> core::ops::Range<u64> {
+        self.base as u64..(self.base + PLIC_SIZE) as u64
+    }
+
+    fn read(&mut self, addr: u64, access_size: u8) -> HyperResult<u64> {
+        // all PLIC registers are 32 bits wide
+        if access_size != 4 || addr & 3 != 0 {
+            return Err(HyperError::InValidMmioRead);
+        }
+        Ok(self.read_u32(addr as usize) as u64)
+    }
+
+    fn write(&mut self, addr: u64, access_size: u8, value: u64) -> HyperResult {
+        if access_size != 4 || addr & 3 != 0 {
+            return Err(HyperError::InValidMmioWrite);
+        }
+        self.write_u32(addr as usize, value as u32);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: usize = 0xc00_0000;
+    /// S-mode context of hart 0.
+    const CTX: usize = 1;
+
+    fn enable(plic: &mut PlicState, context: usize, source: usize) {
+        let addr = BASE + ENABLE_BASE + ENABLE_STRIDE * context + source / 32 * 4;
+        let val = plic.read_u32(addr) | 1 << (source % 32);
+        plic.write_u32(addr, val);
+    }
+
+    fn claim_addr(context: usize) -> usize {
+        BASE + CONTEXT_BASE + CONTEXT_STRIDE * context + 4
+    }
+
+    fn plic_with(sources: &[(u32, u32)]) -> PlicState {
+        let mut plic = PlicState::new(BASE, 1);
+        for &(source, priority) in sources {
+            plic.write_u32(BASE + 4 * source as usize, priority);
+            enable(&mut plic, CTX, source as usize);
         }
+        plic
+    }
+
+    #[test]
+    fn claims_highest_priority_then_lowest_id() {
+        let mut plic = plic_with(&[(5, 2), (40, 6), (41, 6)]);
+        for source in [5, 40, 41] {
+            plic.set_irq(source, true).unwrap();
+        }
+        assert_eq!(plic.read_u32(BASE + PENDING_BASE), 1 << 5);
+        assert_eq!(plic.read_u32(BASE + PENDING_BASE + 4), 0b11 << 8);
+        assert!(plic.has_irq(CTX));
+        assert!(!plic.has_irq(CTX - 1));
+        assert_eq!(plic.read_u32(claim_addr(CTX)), 40);
+        assert_eq!(plic.read_u32(claim_addr(CTX)), 41);
+        assert_eq!(plic.read_u32(claim_addr(CTX)), 5);
+        assert_eq!(plic.read_u32(claim_addr(CTX)), 0);
+        assert!(!plic.has_irq(CTX));
+    }
+
+    #[test]
+    fn threshold_and_priority_zero_mask_interrupts() {
+        let mut plic = plic_with(&[(1, 0), (2, 3)]);
+        plic.set_irq(1, true).unwrap();
+        plic.set_irq(2, true).unwrap();
+        plic.write_u32(BASE + CONTEXT_BASE + CONTEXT_STRIDE * CTX, 3);
+        assert!(!plic.has_irq(CTX));
+        plic.write_u32(BASE + CONTEXT_BASE + CONTEXT_STRIDE * CTX, 2);
+        assert_eq!(plic.read_u32(claim_addr(CTX)), 2);
+        assert_eq!(plic.read_u32(claim_addr(CTX)), 0);
+    }
+
+    #[test]
+    fn level_line_pends_again_after_completion() {
+        let mut plic = plic_with(&[(7, 1)]);
+        plic.set_irq(7, true).unwrap();
+        assert_eq!(plic.read_u32(claim_addr(CTX)), 7);
+        // still high while in service, but not pending
+        assert!(!plic.has_irq(CTX));
+        plic.write_u32(claim_addr(CTX), 7);
+        assert_eq!(plic.read_u32(claim_addr(CTX)), 7);
+        plic.set_irq(7, false).unwrap();
+        plic.write_u32(claim_addr(CTX), 7);
+        assert!(!plic.has_irq(CTX));
+    }
+
+    #[test]
+    fn lowering_the_line_withdraws_the_request() {
+        let mut plic = plic_with(&[(3, 1)]);
+        plic.set_irq(3, true).unwrap();
+        plic.set_irq(3, false).unwrap();
+        assert!(!plic.has_irq(CTX));
+        assert_eq!(plic.read_u32(claim_addr(CTX)), 0);
+    }
+
+    #[test]
+    fn completion_of_disabled_source_is_ignored() {
+        let mut plic = plic_with(&[(9, 1)]);
+        plic.set_irq(9, true).unwrap();
+        assert_eq!(plic.read_u32(claim_addr(CTX)), 9);
+        plic.write_u32(BASE + ENABLE_BASE + ENABLE_STRIDE * CTX, 0);
+        plic.write_u32(claim_addr(CTX), 9);
+        enable(&mut plic, CTX, 9);
+        // still claimed, so the high line doesn't make it pending
+        assert!(!plic.has_irq(CTX));
+        plic.write_u32(claim_addr(CTX), 9);
+        assert!(plic.has_irq(CTX));
+    }
+
+    #[test]
+    fn warl_fields_and_invalid_sources() {
+        let mut plic = PlicState::new(BASE, 1);
+        plic.write_u32(BASE, 5);
+        assert_eq!(plic.read_u32(BASE), 0);
+        plic.write_u32(BASE + 4, 0xff);
+        assert_eq!(plic.read_u32(BASE + 4), MAX_PRIORITY);
+        plic.write_u32(BASE + ENABLE_BASE, u32::MAX);
+        assert_eq!(plic.read_u32(BASE + ENABLE_BASE), u32::MAX - 1);
+        assert!(plic.set_irq(0, true).is_err());
+        assert!(plic.set_irq(PLIC_NUM_SOURCES as u32, true).is_err());
+        // contexts of harts that don't exist
+        assert_eq!(plic.read_u32(claim_addr(2)), 0);
+        assert!(!plic.has_irq(2));
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let mut plic = plic_with(&[(100, 4)]);
+        plic.set_irq(100, true).unwrap();
+        let mut w = SnapshotWriter::new();
+        plic.save_state(&mut w);
+        let bytes = w.into_bytes();
+        let mut restored = PlicState::new(BASE, 1);
+        let mut r = SnapshotReader::new(&bytes).unwrap();
+        restored.load_state(&mut r).unwrap();
+        assert_eq!(restored.read_u32(claim_addr(CTX)), 100);
+        assert!(PlicState::new(BASE, 2)
+            .load_state(&mut SnapshotReader::new(&bytes).unwrap())
+            .is_err());
     }
 }
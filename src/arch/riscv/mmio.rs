@@ -0,0 +1,216 @@
+//! Decoding of the loads and stores that trap on emulated MMIO.
+
+use crate::{HyperError, HyperResult, PendingRead};
+
+/// Register-side half of an emulated access.
+pub(crate) enum MmioOp {
+    /// A load into the register described by the [`PendingRead`].
+    Load(PendingRead),
+    /// A store of the low bytes of register `rs2`.
+    Store { rs2: usize },
+}
+
+/// A decoded load or store that faulted on an MMIO address.
+pub(crate) struct MmioAccess {
+    /// Access width in bytes.
+    pub size: u8,
+    /// Whether it is a load or a store, and the register involved.
+    pub op: MmioOp,
+    /// Length of the trapping instruction, to advance `sepc` by.
+    pub len: usize,
+}
+
+impl MmioAccess {
+    fn load(rd: u32, size: u8, sign_extend: bool, len: usize) -> Self {
+        Self {
+            size,
+            op: MmioOp::Load(PendingRead {
+                sign_extend,
+                ..PendingRead::new(rd as usize, size)
+            }),
+            len,
+        }
+    }
+
+    fn store(rs2: u32, size: u8, len: usize) -> Self {
+        Self {
+            size,
+            op: MmioOp::Store { rs2: rs2 as usize },
+            len,
+        }
+    }
+}
+
+const OPCODE_LOAD: u32 = 0b000_0011;
+const OPCODE_STORE: u32 = 0b010_0011;
+
+/// Decodes the load or store `inst`.
+///
+/// If `transformed` is set, `inst` was reported by `htinst`: the hardware replaced a compressed
+/// instruction by its 32-bit equivalent with bit 1 cleared, so the original length is recovered
+/// from that bit. Otherwise `inst` was fetched from guest memory and its length follows from the
+/// encoding.
+pub(crate) fn decode_access(inst: u32, transformed: bool) -> HyperResult<MmioAccess> {
+    if transformed {
+        // Bit 0 is clear for the pseudoinstructions reported for faults on implicit accesses
+        // by the guest page table walk, which aren't emulated.
+        if inst & 0b01 == 0 {
+            return Err(HyperError::InvalidInstruction);
+        }
+        let len = if inst & 0b10 != 0 { 4 } else { 2 };
+        decode_standard(inst | 0b10, len)
+    } else if inst & 0b11 == 0b11 {
+        decode_standard(inst, 4)
+    } else {
+        decode_compressed(inst as u16)
+    }
+}
+
+fn decode_standard(inst: u32, len: usize) -> HyperResult<MmioAccess> {
+    let funct3 = (inst >> 12) & 0b111;
+    let rd = (inst >> 7) & 0x1f;
+    let rs2 = (inst >> 20) & 0x1f;
+    let access = match (inst & 0x7f, funct3) {
+        (OPCODE_LOAD, 0b000) => MmioAccess::load(rd, 1, true, len),
+        (OPCODE_LOAD, 0b001) => MmioAccess::load(rd, 2, true, len),
+        (OPCODE_LOAD, 0b010) => MmioAccess::load(rd, 4, true, len),
+        (OPCODE_LOAD, 0b011) => MmioAccess::load(rd, 8, false, len),
+        (OPCODE_LOAD, 0b100) => MmioAccess::load(rd, 1, false, len),
+        (OPCODE_LOAD, 0b101) => MmioAccess::load(rd, 2, false, len),
+        (OPCODE_LOAD, 0b110) => MmioAccess::load(rd, 4, false, len),
+        (OPCODE_STORE, 0b000) => MmioAccess::store(rs2, 1, len),
+        (OPCODE_STORE, 0b001) => MmioAccess::store(rs2, 2, len),
+        (OPCODE_STORE, 0b010) => MmioAccess::store(rs2, 4, len),
+        (OPCODE_STORE, 0b011) => MmioAccess::store(rs2, 8, len),
+        _ => return Err(HyperError::InvalidInstruction),
+    };
+    Ok(access)
+}
+
+fn decode_compressed(inst: u16) -> HyperResult<MmioAccess> {
+    let inst = inst as u32;
+    let funct3 = (inst >> 13) & 0b111;
+    // rd' and rs2' of the CL/CS formats name x8-x15
+    let reg_prime = 8 + ((inst >> 2) & 0b111);
+    let rd = (inst >> 7) & 0x1f;
+    let rs2 = (inst >> 2) & 0x1f;
+    let access = match (inst & 0b11, funct3) {
+        (0b00, 0b010) => MmioAccess::load(reg_prime, 4, true, 2),
+        (0b00, 0b011) => MmioAccess::load(reg_prime, 8, false, 2),
+        (0b00, 0b110) => MmioAccess::store(reg_prime, 4, 2),
+        (0b00, 0b111) => MmioAccess::store(reg_prime, 8, 2),
+        // C.LWSP and C.LDSP with rd == 0 are reserved
+        (0b10, 0b010) if rd != 0 => MmioAccess::load(rd, 4, true, 2),
+        (0b10, 0b011) if rd != 0 => MmioAccess::load(rd, 8, false, 2),
+        (0b10, 0b110) => MmioAccess::store(rs2, 4, 2),
+        (0b10, 0b111) => MmioAccess::store(rs2, 8, 2),
+        _ => return Err(HyperError::InvalidInstruction),
+    };
+    Ok(access)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[track_caller]
+    fn assert_load(
+        inst: u32,
+        transformed: bool,
+        rd: usize,
+        size: u8,
+        sign_extend: bool,
+        len: usize,
+    ) {
+        let access = decode_access(inst, transformed).unwrap();
+        let MmioOp::Load(read) = access.op else {
+            panic!("{inst:#x} decoded as a store");
+        };
+        assert_eq!(
+            (read.reg, access.size, read.sign_extend, access.len),
+            (rd, size, sign_extend, len),
+            "{inst:#x}"
+        );
+        assert_eq!(read.size, size);
+    }
+
+    #[track_caller]
+    fn assert_store(inst: u32, transformed: bool, rs2: usize, size: u8, len: usize) {
+        let access = decode_access(inst, transformed).unwrap();
+        let MmioOp::Store { rs2: reg } = access.op else {
+            panic!("{inst:#x} decoded as a load");
+        };
+        assert_eq!(
+            (reg, access.size, access.len),
+            (rs2, size, len),
+            "{inst:#x}"
+        );
+    }
+
+    #[test]
+    fn standard_loads_and_stores() {
+        // `l* a0, 8(a1)`
+        let load = |funct3: u32| 8 << 20 | 11 << 15 | funct3 << 12 | 10 << 7 | OPCODE_LOAD;
+        let loads = [
+            (0b000, 1, true),  // lb
+            (0b001, 2, true),  // lh
+            (0b010, 4, true),  // lw
+            (0b011, 8, false), // ld
+            (0b100, 1, false), // lbu
+            (0b101, 2, false), // lhu
+            (0b110, 4, false), // lwu
+        ];
+        for (funct3, size, sign_extend) in loads {
+            assert_load(load(funct3), false, 10, size, sign_extend, 4);
+        }
+        // `s* a2, 16(a1)`
+        let store = |funct3: u32| 12 << 20 | 11 << 15 | funct3 << 12 | 16 << 7 | OPCODE_STORE;
+        for (funct3, size) in [(0b000, 1), (0b001, 2), (0b010, 4), (0b011, 8)] {
+            assert_store(store(funct3), false, 12, size, 4);
+        }
+        assert_load(0x0085_a503, false, 10, 4, true, 4); // lw a0, 8(a1)
+        assert_store(0x00c5_b823, false, 12, 8, 4); // sd a2, 16(a1)
+        assert!(decode_access(load(0b111), false).is_err());
+        assert!(decode_access(store(0b100), false).is_err());
+        assert!(decode_access(0x0085_a507, false).is_err()); // flw fa0, 8(a1)
+    }
+
+    #[test]
+    fn compressed_loads_and_stores() {
+        assert_load(0x41c8, false, 10, 4, true, 2); // c.lw a0, 4(a1)
+        assert_load(0x641c, false, 15, 8, false, 2); // c.ld a5, 8(s0)
+        assert_store(0xc384, false, 9, 4, 2); // c.sw s1, 0(a5)
+        assert_store(0xe118, false, 14, 8, 2); // c.sd a4, 0(a0)
+        assert_load(0x40b2, false, 1, 4, true, 2); // c.lwsp ra, 12(sp)
+        assert_load(0x6422, false, 8, 8, false, 2); // c.ldsp s0, 8(sp)
+        assert_store(0xc22a, false, 10, 4, 2); // c.swsp a0, 4(sp)
+        assert_store(0xe006, false, 1, 8, 2); // c.sdsp ra, 0(sp)
+        assert!(decode_access(0x4002, false).is_err()); // c.lwsp with rd == 0
+        assert!(decode_access(0x2000, false).is_err()); // c.fld
+        assert!(decode_access(0x0505, false).is_err()); // c.addi a0, 1
+    }
+
+    #[test]
+    fn compressed_registers_name_x8_to_x15() {
+        for reg in 0..8 {
+            // `c.lw rd', 0(s0)` and `c.sd rs2', 0(s0)`
+            assert_load(0x4000 | reg << 2, false, 8 + reg as usize, 4, true, 2);
+            assert_store(0xe000 | reg << 2, false, 8 + reg as usize, 8, 2);
+        }
+    }
+
+    #[test]
+    fn transformed_instructions() {
+        // c.lw a0, 4(a1) reported as `lw a0, 0(x0)` with bit 1 cleared
+        assert_load(0x0000_2501, true, 10, 4, true, 2);
+        // c.sdsp ra, 0(sp) reported as `sd ra, 0(x0)` with bit 1 cleared
+        assert_store(0x0010_3021, true, 1, 8, 2);
+        // lhu a0, 8(a1) reported as `lhu a0, 0(x0)`
+        assert_load(0x0000_5503, true, 10, 2, false, 4);
+        // sd a2, 16(a1) reported as `sd a2, 0(x0)`
+        assert_store(0x00c0_3023, true, 12, 8, 4);
+        // pseudoinstructions for implicit accesses of the guest page table walk
+        assert!(decode_access(0x0000_2000, true).is_err());
+        assert!(decode_access(0x0000_3020, true).is_err());
+    }
+}
@@ -1,19 +1,27 @@
+mod aia;
 mod csrs;
 mod detect;
 mod devices;
 mod ept;
+mod mmio;
+mod page_walk;
 mod regs;
 mod sbi;
 mod smp;
+mod timer;
 mod vcpu;
 mod vm;
 mod vm_pages;
 mod vmexit;
 
+pub use aia::{AiaConfig, HostImsic};
 pub use ept::NestedPageTable;
+pub use page_walk::GuestPageWalkInfo;
 pub use regs::GprIndex;
+pub use sbi::HartState;
 pub use sbi::SbiMessage as HyperCallMsg;
 pub use smp::PerCpu;
+pub use timer::set_host_timer;
 pub use vcpu::VCpu;
 pub use vm::VM;
 pub use vmexit::VmExitInfo;
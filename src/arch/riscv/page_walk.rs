@@ -0,0 +1,365 @@
+use crate::{
+    GuestAccess, GuestMemory, GuestPageFault, GuestPageTableTrait, GuestPageWalk, GuestPhysAddr,
+    GuestVirtAddr, HyperCraftHal, HyperError, HyperResult,
+};
+
+/// `satp.MODE` values.
+const SATP_MODE_BARE: usize = 0;
+const SATP_MODE_SV39: usize = 8;
+const SATP_MODE_SV48: usize = 9;
+const SATP_MODE_SV57: usize = 10;
+const SATP_PPN_MASK: usize = (1 << 44) - 1;
+
+/// Page table entry bits.
+const PTE_V: usize = 1 << 0;
+const PTE_R: usize = 1 << 1;
+const PTE_W: usize = 1 << 2;
+const PTE_X: usize = 1 << 3;
+const PTE_U: usize = 1 << 4;
+const PTE_A: usize = 1 << 6;
+const PTE_D: usize = 1 << 7;
+const PTE_PPN_SHIFT: usize = 10;
+const PTE_PPN_MASK: usize = (1 << 44) - 1;
+/// Bits 54 to 60 must be zero. `PBMT` (bits 61 and 62) and `N` (bit 63) are left to the host.
+const PTE_RESERVED: usize = 0x7f << 54;
+
+/// `sstatus` bits that change the permissions of a VS-stage translation.
+pub(crate) const SSTATUS_SUM: usize = 1 << 18;
+pub(crate) const SSTATUS_MXR: usize = 1 << 19;
+
+#[derive(Debug)]
+/// The information of guest page walk: the `vsatp` and `vsstatus` of the vCPU and its privilege.
+pub struct GuestPageWalkInfo {
+    /// `vsatp`, which selects Bare, Sv39, Sv48 or Sv57 and holds the root table.
+    pub satp: usize,
+    /// The access is made from VU-mode.
+    pub is_user_mode_access: bool,
+    /// `vsstatus.SUM`, which lets VS-mode read and write user pages.
+    pub sum: bool,
+    /// `vsstatus.MXR`, which makes executable pages readable.
+    pub mxr: bool,
+    /// Set the `A` and `D` bits of the leaf entry, as Svadu does. Otherwise the entries are left
+    /// alone, as inspecting guest memory should, and a leaf without `A`, or without `D` for a
+    /// write, faults as with Svade.
+    pub set_accessed_dirty: bool,
+}
+
+impl GuestPageWalkInfo {
+    /// Whether the leaf entry `pte` denies `access`.
+    fn denies(&self, access: GuestAccess, pte: usize) -> bool {
+        let user_page = pte & PTE_U != 0;
+        let allowed = match access {
+            GuestAccess::Read => pte & PTE_R != 0 || (self.mxr && pte & PTE_X != 0),
+            GuestAccess::Write => pte & PTE_W != 0,
+            GuestAccess::Fetch => pte & PTE_X != 0,
+        };
+        let privilege = if self.is_user_mode_access {
+            user_page
+        } else {
+            // supervisor code never runs from user pages, SUM only opens them to loads and stores
+            !user_page || (self.sum && access != GuestAccess::Fetch)
+        };
+        !(allowed && privilege)
+    }
+}
+
+impl GuestPageWalk for GuestPageWalkInfo {
+    /// Walks Sv39, Sv48 or Sv57 VS-stage page tables. A failed walk is the page fault the guest
+    /// would take, its address being `gva`.
+    fn translate<H: HyperCraftHal, G: GuestPageTableTrait>(
+        &self,
+        memory: &GuestMemory<H>,
+        gpt: &G,
+        gva: GuestVirtAddr,
+        access: GuestAccess,
+    ) -> HyperResult<GuestPhysAddr> {
+        let levels = match self.satp >> 60 {
+            SATP_MODE_BARE => return Ok(gva as GuestPhysAddr),
+            SATP_MODE_SV39 => 3,
+            SATP_MODE_SV48 => 4,
+            SATP_MODE_SV57 => 5,
+            _ => return Err(HyperError::NotSupported),
+        };
+        let fault = HyperError::GuestPageFault(GuestPageFault {
+            addr: gva,
+            physical: false,
+            access,
+            error_code: 0,
+        });
+        // bits above the virtual address must all equal its top bit
+        let va_bits = 12 + 9 * levels;
+        let upper = (gva as isize) >> (va_bits - 1);
+        if upper != 0 && upper != -1 {
+            return Err(fault);
+        }
+
+        let mut table = (self.satp & SATP_PPN_MASK) << 12;
+        let mut level = levels;
+        loop {
+            level -= 1;
+            let shift = 12 + 9 * level;
+            let pte_addr = table + ((gva >> shift) & 0x1ff) * 8;
+            let pte = memory.read_obj::<u64, G>(gpt, pte_addr)? as usize;
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) || pte & PTE_RESERVED != 0
+            {
+                return Err(fault);
+            }
+            let ppn = (pte >> PTE_PPN_SHIFT) & PTE_PPN_MASK;
+            if pte & (PTE_R | PTE_X) == 0 {
+                // pointer to the next level
+                if level == 0 {
+                    return Err(fault);
+                }
+                table = ppn << 12;
+                continue;
+            }
+
+            let page_mask = (1 << shift) - 1;
+            // superpages must be aligned to their size
+            if self.denies(access, pte) || (ppn << 12) & page_mask != 0 {
+                return Err(fault);
+            }
+            let mut bits = PTE_A;
+            if access == GuestAccess::Write {
+                bits |= PTE_D;
+            }
+            if pte & bits != bits {
+                if !self.set_accessed_dirty {
+                    return Err(fault);
+                }
+                memory.fetch_or_u32(gpt, pte_addr, bits as u32)?;
+            }
+            return Ok((ppn << 12) | (gva & page_mask));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::guest_memory::tests::{ram, TestGpt, TestHal};
+
+    const SV48_ROOT: usize = 0x1000;
+    const ROOT: usize = 0x2000;
+    const L1: usize = 0x3000;
+    const L0: usize = 0x4000;
+    const PAGE: usize = 0x8000;
+
+    /// A leaf mapping `pa` with `flags`.
+    const fn leaf(pa: usize, flags: usize) -> usize {
+        (pa >> 12) << PTE_PPN_SHIFT | flags | PTE_V
+    }
+
+    /// A pointer to the table at `pa`.
+    const fn table(pa: usize) -> usize {
+        leaf(pa, 0)
+    }
+
+    /// Sv39 as seen from VS-mode with `vsstatus.SUM` and `MXR` clear.
+    fn sv39() -> GuestPageWalkInfo {
+        GuestPageWalkInfo {
+            satp: SATP_MODE_SV39 << 60 | ROOT >> 12,
+            is_user_mode_access: false,
+            sum: false,
+            mxr: false,
+            set_accessed_dirty: false,
+        }
+    }
+
+    /// Sv39 tables mapping the page at virtual address 0x1000 to [`PAGE`] with `flags`, and a
+    /// Sv48 root whose first two entries both point to the Sv39 root.
+    fn tables(flags: usize) -> (GuestMemory<TestHal>, TestGpt) {
+        let (memory, gpt) = ram(0x10000);
+        memory.write_obj(&gpt, SV48_ROOT, &table(ROOT)).unwrap();
+        memory.write_obj(&gpt, SV48_ROOT + 8, &table(ROOT)).unwrap();
+        memory.write_obj(&gpt, ROOT, &table(L1)).unwrap();
+        memory.write_obj(&gpt, L1, &table(L0)).unwrap();
+        memory.write_obj(&gpt, L0 + 8, &leaf(PAGE, flags)).unwrap();
+        (memory, gpt)
+    }
+
+    fn translate(
+        info: &GuestPageWalkInfo,
+        (memory, gpt): &(GuestMemory<TestHal>, TestGpt),
+        gva: GuestVirtAddr,
+        access: GuestAccess,
+    ) -> HyperResult<GuestPhysAddr> {
+        info.translate(memory, gpt, gva, access)
+    }
+
+    fn fault(gva: GuestVirtAddr, access: GuestAccess) -> HyperResult<usize> {
+        Err(HyperError::GuestPageFault(GuestPageFault {
+            addr: gva,
+            physical: false,
+            access,
+            error_code: 0,
+        }))
+    }
+
+    #[test]
+    fn sv39_and_sv48() {
+        use GuestAccess::*;
+        let mem = tables(PTE_R | PTE_W | PTE_A | PTE_D);
+        assert_eq!(translate(&sv39(), &mem, 0x1234, Read), Ok(0x8234));
+        assert_eq!(translate(&sv39(), &mem, 0x1ff8, Write), Ok(0x8ff8));
+        // unmapped, and bits above bit 38 not copies of it
+        assert_eq!(translate(&sv39(), &mem, 0x2000, Read), fault(0x2000, Read));
+        let gva = 1 << 39 | 0x1234;
+        assert_eq!(translate(&sv39(), &mem, gva, Read), fault(gva, Read));
+
+        // the same tables one level down, an address above 512G goes through the second entry
+        let sv48 = GuestPageWalkInfo {
+            satp: SATP_MODE_SV48 << 60 | SV48_ROOT >> 12,
+            ..sv39()
+        };
+        assert_eq!(translate(&sv48, &mem, 0x1234, Read), Ok(0x8234));
+        assert_eq!(translate(&sv48, &mem, gva, Read), Ok(0x8234));
+        let gva = 1 << 48 | 0x1234;
+        assert_eq!(translate(&sv48, &mem, gva, Read), fault(gva, Read));
+
+        // Bare doesn't translate, unknown modes aren't walked
+        let bare = GuestPageWalkInfo { satp: 0, ..sv39() };
+        assert_eq!(translate(&bare, &mem, 0x1234, Read), Ok(0x1234));
+        let sv64 = GuestPageWalkInfo {
+            satp: 11 << 60,
+            ..sv39()
+        };
+        assert_eq!(
+            translate(&sv64, &mem, 0x1234, Read),
+            Err(HyperError::NotSupported)
+        );
+    }
+
+    #[test]
+    fn malformed_entries() {
+        use GuestAccess::*;
+        let rw = PTE_R | PTE_W | PTE_A | PTE_D;
+        // not valid, writable but not readable, reserved bits set
+        let ptes = [
+            leaf(PAGE, rw) & !PTE_V,
+            leaf(PAGE, PTE_W | PTE_A | PTE_D),
+            leaf(PAGE, rw) | 1 << 54,
+            leaf(PAGE, rw) | 1 << 60,
+        ];
+        for pte in ptes {
+            let mem = tables(0);
+            mem.0.write_obj(&mem.1, L0 + 8, &pte).unwrap();
+            assert_eq!(translate(&sv39(), &mem, 0x1000, Read), fault(0x1000, Read));
+        }
+        // PBMT and N are not reserved
+        let mem = tables(rw | 1 << 61 | 1 << 63);
+        assert_eq!(translate(&sv39(), &mem, 0x1000, Read), Ok(PAGE));
+        // a pointer at the last level
+        let mem = tables(0);
+        mem.0.write_obj(&mem.1, L0 + 8, &table(PAGE)).unwrap();
+        assert_eq!(translate(&sv39(), &mem, 0x1000, Read), fault(0x1000, Read));
+    }
+
+    #[test]
+    fn superpages_must_be_aligned() {
+        use GuestAccess::*;
+        let (memory, gpt) = tables(0);
+        let flags = PTE_R | PTE_A;
+        memory
+            .write_obj(&gpt, L1 + 8, &leaf(0x60_0000, flags))
+            .unwrap();
+        memory
+            .write_obj(&gpt, L1 + 16, &leaf(0x60_1000, flags))
+            .unwrap();
+        memory
+            .write_obj(&gpt, ROOT + 8, &leaf(0x8000_0000, flags))
+            .unwrap();
+        memory
+            .write_obj(&gpt, ROOT + 16, &leaf(0x8020_0000, flags))
+            .unwrap();
+        let mem = (memory, gpt);
+        assert_eq!(translate(&sv39(), &mem, 0x21_2345, Read), Ok(0x61_2345));
+        assert_eq!(translate(&sv39(), &mem, 0x4012_3456, Read), Ok(0x8012_3456));
+        for gva in [0x41_2345, 0x8012_3456] {
+            assert_eq!(translate(&sv39(), &mem, gva, Read), fault(gva, Read));
+        }
+    }
+
+    #[test]
+    fn user_pages_sum_and_mxr() {
+        use GuestAccess::*;
+        let user = GuestPageWalkInfo {
+            is_user_mode_access: true,
+            ..sv39()
+        };
+        let sum = GuestPageWalkInfo {
+            sum: true,
+            ..sv39()
+        };
+        let mxr = GuestPageWalkInfo {
+            mxr: true,
+            ..sv39()
+        };
+
+        // a user page is only open to VS-mode loads and stores with SUM
+        let mem = tables(PTE_U | PTE_R | PTE_W | PTE_X | PTE_A | PTE_D);
+        for access in [Read, Write, Fetch] {
+            assert_eq!(translate(&user, &mem, 0x1000, access), Ok(PAGE));
+            assert_eq!(
+                translate(&sv39(), &mem, 0x1000, access),
+                fault(0x1000, access)
+            );
+        }
+        assert_eq!(translate(&sum, &mem, 0x1000, Read), Ok(PAGE));
+        assert_eq!(translate(&sum, &mem, 0x1000, Write), Ok(PAGE));
+        assert_eq!(translate(&sum, &mem, 0x1000, Fetch), fault(0x1000, Fetch));
+
+        // a supervisor page is closed to VU-mode
+        let mem = tables(PTE_R | PTE_W | PTE_X | PTE_A | PTE_D);
+        assert_eq!(translate(&sv39(), &mem, 0x1000, Fetch), Ok(PAGE));
+        assert_eq!(translate(&user, &mem, 0x1000, Read), fault(0x1000, Read));
+
+        // an execute-only page is readable with MXR
+        let mem = tables(PTE_X | PTE_A);
+        assert_eq!(translate(&sv39(), &mem, 0x1000, Read), fault(0x1000, Read));
+        assert_eq!(translate(&mxr, &mem, 0x1000, Read), Ok(PAGE));
+        assert_eq!(translate(&mxr, &mem, 0x1000, Write), fault(0x1000, Write));
+    }
+
+    #[test]
+    fn accessed_and_dirty() {
+        use GuestAccess::*;
+        // without Svadu a clear A, or a clear D for a write, faults
+        let mem = tables(PTE_R | PTE_W);
+        assert_eq!(translate(&sv39(), &mem, 0x1000, Read), fault(0x1000, Read));
+        let mem = tables(PTE_R | PTE_W | PTE_A);
+        assert_eq!(translate(&sv39(), &mem, 0x1000, Read), Ok(PAGE));
+        assert_eq!(
+            translate(&sv39(), &mem, 0x1000, Write),
+            fault(0x1000, Write)
+        );
+        assert_eq!(
+            mem.0.read_obj::<usize, _>(&mem.1, L0 + 8),
+            Ok(leaf(PAGE, PTE_R | PTE_W | PTE_A))
+        );
+
+        // with it they are set
+        let svadu = GuestPageWalkInfo {
+            set_accessed_dirty: true,
+            ..sv39()
+        };
+        let mem = tables(PTE_R | PTE_W);
+        assert_eq!(translate(&svadu, &mem, 0x1000, Read), Ok(PAGE));
+        assert_eq!(
+            mem.0.read_obj::<usize, _>(&mem.1, L0 + 8),
+            Ok(leaf(PAGE, PTE_R | PTE_W | PTE_A))
+        );
+        assert_eq!(translate(&svadu, &mem, 0x1000, Write), Ok(PAGE));
+        assert_eq!(
+            mem.0.read_obj::<usize, _>(&mem.1, L0 + 8),
+            Ok(leaf(PAGE, PTE_R | PTE_W | PTE_A | PTE_D))
+        );
+        // but not on a denied access
+        let mem = tables(PTE_R);
+        assert_eq!(translate(&svadu, &mem, 0x1000, Write), fault(0x1000, Write));
+        assert_eq!(
+            mem.0.read_obj::<usize, _>(&mem.1, L0 + 8),
+            Ok(leaf(PAGE, PTE_R))
+        );
+    }
+}
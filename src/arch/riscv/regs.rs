@@ -101,6 +101,18 @@ impl GeneralPurposeRegisters {
         self.0[reg_index as usize] = val;
     }
 
+    /// Returns the value of register `x<index>`, or 0 if there is no such register.
+    pub fn reg_by_index(&self, index: usize) -> usize {
+        self.0.get(index).copied().unwrap_or(0)
+    }
+
+    /// Sets register `x<index>`, ignoring writes to `x0` and to registers that don't exist.
+    pub fn set_reg_by_index(&mut self, index: usize, val: usize) {
+        if let Some(reg) = self.0.get_mut(index).filter(|_| index != 0) {
+            *reg = val;
+        }
+    }
+
     /// Returns the argument registers.
     /// This is avoids many calls when an SBI handler needs all of the argmuent regs.
     pub fn a_regs(&self) -> &[usize] {
@@ -0,0 +1,84 @@
+use sbi_spec::hsm::{
+    HART_GET_STATUS, HART_START, HART_STATE_STARTED, HART_STATE_START_PENDING, HART_STATE_STOPPED,
+    HART_STATE_SUSPENDED, HART_STOP, HART_SUSPEND,
+};
+
+use crate::{HyperError, HyperResult};
+
+/// Functions for the Hart State Management extension.
+#[derive(Clone, Copy, Debug)]
+pub enum HsmFunction {
+    /// Starts the stopped hart `hartid` at `start_addr`, with `opaque` in `a1`.
+    Start {
+        /// The hart to start, a vCPU ID.
+        hartid: usize,
+        /// Guest physical address the hart starts at in S-mode.
+        start_addr: usize,
+        /// Passed to the hart in `a1`.
+        opaque: usize,
+    },
+    /// Stops the calling hart.
+    Stop,
+    /// Returns the state of hart `hartid`.
+    GetStatus {
+        /// The hart to query, a vCPU ID.
+        hartid: usize,
+    },
+    /// Suspends the calling hart until it gets an interrupt.
+    Suspend {
+        /// Retentive or non-retentive, and platform-specific types.
+        suspend_type: u32,
+        /// Where a non-retentive suspend resumes, like a start.
+        resume_addr: usize,
+        /// Passed in `a1` when resuming from a non-retentive suspend.
+        opaque: usize,
+    },
+}
+
+impl HsmFunction {
+    pub(crate) fn from_regs(args: &[usize]) -> HyperResult<Self> {
+        match args[6] {
+            HART_START => Ok(Self::Start {
+                hartid: args[0],
+                start_addr: args[1],
+                opaque: args[2],
+            }),
+            HART_STOP => Ok(Self::Stop),
+            HART_GET_STATUS => Ok(Self::GetStatus { hartid: args[0] }),
+            HART_SUSPEND => Ok(Self::Suspend {
+                suspend_type: args[0] as u32,
+                resume_addr: args[1],
+                opaque: args[2],
+            }),
+            _ => Err(HyperError::NotSupported),
+        }
+    }
+}
+
+/// The state of a vCPU, as the HSM extension reports it. The transitions a vCPU goes through
+/// when it runs next are done at once, so it is never stop-, suspend- or resume-pending.
+#[repr(usize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HartState {
+    /// Running guest code.
+    Started = HART_STATE_STARTED,
+    /// Not running until another vCPU starts it.
+    Stopped = HART_STATE_STOPPED,
+    /// Started by another vCPU, running from the start address when it runs next.
+    StartPending = HART_STATE_START_PENDING,
+    /// Waiting for an interrupt, resuming when it runs next.
+    Suspended = HART_STATE_SUSPENDED,
+}
+
+impl HartState {
+    /// Creates a state from the value returned by `hart_get_status`.
+    pub(crate) fn from_raw(raw: usize) -> HyperResult<Self> {
+        Ok(match raw {
+            HART_STATE_STARTED => Self::Started,
+            HART_STATE_STOPPED => Self::Stopped,
+            HART_STATE_START_PENDING => Self::StartPending,
+            HART_STATE_SUSPENDED => Self::Suspended,
+            _ => return Err(HyperError::DecodeError),
+        })
+    }
+}
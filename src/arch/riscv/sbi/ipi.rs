@@ -0,0 +1,27 @@
+use sbi_spec::spi::SEND_IPI;
+
+use crate::{HyperError, HyperResult};
+
+/// Functions for the IPI extension.
+#[derive(Clone, Copy, Debug)]
+pub enum IpiFunction {
+    /// Sends a supervisor software interrupt to the harts in `hart_mask`.
+    SendIpi {
+        /// Bit `i` selects hart `hart_mask_base + i`.
+        hart_mask: usize,
+        /// The first hart of the mask, or `usize::MAX` to select every hart.
+        hart_mask_base: usize,
+    },
+}
+
+impl IpiFunction {
+    pub(crate) fn from_regs(args: &[usize]) -> HyperResult<Self> {
+        match args[6] {
+            SEND_IPI => Ok(Self::SendIpi {
+                hart_mask: args[0],
+                hart_mask_base: args[1],
+            }),
+            _ => Err(HyperError::NotSupported),
+        }
+    }
+}
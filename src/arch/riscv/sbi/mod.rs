@@ -1,5 +1,7 @@
 mod base;
 mod dbcn;
+mod hsm;
+mod ipi;
 mod pmu;
 mod rfnc;
 mod srst;
@@ -7,10 +9,12 @@ mod srst;
 use crate::{HyperError, HyperResult};
 pub use base::BaseFunction;
 use dbcn::DebugConsoleFunction;
+pub use hsm::{HartState, HsmFunction};
+pub use ipi::IpiFunction;
 pub use pmu::PmuFunction;
 pub use rfnc::RemoteFenceFunction;
 use sbi_spec;
-pub use srst::ResetFunction;
+pub use srst::{ResetFunction, ResetReason, ResetType};
 
 pub const SBI_SUCCESS: usize = 0;
 pub const SBI_ERR_FAILUER: isize = -1;
@@ -48,6 +52,10 @@ pub enum SbiMessage {
     GetChar,
     /// The legacy PutChar extension.
     PutChar(usize),
+    /// The legacy SendIpi extension, with the guest virtual address of the hart mask.
+    SendIpi(usize),
+    /// The legacy ClearIpi extension.
+    ClearIpi,
     /// The SetTimer Extension
     SetTimer(usize),
     /// Handles output to the console for debug
@@ -58,6 +66,10 @@ pub enum SbiMessage {
     RemoteFence(RemoteFenceFunction),
     /// The PMU Extension
     PMU(PmuFunction),
+    /// The Hart State Management extension.
+    Hsm(HsmFunction),
+    /// The IPI extension.
+    Ipi(IpiFunction),
 }
 
 impl SbiMessage {
@@ -70,12 +82,16 @@ impl SbiMessage {
             sbi_spec::legacy::LEGACY_CONSOLE_PUTCHAR => Ok(SbiMessage::PutChar(args[0])),
             sbi_spec::legacy::LEGACY_CONSOLE_GETCHAR => Ok(SbiMessage::GetChar),
             sbi_spec::legacy::LEGACY_SET_TIMER => Ok(SbiMessage::SetTimer(args[0])),
+            sbi_spec::legacy::LEGACY_SEND_IPI => Ok(SbiMessage::SendIpi(args[0])),
+            sbi_spec::legacy::LEGACY_CLEAR_IPI => Ok(SbiMessage::ClearIpi),
             sbi_spec::time::EID_TIME => Ok(SbiMessage::SetTimer(args[0])),
             sbi_spec::srst::EID_SRST => ResetFunction::from_regs(args).map(SbiMessage::Reset),
             sbi_spec::rfnc::EID_RFNC => {
                 RemoteFenceFunction::from_args(args).map(SbiMessage::RemoteFence)
             }
             sbi_spec::pmu::EID_PMU => PmuFunction::from_regs(args).map(SbiMessage::PMU),
+            sbi_spec::hsm::EID_HSM => HsmFunction::from_regs(args).map(SbiMessage::Hsm),
+            sbi_spec::spi::EID_SPI => IpiFunction::from_regs(args).map(SbiMessage::Ipi),
             _ => {
                 error!("args: {:?}", args);
                 error!("args[7]: {:#x}", args[7]);
@@ -1,6 +1,6 @@
 use sbi_spec::rfnc::{REMOTE_FENCE_I, REMOTE_SFENCE_VMA};
 
-use crate::HyperResult;
+use crate::{HyperError, HyperResult};
 
 #[derive(Clone, Copy, Debug)]
 pub enum RemoteFenceFunction {
@@ -29,7 +29,7 @@ impl RemoteFenceFunction {
                 start_addr: args[2] as u64,
                 size: args[3] as u64,
             }),
-            _ => panic!("Unsupported yet!"),
+            _ => Err(HyperError::NotSupported),
         }
     }
 }
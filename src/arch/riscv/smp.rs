@@ -1,7 +1,10 @@
 //! reference: https://github.com/rivosinc/salus/blob/main/src/smp.rs
 use core::arch::asm;
 
-use alloc::{collections::VecDeque, vec::Vec};
+use alloc::{
+    collections::{BTreeSet, VecDeque},
+    vec::Vec,
+};
 use spin::{Mutex, Once};
 
 use crate::{
@@ -22,6 +25,31 @@ pub struct PerCpu<H: HyperCraftHal> {
     vcpu_queue: Mutex<VecDeque<usize>>,
 }
 
+/// Returns the ID of the CPU this runs on, its hart ID.
+pub fn this_cpu_id() -> usize {
+    // `cpu_id` is the first field of the `PerCpu` that TP points to, whatever the HAL is.
+    assert!(PER_CPU_BASE.get().is_some());
+    let tp: usize;
+    unsafe { asm!("mv {rd}, tp", rd = out(reg) tp) };
+    unsafe { *(tp as *const usize) }
+}
+
+/// The CPUs sent an IPI only to make the vCPU they run exit the guest, which their host must
+/// not handle.
+static KICKED: Mutex<BTreeSet<usize>> = Mutex::new(BTreeSet::new());
+
+/// Sends an IPI to CPU `cpu` to make the vCPU running there exit the guest.
+pub(crate) fn kick_cpu(cpu: usize) {
+    KICKED.lock().insert(cpu);
+    sbi_rt::send_ipi(1, cpu);
+}
+
+/// Whether a software interrupt taken on this CPU may be a kick from [`kick_cpu`], which
+/// it consumes.
+pub(crate) fn take_kick() -> bool {
+    KICKED.lock().remove(&this_cpu_id())
+}
+
 /// The base address of the per-CPU memory region.
 static PER_CPU_BASE: Once<HostPhysAddr> = Once::new();
 
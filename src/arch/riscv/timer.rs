@@ -0,0 +1,76 @@
+//! Multiplexing of the supervisor timer of each hart between the host and the vCPUs that run
+//! there, each with a deadline of its own.
+
+use alloc::collections::BTreeMap;
+use spin::Mutex;
+
+use super::smp::this_cpu_id;
+
+/// Whose deadline it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum TimerOwner {
+    Host,
+    VCpu { vm_id: usize, vcpu_id: usize },
+}
+
+/// The deadlines on each hart, in host time.
+static DEADLINES: Mutex<BTreeMap<usize, BTreeMap<TimerOwner, u64>>> = Mutex::new(BTreeMap::new());
+
+/// The host time.
+pub(crate) fn host_time() -> u64 {
+    let time: u64;
+    unsafe { core::arch::asm!("rdtime {}", out(reg) time) };
+    time
+}
+
+/// Programs the timer of this hart for the earliest deadline left, dropping those of vCPUs
+/// that passed: a vCPU finds its timer fired when it enters the guest anyway.
+fn program(deadlines: &mut BTreeMap<TimerOwner, u64>) {
+    let now = host_time();
+    deadlines.retain(|&owner, &mut deadline| owner == TimerOwner::Host || deadline > now);
+    let next = deadlines.values().copied().min().unwrap_or(u64::MAX);
+    sbi_rt::set_timer(next);
+}
+
+/// Sets or clears the deadline of `owner` on this hart and reprograms its timer. A vCPU
+/// deadline left on another hart it ran on before is dropped.
+pub(crate) fn set_deadline(owner: TimerOwner, deadline: Option<u64>) {
+    let hart = this_cpu_id();
+    let mut all = DEADLINES.lock();
+    for (_, deadlines) in all.iter_mut().filter(|(&other, _)| other != hart) {
+        deadlines.remove(&owner);
+    }
+    let deadlines = all.entry(hart).or_default();
+    let changed = match deadline {
+        Some(deadline) => deadlines.insert(owner, deadline) != Some(deadline),
+        None => deadlines.remove(&owner).is_some(),
+    };
+    if changed {
+        program(deadlines);
+    }
+}
+
+/// Reprograms the timer of this hart after it fired. Returns whether the deadline of the host
+/// passed, which is then dropped.
+pub(crate) fn expire() -> bool {
+    let now = host_time();
+    let mut all = DEADLINES.lock();
+    let deadlines = all.entry(this_cpu_id()).or_default();
+    let host_expired = deadlines
+        .get(&TimerOwner::Host)
+        .is_some_and(|&deadline| deadline <= now);
+    if host_expired {
+        deadlines.remove(&TimerOwner::Host);
+    }
+    program(deadlines);
+    host_expired
+}
+
+/// Sets the deadline of the host on the current hart, in place of `sbi_rt::set_timer`, which
+/// would clobber the deadlines of the vCPUs. When it passes while a vCPU runs, the vCPU exits
+/// with [`VmExitInfo::TimerInterruptEmulation`](super::VmExitInfo::TimerInterruptEmulation);
+/// otherwise the host timer interrupt handler sets the next one, which also drops the vCPU
+/// deadlines that passed meanwhile.
+pub fn set_host_timer(deadline: u64) {
+    set_deadline(TimerOwner::Host, Some(deadline));
+}
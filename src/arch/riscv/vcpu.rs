@@ -6,13 +6,15 @@ use memoffset::offset_of;
 use tock_registers::LocalRegisterCopy;
 
 // use alloc::sync::Arc;
-use riscv::register::{htinst, htval, hvip, scause, sstatus, stval};
+use riscv::register::{htinst, htval, hvip, mcause, scause, sstatus, stval};
 
+use crate::arch::page_walk::{GuestPageWalkInfo, SSTATUS_MXR, SSTATUS_SUM};
 use crate::arch::vmexit::PrivilegeLevel;
 use crate::arch::{traps, RiscvCsrTrait, CSR};
 use crate::{
-    arch::sbi::SbiMessage, GuestPageTableTrait, GuestPhysAddr, GuestVirtAddr, HostPhysAddr,
-    HyperCraftHal, VmExitInfo,
+    arch::sbi::SbiMessage, GuestAccess, GuestFault, GuestPageTableTrait, GuestPhysAddr,
+    GuestVirtAddr, HostPhysAddr, HyperCraftHal, HyperError, HyperResult, PendingRead,
+    SnapshotReader, SnapshotWriter, VCpuTrait, VmExitInfo,
 };
 
 use super::csrs::defs::hstatus;
@@ -59,6 +61,59 @@ pub struct GuestVsCsrs {
     vstimecmp: usize,
 }
 
+impl GuestVsCsrs {
+    /// Reads the VS-level CSRs of the current hart. `htimedelta` is loaded from the ACLINT on
+    /// every entry and `vstimecmp` needs Sstc, both are left alone.
+    fn read_hw() -> Self {
+        let mut csrs = Self::default();
+        unsafe {
+            core::arch::asm!(
+                "csrr {vsstatus}, vsstatus",
+                "csrr {vsie}, vsie",
+                "csrr {vstvec}, vstvec",
+                "csrr {vsscratch}, vsscratch",
+                "csrr {vsepc}, vsepc",
+                "csrr {vscause}, vscause",
+                "csrr {vstval}, vstval",
+                "csrr {vsatp}, vsatp",
+                vsstatus = out(reg) csrs.vsstatus,
+                vsie = out(reg) csrs.vsie,
+                vstvec = out(reg) csrs.vstvec,
+                vsscratch = out(reg) csrs.vsscratch,
+                vsepc = out(reg) csrs.vsepc,
+                vscause = out(reg) csrs.vscause,
+                vstval = out(reg) csrs.vstval,
+                vsatp = out(reg) csrs.vsatp,
+            );
+        }
+        csrs
+    }
+
+    /// Writes the VS-level CSRs of the current hart, but `htimedelta` and `vstimecmp`.
+    fn write_hw(&self) {
+        unsafe {
+            core::arch::asm!(
+                "csrw vsstatus, {vsstatus}",
+                "csrw vsie, {vsie}",
+                "csrw vstvec, {vstvec}",
+                "csrw vsscratch, {vsscratch}",
+                "csrw vsepc, {vsepc}",
+                "csrw vscause, {vscause}",
+                "csrw vstval, {vstval}",
+                "csrw vsatp, {vsatp}",
+                vsstatus = in(reg) self.vsstatus,
+                vsie = in(reg) self.vsie,
+                vstvec = in(reg) self.vstvec,
+                vsscratch = in(reg) self.vsscratch,
+                vsepc = in(reg) self.vsepc,
+                vscause = in(reg) self.vscause,
+                vstval = in(reg) self.vstval,
+                vsatp = in(reg) self.vsatp,
+            );
+        }
+    }
+}
+
 /// Virtualized HS-level CSRs that are used to emulate (part of) the hypervisor extension for the
 /// guest.
 #[derive(Default)]
@@ -216,6 +271,10 @@ pub enum VmCpuStatus {
 pub struct VCpu<H: HyperCraftHal> {
     vcpu_id: usize,
     regs: VmCpuRegisters,
+    exit_info: Option<VmExitInfo>,
+    pending_read: Option<PendingRead>,
+    entry: GuestPhysAddr,
+    boot_arg: usize,
     // gpt: G,
     // pub guest: Arc<Guest>,
     marker: PhantomData<H>,
@@ -248,6 +307,10 @@ impl<H: HyperCraftHal> VCpu<H> {
         Self {
             vcpu_id,
             regs,
+            exit_info: None,
+            pending_read: None,
+            entry,
+            boot_arg: 0x9000_0000,
             // gpt,
             marker: PhantomData,
         }
@@ -293,11 +356,14 @@ impl<H: HyperCraftHal> VCpu<H> {
     /// Runs this vCPU until traps.
     pub fn run(&mut self) -> VmExitInfo {
         let regs = &mut self.regs;
+        // the hart may have run other vCPUs since this one last did
+        regs.vs_csrs.write_hw();
         unsafe {
             // Safe to run the guest as it only touches memory assigned to it by being owned
             // by its page table
             _run_guest(regs);
         }
+        regs.vs_csrs = GuestVsCsrs::read_hw();
         // Save off the trap information
         regs.trap_csrs.scause = scause::read().bits();
         regs.trap_csrs.stval = stval::read();
@@ -306,7 +372,7 @@ impl<H: HyperCraftHal> VCpu<H> {
 
         let scause = scause::read();
         use scause::{Exception, Interrupt, Trap};
-        match scause.cause() {
+        let exit_info = match scause.cause() {
             Trap::Exception(Exception::VirtualSupervisorEnvCall) => {
                 let sbi_msg = SbiMessage::from_regs(regs.guest_regs.gprs.a_regs()).ok();
                 VmExitInfo::Ecall(sbi_msg)
@@ -315,6 +381,9 @@ impl<H: HyperCraftHal> VCpu<H> {
             Trap::Interrupt(Interrupt::SupervisorExternal) => {
                 VmExitInfo::ExternalInterruptEmulation
             }
+            Trap::Interrupt(Interrupt::SupervisorSoft) => {
+                VmExitInfo::HostInterruot(mcause::Interrupt::SupervisorSoft)
+            }
             Trap::Exception(Exception::LoadGuestPageFault)
             | Trap::Exception(Exception::StoreGuestPageFault) => {
                 let fault_addr = regs.trap_csrs.htval << 2 | regs.trap_csrs.stval & 0x3;
@@ -339,35 +408,107 @@ impl<H: HyperCraftHal> VCpu<H> {
                     priv_level: PrivilegeLevel::from_hstatus(regs.guest_regs.hstatus),
                 }
             }
+            Trap::Exception(Exception::InstructionGuestPageFault) => {
+                VmExitInfo::InstructionPageFault {
+                    fault_addr: regs.trap_csrs.htval << 2 | regs.trap_csrs.stval & 0x3,
+                    fault_pc: regs.guest_regs.sepc,
+                }
+            }
+            Trap::Exception(Exception::VirtualInstruction) => VmExitInfo::VirtualInstruction {
+                fault_pc: regs.guest_regs.sepc,
+                priv_level: PrivilegeLevel::from_hstatus(regs.guest_regs.hstatus),
+            },
             _ => {
-                panic!(
+                warn!(
                     "Unhandled trap: {:?}, sepc: {:#x}, stval: {:#x}",
                     scause.cause(),
                     regs.guest_regs.sepc,
                     regs.trap_csrs.stval
                 );
+                VmExitInfo::UnhandledTrap {
+                    scause: regs.trap_csrs.scause,
+                    stval: regs.trap_csrs.stval,
+                    fault_pc: regs.guest_regs.sepc,
+                }
             }
+        };
+        self.exit_info = Some(exit_info);
+        exit_info
+    }
+
+    /// Advance guest pc by `instr_len` bytes
+    pub fn advance_pc(&mut self, instr_len: usize) {
+        self.regs.guest_regs.sepc += instr_len
+    }
+
+    /// Remember where the data of an emulated read goes once the VMM supplies it.
+    pub(crate) fn set_pending_read(&mut self, pending: PendingRead) {
+        self.pending_read = Some(pending);
+    }
+
+    /// Whether an emulated read is waiting for [`VCpuTrait::complete_read`].
+    pub(crate) fn has_pending_read(&self) -> bool {
+        self.pending_read.is_some()
+    }
+
+    /// Whether the last trap was a store (or AMO) guest-page fault.
+    pub(crate) fn trap_is_store(&self) -> bool {
+        const STORE_GUEST_PAGE_FAULT: usize = 23;
+        self.regs.trap_csrs.scause == STORE_GUEST_PAGE_FAULT
+    }
+
+    /// The kind of access of the last guest-page fault of a load or store.
+    pub(crate) fn trap_access(&self) -> GuestAccess {
+        if self.trap_is_store() {
+            GuestAccess::Write
+        } else {
+            GuestAccess::Read
         }
     }
 
-    /// Gets one of the vCPU's general purpose registers.
-    pub fn get_gpr(&self, index: GprIndex) -> usize {
-        self.regs.guest_regs.gprs.reg(index)
+    /// The trap value of the last trap, which holds the trapping instruction for virtual
+    /// instruction exceptions if the hart reports it, and 0 otherwise.
+    pub(crate) fn trap_value(&self) -> usize {
+        self.regs.trap_csrs.stval
     }
 
-    /// Set one of the vCPU's general purpose register.
-    pub fn set_gpr(&mut self, index: GprIndex, val: usize) {
-        self.regs.guest_regs.gprs.set_reg(index, val);
+    /// Selects guest interrupt file `file` of the hart for VS-level external interrupts, none
+    /// if it is 0.
+    pub(crate) fn set_vgein(&mut self, file: usize) {
+        let mut hstatus =
+            LocalRegisterCopy::<usize, hstatus::Register>::new(self.regs.guest_regs.hstatus);
+        hstatus.modify(hstatus::vgein.val(file));
+        self.regs.guest_regs.hstatus = hstatus.get();
     }
 
-    /// Advance guest pc by `instr_len` bytes
-    pub fn advance_pc(&mut self, instr_len: usize) {
-        self.regs.guest_regs.sepc += instr_len
+    /// Makes the vCPU start at `start_addr` in VS-mode with its ID in `a0` and `opaque` in `a1`,
+    /// and with address translation and interrupts off, as SBI `hart_start` does.
+    pub(crate) fn start_at(&mut self, start_addr: GuestPhysAddr, opaque: usize) {
+        const SSTATUS_SIE: usize = 1 << 1;
+        const SSTATUS_SPP: usize = 1 << 8;
+
+        let gprs = &mut self.regs.guest_regs.gprs;
+        gprs.set_reg(GprIndex::A0, self.vcpu_id);
+        gprs.set_reg(GprIndex::A1, opaque);
+        self.regs.guest_regs.sepc = start_addr;
+        self.regs.guest_regs.sstatus |= SSTATUS_SPP;
+        self.regs.vs_csrs.vsatp = 0;
+        self.regs.vs_csrs.vsstatus &= !SSTATUS_SIE;
     }
 
-    /// Gets the vCPU's id.
-    pub fn vcpu_id(&self) -> usize {
-        self.vcpu_id
+    /// Gets what a walk of the guest page tables needs, as the last trap left it.
+    pub fn get_ptw_info(&self) -> GuestPageWalkInfo {
+        let vsstatus = self.regs.vs_csrs.vsstatus;
+        GuestPageWalkInfo {
+            satp: self.regs.vs_csrs.vsatp,
+            is_user_mode_access: matches!(
+                PrivilegeLevel::from_hstatus(self.regs.guest_regs.hstatus),
+                PrivilegeLevel::User
+            ),
+            sum: vsstatus & SSTATUS_SUM != 0,
+            mxr: vsstatus & SSTATUS_MXR != 0,
+            set_accessed_dirty: false,
+        }
     }
 
     /// Gets the vCPU's registers.
@@ -376,20 +517,223 @@ impl<H: HyperCraftHal> VCpu<H> {
     }
 }
 
+impl<H: HyperCraftHal> VCpuTrait for VCpu<H> {
+    fn vcpu_id(&self) -> usize {
+        self.vcpu_id
+    }
+
+    /// Set the entry point, the hart id in `a0` and the device tree address in `a1`.
+    fn init(&mut self, entry: GuestPhysAddr, boot_arg: usize) -> HyperResult {
+        self.regs
+            .guest_regs
+            .gprs
+            .set_reg(GprIndex::A0, self.vcpu_id);
+        self.regs.guest_regs.gprs.set_reg(GprIndex::A1, boot_arg);
+        self.regs.guest_regs.sepc = entry;
+        self.entry = entry;
+        self.boot_arg = boot_arg;
+        Ok(())
+    }
+
+    fn pc(&self) -> GuestVirtAddr {
+        self.regs.guest_regs.sepc
+    }
+
+    fn set_pc(&mut self, pc: GuestVirtAddr) {
+        self.regs.guest_regs.sepc = pc;
+    }
+
+    fn gpr(&self, index: usize) -> usize {
+        self.regs.guest_regs.gprs.reg_by_index(index)
+    }
+
+    fn set_gpr(&mut self, index: usize, val: usize) {
+        self.regs.guest_regs.gprs.set_reg_by_index(index, val);
+    }
+
+    fn exit_info(&self) -> HyperResult<VmExitInfo> {
+        self.exit_info.ok_or(HyperError::BadState)
+    }
+
+    fn complete_read(&mut self, data: u64) -> HyperResult {
+        let pending = self.pending_read.take().ok_or(HyperError::BadState)?;
+        let old = self.gpr(pending.reg);
+        self.set_gpr(pending.reg, pending.apply(old, data));
+        Ok(())
+    }
+
+    /// Inject an illegal instruction, an access fault or a page fault.
+    fn inject_fault(&mut self, fault: GuestFault) -> HyperResult {
+        const INSTRUCTION_ACCESS_FAULT: usize = 1;
+        const ILLEGAL_INSTRUCTION: usize = 2;
+        const LOAD_ACCESS_FAULT: usize = 5;
+        const STORE_ACCESS_FAULT: usize = 7;
+        const INSTRUCTION_PAGE_FAULT: usize = 12;
+        const LOAD_PAGE_FAULT: usize = 13;
+        const STORE_PAGE_FAULT: usize = 15;
+
+        match fault {
+            GuestFault::IllegalInstruction { .. } | GuestFault::GeneralProtection { .. } => {
+                self.inject_exception(ILLEGAL_INSTRUCTION, 0)
+            }
+            // stval of the G-stage fault holds the guest virtual address
+            GuestFault::AccessFault { access, .. } => self.inject_exception(
+                match access {
+                    GuestAccess::Read => LOAD_ACCESS_FAULT,
+                    GuestAccess::Write => STORE_ACCESS_FAULT,
+                    GuestAccess::Fetch => INSTRUCTION_ACCESS_FAULT,
+                },
+                self.regs.trap_csrs.stval,
+            ),
+            GuestFault::PageFault { fault, .. } => self.inject_exception(
+                match fault.access {
+                    GuestAccess::Read => LOAD_PAGE_FAULT,
+                    GuestAccess::Write => STORE_PAGE_FAULT,
+                    GuestAccess::Fetch => INSTRUCTION_PAGE_FAULT,
+                },
+                fault.addr,
+            ),
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) -> HyperResult {
+        self.regs.guest_regs.gprs = GeneralPurposeRegisters::default();
+        let mut sstatus = sstatus::read();
+        sstatus.set_spp(sstatus::SPP::Supervisor);
+        self.regs.guest_regs.sstatus = sstatus.bits();
+        self.regs.vs_csrs = GuestVsCsrs::default();
+        self.exit_info = None;
+        self.pending_read = None;
+        self.init(self.entry, self.boot_arg)
+    }
+
+    /// Saves the guest registers and CSRs. The pending virtual interrupts in `hvip` and
+    /// `htimedelta` come from the emulated interrupt controllers, which are saved with the VM.
+    fn save_state(&self, w: &mut SnapshotWriter) -> HyperResult {
+        if self.has_pending_read() {
+            return Err(HyperError::BadState);
+        }
+        let guest = &self.regs.guest_regs;
+        (0..32).for_each(|index| w.put_usize(self.gpr(index)));
+        w.put_usize(guest.sstatus);
+        w.put_usize(guest.hstatus);
+        w.put_usize(guest.scounteren);
+        w.put_usize(guest.sepc);
+        let vs = &self.regs.vs_csrs;
+        w.put_usize(vs.vsstatus);
+        w.put_usize(vs.vsie);
+        w.put_usize(vs.vstvec);
+        w.put_usize(vs.vsscratch);
+        w.put_usize(vs.vsepc);
+        w.put_usize(vs.vscause);
+        w.put_usize(vs.vstval);
+        w.put_usize(vs.vsatp);
+        w.put_usize(self.regs.virtual_hs_csrs.hie);
+        w.put_usize(self.regs.virtual_hs_csrs.hgeie);
+        Ok(())
+    }
+
+    /// `hgatp` is left pointing at the current guest page table.
+    fn load_state(&mut self, r: &mut SnapshotReader) -> HyperResult {
+        let state = self.read_state(r)?;
+        for (index, &val) in state.gprs.iter().enumerate() {
+            self.set_gpr(index, val);
+        }
+        let guest = &mut self.regs.guest_regs;
+        guest.sstatus = state.sstatus;
+        guest.hstatus = state.hstatus;
+        guest.scounteren = state.scounteren;
+        guest.sepc = state.sepc;
+        self.regs.vs_csrs = state.vs_csrs;
+        self.regs.virtual_hs_csrs.hie = state.hie;
+        self.regs.virtual_hs_csrs.hgeie = state.hgeie;
+        self.exit_info = None;
+        self.pending_read = None;
+        Ok(())
+    }
+}
+
+/// The state written by [`VCpuTrait::save_state`], read back but not loaded yet.
+pub(crate) struct SavedState {
+    gprs: [usize; 32],
+    sstatus: usize,
+    hstatus: usize,
+    scounteren: usize,
+    sepc: usize,
+    vs_csrs: GuestVsCsrs,
+    hie: usize,
+    hgeie: usize,
+}
+
+impl<H: HyperCraftHal> VCpu<H> {
+    /// Reads the state written by [`VCpuTrait::save_state`] without loading it.
+    pub(crate) fn read_state(&self, r: &mut SnapshotReader) -> HyperResult<SavedState> {
+        let mut gprs = [0; 32];
+        for val in &mut gprs {
+            *val = r.get_usize()?;
+        }
+        // fields are read in the order they are written
+        Ok(SavedState {
+            gprs,
+            sstatus: r.get_usize()?,
+            hstatus: r.get_usize()?,
+            scounteren: r.get_usize()?,
+            sepc: r.get_usize()?,
+            vs_csrs: GuestVsCsrs {
+                vsstatus: r.get_usize()?,
+                vsie: r.get_usize()?,
+                vstvec: r.get_usize()?,
+                vsscratch: r.get_usize()?,
+                vsepc: r.get_usize()?,
+                vscause: r.get_usize()?,
+                vstval: r.get_usize()?,
+                vsatp: r.get_usize()?,
+                ..Default::default()
+            },
+            hie: r.get_usize()?,
+            hgeie: r.get_usize()?,
+        })
+    }
+}
+
 // Private methods implements
 impl<H: HyperCraftHal> VCpu<H> {
     /// Delivers the given exception to the vCPU, setting its register state
     /// to handle the trap the next time it is run.
-    fn inject_exception(&mut self) {
+    fn inject_exception(&mut self, cause: usize, tval: usize) {
+        const SSTATUS_SIE: usize = 1 << 1;
+        const SSTATUS_SPIE: usize = 1 << 5;
+        const SSTATUS_SPP: usize = 1 << 8;
+
+        // The trap is taken from the guest's current privilege level into VS-mode.
+        let spp = self.regs.guest_regs.sstatus & SSTATUS_SPP;
+        let mut vsstatus: usize;
+        unsafe {
+            core::arch::asm!("csrr {0}, vsstatus", out(reg) vsstatus);
+        }
+        let spie = if vsstatus & SSTATUS_SIE != 0 {
+            SSTATUS_SPIE
+        } else {
+            0
+        };
+        vsstatus = (vsstatus & !(SSTATUS_SIE | SSTATUS_SPIE | SSTATUS_SPP)) | spie | spp;
         unsafe {
             core::arch::asm!(
+                "csrw vsstatus, {vsstatus}",
                 "csrw vsepc, {hyp_sepc}",
-                "csrw vscause, {hyp_scause}",
+                "csrw vscause, {cause}",
+                "csrw vstval, {tval}",
                 "csrr {guest_sepc}, vstvec",
+                vsstatus = in(reg) vsstatus,
                 hyp_sepc = in(reg) self.regs.guest_regs.sepc,
-                hyp_scause = in(reg) self.regs.trap_csrs.scause,
+                cause = in(reg) cause,
+                tval = in(reg) tval,
                 guest_sepc = out(reg) self.regs.guest_regs.sepc,
             );
         }
+        // vstvec may be vectored, exceptions always go to its base.
+        self.regs.guest_regs.sepc &= !0b11;
+        self.regs.guest_regs.sstatus |= SSTATUS_SPP;
     }
 }
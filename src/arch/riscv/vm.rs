@@ -1,60 +1,195 @@
 use core::panic;
 
 use super::{
-    devices::plic::{PlicState, MAX_CONTEXTS},
+    aia::{
+        alloc_guest_file, decode_csr_access, emulate_imsic_csr, free_guest_file, AiaConfig,
+        AiaState,
+    },
+    devices::{aclint::AclintState, imsic::IMSIC_PAGE_SIZE, plic::PlicState},
+    mmio::{decode_access, MmioAccess, MmioOp},
     regs::GeneralPurposeRegisters,
-    sbi::PmuFunction,
-    sbi::{BaseFunction, RemoteFenceFunction},
+    sbi::{BaseFunction, RemoteFenceFunction, ResetFunction, ResetReason, ResetType},
+    sbi::{HartState, HsmFunction, IpiFunction, PmuFunction},
+    smp::{kick_cpu, take_kick, this_cpu_id},
+    timer::{self, host_time, TimerOwner},
     traps,
     vcpu::{self, VmCpuRegisters},
-    vm_pages::VmPages,
     HyperCallMsg, RiscvCsrTrait, CSR,
 };
 use crate::{
-    arch::sbi::SBI_ERR_NOT_SUPPORTED, vcpus::VM_CPUS_MAX, GprIndex, GuestPageTableTrait,
-    GuestPhysAddr, GuestVirtAddr, HyperCraftHal, HyperError, HyperResult, VCpu, VmCpus, VmExitInfo,
+    arch::sbi::{
+        SBI_ERR_ALREADY_AVAILABLE, SBI_ERR_INAVLID_PARAM, SBI_ERR_INVALID_ADDRESS,
+        SBI_ERR_NOT_SUPPORTED,
+    },
+    guest_memory::SavedRegions,
+    handle_guest_fault,
+    snapshot::{
+        SECTION_IRQCHIP, SECTION_MEMORY, SECTION_MMIO_DEVICES, SECTION_MSI_IRQCHIP, SECTION_TIMER,
+        SECTION_VCPU,
+    },
+    AtomicVmState, GprIndex, GuestAccess, GuestFault, GuestFaultPolicy, GuestMemory,
+    GuestMemoryRegion, GuestPageTableTrait, GuestPhysAddr, GuestVirtAddr, HostVirtAddr,
+    HyperCraftHal, HyperError, HyperResult, MmioBus, MmioOps, PendingRead, SnapshotReader,
+    SnapshotWriter, VCpu, VCpuGuard, VCpuTrait, VmCpus, VmExit, VmExitInfo, VmState, VmTrait,
 };
-use riscv_decode::Instruction;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use page_table_entry::MappingFlags;
+use riscv::register::mcause;
 use sbi_rt::{pmu_counter_get_info, pmu_counter_stop};
+use sbi_spec::hsm::{HART_SUSPEND_TYPE_NON_RETENTIVE, HART_SUSPEND_TYPE_RETENTIVE};
+use spin::Mutex;
+
+/// Base address of the PLIC, the same for the host and its guests.
+const PLIC_BASE: usize = 0xC00_0000;
+/// Base address of the CLINT of the guests.
+const CLINT_BASE: usize = 0x200_0000;
+
+/// The SBI HSM state of a vCPU.
+#[derive(Clone, Copy)]
+struct Hart {
+    state: HartState,
+    /// Where the vCPU starts, or resumes from a non-retentive suspend, when it runs next, with
+    /// the value of `a1`.
+    entry: Option<(GuestPhysAddr, usize)>,
+    /// Whether the VMM gave the vCPU its entry point, so that it is started again on reset.
+    boot: bool,
+    /// The physical CPU running the vCPU, if it is running.
+    cpu: Option<usize>,
+}
+
+impl Hart {
+    const STOPPED: Self = Self {
+        state: HartState::Stopped,
+        entry: None,
+        boot: false,
+        cpu: None,
+    };
+
+    /// Writes the state of the vCPU into its [`SECTION_VCPU`] section of a snapshot.
+    fn save_state(&self, w: &mut SnapshotWriter) {
+        w.put_usize(self.state as usize);
+        let (entry, opaque) = self.entry.unwrap_or((usize::MAX, 0));
+        w.put_usize(entry);
+        w.put_usize(opaque);
+        w.put_u8(self.boot as u8);
+    }
+
+    /// Reads back what [`Hart::save_state`] wrote. The vCPU isn't running.
+    fn read_state(r: &mut SnapshotReader) -> HyperResult<Self> {
+        let state = HartState::from_raw(r.get_usize()?)?;
+        let entry = match (r.get_usize()?, r.get_usize()?) {
+            (usize::MAX, _) => None,
+            entry => Some(entry),
+        };
+        Ok(Self {
+            state,
+            entry,
+            boot: r.get_u8()? != 0,
+            cpu: None,
+        })
+    }
+}
+
+/// Marks a vCPU as running on this CPU until dropped, when its run returns.
+struct Running<'a> {
+    harts: &'a Mutex<Vec<Hart>>,
+    vcpu_id: usize,
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.harts.lock()[self.vcpu_id].cpu = None;
+    }
+}
 
 /// A VM that is being run.
 pub struct VM<H: HyperCraftHal, G: GuestPageTableTrait> {
     vcpus: VmCpus<H>,
-    gpt: G,
-    vm_pages: VmPages,
-    plic: PlicState,
+    gpt: Mutex<G>,
+    vm_id: usize,
+    plic: Arc<Mutex<PlicState>>,
+    /// The AIA interrupt controllers, which replace the PLIC once enabled.
+    aia: Option<AiaState>,
+    aclint: Arc<Mutex<AclintState>>,
+    harts: Mutex<Vec<Hart>>,
+    mmio_bus: MmioBus,
+    fault_policy: GuestFaultPolicy,
+    state: AtomicVmState,
+    memory: GuestMemory<H>,
 }
 
-impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
-    /// Create a new VM with `vcpus` vCPUs and `gpt` as the guest page table.
-    pub fn new(vcpus: VmCpus<H>, gpt: G) -> HyperResult<Self> {
+impl<H: HyperCraftHal, G: GuestPageTableTrait> VmTrait<H, G> for VM<H, G> {
+    fn new(vcpus: VmCpus<H>, gpt: G, vm_id: usize) -> HyperResult<Self> {
+        let num_harts = vcpus.capacity();
+        let plic = Arc::new(Mutex::new(PlicState::new(PLIC_BASE, num_harts)));
+        let aclint = Arc::new(Mutex::new(AclintState::new(
+            CLINT_BASE, num_harts, host_time,
+        )));
+        let mut mmio_bus = MmioBus::new();
+        mmio_bus.register(plic.clone())?;
+        mmio_bus.register(aclint.clone())?;
         Ok(Self {
             vcpus,
-            gpt,
-            vm_pages: VmPages::default(),
-            plic: PlicState::new(0xC00_0000),
+            gpt: Mutex::new(gpt),
+            vm_id,
+            plic,
+            aia: None,
+            aclint,
+            harts: Mutex::new(vec![Hart::STOPPED; num_harts]),
+            mmio_bus,
+            fault_policy: GuestFaultPolicy::default(),
+            state: AtomicVmState::new(VmState::Created),
+            memory: GuestMemory::new(),
         })
     }
 
-    /// Initialize `VCpu` by `vcpu_id`.
-    pub fn init_vcpu(&mut self, vcpu_id: usize) {
-        let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
-        vcpu.init_page_map(self.gpt.token());
+    fn vm_id(&self) -> usize {
+        self.vm_id
+    }
+
+    fn init_vcpu(&self, vcpu_id: usize, entry: GuestPhysAddr, boot_arg: usize) -> HyperResult {
+        if self.state.is_destroyed() {
+            return Err(HyperError::BadState);
+        }
+        let mut vcpu = self.vcpus.lock_vcpu(vcpu_id)?;
+        vcpu.init(entry, boot_arg)?;
+        vcpu.init_page_map(self.gpt.lock().token());
+        self.harts.lock()[vcpu_id] = Hart {
+            state: HartState::Started,
+            entry: None,
+            boot: true,
+            cpu: None,
+        };
+        Ok(())
     }
 
     #[allow(unused_variables, deprecated)]
-    /// Run the host VM's vCPU with ID `vcpu_id`. Does not return.
-    pub fn run(&mut self, vcpu_id: usize) {
+    fn run_vcpu(&self, vcpu_id: usize) -> HyperResult<VmExit> {
         let mut vm_exit_info: VmExitInfo;
         let mut gprs = GeneralPurposeRegisters::default();
+        let mut guard = self.vcpus.lock_vcpu(vcpu_id)?;
+        let vcpu = &mut *guard;
+        if vcpu.has_pending_read() {
+            return Err(HyperError::BadState);
+        }
+        let Some(_running) = self.wake_hart(vcpu) else {
+            return Ok(VmExit::Halt);
+        };
+        self.bind_guest_file(vcpu)?;
+        self.state.enter_guest()?;
         loop {
             let mut len = 4;
             let mut advance_pc = false;
-            {
-                let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
-                vm_exit_info = vcpu.run();
-                vcpu.save_gprs(&mut gprs);
+            let mut exit_to_vmm = false;
+            if let Some(exit) = self.state.stop_exit() {
+                return Ok(exit);
             }
+            self.sync_external_irq(vcpu_id);
+            let ssip = self.sync_aclint_irqs(vcpu_id);
+            vm_exit_info = vcpu.run();
+            self.save_guest_ssip(vcpu_id, ssip);
+            vcpu.save_gprs(&mut gprs);
 
             match vm_exit_info {
                 VmExitInfo::Ecall(sbi_msg) => {
@@ -70,18 +205,35 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
                             HyperCallMsg::PutChar(c) => {
                                 sbi_rt::legacy::console_putchar(c);
                             }
-                            HyperCallMsg::SetTimer(timer) => {
-                                sbi_rt::set_timer(timer as u64);
-                                // Clear guest timer interrupt
-                                CSR.hvip.read_and_clear_bits(
-                                    traps::interrupt::VIRTUAL_SUPERVISOR_TIMER,
-                                );
-                                //  Enable host timer interrupt
-                                CSR.sie
-                                    .read_and_set_bits(traps::interrupt::SUPERVISOR_TIMER);
+                            HyperCallMsg::Ipi(IpiFunction::SendIpi {
+                                hart_mask,
+                                hart_mask_base,
+                            }) => {
+                                let error = match self.send_ipi(hart_mask, hart_mask_base) {
+                                    Ok(()) => 0,
+                                    Err(_) => SBI_ERR_INAVLID_PARAM,
+                                };
+                                gprs.set_reg(GprIndex::A0, error as usize);
+                            }
+                            HyperCallMsg::SendIpi(hart_mask_addr) => {
+                                let error = match self.read_legacy_hart_mask(vcpu, hart_mask_addr) {
+                                    Ok(hart_mask) => match self.send_ipi(hart_mask, 0) {
+                                        Ok(()) => 0,
+                                        Err(_) => SBI_ERR_INAVLID_PARAM,
+                                    },
+                                    Err(_) => SBI_ERR_INVALID_ADDRESS,
+                                };
+                                gprs.set_reg(GprIndex::A0, error as usize);
+                            }
+                            HyperCallMsg::ClearIpi => {
+                                // hvip.VSSIP follows when the vCPU enters the guest again
+                                self.aclint.lock().clear_ssip(vcpu_id);
+                                gprs.set_reg(GprIndex::A0, 0);
                             }
-                            HyperCallMsg::Reset(_) => {
-                                sbi_rt::system_reset(sbi_rt::Shutdown, sbi_rt::SystemFailure);
+                            HyperCallMsg::SetTimer(timer) => {
+                                // takes effect when the vCPU enters the guest again
+                                self.aclint.lock().set_timer(vcpu_id, timer as u64)?;
+                                gprs.set_reg(GprIndex::A0, 0);
                             }
                             HyperCallMsg::RemoteFence(rfnc) => {
                                 self.handle_rfnc_function(rfnc, &mut gprs).unwrap();
@@ -89,13 +241,26 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
                             HyperCallMsg::PMU(pmu) => {
                                 self.handle_pmu_function(pmu, &mut gprs).unwrap();
                             }
-                            _ => todo!(),
+                            HyperCallMsg::Hsm(hsm) => {
+                                if let Some(exit) =
+                                    self.handle_hsm_function(vcpu_id, hsm, &mut gprs)
+                                {
+                                    vcpu.restore_gprs(&gprs);
+                                    vcpu.advance_pc(len);
+                                    return Ok(exit);
+                                }
+                            }
+                            // System reset and the debug console are up to the VMM.
+                            _ => exit_to_vmm = true,
                         }
                         advance_pc = true;
                     } else {
-                        panic!()
+                        exit_to_vmm = true;
                     }
                 }
+                // a write to a page protected for dirty logging, retry it
+                VmExitInfo::PageFault { fault_addr, .. }
+                    if vcpu.trap_is_store() && self.handle_dirty_write(fault_addr)? => {}
                 VmExitInfo::PageFault {
                     fault_addr,
                     falut_pc,
@@ -103,110 +268,914 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
                     priv_level,
                 } => match priv_level {
                     super::vmexit::PrivilegeLevel::Supervisor => {
-                        match self.handle_page_fault(falut_pc, inst, fault_addr, &mut gprs) {
+                        match self.handle_page_fault(vcpu, falut_pc, inst, fault_addr, &mut gprs) {
                             Ok(inst_len) => {
                                 len = inst_len;
+                                advance_pc = true;
                             }
+                            // Not one of our devices, let the VMM emulate it.
+                            Err(HyperError::PageFault) => exit_to_vmm = true,
                             Err(err) => {
-                                panic!(
-                                    "Page fault at {:#x} addr@{:#x} with error {:?}",
-                                    falut_pc, fault_addr, err
-                                )
+                                let fault = GuestFault::AccessFault {
+                                    pc: falut_pc,
+                                    addr: fault_addr,
+                                    access: vcpu.trap_access(),
+                                };
+                                if let Some(exit) =
+                                    handle_guest_fault(self.fault_policy, vcpu, fault)?
+                                {
+                                    return Ok(exit);
+                                }
                             }
                         }
-                        advance_pc = true;
                     }
-                    super::vmexit::PrivilegeLevel::User => {
-                        panic!("User page fault")
+                    super::vmexit::PrivilegeLevel::User => exit_to_vmm = true,
+                },
+                // the guest timer is checked before entering the guest again, the host timer
+                // is up to the VMM
+                VmExitInfo::TimerInterruptEmulation => exit_to_vmm = timer::expire(),
+                VmExitInfo::ExternalInterruptEmulation => self.handle_irq(),
+                // a kick from another vCPU of a VM, only there to make this one exit
+                VmExitInfo::HostInterruot(mcause::Interrupt::SupervisorSoft) if take_kick() => {
+                    unsafe {
+                        core::arch::asm!("csrc sip, {}", in(reg) traps::interrupt::SUPERVISOR_SOFT)
+                    };
+                }
+                VmExitInfo::HostInterruot(_) => exit_to_vmm = true,
+                VmExitInfo::VirtualInstruction { fault_pc, .. } => {
+                    match self.handle_imsic_csr(vcpu, fault_pc, &mut gprs) {
+                        Ok(()) => advance_pc = true,
+                        // not an access to an emulated interrupt file
+                        Err(_) => exit_to_vmm = true,
+                    }
+                }
+                VmExitInfo::InstructionPageFault { .. } | VmExitInfo::UnhandledTrap { .. } => {
+                    exit_to_vmm = true
+                }
+            }
+
+            if exit_to_vmm {
+                match self.translate_exit(vcpu, vm_exit_info)? {
+                    VmExit::GuestFault(fault) => {
+                        match handle_guest_fault(self.fault_policy, vcpu, fault)? {
+                            Some(exit) => return Ok(exit),
+                            None => continue,
+                        }
                     }
+                    VmExit::Shutdown => {
+                        self.state.store(VmState::Shutdown);
+                        self.kick_running_harts();
+                        return Ok(VmExit::Shutdown);
+                    }
+                    exit => match self.mmio_bus.dispatch(vcpu, exit) {
+                        Some(exit) => return Ok(exit),
+                        None => continue,
+                    },
+                }
+            }
+            vcpu.restore_gprs(&gprs);
+            if advance_pc {
+                vcpu.advance_pc(len);
+            }
+        }
+    }
+
+    fn vcpu(&mut self, vcpu_id: usize) -> HyperResult<&mut VCpu<H>> {
+        self.vcpus.get_vcpu(vcpu_id)
+    }
+
+    fn lock_vcpu(&self, vcpu_id: usize) -> HyperResult<VCpuGuard<'_, VCpu<H>>> {
+        self.vcpus.lock_vcpu(vcpu_id)
+    }
+
+    fn add_vcpu(&mut self, vcpu: VCpu<H>) -> HyperResult {
+        if self.state.is_destroyed() {
+            return Err(HyperError::BadState);
+        }
+        self.vcpus.add_vcpu(vcpu)
+    }
+
+    fn remove_vcpu(&mut self, vcpu_id: usize) -> HyperResult<VCpu<H>> {
+        self.vcpus.remove_vcpu(vcpu_id)
+    }
+
+    fn register_mmio_device(&mut self, device: Arc<Mutex<dyn MmioOps>>) -> HyperResult {
+        self.mmio_bus.register(device)
+    }
+
+    fn unregister_mmio_device(&mut self, base: u64) -> HyperResult<Arc<Mutex<dyn MmioOps>>> {
+        self.mmio_bus.unregister(base)
+    }
+
+    fn set_fault_policy(&mut self, policy: GuestFaultPolicy) {
+        self.fault_policy = policy;
+    }
+
+    fn state(&self) -> VmState {
+        self.state.load()
+    }
+
+    /// vCPUs running on other CPUs are kicked out of the guest to return [`VmExit::Paused`].
+    fn pause(&self) -> HyperResult {
+        self.state.pause()?;
+        self.kick_running_harts();
+        Ok(())
+    }
+
+    fn resume(&self) -> HyperResult {
+        self.state.resume()
+    }
+
+    fn reset(&mut self) -> HyperResult {
+        self.state.reset()?;
+        for vcpu_id in 0..self.vcpus.capacity() {
+            if let Ok(vcpu) = self.vcpus.get_vcpu(vcpu_id) {
+                vcpu.reset()?;
+            }
+        }
+        self.plic.lock().reset();
+        self.aclint.lock().reset();
+        for hart in self.harts.lock().iter_mut() {
+            *hart = Hart {
+                state: if hart.boot {
+                    HartState::Started
+                } else {
+                    HartState::Stopped
                 },
-                VmExitInfo::TimerInterruptEmulation => {
-                    // debug!("timer irq emulation");
-                    // Enable guest timer interrupt
-                    CSR.hvip
-                        .read_and_set_bits(traps::interrupt::VIRTUAL_SUPERVISOR_TIMER);
-                    // Clear host timer interrupt
-                    CSR.sie
-                        .read_and_clear_bits(traps::interrupt::SUPERVISOR_TIMER);
+                entry: None,
+                boot: hart.boot,
+                cpu: None,
+            };
+        }
+        if let Some(aia) = &self.aia {
+            aia.aplic.lock().reset();
+            aia.imsic.lock().reset();
+        }
+        self.release_guest_files()
+    }
+
+    fn destroy(&mut self) -> HyperResult {
+        if self.state.is_destroyed() {
+            return Err(HyperError::BadState);
+        }
+        self.release_guest_files()?;
+        for vcpu_id in 0..self.vcpus.capacity() {
+            timer::set_deadline(self.timer_owner(vcpu_id), None);
+        }
+        self.state.store(VmState::Destroyed);
+        self.vcpus.clear();
+        self.memory.release(self.gpt.get_mut());
+        Ok(())
+    }
+
+    fn alloc_memory(
+        &mut self,
+        gpa: GuestPhysAddr,
+        size: usize,
+        flags: MappingFlags,
+    ) -> HyperResult<HostVirtAddr> {
+        if self.state.is_destroyed() {
+            return Err(HyperError::BadState);
+        }
+        self.memory
+            .alloc_region(self.gpt.get_mut(), gpa, size, flags)
+    }
+
+    fn save_snapshot(&mut self) -> HyperResult<Vec<u8>> {
+        let mut w = SnapshotWriter::new();
+        self.save_vcpu_state(&mut w)?;
+        self.save_device_state(&mut w)?;
+        self.memory.save(&mut w)?;
+        Ok(w.into_bytes())
+    }
+
+    fn restore_snapshot(&mut self, data: &[u8]) -> HyperResult {
+        self.state.ensure_stopped()?;
+        // check the snapshot before the guest memory is gone
+        self.check_snapshot(data)?;
+        self.memory.release(self.gpt.get_mut());
+        self.load_state(data)
+    }
+
+    fn save_vcpu_state(&mut self, w: &mut SnapshotWriter) -> HyperResult {
+        self.state.ensure_stopped()?;
+        for vcpu_id in 0..self.vcpus.capacity() {
+            if let Ok(vcpu) = self.vcpus.get_vcpu(vcpu_id) {
+                let hart = self.harts.lock()[vcpu_id];
+                w.section(SECTION_VCPU, |w| {
+                    w.put_usize(vcpu_id);
+                    vcpu.save_state(w)?;
+                    hart.save_state(w);
+                    Ok(())
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn save_device_state(&mut self, w: &mut SnapshotWriter) -> HyperResult {
+        self.state.ensure_stopped()?;
+        w.section(SECTION_IRQCHIP, |w| {
+            self.plic.lock().save_state(w);
+            Ok(())
+        })?;
+        w.section(SECTION_TIMER, |w| {
+            self.aclint.lock().save_state(w);
+            Ok(())
+        })?;
+        if let Some(aia) = &self.aia {
+            w.section(SECTION_MSI_IRQCHIP, |w| {
+                aia.aplic.lock().save_state(w);
+                aia.imsic.lock().save_state(w);
+                Ok(())
+            })?;
+        }
+        w.section(SECTION_MMIO_DEVICES, |w| self.mmio_bus.save_state(w))
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> HyperResult {
+        self.state.ensure_stopped()?;
+        let mut r = SnapshotReader::new(data)?;
+        while let Some((tag, mut section)) = r.next_section()? {
+            match tag {
+                SECTION_VCPU => {
+                    let vcpu_id = section.get_usize()?;
+                    self.vcpus.get_vcpu(vcpu_id)?.load_state(&mut section)?;
+                    self.harts.lock()[vcpu_id] = Hart::read_state(&mut section)?;
                 }
-                VmExitInfo::ExternalInterruptEmulation => self.handle_irq(),
+                SECTION_IRQCHIP => self.plic.lock().load_state(&mut section)?,
+                SECTION_TIMER => self.aclint.lock().load_state(&mut section)?,
+                SECTION_MSI_IRQCHIP => {
+                    let aia = self.aia.as_ref().ok_or(HyperError::BadState)?;
+                    aia.aplic.lock().load_state(&mut section)?;
+                    aia.imsic.lock().load_state(&mut section)?;
+                }
+                SECTION_MMIO_DEVICES => self.mmio_bus.load_state(&mut section)?,
+                SECTION_MEMORY => self.memory.load_region(self.gpt.get_mut(), &mut section)?,
+                // written by a later version, skip it
                 _ => {}
             }
+        }
+        self.state.store(VmState::Paused);
+        Ok(())
+    }
 
-            {
-                let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
-                vcpu.restore_gprs(&gprs);
-                if advance_pc {
-                    vcpu.advance_pc(len);
-                }
+    fn memory_regions(&self) -> &[GuestMemoryRegion] {
+        self.memory.regions()
+    }
+
+    /// Maps guest RAM read-only in the G-stage page table until the guest writes each page.
+    fn enable_dirty_log(&mut self) -> HyperResult {
+        if self.state.is_destroyed() {
+            return Err(HyperError::BadState);
+        }
+        self.memory.start_dirty_log(self.gpt.get_mut(), true)?;
+        flush_guest_tlbs();
+        Ok(())
+    }
+
+    fn disable_dirty_log(&mut self) -> HyperResult {
+        self.memory.stop_dirty_log(self.gpt.get_mut())?;
+        flush_guest_tlbs();
+        Ok(())
+    }
+
+    fn get_dirty_log(&mut self, gpa: GuestPhysAddr, size: usize) -> HyperResult<Vec<u64>> {
+        let dirty = self.memory.take_dirty_log(self.gpt.get_mut(), gpa, size)?;
+        flush_guest_tlbs();
+        Ok(dirty)
+    }
+}
+
+/// Mask of the low `size` bytes of a register.
+fn size_mask(size: u8) -> u64 {
+    u64::MAX >> (64 - 8 * size as u32)
+}
+
+/// Flushes the G-stage translations of every hart, after pages were write-protected.
+fn flush_guest_tlbs() {
+    // a hart mask base of -1 selects every hart, a size of -1 the whole address space
+    sbi_rt::remote_hfence_gvma(0, usize::MAX, 0, usize::MAX);
+}
+
+impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
+    /// Sets the level of interrupt line `source` of the emulated PLIC, or of the APLIC once AIA
+    /// is enabled, for device models. The vCPUs see the change the next time they enter the
+    /// guest.
+    pub fn set_irq_line(&self, source: u32, level: bool) -> HyperResult {
+        match &self.aia {
+            Some(aia) => aia.aplic.lock().set_irq(source, level),
+            None => self.plic.lock().set_irq(source, level),
+        }
+    }
+
+    /// The SBI HSM state of vCPU `vcpu_id`. A VMM runs the vCPUs that are not stopped:
+    /// [`VmTrait::init_vcpu`] starts a vCPU, and a stopped one starts once another vCPU calls
+    /// `hart_start` for it, which returns [`VmExit::VCpuStarted`]. Running a stopped or
+    /// suspended vCPU returns [`VmExit::Halt`] at once for the former, and resumes the latter.
+    pub fn hart_state(&self, vcpu_id: usize) -> HyperResult<HartState> {
+        self.harts
+            .lock()
+            .get(vcpu_id)
+            .map(|hart| hart.state)
+            .ok_or(HyperError::InvalidParam)
+    }
+
+    /// Replaces the PLIC by the interrupt controllers of the Advanced Interrupt Architecture: an
+    /// APLIC in MSI delivery mode and an IMSIC S-level interrupt file per vCPU.
+    ///
+    /// If `config.host_imsic` is set and the harts have guest interrupt files, each vCPU gets
+    /// one the first time it runs, mapped at its page of the IMSIC and selected with
+    /// `hstatus.VGEIN`, and the vCPU must keep running on that hart. Otherwise the interrupt
+    /// files are emulated, which needs a host with Ssaia so that the guest accesses to them
+    /// trap. Must be called before any vCPU runs.
+    pub fn enable_aia(&mut self, config: AiaConfig) -> HyperResult {
+        if self.state.load() != VmState::Created || self.aia.is_some() {
+            return Err(HyperError::BadState);
+        }
+        let aia = AiaState::new(config, self.vcpus.capacity());
+        let plic = self.mmio_bus.unregister(PLIC_BASE as u64)?;
+        let mut registered = self.mmio_bus.register(aia.aplic.clone());
+        if registered.is_ok() && aia.host_imsic.is_none() {
+            registered = self.mmio_bus.register(aia.imsic.clone());
+            if registered.is_err() {
+                self.mmio_bus.unregister(config.aplic_base as u64)?;
             }
         }
+        if let Err(err) = registered {
+            self.mmio_bus.register(plic)?;
+            return Err(err);
+        }
+        self.aia = Some(aia);
+        Ok(())
+    }
+
+    /// Sends MSI `eiid` to the S-level interrupt file of vCPU `vcpu_id`, for device models
+    /// that signal MSIs once AIA is enabled.
+    pub fn send_msi(&self, vcpu_id: usize, eiid: u32) -> HyperResult {
+        self.aia
+            .as_ref()
+            .ok_or(HyperError::Disabled)?
+            .send_msi(vcpu_id, eiid)
+    }
+
+    /// Whether vCPU `vcpu_id` has an external interrupt to take, for a VMM deciding whether to
+    /// wake it up from `wfi`. For a guest interrupt file, this reads `hgeip` and must run on
+    /// the hart the vCPU runs on.
+    pub fn has_pending_external_irq(&self, vcpu_id: usize) -> bool {
+        match &self.aia {
+            Some(aia) => match aia.files.lock().get(vcpu_id) {
+                Some(Some(file)) => file.is_pending(),
+                _ => aia
+                    .imsic
+                    .lock()
+                    .files
+                    .get(vcpu_id)
+                    .is_some_and(|file| file.has_irq()),
+            },
+            None => self.plic.lock().has_irq(2 * vcpu_id + 1),
+        }
     }
 }
 
 // Privaie methods implementation
 impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
+    /// Checks that the vCPU, MMIO device and memory sections of `data` are well-formed and can
+    /// be loaded into this VM. The device state is checked by the devices as they load it.
+    fn check_snapshot(&mut self, data: &[u8]) -> HyperResult {
+        let mut r = SnapshotReader::new(data)?;
+        let mut regions = SavedRegions::default();
+        while let Some((tag, mut section)) = r.next_section()? {
+            match tag {
+                SECTION_VCPU => {
+                    let vcpu_id = section.get_usize()?;
+                    self.vcpus.get_vcpu(vcpu_id)?.read_state(&mut section)?;
+                    Hart::read_state(&mut section)?;
+                }
+                SECTION_MSI_IRQCHIP if self.aia.is_none() => return Err(HyperError::BadState),
+                SECTION_MMIO_DEVICES => self.mmio_bus.check_state(&mut section)?,
+                SECTION_MEMORY => regions.check(&mut section)?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Maps the page at `fault_addr` writable again if it was write-protected for dirty
+    /// logging, returning whether it was.
+    fn handle_dirty_write(&self, fault_addr: GuestPhysAddr) -> HyperResult<bool> {
+        let handled = self
+            .memory
+            .handle_write_fault(&mut *self.gpt.lock(), fault_addr)?;
+        if handled {
+            unsafe { core::arch::riscv64::hfence_gvma_all() };
+        }
+        Ok(handled)
+    }
+
+    /// Emulates the load or store at `inst_addr` if `fault_addr` belongs to a device on the
+    /// MMIO bus, returning the length of the instruction.
     fn handle_page_fault(
-        &mut self,
+        &self,
+        vcpu: &VCpu<H>,
         inst_addr: GuestVirtAddr,
         inst: u32,
         fault_addr: GuestPhysAddr,
         gprs: &mut GeneralPurposeRegisters,
     ) -> HyperResult<usize> {
-        //  plic
-        if fault_addr >= self.plic.base() && fault_addr < self.plic.base() + 0x0400_0000 {
-            self.handle_plic(inst_addr, inst, fault_addr, gprs)
+        let addr = fault_addr as u64;
+        if !self.mmio_bus.contains(addr) {
+            debug!("inst_addr: {:#x}, fault_addr: {:#x}", inst_addr, fault_addr);
+            return Err(HyperError::PageFault);
+        }
+        let access = self.decode_access(vcpu, inst_addr, inst)?;
+        match access.op {
+            MmioOp::Load(pending) => {
+                let data = self
+                    .mmio_bus
+                    .read(addr, access.size)
+                    .ok_or(HyperError::PageFault)??;
+                let old = gprs.reg_by_index(pending.reg);
+                gprs.set_reg_by_index(pending.reg, pending.apply(old, data));
+            }
+            MmioOp::Store { rs2 } => {
+                let data = gprs.reg_by_index(rs2) as u64;
+                self.mmio_bus
+                    .write(addr, access.size, data & size_mask(access.size))
+                    .ok_or(HyperError::PageFault)??;
+            }
+        }
+        Ok(access.len)
+    }
+
+    /// Decodes the load or store at `inst_addr`, fetching it from guest memory if `htinst`
+    /// didn't report it.
+    fn decode_access(
+        &self,
+        vcpu: &VCpu<H>,
+        inst_addr: GuestVirtAddr,
+        inst: u32,
+    ) -> HyperResult<MmioAccess> {
+        if inst == 0 {
+            decode_access(self.fetch_instruction(vcpu, inst_addr)?, false)
         } else {
-            error!("inst_addr: {:#x}, fault_addr: {:#x}", inst_addr, fault_addr);
-            Err(HyperError::PageFault)
+            decode_access(inst, true)
         }
     }
 
-    #[allow(clippy::needless_late_init)]
-    fn handle_plic(
-        &mut self,
+    /// Fetches the instruction at `pc` by walking the guest page tables, for traps that don't
+    /// report it in `htinst`.
+    fn fetch_instruction(&self, vcpu: &VCpu<H>, pc: GuestVirtAddr) -> HyperResult<u32> {
+        let walk = vcpu.get_ptw_info();
+        let gpt = self.gpt.lock();
+        let mut parcel = [0u8; 2];
+        self.memory.fetch_gva(&*gpt, &walk, pc, &mut parcel)?;
+        let low = u16::from_le_bytes(parcel);
+        if riscv_decode::instruction_length(low) == 2 {
+            return Ok(low as u32);
+        }
+        // the second parcel may be on the next page
+        self.memory.fetch_gva(&*gpt, &walk, pc + 2, &mut parcel)?;
+        Ok(low as u32 | (u16::from_le_bytes(parcel) as u32) << 16)
+    }
+
+    /// Translates a [`VmExitInfo`] into an arch-independent [`VmExit`], remembering where the
+    /// data of a load goes and moving `sepc` past the instruction that caused the exit.
+    ///
+    /// SBI calls the hypervisor doesn't know are forwarded as hypercalls with the extension ID
+    /// in `a7` and arguments in `a0`-`a5`.
+    fn translate_exit(&self, vcpu: &mut VCpu<H>, exit_info: VmExitInfo) -> HyperResult<VmExit> {
+        let (exit, len) = match exit_info {
+            VmExitInfo::Ecall(Some(HyperCallMsg::Reset(ResetFunction::Reset {
+                reset_type,
+                reason,
+            }))) => match (reset_type, reason) {
+                (ResetType::Shutdown, ResetReason::NoReason) => (VmExit::Shutdown, 4),
+                (_, ResetReason::NoReason) => (VmExit::Reset, 4),
+                _ => (
+                    VmExit::SystemEvent {
+                        event: reset_type as u32,
+                        data: reason as u64,
+                    },
+                    4,
+                ),
+            },
+            VmExitInfo::Ecall(None) => (
+                VmExit::Hypercall {
+                    nr: vcpu.gpr(GprIndex::A7 as usize),
+                    args: core::array::from_fn(|i| vcpu.gpr(GprIndex::A0 as usize + i)),
+                },
+                4,
+            ),
+            VmExitInfo::PageFault {
+                fault_addr,
+                falut_pc,
+                inst,
+                priv_level,
+            } => {
+                let decoded = match priv_level {
+                    super::vmexit::PrivilegeLevel::Supervisor => {
+                        self.decode_mmio(vcpu, falut_pc, inst, fault_addr)
+                    }
+                    super::vmexit::PrivilegeLevel::User => Err(HyperError::PageFault),
+                };
+                match decoded {
+                    Ok(decoded) => decoded,
+                    // neither memory nor an access we can emulate
+                    Err(_) => {
+                        return Ok(VmExit::GuestFault(GuestFault::AccessFault {
+                            pc: falut_pc,
+                            addr: fault_addr,
+                            access: vcpu.trap_access(),
+                        }))
+                    }
+                }
+            }
+            VmExitInfo::InstructionPageFault {
+                fault_addr,
+                fault_pc,
+            } => {
+                return Ok(VmExit::GuestFault(GuestFault::AccessFault {
+                    pc: fault_pc,
+                    addr: fault_addr,
+                    access: GuestAccess::Fetch,
+                }))
+            }
+            VmExitInfo::VirtualInstruction { fault_pc, .. } => {
+                return Ok(VmExit::GuestFault(GuestFault::IllegalInstruction {
+                    pc: fault_pc,
+                }))
+            }
+            _ => return Ok(VmExit::ArchSpecific(exit_info)),
+        };
+        vcpu.advance_pc(len);
+        Ok(exit)
+    }
+
+    /// Decodes the load or store at `inst_addr` which faulted on `fault_addr`, returning the
+    /// exit and the instruction length.
+    fn decode_mmio(
+        &self,
+        vcpu: &mut VCpu<H>,
         inst_addr: GuestVirtAddr,
-        mut inst: u32,
+        inst: u32,
         fault_addr: GuestPhysAddr,
-        gprs: &mut GeneralPurposeRegisters,
-    ) -> HyperResult<usize> {
-        if inst == 0 {
-            // If hinst does not provide information about trap,
-            // we must read the instruction from guest's memory maunally.
-            inst = self.vm_pages.fetch_guest_instruction(inst_addr)?;
-        }
-        let i1 = inst as u16;
-        let len = riscv_decode::instruction_length(i1);
-        let inst = match len {
-            2 => i1 as u32,
-            4 => inst,
-            _ => unreachable!(),
+    ) -> HyperResult<(VmExit, usize)> {
+        let access = self.decode_access(vcpu, inst_addr, inst)?;
+        let exit = match access.op {
+            MmioOp::Store { rs2 } => VmExit::MmioWrite {
+                addr: fault_addr,
+                size: access.size,
+                data: (vcpu.gpr(rs2) as u64) & size_mask(access.size),
+            },
+            MmioOp::Load(pending) => {
+                vcpu.set_pending_read(pending);
+                VmExit::MmioRead {
+                    addr: fault_addr,
+                    size: access.size,
+                }
+            }
+        };
+        Ok((exit, access.len))
+    }
+
+    /// Claims the external interrupt pending at the host PLIC for this hart and raises the same
+    /// source of the guest PLIC. The guest completing it completes it at the host.
+    fn handle_irq(&self) {
+        // the S-mode context of this hart
+        let context = 2 * this_cpu_id() + 1;
+        let claim_complete_addr = PLIC_BASE + 0x0020_0004 + 0x1000 * context;
+        let irq = unsafe { core::ptr::read_volatile(claim_complete_addr as *const u32) };
+        // claimed by another hart meanwhile
+        if irq == 0 {
+            return;
+        }
+        if let Err(err) = self.plic.lock().forward_host_irq(irq, claim_complete_addr) {
+            warn!("cannot forward host irq {}: {:?}", irq, err);
+            unsafe { core::ptr::write_volatile(claim_complete_addr as *mut u32, irq) };
+        }
+    }
+
+    /// Sets `hvip.VSEIP` while the S-mode PLIC context of vCPU `vcpu_id` has an interrupt to
+    /// take, before entering the guest.
+    ///
+    /// With AIA, the MSIs the APLIC sent are delivered first. A guest interrupt file raises
+    /// VSEIP by itself through `hgeip`, so `hvip.VSEIP` only reflects an emulated one.
+    fn sync_external_irq(&self, vcpu_id: usize) {
+        let pending = match &self.aia {
+            Some(aia) => {
+                let msis = aia.aplic.lock().take_msis();
+                for msi in msis {
+                    if let Err(err) = aia.send_msi(msi.hart_index, msi.eiid) {
+                        warn!("cannot deliver MSI to hart {}: {:?}", msi.hart_index, err);
+                    }
+                }
+                aia.host_imsic.is_none() && self.has_pending_external_irq(vcpu_id)
+            }
+            None => self.plic.lock().has_irq(2 * vcpu_id + 1),
+        };
+        if pending {
+            CSR.hvip
+                .read_and_set_bits(traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL);
+        } else {
+            CSR.hvip
+                .read_and_clear_bits(traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL);
+        }
+    }
+
+    /// Moves vCPU `vcpu` to the started state before it runs, to the address it was started or
+    /// resumed at if any, and marks it as running on this CPU until the returned guard is
+    /// dropped. Returns `None` if it is stopped.
+    fn wake_hart(&self, vcpu: &mut VCpu<H>) -> Option<Running<'_>> {
+        let vcpu_id = vcpu.vcpu_id();
+        let mut harts = self.harts.lock();
+        let hart = &mut harts[vcpu_id];
+        if hart.state == HartState::Stopped {
+            return None;
+        }
+        if let Some((start_addr, opaque)) = hart.entry.take() {
+            vcpu.start_at(start_addr, opaque);
+        }
+        hart.state = HartState::Started;
+        hart.cpu = Some(this_cpu_id());
+        Some(Running {
+            harts: &self.harts,
+            vcpu_id,
+        })
+    }
+
+    /// Kicks the vCPUs running on other CPUs out of the guest, for them to see a new VM state.
+    /// One about to enter the guest is marked as running before it checks the state.
+    fn kick_running_harts(&self) {
+        let this_cpu = this_cpu_id();
+        for cpu in self.harts.lock().iter().filter_map(|hart| hart.cpu) {
+            if cpu != this_cpu {
+                kick_cpu(cpu);
+            }
+        }
+    }
+
+    /// Raises a supervisor software interrupt on the vCPUs in `hart_mask`, bit `i` selecting
+    /// vCPU `hart_mask_base + i`, or on all of them if `hart_mask_base` is `usize::MAX`. The
+    /// targets running on another CPU are kicked out of the guest to take it, the others take
+    /// it when they enter the guest again.
+    fn send_ipi(&self, hart_mask: usize, hart_mask_base: usize) -> HyperResult {
+        let num_vcpus = self.harts.lock().len();
+        let targets: Vec<usize> = if hart_mask_base == usize::MAX {
+            (0..num_vcpus).collect()
+        } else {
+            (0..usize::BITS as usize)
+                .filter(|i| hart_mask & (1 << i) != 0)
+                .map(|i| hart_mask_base.checked_add(i))
+                .collect::<Option<_>>()
+                .ok_or(HyperError::InvalidParam)?
         };
-        // assert!(len == 4);
-        let decode_inst = riscv_decode::decode(inst).map_err(|_| HyperError::DecodeError)?;
-        match decode_inst {
-            Instruction::Sw(i) => {
-                let val = gprs.reg(GprIndex::from_raw(i.rs2()).unwrap()) as u32;
-                self.plic.write_u32(fault_addr, val)
+        // all or nothing
+        if targets.iter().any(|&vcpu_id| vcpu_id >= num_vcpus) {
+            return Err(HyperError::InvalidParam);
+        }
+        let mut aclint = self.aclint.lock();
+        for &vcpu_id in &targets {
+            aclint.set_ssip(vcpu_id)?;
+        }
+        drop(aclint);
+        let this_cpu = this_cpu_id();
+        let harts = self.harts.lock();
+        for vcpu_id in targets {
+            if let Some(cpu) = harts[vcpu_id].cpu.filter(|&cpu| cpu != this_cpu) {
+                kick_cpu(cpu);
             }
-            Instruction::Lw(i) => {
-                let val = self.plic.read_u32(fault_addr);
-                gprs.set_reg(GprIndex::from_raw(i.rd()).unwrap(), val as usize)
+        }
+        Ok(())
+    }
+
+    /// Reads the hart mask passed to the legacy `sbi_send_ipi` at guest virtual address `addr`.
+    fn read_legacy_hart_mask(&self, vcpu: &VCpu<H>, addr: GuestVirtAddr) -> HyperResult<usize> {
+        let mut mask = [0u8; core::mem::size_of::<usize>()];
+        let gpt = self.gpt.lock();
+        self.memory
+            .read_gva(&*gpt, &vcpu.get_ptw_info(), addr, &mut mask)?;
+        Ok(usize::from_le_bytes(mask))
+    }
+
+    /// Handles the SBI HSM call of vCPU `vcpu_id`. Returns the exit to return if the vCPU
+    /// stopped or suspended itself, or started another one.
+    fn handle_hsm_function(
+        &self,
+        vcpu_id: usize,
+        hsm: HsmFunction,
+        gprs: &mut GeneralPurposeRegisters,
+    ) -> Option<VmExit> {
+        let entry_valid = match hsm {
+            HsmFunction::Start { start_addr, .. } => self.is_guest_code(start_addr),
+            HsmFunction::Suspend { resume_addr, .. } => self.is_guest_code(resume_addr),
+            _ => true,
+        };
+        let mut harts = self.harts.lock();
+        let mut suspend = |entry| {
+            harts[vcpu_id].state = HartState::Suspended;
+            harts[vcpu_id].entry = entry;
+            (0, 0, Some(VmExit::Halt))
+        };
+        let (error, value, exit) = match hsm {
+            HsmFunction::Start {
+                hartid,
+                start_addr,
+                opaque,
+            } => match harts.get_mut(hartid) {
+                None => (SBI_ERR_INAVLID_PARAM, 0, None),
+                Some(hart) if hart.state != HartState::Stopped => {
+                    (SBI_ERR_ALREADY_AVAILABLE, 0, None)
+                }
+                Some(_) if !entry_valid => (SBI_ERR_INVALID_ADDRESS, 0, None),
+                Some(hart) => {
+                    hart.state = HartState::StartPending;
+                    hart.entry = Some((start_addr, opaque));
+                    let exit = VmExit::VCpuStarted { vcpu_id: hartid };
+                    (0, 0, Some(exit))
+                }
+            },
+            HsmFunction::Stop => {
+                harts[vcpu_id].state = HartState::Stopped;
+                (0, 0, Some(VmExit::Halt))
             }
-            _ => return Err(HyperError::InvalidInstruction),
+            HsmFunction::GetStatus { hartid } => match harts.get(hartid) {
+                None => (SBI_ERR_INAVLID_PARAM, 0, None),
+                Some(hart) => (0, hart.state as usize, None),
+            },
+            HsmFunction::Suspend {
+                suspend_type,
+                resume_addr,
+                opaque,
+            } => match suspend_type {
+                HART_SUSPEND_TYPE_RETENTIVE => suspend(None),
+                HART_SUSPEND_TYPE_NON_RETENTIVE if !entry_valid => {
+                    (SBI_ERR_INVALID_ADDRESS, 0, None)
+                }
+                HART_SUSPEND_TYPE_NON_RETENTIVE => suspend(Some((resume_addr, opaque))),
+                // platform-specific types
+                0x1000_0000..=0x7fff_ffff | 0x9000_0000.. => (SBI_ERR_NOT_SUPPORTED, 0, None),
+                _ => (SBI_ERR_INAVLID_PARAM, 0, None),
+            },
+        };
+        gprs.set_reg(GprIndex::A0, error as usize);
+        gprs.set_reg(GprIndex::A1, value);
+        exit
+    }
+
+    /// Whether the guest can run code at `gpa`, where a vCPU starts or resumes.
+    fn is_guest_code(&self, gpa: GuestPhysAddr) -> bool {
+        self.gpt.lock().query(gpa).is_ok_and(|(_, flags)| {
+            flags.contains(MappingFlags::EXECUTE) && !flags.contains(MappingFlags::DEVICE)
+        })
+    }
+
+    /// The owner of the deadline of vCPU `vcpu_id` on the host timer.
+    fn timer_owner(&self, vcpu_id: usize) -> TimerOwner {
+        TimerOwner::VCpu {
+            vm_id: self.vm_id,
+            vcpu_id,
+        }
+    }
+
+    /// Loads the guest time of the VM into `htimedelta`, sets `hvip.VSTIP` and `hvip.VSSIP` from
+    /// the emulated CLINT state of vCPU `vcpu_id` before entering the guest, and has the host
+    /// timer fire at the vCPU deadline if it didn't pass yet. Returns whether `hvip.VSSIP` is set.
+    fn sync_aclint_irqs(&self, vcpu_id: usize) -> bool {
+        let aclint = self.aclint.lock();
+        unsafe { core::arch::asm!("csrw htimedelta, {}", in(reg) aclint.time_delta()) };
+        if aclint.timer_pending(vcpu_id) {
+            timer::set_deadline(self.timer_owner(vcpu_id), None);
+            CSR.hvip
+                .read_and_set_bits(traps::interrupt::VIRTUAL_SUPERVISOR_TIMER);
+        } else {
+            timer::set_deadline(self.timer_owner(vcpu_id), aclint.deadline(vcpu_id));
+            CSR.hvip
+                .read_and_clear_bits(traps::interrupt::VIRTUAL_SUPERVISOR_TIMER);
+        }
+        let ssip = aclint.soft_pending(vcpu_id);
+        if ssip {
+            CSR.hvip
+                .read_and_set_bits(traps::interrupt::VIRTUAL_SUPERVISOR_SOFT);
+        } else {
+            CSR.hvip
+                .read_and_clear_bits(traps::interrupt::VIRTUAL_SUPERVISOR_SOFT);
+        }
+        ssip
+    }
+
+    /// Records the supervisor software interrupt of vCPU `vcpu_id` after a VM exit: the guest
+    /// sets and clears it through `sip`, which is `hvip.VSSIP`, set on entry if `entry_ssip`.
+    /// It is only cleared if the guest cleared it, not if an IPI raised it meanwhile.
+    fn save_guest_ssip(&self, vcpu_id: usize, entry_ssip: bool) {
+        let mut aclint = self.aclint.lock();
+        if CSR.hvip.get_value() & traps::interrupt::VIRTUAL_SUPERVISOR_SOFT != 0 {
+            let _ = aclint.set_ssip(vcpu_id);
+        } else if entry_ssip {
+            aclint.clear_ssip(vcpu_id);
+        }
+    }
+
+    /// Gives vCPU `vcpu` a guest interrupt file of this hart if AIA uses them and it has none
+    /// yet, replaying the MSIs it was sent before.
+    fn bind_guest_file(&self, vcpu: &mut VCpu<H>) -> HyperResult {
+        let Some(aia) = &self.aia else {
+            return Ok(());
+        };
+        let Some(host_imsic) = aia.host_imsic else {
+            return Ok(());
+        };
+        let vcpu_id = vcpu.vcpu_id();
+        let hart = this_cpu_id();
+        let mut files = aia.files.lock();
+        match files[vcpu_id] {
+            Some(file) if file.hart == hart => return Ok(()),
+            // the file can't follow the vCPU to another hart
+            Some(_) => return Err(HyperError::BadState),
+            None => {}
         }
-        Ok(len)
+        let file = alloc_guest_file(hart)?;
+        file.clear();
+        let gpa = aia.config.imsic_base + vcpu_id * IMSIC_PAGE_SIZE;
+        // G-stage leaf entries must be user pages
+        let flags =
+            MappingFlags::READ | MappingFlags::WRITE | MappingFlags::USER | MappingFlags::DEVICE;
+        if let Err(err) = self.gpt.lock().map(gpa, file.host_page(&host_imsic), flags) {
+            free_guest_file(file);
+            return Err(err);
+        }
+        unsafe { core::arch::riscv64::hfence_gvma_all() };
+        vcpu.set_vgein(file.index);
+        files[vcpu_id] = Some(file);
+        drop(files);
+
+        let mut imsic = aia.imsic.lock();
+        let pending: Vec<u32> = imsic.files[vcpu_id].pending_ids().collect();
+        imsic.files[vcpu_id] = Default::default();
+        drop(imsic);
+        pending
+            .into_iter()
+            .try_for_each(|eiid| aia.send_msi(vcpu_id, eiid))
     }
 
-    fn handle_irq(&mut self) {
-        let context_id = 1;
-        let claim_and_complete_addr = self.plic.base() + 0x0020_0004 + 0x1000 * context_id;
-        let irq = unsafe { core::ptr::read_volatile(claim_and_complete_addr as *const u32) };
-        assert!(irq != 0);
-        self.plic.claim_complete[context_id] = irq;
+    /// Unmaps and frees the guest interrupt files of the vCPUs, which get new ones when they
+    /// run again.
+    fn release_guest_files(&mut self) -> HyperResult {
+        let Some(aia) = &self.aia else {
+            return Ok(());
+        };
+        for (vcpu_id, file) in aia.files.lock().iter_mut().enumerate() {
+            let Some(guest_file) = file.take() else {
+                continue;
+            };
+            self.gpt
+                .get_mut()
+                .unmap(aia.config.imsic_base + vcpu_id * IMSIC_PAGE_SIZE)?;
+            free_guest_file(guest_file);
+            if let Ok(vcpu) = self.vcpus.get_vcpu(vcpu_id) {
+                vcpu.set_vgein(0);
+            }
+        }
+        unsafe { core::arch::riscv64::hfence_gvma_all() };
+        Ok(())
+    }
 
-        CSR.hvip
-            .read_and_set_bits(traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL);
+    /// Emulates the guest access to `sireg` or `stopei` of an emulated interrupt file at `pc`,
+    /// which raised a virtual instruction exception as `hstatus.VGEIN` selects no file.
+    fn handle_imsic_csr(
+        &self,
+        vcpu: &VCpu<H>,
+        pc: GuestVirtAddr,
+        gprs: &mut GeneralPurposeRegisters,
+    ) -> HyperResult {
+        let aia = self
+            .aia
+            .as_ref()
+            .filter(|aia| aia.host_imsic.is_none())
+            .ok_or(HyperError::NotSupported)?;
+        let inst = match vcpu.trap_value() {
+            0 => self.fetch_instruction(vcpu, pc)?,
+            inst => inst as u32,
+        };
+        let access = decode_csr_access(inst)?;
+        let operand = if access.imm {
+            access.rs1
+        } else {
+            gprs.reg(GprIndex::from_raw(access.rs1 as u32).unwrap())
+        };
+        let iselect: usize;
+        // vsiselect, which the guest writes directly
+        unsafe { core::arch::asm!("csrr {}, 0x250", out(reg) iselect) };
+        let mut imsic = aia.imsic.lock();
+        let file = imsic
+            .files
+            .get_mut(vcpu.vcpu_id())
+            .ok_or(HyperError::BadState)?;
+        let old = emulate_imsic_csr(file, &access, iselect, operand as u64)?;
+        if access.rd != 0 {
+            gprs.set_reg(GprIndex::from_raw(access.rd as u32).unwrap(), old as usize);
+        }
+        Ok(())
     }
 
     fn handle_base_function(
@@ -231,6 +1200,15 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
                 let impl_version = sbi_rt::get_sbi_impl_version();
                 gprs.set_reg(GprIndex::A1, impl_version);
             }
+            // emulated for the guest whatever the host supports
+            BaseFunction::ProbeSbiExtension(extension)
+                if matches!(
+                    extension as usize,
+                    sbi_spec::hsm::EID_HSM | sbi_spec::spi::EID_SPI
+                ) =>
+            {
+                gprs.set_reg(GprIndex::A1, 1);
+            }
             BaseFunction::ProbeSbiExtension(extension) => {
                 let extension = sbi_rt::probe_extension(extension as usize).raw;
                 gprs.set_reg(GprIndex::A1, extension);
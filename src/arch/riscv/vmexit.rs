@@ -47,6 +47,22 @@ pub enum VmExitInfo {
         /// Virtual instruction privilege level.
         priv_level: PrivilegeLevel,
     },
+    /// G-stage page fault on instruction fetch.
+    InstructionPageFault {
+        /// Guest physical address of the fetch.
+        fault_addr: GuestPhysAddr,
+        /// Faulting instruction addr.
+        fault_pc: GuestVirtAddr,
+    },
+    /// A trap the hypervisor has no handler for.
+    UnhandledTrap {
+        /// Trap cause (`scause`).
+        scause: usize,
+        /// Trap value (`stval`).
+        stval: usize,
+        /// Trapping instruction addr.
+        fault_pc: GuestVirtAddr,
+    },
     /// An interrupt intended for the vCPU's host.
     HostInterruot(Interrupt),
     /// An timer interrupt for the running vCPU that can't be delegated and must be injected. The
@@ -0,0 +1,675 @@
+use iced_x86::{CodeSize, Decoder, DecoderOptions, Instruction, Mnemonic, OpKind, Register};
+
+use crate::{HyperError, HyperResult};
+
+/// Iterations of a `rep movs` or `rep stos` emulated at once, the guest executes the instruction
+/// again for the rest so that interrupts are not held off.
+const MAX_STRING_ITERATIONS: u64 = 4096;
+
+/// `RFLAGS` bits.
+const RFLAGS_CF: u64 = 1 << 0;
+const RFLAGS_PF: u64 = 1 << 2;
+const RFLAGS_AF: u64 = 1 << 4;
+const RFLAGS_ZF: u64 = 1 << 6;
+const RFLAGS_SF: u64 = 1 << 7;
+const RFLAGS_DF: u64 = 1 << 10;
+const RFLAGS_OF: u64 = 1 << 11;
+/// The status flags set by arithmetic instructions.
+const RFLAGS_STATUS: u64 = RFLAGS_CF | RFLAGS_PF | RFLAGS_AF | RFLAGS_ZF | RFLAGS_SF | RFLAGS_OF;
+
+/// Indexes of the general-purpose registers strings and `cmpxchg` use implicitly.
+const RAX: usize = 0;
+const RCX: usize = 1;
+const RSI: usize = 6;
+const RDI: usize = 7;
+
+/// The vCPU registers and guest memory an emulated instruction works on.
+pub trait EmulatorContext {
+    /// The 64-bit general-purpose register `index`, in the x86 encoding where 4 is `RSP`.
+    fn gpr(&self, index: usize) -> u64;
+    /// Sets the 64-bit general-purpose register `index`.
+    fn set_gpr(&mut self, index: usize, value: u64);
+    /// `RFLAGS`.
+    fn rflags(&self) -> u64;
+    /// Sets `RFLAGS`.
+    fn set_rflags(&mut self, rflags: u64);
+    /// The base address of the segment register `index`, in the order `ES`, `CS`, `SS`, `DS`,
+    /// `FS` and `GS`.
+    fn segment_base(&self, index: usize) -> u64;
+    /// Reads `size` bytes at the guest-linear address `gla`, from memory or an emulated device.
+    fn read_memory(&mut self, gla: u64, size: u8) -> HyperResult<u64>;
+    /// Writes the low `size` bytes of `value` at the guest-linear address `gla`.
+    fn write_memory(&mut self, gla: u64, size: u8, value: u64) -> HyperResult;
+}
+
+/// Decodes the instruction at the start of `bytes`, executed at `ip` in `bitness`-bit code.
+/// Fails with [`HyperError::DecodeError`] if the bytes are not a whole valid instruction.
+pub fn decode_instruction(bytes: &[u8], bitness: u32, ip: u64) -> HyperResult<Instruction> {
+    let instr = Decoder::with_ip(bitness, bytes, ip, DecoderOptions::NONE).decode();
+    if instr.is_invalid() {
+        return Err(HyperError::DecodeError);
+    }
+    Ok(instr)
+}
+
+/// Executes `instr` against `ctx`: `mov` in all its forms, `movzx`, `movsx`, `movsxd`, `stos`
+/// and `movs` with or without `rep`, `and`, `or`, `xor`, `add`, `sub`, `cmp`, `test`, `bt`,
+/// `xchg` and `cmpxchg`. Other instructions fail with [`HyperError::NotSupported`] before
+/// anything is changed.
+///
+/// Returns whether the instruction is done, `RIP` is left to the caller. A `rep` prefix with
+/// iterations left returns `false` so that the guest executes it again. The registers and
+/// memory an instruction already changed stay changed when a later access fails.
+pub fn emulate_instruction<C: EmulatorContext>(
+    ctx: &mut C,
+    instr: &Instruction,
+) -> HyperResult<bool> {
+    let mut emu = Emulation { ctx, instr };
+    let mnemonic = instr.mnemonic();
+    match mnemonic {
+        Mnemonic::Mov => {
+            let size = emu.op_size(0)?;
+            let value = emu.read_op(1, size)?;
+            emu.write_op(0, size, value)?;
+        }
+        Mnemonic::Movzx | Mnemonic::Movsx | Mnemonic::Movsxd => {
+            let src_size = emu.op_size(1)?;
+            let mut value = emu.read_op(1, src_size)?;
+            if mnemonic != Mnemonic::Movzx {
+                value = sign_extend(value, src_size);
+            }
+            let size = emu.op_size(0)?;
+            emu.write_op(0, size, value)?;
+        }
+        Mnemonic::Stosb | Mnemonic::Stosw | Mnemonic::Stosd | Mnemonic::Stosq => {
+            return emu.string(false)
+        }
+        // `movsd` is also the SSE move, whose first operand is not `[rdi]`
+        Mnemonic::Movsb | Mnemonic::Movsw | Mnemonic::Movsd | Mnemonic::Movsq
+            if is_string_destination(instr.op0_kind()) =>
+        {
+            return emu.string(true)
+        }
+        Mnemonic::Add
+        | Mnemonic::Sub
+        | Mnemonic::And
+        | Mnemonic::Or
+        | Mnemonic::Xor
+        | Mnemonic::Cmp
+        | Mnemonic::Test => {
+            let size = emu.op_size(0)?;
+            let a = emu.read_op(0, size)?;
+            let b = emu.read_op(1, size)?;
+            let (result, flags) = alu(mnemonic, a, b, size);
+            if !matches!(mnemonic, Mnemonic::Cmp | Mnemonic::Test) {
+                emu.write_op(0, size, result)?;
+            }
+            emu.update_flags(RFLAGS_STATUS, flags);
+        }
+        Mnemonic::Bt => emu.bt()?,
+        Mnemonic::Xchg => {
+            let size = emu.op_size(0)?;
+            let a = emu.read_op(0, size)?;
+            let b = emu.read_op(1, size)?;
+            emu.write_op(0, size, b)?;
+            emu.write_op(1, size, a)?;
+        }
+        Mnemonic::Cmpxchg => {
+            let size = emu.op_size(0)?;
+            let dest = emu.read_op(0, size)?;
+            let accumulator = match size {
+                1 => Register::AL,
+                2 => Register::AX,
+                4 => Register::EAX,
+                _ => Register::RAX,
+            };
+            let expected = emu.read_reg(accumulator)?;
+            let (_, flags) = alu(Mnemonic::Cmp, expected, dest, size);
+            // unlike the processor, a failed compare does not write the old value back, which
+            // a device would take for a store
+            if expected == dest {
+                let src = emu.read_op(1, size)?;
+                emu.write_op(0, size, src)?;
+            } else {
+                emu.write_reg(accumulator, dest)?;
+            }
+            emu.update_flags(RFLAGS_STATUS, flags);
+        }
+        _ => return Err(HyperError::NotSupported),
+    }
+    Ok(true)
+}
+
+/// An instruction being executed against a context.
+struct Emulation<'a, C: EmulatorContext> {
+    ctx: &'a mut C,
+    instr: &'a Instruction,
+}
+
+impl<C: EmulatorContext> Emulation<'_, C> {
+    /// Size in bytes of operand `op`, a general-purpose register or memory.
+    fn op_size(&self, op: u32) -> HyperResult<u8> {
+        let size = match self.instr.op_kind(op) {
+            OpKind::Register if self.instr.op_register(op).is_gpr() => {
+                self.instr.op_register(op).size()
+            }
+            kind if is_memory(kind) => self.instr.memory_size().size(),
+            _ => 0,
+        };
+        match size {
+            1 | 2 | 4 | 8 => Ok(size as u8),
+            _ => Err(HyperError::NotSupported),
+        }
+    }
+
+    /// The value of the general-purpose register `reg`, zero-extended.
+    fn read_reg(&self, reg: Register) -> HyperResult<u64> {
+        if !reg.is_gpr() {
+            return Err(HyperError::NotSupported);
+        }
+        let mut value = self.ctx.gpr(reg.full_register().number());
+        if is_high_byte(reg) {
+            value >>= 8;
+        }
+        Ok(value & mask(reg.size() as u8))
+    }
+
+    /// Writes `value` to `reg` the way the processor does: 32-bit registers clear the upper half
+    /// of the 64-bit one, 8-bit and 16-bit ones leave the rest alone.
+    fn write_reg(&mut self, reg: Register, value: u64) -> HyperResult {
+        if !reg.is_gpr() {
+            return Err(HyperError::NotSupported);
+        }
+        let index = reg.full_register().number();
+        let value = match reg.size() {
+            8 => value,
+            4 => value & mask(4),
+            size => {
+                let shift = if is_high_byte(reg) { 8 } else { 0 };
+                let bits = mask(size as u8) << shift;
+                (self.ctx.gpr(index) & !bits) | ((value << shift) & bits)
+            }
+        };
+        self.ctx.set_gpr(index, value);
+        Ok(())
+    }
+
+    /// The guest-linear address of memory operand `op`.
+    fn address(&self, op: u32) -> HyperResult<u64> {
+        let gla = self
+            .instr
+            .virtual_address(op, 0, |reg, _, _| {
+                if reg.is_segment_register() {
+                    Some(self.ctx.segment_base(reg.number()))
+                } else {
+                    self.read_reg(reg).ok()
+                }
+            })
+            .ok_or(HyperError::NotSupported)?;
+        // linear addresses are 32 bits wide outside of 64-bit mode
+        Ok(match self.instr.code_size() {
+            CodeSize::Code64 => gla,
+            _ => gla & mask(4),
+        })
+    }
+
+    /// The value of operand `op`, `size` bytes wide. Immediates are sign-extended to `size`.
+    fn read_op(&mut self, op: u32, size: u8) -> HyperResult<u64> {
+        match self.instr.op_kind(op) {
+            OpKind::Register => self.read_reg(self.instr.op_register(op)),
+            kind if is_memory(kind) => {
+                let gla = self.address(op)?;
+                Ok(self.ctx.read_memory(gla, size)? & mask(size))
+            }
+            _ => Ok(self.instr.immediate(op) & mask(size)),
+        }
+    }
+
+    /// Writes `value` to operand `op`, `size` bytes wide.
+    fn write_op(&mut self, op: u32, size: u8, value: u64) -> HyperResult {
+        match self.instr.op_kind(op) {
+            OpKind::Register => self.write_reg(self.instr.op_register(op), value),
+            kind if is_memory(kind) => {
+                let gla = self.address(op)?;
+                self.ctx.write_memory(gla, size, value & mask(size))
+            }
+            _ => Err(HyperError::NotSupported),
+        }
+    }
+
+    /// Replaces the `RFLAGS` bits of `bits` with `flags`.
+    fn update_flags(&mut self, bits: u64, flags: u64) {
+        let rflags = self.ctx.rflags();
+        self.ctx.set_rflags((rflags & !bits) | (flags & bits));
+    }
+
+    /// `bt`, which copies a bit of its first operand to `CF`. A register bit offset reaches
+    /// past a memory operand, to the bit string it starts.
+    fn bt(&mut self) -> HyperResult {
+        let size = self.op_size(0)?;
+        let bits = size as i64 * 8;
+        let (offset, reg_offset) = match self.instr.op1_kind() {
+            OpKind::Register => (self.read_op(1, size)?, true),
+            _ => (self.instr.immediate(1), false),
+        };
+        let value = if self.instr.op0_kind() == OpKind::Register {
+            self.read_op(0, size)? >> (offset % bits as u64)
+        } else {
+            let mut gla = self.address(0)?;
+            let mut bit = offset % bits as u64;
+            if reg_offset {
+                let offset = sign_extend(offset, size) as i64;
+                gla = gla.wrapping_add((offset.div_euclid(bits) * size as i64) as u64);
+                bit = offset.rem_euclid(bits) as u64;
+            }
+            self.ctx.read_memory(gla, size)? >> bit
+        };
+        self.update_flags(RFLAGS_CF, if value & 1 != 0 { RFLAGS_CF } else { 0 });
+        Ok(())
+    }
+
+    /// `stos` or `movs`, with or without a `rep` prefix. Returns whether the instruction is done.
+    fn string(&mut self, movs: bool) -> HyperResult<bool> {
+        let size = self.op_size(0)?;
+        let addr_mask = match self.instr.op0_kind() {
+            OpKind::MemoryESDI => mask(2),
+            OpKind::MemoryESEDI => mask(4),
+            _ => mask(8),
+        };
+        // `repne` repeats these like `rep`
+        let rep = self.instr.has_rep_prefix() || self.instr.has_repne_prefix();
+        let step = if self.ctx.rflags() & RFLAGS_DF != 0 {
+            (size as u64).wrapping_neg()
+        } else {
+            size as u64
+        };
+        let mut done = 0;
+        loop {
+            if rep {
+                if self.ctx.gpr(RCX) & addr_mask == 0 {
+                    return Ok(true);
+                }
+                if done == MAX_STRING_ITERATIONS {
+                    return Ok(false);
+                }
+            }
+            let value = if movs {
+                let src = self.address(1)?;
+                self.ctx.read_memory(src, size)?
+            } else {
+                self.ctx.gpr(RAX)
+            };
+            let dest = self.address(0)?;
+            self.ctx.write_memory(dest, size, value & mask(size))?;
+            self.advance(RDI, step, addr_mask);
+            if movs {
+                self.advance(RSI, step, addr_mask);
+            }
+            if !rep {
+                return Ok(true);
+            }
+            self.advance(RCX, u64::MAX, addr_mask);
+            done += 1;
+        }
+    }
+
+    /// Adds `by` to the register `index` used as an address of `addr_mask` bits. 16-bit
+    /// addresses leave the upper bits of the register alone.
+    fn advance(&mut self, index: usize, by: u64, addr_mask: u64) {
+        let old = self.ctx.gpr(index);
+        let value = old.wrapping_add(by) & addr_mask;
+        let value = if addr_mask == mask(2) {
+            (old & !addr_mask) | value
+        } else {
+            value
+        };
+        self.ctx.set_gpr(index, value);
+    }
+}
+
+/// The result of `a op b`, `size` bytes wide, and the status flags it sets.
+fn alu(op: Mnemonic, a: u64, b: u64, size: u8) -> (u64, u64) {
+    let sign = 1 << (size as u32 * 8 - 1);
+    let mut flags = 0;
+    let result = match op {
+        Mnemonic::Add => {
+            let result = a.wrapping_add(b) & mask(size);
+            if result < a {
+                flags |= RFLAGS_CF;
+            }
+            if (a ^ result) & (b ^ result) & sign != 0 {
+                flags |= RFLAGS_OF;
+            }
+            result
+        }
+        Mnemonic::Sub | Mnemonic::Cmp => {
+            let result = a.wrapping_sub(b) & mask(size);
+            if a < b {
+                flags |= RFLAGS_CF;
+            }
+            if (a ^ b) & (a ^ result) & sign != 0 {
+                flags |= RFLAGS_OF;
+            }
+            result
+        }
+        Mnemonic::And | Mnemonic::Test => a & b,
+        Mnemonic::Or => a | b,
+        Mnemonic::Xor => a ^ b,
+        _ => unreachable!(),
+    };
+    if matches!(op, Mnemonic::Add | Mnemonic::Sub | Mnemonic::Cmp) && (a ^ b ^ result) & 0x10 != 0 {
+        flags |= RFLAGS_AF;
+    }
+    if result == 0 {
+        flags |= RFLAGS_ZF;
+    }
+    if result & sign != 0 {
+        flags |= RFLAGS_SF;
+    }
+    // parity of the low byte only
+    if (result as u8).count_ones() & 1 == 0 {
+        flags |= RFLAGS_PF;
+    }
+    (result, flags)
+}
+
+fn mask(size: u8) -> u64 {
+    u64::MAX >> (64 - 8 * size as u32)
+}
+
+fn sign_extend(value: u64, size: u8) -> u64 {
+    let shift = 64 - 8 * size as u32;
+    (((value << shift) as i64) >> shift) as u64
+}
+
+/// Whether `reg` is one of `AH`, `CH`, `DH` and `BH`, bits 8 to 15 of a 64-bit register.
+pub(super) fn is_high_byte(reg: Register) -> bool {
+    matches!(
+        reg,
+        Register::AH | Register::CH | Register::DH | Register::BH
+    )
+}
+
+fn is_string_destination(kind: OpKind) -> bool {
+    matches!(
+        kind,
+        OpKind::MemoryESDI | OpKind::MemoryESEDI | OpKind::MemoryESRDI
+    )
+}
+
+fn is_memory(kind: OpKind) -> bool {
+    matches!(
+        kind,
+        OpKind::Memory
+            | OpKind::MemorySegSI
+            | OpKind::MemorySegESI
+            | OpKind::MemorySegRSI
+            | OpKind::MemorySegDI
+            | OpKind::MemorySegEDI
+            | OpKind::MemorySegRDI
+    ) || is_string_destination(kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::BTreeMap;
+    use alloc::vec::Vec;
+
+    const MMIO: u64 = 0xfee0_0000;
+
+    /// A vCPU whose memory is a sparse byte map, logging the accesses to `MMIO` and above.
+    #[derive(Default)]
+    struct TestContext {
+        regs: [u64; 16],
+        rflags: u64,
+        memory: BTreeMap<u64, u8>,
+        /// `(address, size, value written)`, `None` for reads.
+        mmio: Vec<(u64, u8, Option<u64>)>,
+    }
+
+    impl TestContext {
+        fn store(&mut self, addr: u64, size: u8, value: u64) {
+            for i in 0..size as u64 {
+                self.memory.insert(addr + i, (value >> (8 * i)) as u8);
+            }
+        }
+
+        fn load(&self, addr: u64, size: u8) -> u64 {
+            (0..size as u64).fold(0, |value, i| {
+                value | (*self.memory.get(&(addr + i)).unwrap_or(&0) as u64) << (8 * i)
+            })
+        }
+
+        fn run(&mut self, bytes: &[u8]) -> HyperResult<bool> {
+            let instr = decode_instruction(bytes, 64, 0x1000)?;
+            assert_eq!(instr.len(), bytes.len());
+            emulate_instruction(self, &instr)
+        }
+    }
+
+    impl EmulatorContext for TestContext {
+        fn gpr(&self, index: usize) -> u64 {
+            self.regs[index]
+        }
+
+        fn set_gpr(&mut self, index: usize, value: u64) {
+            self.regs[index] = value;
+        }
+
+        fn rflags(&self) -> u64 {
+            self.rflags
+        }
+
+        fn set_rflags(&mut self, rflags: u64) {
+            self.rflags = rflags;
+        }
+
+        fn segment_base(&self, _index: usize) -> u64 {
+            0
+        }
+
+        fn read_memory(&mut self, gla: u64, size: u8) -> HyperResult<u64> {
+            if gla >= MMIO {
+                self.mmio.push((gla, size, None));
+            }
+            Ok(self.load(gla, size))
+        }
+
+        fn write_memory(&mut self, gla: u64, size: u8, value: u64) -> HyperResult {
+            if gla >= MMIO {
+                self.mmio.push((gla, size, Some(value)));
+            }
+            self.store(gla, size, value);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn mov_loads_and_stores() {
+        let mut ctx = TestContext::default();
+        ctx.regs[0] = MMIO; // rax
+        ctx.regs[1] = 0xdead_beef_1234_5678; // rcx
+        ctx.regs[3] = MMIO + 0x10; // rbx
+        ctx.regs[2] = u64::MAX; // rdx
+
+        // mov [rax], ecx
+        ctx.run(&[0x89, 0x08]).unwrap();
+        // mov dword [rax+4], 0x80000001
+        ctx.run(&[0xc7, 0x40, 0x04, 0x01, 0x00, 0x00, 0x80])
+            .unwrap();
+        // mov qword [rax+8], -2
+        ctx.run(&[0x48, 0xc7, 0x40, 0x08, 0xfe, 0xff, 0xff, 0xff])
+            .unwrap();
+        assert_eq!(
+            ctx.mmio,
+            [
+                (MMIO, 4, Some(0x1234_5678)),
+                (MMIO + 4, 4, Some(0x8000_0001)),
+                (MMIO + 8, 8, Some(u64::MAX - 1)),
+            ]
+        );
+
+        ctx.store(MMIO + 0x10, 8, 0x8182_8384_8586_8788);
+        // mov dh, [rbx]
+        ctx.run(&[0x8a, 0x33]).unwrap();
+        assert_eq!(ctx.regs[2], 0xffff_ffff_ffff_88ff);
+        // mov edx, [rbx]
+        ctx.run(&[0x8b, 0x13]).unwrap();
+        assert_eq!(ctx.regs[2], 0x8586_8788);
+        // movzx edx, word [rbx]
+        ctx.run(&[0x0f, 0xb7, 0x13]).unwrap();
+        assert_eq!(ctx.regs[2], 0x8788);
+        // movsx rdx, byte [rbx]
+        ctx.run(&[0x48, 0x0f, 0xbe, 0x13]).unwrap();
+        assert_eq!(ctx.regs[2], 0xffff_ffff_ffff_ff88);
+        // movsxd rdx, dword [rbx]
+        ctx.run(&[0x48, 0x63, 0x13]).unwrap();
+        assert_eq!(ctx.regs[2], 0xffff_ffff_8586_8788);
+        // mov eax, [rip+0x10], at 0x1000 with 6 bytes
+        ctx.store(0x1016, 4, 0x42);
+        ctx.run(&[0x8b, 0x05, 0x10, 0x00, 0x00, 0x00]).unwrap();
+        assert_eq!(ctx.regs[0], 0x42);
+    }
+
+    #[test]
+    fn rep_string_instructions() {
+        let mut ctx = TestContext::default();
+        ctx.regs[0] = 0x1122_3344; // eax
+        ctx.regs[1] = 3; // rcx
+        ctx.regs[7] = MMIO; // rdi
+
+        // rep stosd
+        assert_eq!(ctx.run(&[0xf3, 0xab]), Ok(true));
+        assert_eq!(
+            ctx.mmio,
+            [
+                (MMIO, 4, Some(0x1122_3344)),
+                (MMIO + 4, 4, Some(0x1122_3344)),
+                (MMIO + 8, 4, Some(0x1122_3344)),
+            ]
+        );
+        assert_eq!((ctx.regs[1], ctx.regs[7]), (0, MMIO + 12));
+
+        // std; rep movsb from memory at 0x2001 down
+        ctx.mmio.clear();
+        ctx.rflags = RFLAGS_DF;
+        ctx.store(0x2000, 2, 0xbbaa);
+        ctx.regs[1] = 2;
+        ctx.regs[6] = 0x2001; // rsi
+        ctx.regs[7] = MMIO + 1;
+        assert_eq!(ctx.run(&[0xf3, 0xa4]), Ok(true));
+        assert_eq!(ctx.mmio, [(MMIO + 1, 1, Some(0xbb)), (MMIO, 1, Some(0xaa))]);
+        assert_eq!((ctx.regs[6], ctx.regs[7]), (0x1fff, MMIO - 1));
+
+        // a long rep goes back to the guest with iterations left
+        ctx.rflags = 0;
+        ctx.regs[1] = MAX_STRING_ITERATIONS + 1;
+        ctx.regs[7] = MMIO;
+        assert_eq!(ctx.run(&[0xf3, 0xaa]), Ok(false));
+        assert_eq!(ctx.regs[1], 1);
+        assert_eq!(ctx.run(&[0xf3, 0xaa]), Ok(true));
+        assert_eq!(ctx.regs[1], 0);
+
+        // stosw with a 16-bit address in 32-bit code wraps di and keeps the rest of edi
+        ctx.regs[7] = 0x1_ffff;
+        let instr = decode_instruction(&[0x67, 0x66, 0xab], 32, 0).unwrap();
+        emulate_instruction(&mut ctx, &instr).unwrap();
+        assert_eq!(ctx.memory.get(&0xffff), Some(&0x44));
+        assert_eq!(ctx.regs[7], 0x1_0001);
+    }
+
+    #[test]
+    fn arithmetic_sets_flags() {
+        let mut ctx = TestContext::default();
+        ctx.regs[3] = MMIO; // rbx
+        ctx.store(MMIO, 4, 5);
+
+        // cmp dword [rbx], 5
+        ctx.run(&[0x83, 0x3b, 0x05]).unwrap();
+        assert_eq!(ctx.rflags, RFLAGS_ZF | RFLAGS_PF);
+        assert!(ctx.mmio.iter().all(|access| access.2.is_none()));
+        // sub dword [rbx], 6
+        ctx.run(&[0x83, 0x2b, 0x06]).unwrap();
+        assert_eq!(ctx.load(MMIO, 4), 0xffff_ffff);
+        assert_eq!(ctx.rflags, RFLAGS_CF | RFLAGS_SF | RFLAGS_AF | RFLAGS_PF);
+        // add byte [rbx+4], 1 on 0x7f
+        ctx.store(MMIO + 4, 1, 0x7f);
+        ctx.run(&[0x80, 0x43, 0x04, 0x01]).unwrap();
+        assert_eq!(ctx.load(MMIO + 4, 1), 0x80);
+        assert_eq!(ctx.rflags, RFLAGS_OF | RFLAGS_SF | RFLAGS_AF);
+        // or dword [rbx], ecx clears CF and OF
+        ctx.rflags |= RFLAGS_CF | RFLAGS_OF | RFLAGS_DF;
+        ctx.regs[1] = 0x100;
+        ctx.store(MMIO, 4, 0x1);
+        ctx.run(&[0x09, 0x0b]).unwrap();
+        assert_eq!(ctx.load(MMIO, 4), 0x101);
+        assert_eq!(ctx.rflags, RFLAGS_DF);
+        // test byte [rbx], 0x80
+        ctx.run(&[0xf6, 0x03, 0x80]).unwrap();
+        assert_eq!(ctx.rflags, RFLAGS_DF | RFLAGS_ZF | RFLAGS_PF);
+        // xor and and write back
+        ctx.run(&[0x81, 0x33, 0xff, 0x00, 0x00, 0x00]).unwrap();
+        assert_eq!(ctx.load(MMIO, 4), 0x1fe);
+        ctx.run(&[0x83, 0x23, 0x0f]).unwrap();
+        assert_eq!(ctx.load(MMIO, 4), 0xe);
+    }
+
+    #[test]
+    fn bit_test_exchange_and_compare_exchange() {
+        let mut ctx = TestContext::default();
+        ctx.regs[3] = MMIO + 8; // rbx
+        ctx.store(MMIO, 8, 1 << 63);
+        ctx.store(MMIO + 8, 8, 1 << 35 | 2);
+
+        // bt dword [rbx], 33
+        ctx.run(&[0x0f, 0xba, 0x23, 0x21]).unwrap();
+        assert_eq!(ctx.rflags, RFLAGS_CF);
+        // bt [rbx], rcx with rcx = 35, then -1 for the top bit of the qword below
+        ctx.regs[1] = 35;
+        ctx.rflags = 0;
+        ctx.run(&[0x48, 0x0f, 0xa3, 0x0b]).unwrap();
+        assert_eq!(ctx.rflags, RFLAGS_CF);
+        ctx.regs[1] = u64::MAX;
+        ctx.rflags = 0;
+        ctx.run(&[0x48, 0x0f, 0xa3, 0x0b]).unwrap();
+        assert_eq!(ctx.rflags, RFLAGS_CF);
+        assert_eq!(ctx.mmio.last(), Some(&(MMIO, 8, None)));
+
+        // xchg [rbx], eax
+        ctx.regs[0] = 0xffff_ffff_0000_0007;
+        ctx.run(&[0x87, 0x03]).unwrap();
+        assert_eq!(ctx.regs[0], 2);
+        assert_eq!(ctx.load(MMIO + 8, 4), 7);
+
+        // cmpxchg [rbx], ecx: a mismatch loads eax and writes nothing
+        ctx.mmio.clear();
+        ctx.regs[1] = 9;
+        ctx.run(&[0x0f, 0xb1, 0x0b]).unwrap();
+        assert_eq!(ctx.regs[0], 7);
+        assert_eq!(ctx.rflags & RFLAGS_ZF, 0);
+        assert_eq!(ctx.mmio, [(MMIO + 8, 4, None)]);
+        // then a match stores ecx
+        ctx.run(&[0x0f, 0xb1, 0x0b]).unwrap();
+        assert_eq!(ctx.load(MMIO + 8, 4), 9);
+        assert_eq!(ctx.rflags & RFLAGS_ZF, RFLAGS_ZF);
+    }
+
+    #[test]
+    fn unsupported_instructions_change_nothing() {
+        let mut ctx = TestContext::default();
+        ctx.regs[3] = MMIO;
+        // inc dword [rbx]
+        assert_eq!(ctx.run(&[0xff, 0x03]), Err(HyperError::NotSupported));
+        // movsd xmm0, [rbx]
+        assert_eq!(
+            ctx.run(&[0xf2, 0x0f, 0x10, 0x03]),
+            Err(HyperError::NotSupported)
+        );
+        assert!(ctx.mmio.is_empty());
+        assert_eq!(
+            decode_instruction(&[0x0f], 64, 0).err(),
+            Some(HyperError::DecodeError)
+        );
+    }
+}
@@ -74,6 +74,8 @@ impl<H: HyperCraftHal> Drop for PhysFrame<H> {
     fn drop(&mut self) {
         if self.start_paddr > 0 {
             trace!("dropping physframe {:#018x}", self.start_paddr);
+            // Scrub it, the page may be handed to another VM next.
+            self.fill(0);
             H::dealloc_page(H::phys_to_virt(self.start_paddr));
             trace!("dropped physframe {:#018x}", self.start_paddr);
         }
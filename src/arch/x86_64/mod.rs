@@ -3,31 +3,48 @@ mod regs;
 
 // Codes in this module come mainly from https://github.com/rcore-os/RVM-Tutorial
 
+mod emulate;
 mod ept;
 mod memory;
 mod msr;
+mod page_walk;
 mod percpu;
 mod vmx;
 
 use crate::{
+    guest_memory::SavedRegions,
     hal::{PerCpuDevices, PerVmDevices},
-    vcpus, GuestPageTableTrait, GuestPhysAddr, GuestVirtAddr, HostPhysAddr, HostVirtAddr,
-    HyperCraftHal, HyperError, HyperResult, VmCpus,
+    handle_guest_fault,
+    snapshot::{
+        SECTION_MEMORY, SECTION_MMIO_DEVICES, SECTION_VCPU, SECTION_VCPU_DEVICES,
+        SECTION_VM_DEVICES,
+    },
+    vcpus, AtomicVmState, GuestAccess, GuestFault, GuestFaultPolicy, GuestMemory,
+    GuestMemoryRegion, GuestPageTableTrait, GuestPageWalk, GuestPhysAddr, GuestVirtAddr,
+    HostPhysAddr, HostVirtAddr, HyperCraftHal, HyperError, HyperResult, MmioBus, MmioOps, MsrBus,
+    PendingRead, PioBus, PioOps, SnapshotReader, SnapshotWriter, UnhandledMsrPolicy, VCpuGuard,
+    VCpuTrait, VirtMsrOps, VmCpus, VmExit, VmState, VmTrait,
 };
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use bit_set::BitSet;
 use core::marker::PhantomData;
-use iced_x86::{Decoder, DecoderOptions, Formatter, Instruction, MasmFormatter, OpKind};
+use iced_x86::{Decoder, DecoderOptions, Formatter, Instruction, MasmFormatter, Mnemonic, OpKind};
 use memory_addr::PhysAddr;
-use page_table::PagingIf;
+use page_table::{MappingFlags, PagingIf};
+use spin::Mutex;
 #[cfg(feature = "type1_5")]
 pub use vmx::LinuxContext;
 use x86::current;
 use x86_64::registers::debug;
 
 const VM_EXIT_INSTR_LEN_VMCALL: u8 = 3;
+/// Iterations of a `rep ins` or `rep outs` emulated per vm-exit, the guest executes the
+/// instruction again for the rest so that interrupts are not held off.
+const MAX_STRING_IO_ITERATIONS: usize = 4096;
+/// `RFLAGS.DF`, string instructions move backwards when set.
+const RFLAGS_DF: usize = 1 << 10;
 
 /// Initialize the hypervisor runtime.
 pub fn init_hv_runtime() {
@@ -36,10 +53,11 @@ pub fn init_hv_runtime() {
     }
 }
 
+pub use emulate::{decode_instruction, emulate_instruction, EmulatorContext};
 /// Nested page table define.
 pub use ept::ExtendedPageTable as NestedPageTable;
 
-pub use ept::GuestPageWalkInfo;
+pub use page_walk::GuestPageWalkInfo;
 pub use percpu::PerCpu;
 /// VCpu define.
 pub use vmx::VmxVcpu as VCpu;
@@ -56,54 +74,373 @@ const PAGE_SIZE: usize = 0x1000;
 
 /// VM define.
 pub struct VM<H: HyperCraftHal, PD: PerCpuDevices<H>, VD: PerVmDevices<H>, G: GuestPageTableTrait> {
-    vcpus: VmCpus<H, PD>,
-    vcpu_bond: BitSet,
-    device: VD,
-    vm_id: u32,
+    vcpus: VmCpus<H>,
+    vcpu_devices: Vec<Mutex<Option<PD>>>,
+    vcpu_bond: Mutex<BitSet>,
+    device: Mutex<VD>,
+    mmio_bus: MmioBus,
+    pio_bus: PioBus,
+    /// Let the guest access the ports of no registered device directly.
+    pio_passthrough: bool,
+    msr_bus: MsrBus,
+    msr_policy: UnhandledMsrPolicy,
+    vm_id: usize,
+    fault_policy: GuestFaultPolicy,
+    state: AtomicVmState,
+    /// vCPUs to reset the next time they run, when their VMCS is loaded
+    reset_pending: Mutex<BitSet>,
+    /// vCPUs to flush the EPT translations of the next time they run, after the EPT changed
+    invept_pending: Mutex<BitSet>,
+    memory: GuestMemory<H>,
     /// EPT
-    pub ept: Arc<G>,
+    pub ept: Arc<Mutex<G>>,
 }
 
 impl<H: HyperCraftHal, PD: PerCpuDevices<H>, VD: PerVmDevices<H>, G: GuestPageTableTrait>
-    VM<H, PD, VD, G>
+    VmTrait<H, G> for VM<H, PD, VD, G>
 {
-    /// Create a new [`VM`].
-    pub fn new(vcpus: VmCpus<H, PD>, ept: Arc<G>, vm_id: u32) -> Self {
-        Self {
+    /// Create a new [`VM`], along with the per-cpu devices of every vCPU in `vcpus`.
+    fn new(mut vcpus: VmCpus<H>, ept: G, vm_id: usize) -> HyperResult<Self> {
+        let num_vcpus = vcpus.capacity();
+        let mut vcpu_devices = Vec::with_capacity(num_vcpus);
+        for vcpu_id in 0..num_vcpus {
+            vcpu_devices.push(Mutex::new(match vcpus.get_vcpu(vcpu_id) {
+                Ok(vcpu) => Some(PD::new(vcpu)?),
+                Err(_) => None,
+            }));
+        }
+        Ok(Self {
             vcpus,
-            vcpu_bond: BitSet::new(),
-            device: VD::new(vm_id).unwrap(),
+            vcpu_devices,
+            vcpu_bond: Mutex::new(BitSet::with_capacity(num_vcpus)),
+            device: Mutex::new(VD::new(vm_id as u32)?),
+            mmio_bus: MmioBus::new(),
+            pio_bus: PioBus::new(),
+            pio_passthrough: false,
+            msr_bus: MsrBus::new(),
+            msr_policy: UnhandledMsrPolicy::default(),
             vm_id,
-            ept,
+            fault_policy: GuestFaultPolicy::default(),
+            state: AtomicVmState::new(VmState::Created),
+            reset_pending: Mutex::new(BitSet::with_capacity(num_vcpus)),
+            invept_pending: Mutex::new(BitSet::with_capacity(num_vcpus)),
+            memory: GuestMemory::new(),
+            ept: Arc::new(Mutex::new(ept)),
+        })
+    }
+
+    fn vm_id(&self) -> usize {
+        self.vm_id
+    }
+
+    /// Initialize a [`VCpu`] and point it at the EPT of this [`VM`].
+    ///
+    /// The vCPU must have been bound by [`VM::bind_vcpu`].
+    fn init_vcpu(&self, vcpu_id: usize, entry: GuestPhysAddr, boot_arg: usize) -> HyperResult {
+        if self.state.is_destroyed() || !self.vcpu_bond.lock().contains(vcpu_id) {
+            return Err(HyperError::BadState);
         }
+        let mut vcpu = self.vcpus.lock_vcpu(vcpu_id)?;
+        vcpu.init(entry, boot_arg)?;
+        vmx::set_ept_pointer(self.ept.lock().token())
     }
 
-    /// Bind the specified [`VCpu`] to current physical processor.
-    pub fn bind_vcpu(&mut self, vcpu_id: usize) -> HyperResult<(&mut VCpu<H>, &mut PD)> {
-        if self.vcpu_bond.contains(vcpu_id) {
-            Err(HyperError::InvalidParam)
-        } else {
-            match self.vcpus.get_vcpu_and_device(vcpu_id) {
-                Ok((vcpu, device)) => {
-                    self.vcpu_bond.insert(vcpu_id);
+    /// Run a specified [`VCpu`] on current logical vcpu.
+    fn run_vcpu(&self, vcpu_id: usize) -> HyperResult<VmExit> {
+        self.state.enter_guest()?;
+        let exit = self.run_until_exit(vcpu_id)?;
+        if matches!(exit, VmExit::Shutdown) {
+            self.state.store(VmState::Shutdown);
+        }
+        Ok(exit)
+    }
+
+    fn vcpu(&mut self, vcpu_id: usize) -> HyperResult<&mut VCpu<H>> {
+        self.vcpus.get_vcpu(vcpu_id)
+    }
+
+    fn lock_vcpu(&self, vcpu_id: usize) -> HyperResult<VCpuGuard<'_, VCpu<H>>> {
+        self.vcpus.lock_vcpu(vcpu_id)
+    }
+
+    /// Also creates the per-cpu devices of the [`VCpu`], and intercepts the ports and MSRs of
+    /// the registered [`PioOps`] and [`VirtMsrOps`] devices.
+    fn add_vcpu(&mut self, mut vcpu: VCpu<H>) -> HyperResult {
+        let vcpu_id = vcpu.vcpu_id();
+        if self.state.is_destroyed() || vcpu_id >= self.vcpus.capacity() {
+            return Err(HyperError::BadState);
+        }
+        vcpu.program_io_bitmap(self.pio_bus.ranges(), self.pio_passthrough)?;
+        vcpu.program_msr_bitmap(self.msr_bus.ranges(), self.intercepts_all_msrs())?;
+        let vcpu_device = PD::new(&vcpu)?;
+        self.vcpus.add_vcpu(vcpu)?;
+        *self.vcpu_devices[vcpu_id].get_mut() = Some(vcpu_device);
+        Ok(())
+    }
+
+    /// The [`VCpu`] must have been unbound by [`VM::unbind_vcpu`]. Its per-cpu devices are
+    /// dropped.
+    fn remove_vcpu(&mut self, vcpu_id: usize) -> HyperResult<VCpu<H>> {
+        if self.vcpu_bond.get_mut().contains(vcpu_id) {
+            return Err(HyperError::BadState);
+        }
+        let vcpu = self.vcpus.remove_vcpu(vcpu_id)?;
+        *self.vcpu_devices[vcpu_id].get_mut() = None;
+        self.reset_pending.get_mut().remove(vcpu_id);
+        Ok(vcpu)
+    }
+
+    fn register_mmio_device(&mut self, device: Arc<Mutex<dyn MmioOps>>) -> HyperResult {
+        self.mmio_bus.register(device)
+    }
+
+    fn unregister_mmio_device(&mut self, base: u64) -> HyperResult<Arc<Mutex<dyn MmioOps>>> {
+        self.mmio_bus.unregister(base)
+    }
+
+    fn set_fault_policy(&mut self, policy: GuestFaultPolicy) {
+        self.fault_policy = policy;
+    }
+
+    fn state(&self) -> VmState {
+        self.state.load()
+    }
+
+    fn pause(&self) -> HyperResult {
+        self.state.pause()
+    }
+
+    fn resume(&self) -> HyperResult {
+        self.state.resume()
+    }
+
+    /// The VMCS of a vCPU can only be written while it is loaded, so each vCPU is reset the
+    /// next time it runs.
+    fn reset(&mut self) -> HyperResult {
+        self.state.reset()?;
+        for vcpu_id in 0..self.vcpus.capacity() {
+            if self.vcpus.get_vcpu(vcpu_id).is_ok() {
+                self.reset_pending.get_mut().insert(vcpu_id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails with [`HyperError::BadState`] while the EPT is shared.
+    fn destroy(&mut self) -> HyperResult {
+        if self.state.is_destroyed() {
+            return Err(HyperError::BadState);
+        }
+        let ept = ept_mut(&mut self.ept)?;
+        self.state.store(VmState::Destroyed);
+        self.vcpu_bond.get_mut().clear();
+        self.reset_pending.get_mut().clear();
+        self.invept_pending.get_mut().clear();
+        self.vcpu_devices
+            .iter_mut()
+            .for_each(|device| *device.get_mut() = None);
+        // Dropping a `VmxVcpu` clears its VMCS and frees it along with the I/O and MSR bitmaps.
+        self.vcpus.clear();
+        self.memory.release(ept);
+        Ok(())
+    }
+
+    fn alloc_memory(
+        &mut self,
+        gpa: GuestPhysAddr,
+        size: usize,
+        flags: MappingFlags,
+    ) -> HyperResult<HostVirtAddr> {
+        if self.state.is_destroyed() {
+            return Err(HyperError::BadState);
+        }
+        let ept = ept_mut(&mut self.ept)?;
+        self.memory.alloc_region(ept, gpa, size, flags)
+    }
+
+    /// Every [`VCpu`] must be unbound, each one is bound to the current processor in turn to
+    /// read its VMCS.
+    fn save_snapshot(&mut self) -> HyperResult<Vec<u8>> {
+        let mut w = SnapshotWriter::new();
+        self.save_vcpu_state(&mut w)?;
+        self.save_device_state(&mut w)?;
+        self.memory.save(&mut w)?;
+        Ok(w.into_bytes())
+    }
+
+    /// Every [`VCpu`] must be unbound. Fails with [`HyperError::BadState`] while the EPT is
+    /// shared.
+    fn restore_snapshot(&mut self, data: &[u8]) -> HyperResult {
+        self.state.ensure_stopped()?;
+        if !self.vcpu_bond.get_mut().is_empty() {
+            return Err(HyperError::BadState);
+        }
+        // check the snapshot before the guest memory is gone
+        self.check_snapshot(data)?;
+        let ept = ept_mut(&mut self.ept)?;
+        self.memory.release(ept);
+        self.load_state(data)
+    }
+
+    /// Every [`VCpu`] must be unbound, each one is bound to the current processor in turn to
+    /// read its VMCS.
+    fn save_vcpu_state(&mut self, w: &mut SnapshotWriter) -> HyperResult {
+        self.state.ensure_stopped()?;
+        if !self.vcpu_bond.get_mut().is_empty() {
+            return Err(HyperError::BadState);
+        }
+        for vcpu_id in 0..self.vcpus.capacity() {
+            let Ok(vcpu) = self.vcpus.get_vcpu(vcpu_id) else {
+                continue;
+            };
+            vcpu.bind_to_current_processor()?;
+            let result = w.section(SECTION_VCPU, |w| {
+                w.put_usize(vcpu_id);
+                vcpu.save_state(w)
+            });
+            vcpu.unbind_from_current_processor()?;
+            result?;
+        }
+        Ok(())
+    }
+
+    fn save_device_state(&mut self, w: &mut SnapshotWriter) -> HyperResult {
+        self.state.ensure_stopped()?;
+        for vcpu_id in 0..self.vcpus.capacity() {
+            let Ok((_, vcpu_device)) =
+                vcpu_and_device(&mut self.vcpus, &mut self.vcpu_devices, vcpu_id)
+            else {
+                continue;
+            };
+            w.section(SECTION_VCPU_DEVICES, |w| {
+                w.put_usize(vcpu_id);
+                vcpu_device.save_state(w)
+            })?;
+        }
+        w.section(SECTION_VM_DEVICES, |w| self.device.get_mut().save_state(w))?;
+        w.section(SECTION_MMIO_DEVICES, |w| self.mmio_bus.save_state(w))
+    }
+
+    /// Every [`VCpu`] must be unbound. Fails with [`HyperError::BadState`] while the EPT is
+    /// shared.
+    fn load_state(&mut self, data: &[u8]) -> HyperResult {
+        self.state.ensure_stopped()?;
+        if !self.vcpu_bond.get_mut().is_empty() {
+            return Err(HyperError::BadState);
+        }
+        let mut r = SnapshotReader::new(data)?;
+        let ept = ept_mut(&mut self.ept)?;
+        while let Some((tag, mut section)) = r.next_section()? {
+            match tag {
+                SECTION_VCPU => {
+                    let vcpu_id = section.get_usize()?;
+                    let vcpu = self.vcpus.get_vcpu(vcpu_id)?;
                     vcpu.bind_to_current_processor()?;
-                    Ok((vcpu, device))
+                    let result = vcpu.load_state(&mut section);
+                    vcpu.unbind_from_current_processor()?;
+                    result?;
+                    // the restored state replaces any reset still to be done
+                    self.reset_pending.get_mut().remove(vcpu_id);
                 }
-                e @ Err(_) => e,
+                SECTION_VCPU_DEVICES => {
+                    let vcpu_id = section.get_usize()?;
+                    vcpu_and_device(&mut self.vcpus, &mut self.vcpu_devices, vcpu_id)?
+                        .1
+                        .load_state(&mut section)?;
+                }
+                SECTION_VM_DEVICES => self.device.get_mut().load_state(&mut section)?,
+                SECTION_MMIO_DEVICES => self.mmio_bus.load_state(&mut section)?,
+                SECTION_MEMORY => self.memory.load_region(ept, &mut section)?,
+                // written by a later version, skip it
+                _ => {}
             }
         }
+        self.state.store(VmState::Paused);
+        Ok(())
+    }
+
+    fn memory_regions(&self) -> &[GuestMemoryRegion] {
+        self.memory.regions()
+    }
+
+    /// Uses Page-Modification Logging, or on CPUs without it maps guest RAM read-only in the
+    /// EPT until the guest writes each page. Fails with [`HyperError::BadState`] while the EPT
+    /// is shared.
+    fn enable_dirty_log(&mut self) -> HyperResult {
+        if self.state.is_destroyed() {
+            return Err(HyperError::BadState);
+        }
+        let ept = ept_mut(&mut self.ept)?;
+        self.memory.start_dirty_log(ept, !vmx::has_pml_support())?;
+        self.flush_ept_later();
+        Ok(())
     }
 
+    /// Fails with [`HyperError::BadState`] while the EPT is shared.
+    fn disable_dirty_log(&mut self) -> HyperResult {
+        let ept = ept_mut(&mut self.ept)?;
+        self.memory.stop_dirty_log(ept)?;
+        self.flush_ept_later();
+        Ok(())
+    }
+
+    /// Fails with [`HyperError::BadState`] while the EPT is shared.
+    fn get_dirty_log(&mut self, gpa: GuestPhysAddr, size: usize) -> HyperResult<Vec<u64>> {
+        let ept = ept_mut(&mut self.ept)?;
+        let dirty = self.memory.take_dirty_log(ept, gpa, size)?;
+        self.flush_ept_later();
+        Ok(dirty)
+    }
+}
+
+impl<H: HyperCraftHal, PD: PerCpuDevices<H>, VD: PerVmDevices<H>, G: GuestPageTableTrait>
+    VM<H, PD, VD, G>
+{
     #[allow(unreachable_code)]
-    /// Run a specified [`VCpu`] on current logical vcpu.
-    pub fn run_vcpu(&mut self, vcpu_id: usize) -> HyperResult {
-        let (vcpu, vcpu_device) = self.vcpus.get_vcpu_and_device(vcpu_id).unwrap();
+    /// Run the vCPU until a vm-exit that has to go back to the caller.
+    fn run_until_exit(&self, vcpu_id: usize) -> HyperResult<VmExit> {
+        let (mut vcpu, mut vcpu_device) = self.lock_vcpu_and_device(vcpu_id)?;
+        let (vcpu, vcpu_device) = (&mut *vcpu, &mut *vcpu_device);
+        if self.reset_pending.lock().remove(vcpu_id) {
+            vcpu.reset()?;
+        }
+        if vcpu.has_pending_read() {
+            return Err(HyperError::BadState);
+        }
+        if self.invept_pending.lock().remove(vcpu_id) {
+            vmx::set_ept_pointer(self.ept.lock().token())?;
+        }
+        let pml = self.memory.is_dirty_logging() && !self.memory.is_write_protecting();
+        if vcpu.pml_enabled() != pml {
+            vcpu.set_pml(pml)?;
+        }
 
         loop {
-            if let Some(exit_info) = vcpu.run() {
+            if let Some(exit) = self.state.stop_exit() {
+                return Ok(exit);
+            }
+            let exit_info = vcpu.run();
+            vcpu.drain_pml(|gpa| {
+                self.memory.mark_dirty(gpa);
+            })?;
+            if let Some(exit_info) = exit_info {
                 // we need to handle vm-exit this by ourselves
 
-                if exit_info.exit_reason == VmxExitReason::VMCALL {
+                if exit_info.entry_failure {
+                    error!("VM entry failed: {:#x?}", exit_info);
+                    return Ok(VmExit::InternalError(HyperError::BadState));
+                } else if matches!(
+                    exit_info.exit_reason,
+                    VmxExitReason::CR_ACCESS | VmxExitReason::XSETBV
+                ) {
+                    // refused by `VmxVcpu`
+                    let fault = GuestFault::GeneralProtection {
+                        pc: exit_info.guest_rip,
+                    };
+                    if let Some(exit) = handle_guest_fault(self.fault_policy, vcpu, fault)? {
+                        return Ok(exit);
+                    }
+                } else if exit_info.exit_reason == VmxExitReason::VMCALL {
                     let regs = vcpu.regs();
                     trace!("{:#x?}", regs);
                     let id = regs.rax as u32;
@@ -111,127 +448,547 @@ impl<H: HyperCraftHal, PD: PerCpuDevices<H>, VD: PerVmDevices<H>, G: GuestPageTa
 
                     match vcpu_device.hypercall_handler(vcpu, id, args) {
                         Ok(result) => vcpu.regs_mut().rax = result as u64,
-                        Err(e) => panic!("Hypercall failed: {e:?}, hypercall id: {id:#x}, args: {args:#x?}, vcpu: {vcpu:#x?}"),
+                        Err(HyperError::NotSupported) => {
+                            return self.translate_exit(vcpu, exit_info)
+                        }
+                        Err(e) => return Ok(VmExit::InternalError(e)),
                     }
 
                     vcpu.advance_rip(VM_EXIT_INSTR_LEN_VMCALL)?;
                 } else if exit_info.exit_reason == VmxExitReason::EXCEPTION_NMI {
                     match vcpu_device.nmi_handler(vcpu) {
                         Ok(result) => vcpu.regs_mut().rax = result as u64,
-                        Err(e) => panic!("nmi_handler failed: {e:?}"),
+                        Err(e) => return Ok(VmExit::InternalError(e)),
+                    }
+                } else if exit_info.exit_reason == VmxExitReason::PML_FULL {
+                    // the log was drained above, the guest retries the write
+                } else if exit_info.exit_reason == VmxExitReason::EPT_VIOLATION
+                    && self.handle_dirty_write(vcpu)?
+                {
+                    // the guest retries the write
+                } else if exit_info.exit_reason == VmxExitReason::IO_INSTRUCTION
+                    && self.pio_bus.contains(vcpu.io_exit_info()?.port)
+                {
+                    if let Some(exit) = self.handle_pio(vcpu, &exit_info)? {
+                        return Ok(exit);
+                    }
+                } else if is_msr_exit(&exit_info) && self.msr_bus.contains(vcpu.regs().rcx as u32) {
+                    if let Some(exit) = self.handle_msr(vcpu, &exit_info)? {
+                        return Ok(exit);
                     }
                 } else {
                     let result = vcpu_device.vmexit_handler(vcpu, &exit_info).or_else(|| {
                         let guest_rip = exit_info.guest_rip;
                         let length = exit_info.exit_instruction_length;
-                        let instr = Self::decode_instr(self.ept.clone(), vcpu, guest_rip, length)
-                            .expect("decode instruction failed");
-                        self.device.vmexit_handler(vcpu, &exit_info, Some(instr))
+                        let instr = Self::decode_instr(
+                            self.ept.clone(),
+                            &self.memory,
+                            vcpu,
+                            guest_rip,
+                            length,
+                        )
+                        .ok();
+                        self.device.lock().vmexit_handler(vcpu, &exit_info, instr)
                     });
 
                     match result {
-                        Some(result) => {
-                            if result.is_err() {
-                                panic!(
-                                    "VM failed to handle a vm-exit: {:?}, error {:?}, vcpu: {:#x?}",
-                                    exit_info.exit_reason,
-                                    result.unwrap_err(),
-                                    vcpu
-                                );
+                        Some(Ok(())) => {}
+                        Some(Err(e)) => return Ok(VmExit::InternalError(e)),
+                        // an MSR no device handles
+                        None if is_msr_exit(&exit_info) => {
+                            if let Some(exit) = self.handle_msr(vcpu, &exit_info)? {
+                                return Ok(exit);
                             }
                         }
-                        None => {
-                            if exit_info.exit_reason == VmxExitReason::IO_INSTRUCTION {
-                                let io_info = vcpu.io_exit_info().unwrap();
-                                panic!(
-                                    "nobody wants to handle this vm-exit: {:#x?}, io-info: {:?}",
-                                    exit_info, io_info
-                                );
-                            } else {
-                                panic!(
-                                    "nobody wants to handle this vm-exit: {:#x?}, vcpu: {:#x?}",
-                                    exit_info, vcpu
-                                );
+                        // an access to a device of the MMIO bus
+                        None if exit_info.exit_reason == VmxExitReason::EPT_VIOLATION
+                            && self.mmio_bus.contains(
+                                vcpu.nested_page_fault_info()?.fault_guest_paddr as u64,
+                            ) =>
+                        {
+                            if let Some(exit) = self.emulate_mmio(vcpu, exit_info)? {
+                                return Ok(exit);
                             }
                         }
+                        // nobody wants to handle this vm-exit, leave it to the caller
+                        None => return self.translate_exit(vcpu, exit_info),
                     }
                 }
             }
 
             vcpu_device.check_events(vcpu)?;
         }
+    }
+
+    /// Maps the page of an EPT violation writable again if the guest wrote it while it was
+    /// write-protected for dirty logging, returning whether it was. The violation flushed the
+    /// stale translation of this vCPU, the other ones fault on it again.
+    fn handle_dirty_write(&self, vcpu: &VCpu<H>) -> HyperResult<bool> {
+        let fault_info = vcpu.nested_page_fault_info()?;
+        if !fault_info.access_flags.contains(MappingFlags::WRITE) {
+            return Ok(false);
+        }
+        self.memory
+            .handle_write_fault(&mut *self.ept.lock(), fault_info.fault_guest_paddr)
+    }
 
+    /// Emulate an `in`, `out`, `ins` or `outs` on a port of a registered [`PioOps`] device.
+    /// A string instruction that touches an unmapped guest page takes the page fault, the
+    /// iterations done before it are kept.
+    fn handle_pio(
+        &self,
+        vcpu: &mut VCpu<H>,
+        exit_info: &VmxExitInfo,
+    ) -> HyperResult<Option<VmExit>> {
+        let io_info = vcpu.io_exit_info()?;
+        let result = if io_info.is_string {
+            self.emulate_string_io(vcpu, &io_info)
+        } else {
+            self.emulate_io(vcpu, &io_info).map(|()| true)
+        };
+        match result {
+            Ok(true) => vcpu.advance_rip(exit_info.exit_instruction_length as u8)?,
+            // a `rep` prefix with iterations left, the guest executes it again
+            Ok(false) => {}
+            Err(HyperError::GuestPageFault(fault)) if !fault.physical => {
+                let fault = GuestFault::PageFault {
+                    pc: exit_info.guest_rip,
+                    fault,
+                };
+                return handle_guest_fault(self.fault_policy, vcpu, fault);
+            }
+            Err(e) => return Ok(Some(VmExit::InternalError(e))),
+        }
+        Ok(None)
+    }
+
+    /// `in` and `out`, which move `AL`, `AX` or `EAX`.
+    fn emulate_io(&self, vcpu: &mut VCpu<H>, io_info: &vmx::VmxIoExitInfo) -> HyperResult {
+        let (port, size) = (io_info.port, io_info.access_size);
+        if io_info.is_in {
+            let data = self
+                .pio_bus
+                .read(port, size)
+                .ok_or(HyperError::NotFound)??;
+            let pending = PendingRead {
+                reg_size: size,
+                merge: size < 4,
+                ..PendingRead::new(0, size)
+            };
+            let rax = &mut vcpu.regs_mut().rax;
+            *rax = pending.apply(*rax as usize, data as u64) as u64;
+            Ok(())
+        } else {
+            let mask = u32::MAX >> (32 - 8 * size as u32);
+            let data = vcpu.regs().rax as u32 & mask;
+            self.pio_bus
+                .write(port, size, data)
+                .ok_or(HyperError::NotFound)?
+        }
+    }
+
+    /// `ins` and `outs`, with or without a `rep` prefix, moving between the port and guest
+    /// memory at `RDI` or `RSI`. Returns whether the instruction is done.
+    fn emulate_string_io(
+        &self,
+        vcpu: &mut VCpu<H>,
+        io_info: &vmx::VmxIoExitInfo,
+    ) -> HyperResult<bool> {
+        let (port, size) = (io_info.port, io_info.access_size);
+        let addr_mask = usize::MAX >> (usize::BITS - 8 * io_info.address_size as u32);
+        let count = if io_info.is_repeat {
+            vcpu.regs().rcx as usize & addr_mask
+        } else {
+            1
+        };
+        let step = if vcpu.rflags() & RFLAGS_DF != 0 {
+            (size as usize).wrapping_neg()
+        } else {
+            size as usize
+        };
+        let index = if io_info.is_in {
+            vcpu.regs().rdi
+        } else {
+            vcpu.regs().rsi
+        } as usize;
+        // the offset wraps around within the address size, the segment base stays
+        let seg_base = io_info.linear_addr.wrapping_sub(index & addr_mask);
+        let walk = vcpu.get_ptw_info();
+
+        let mut done = 0;
+        let mut result = Ok(());
+        while done < count.min(MAX_STRING_IO_ITERATIONS) {
+            let offset = index.wrapping_add(step.wrapping_mul(done)) & addr_mask;
+            let gva = seg_base.wrapping_add(offset);
+            let mut buf = [0; 4];
+            let buf = &mut buf[..size as usize];
+            result = if io_info.is_in {
+                self.pio_bus
+                    .read(port, size)
+                    .ok_or(HyperError::NotFound)
+                    .and_then(|data| data)
+                    .and_then(|data| {
+                        buf.copy_from_slice(&data.to_le_bytes()[..size as usize]);
+                        self.memory.write_gva(&*self.ept.lock(), &walk, gva, buf)
+                    })
+            } else {
+                self.memory
+                    .read_gva(&*self.ept.lock(), &walk, gva, buf)
+                    .and_then(|()| {
+                        let mut data = [0; 4];
+                        data[..size as usize].copy_from_slice(buf);
+                        let data = u32::from_le_bytes(data);
+                        self.pio_bus
+                            .write(port, size, data)
+                            .ok_or(HyperError::NotFound)?
+                    })
+            };
+            if result.is_err() {
+                break;
+            }
+            done += 1;
+        }
+
+        // keep the progress made, even when an iteration faulted
+        let advance = |reg: u64, by: usize| -> u64 {
+            let value = (reg as usize).wrapping_add(by) & addr_mask;
+            if io_info.address_size == 2 {
+                // 16-bit addressing leaves the upper bits of the register alone
+                (reg & !0xffff) | value as u64
+            } else {
+                value as u64
+            }
+        };
+        let regs = vcpu.regs_mut();
+        if io_info.is_in {
+            regs.rdi = advance(regs.rdi, step.wrapping_mul(done));
+        } else {
+            regs.rsi = advance(regs.rsi, step.wrapping_mul(done));
+        }
+        if io_info.is_repeat {
+            regs.rcx = advance(regs.rcx, done.wrapping_neg());
+        }
+        result.map(|()| done == count)
+    }
+
+    /// Emulate the instruction that accessed a device of the MMIO bus. Instructions the
+    /// emulator does not know are left to the caller.
+    fn emulate_mmio(
+        &self,
+        vcpu: &mut VCpu<H>,
+        exit_info: VmxExitInfo,
+    ) -> HyperResult<Option<VmExit>> {
+        let walk = vcpu.get_ptw_info();
+        let result = Self::get_gva_content_bytes(
+            self.ept.clone(),
+            &self.memory,
+            exit_info.guest_rip,
+            exit_info.exit_instruction_length,
+            vcpu,
+        )
+        .and_then(|bytes| {
+            decode_instruction(&bytes, vcpu.code_bitness(), exit_info.guest_rip as u64)
+        })
+        .and_then(|instr| {
+            let ept = self.ept.lock();
+            let mut ctx = MmioEmulation {
+                vcpu: &mut *vcpu,
+                memory: &self.memory,
+                ept: &*ept,
+                mmio_bus: &self.mmio_bus,
+                walk,
+            };
+            Ok((emulate_instruction(&mut ctx, &instr)?, instr.len()))
+        });
+        match result {
+            Ok((true, len)) => vcpu.advance_rip(len as u8)?,
+            // a `rep` prefix with iterations left, the guest executes it again
+            Ok((false, _)) => {}
+            Err(HyperError::GuestPageFault(fault)) if !fault.physical => {
+                let fault = GuestFault::PageFault {
+                    pc: exit_info.guest_rip,
+                    fault,
+                };
+                return handle_guest_fault(self.fault_policy, vcpu, fault);
+            }
+            Err(HyperError::NotSupported | HyperError::DecodeError) => {
+                return Ok(Some(VmExit::ArchSpecific(exit_info)))
+            }
+            Err(e) => return Ok(Some(VmExit::InternalError(e))),
+        }
+        Ok(None)
+    }
+
+    /// Emulate an `rdmsr` or `wrmsr`, which take the MSR in `ECX` and its value in `EDX:EAX`,
+    /// with the [`VirtMsrOps`] device handling the MSR or according to the
+    /// [`UnhandledMsrPolicy`].
+    fn handle_msr(
+        &self,
+        vcpu: &mut VCpu<H>,
+        exit_info: &VmxExitInfo,
+    ) -> HyperResult<Option<VmExit>> {
+        let msr = vcpu.regs().rcx as u32;
+        let result = if exit_info.exit_reason == VmxExitReason::MSR_READ {
+            self.read_msr(msr).map(|value| {
+                value.map(|value| {
+                    let regs = vcpu.regs_mut();
+                    regs.rax = value & 0xffff_ffff;
+                    regs.rdx = value >> 32;
+                })
+            })
+        } else {
+            let regs = vcpu.regs();
+            let value = (regs.rdx << 32) | (regs.rax & 0xffff_ffff);
+            self.write_msr(msr, value)
+        };
+        match result {
+            Ok(Some(())) => vcpu.advance_rip(exit_info.exit_instruction_length as u8)?,
+            Ok(None) => {
+                let fault = GuestFault::GeneralProtection {
+                    pc: exit_info.guest_rip,
+                };
+                return handle_guest_fault(self.fault_policy, vcpu, fault);
+            }
+            Err(e) => return Ok(Some(VmExit::InternalError(e))),
+        }
+        Ok(None)
+    }
+
+    /// The value of `msr`, or `None` if the guest takes a `#GP` for reading it.
+    fn read_msr(&self, msr: u32) -> HyperResult<Option<u64>> {
+        if let Some(result) = self.msr_bus.read(msr) {
+            return result.map(Some);
+        }
+        Ok(match self.msr_policy {
+            UnhandledMsrPolicy::InjectGp | UnhandledMsrPolicy::Passthrough => None,
+            UnhandledMsrPolicy::IgnoreWrites => Some(0),
+        })
+    }
+
+    /// Write `value` to `msr`, returning `None` if the guest takes a `#GP` for it.
+    fn write_msr(&self, msr: u32, value: u64) -> HyperResult<Option<()>> {
+        if let Some(result) = self.msr_bus.write(msr, value) {
+            return result.map(Some);
+        }
+        Ok(match self.msr_policy {
+            UnhandledMsrPolicy::InjectGp | UnhandledMsrPolicy::Passthrough => None,
+            UnhandledMsrPolicy::IgnoreWrites => Some(()),
+        })
+    }
+
+    /// Register a [`VirtMsrOps`] device and intercept its MSRs on every [`VCpu`]. Fails with
+    /// [`HyperError::InvalidParam`] if its MSRs overlap a registered device.
+    pub fn register_msr_device(&mut self, device: Arc<Mutex<dyn VirtMsrOps>>) -> HyperResult {
+        self.msr_bus.register(device)?;
+        self.program_msr_bitmaps()
+    }
+
+    /// Unregister the [`VirtMsrOps`] device whose MSRs start at `base` and give it back. Its
+    /// MSRs are then left to the [`UnhandledMsrPolicy`].
+    pub fn unregister_msr_device(&mut self, base: u32) -> HyperResult<Arc<Mutex<dyn VirtMsrOps>>> {
+        let device = self.msr_bus.unregister(base)?;
+        self.program_msr_bitmaps()?;
+        Ok(device)
+    }
+
+    /// Set what the guest gets for the MSRs no [`VirtMsrOps`] device handles.
+    pub fn set_msr_policy(&mut self, policy: UnhandledMsrPolicy) -> HyperResult {
+        self.msr_policy = policy;
+        self.program_msr_bitmaps()
+    }
+
+    /// Whether the MSRs no device handles must be intercepted to apply the policy.
+    fn intercepts_all_msrs(&self) -> bool {
+        self.msr_policy != UnhandledMsrPolicy::Passthrough
+    }
+
+    /// Make the MSR bitmap of every [`VCpu`] match the registered [`VirtMsrOps`] devices.
+    fn program_msr_bitmaps(&mut self) -> HyperResult {
+        let intercept_all = self.intercepts_all_msrs();
+        for vcpu_id in 0..self.vcpus.capacity() {
+            if let Ok(vcpu) = self.vcpus.get_vcpu(vcpu_id) {
+                vcpu.program_msr_bitmap(self.msr_bus.ranges(), intercept_all)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Register a [`PioOps`] device and intercept its ports on every [`VCpu`]. Fails with
+    /// [`HyperError::InvalidParam`] if its ports overlap a registered device.
+    pub fn register_pio_device(&mut self, device: Arc<Mutex<dyn PioOps>>) -> HyperResult {
+        self.pio_bus.register(device)?;
+        self.program_io_bitmaps()
+    }
+
+    /// Unregister the [`PioOps`] device whose ports start at `base` and give it back. Its
+    /// ports go back to the default interception.
+    pub fn unregister_pio_device(&mut self, base: u16) -> HyperResult<Arc<Mutex<dyn PioOps>>> {
+        let device = self.pio_bus.unregister(base)?;
+        self.program_io_bitmaps()?;
+        Ok(device)
+    }
+
+    /// Let the guest access the ports of no registered device directly, instead of
+    /// intercepting the legacy devices the host does not hand to it.
+    pub fn set_pio_passthrough(&mut self, passthrough: bool) -> HyperResult {
+        self.pio_passthrough = passthrough;
+        self.program_io_bitmaps()
+    }
+
+    /// Make the I/O bitmap of every [`VCpu`] match the registered [`PioOps`] devices.
+    fn program_io_bitmaps(&mut self) -> HyperResult {
+        for vcpu_id in 0..self.vcpus.capacity() {
+            if let Ok(vcpu) = self.vcpus.get_vcpu(vcpu_id) {
+                vcpu.program_io_bitmap(self.pio_bus.ranges(), self.pio_passthrough)?;
+            }
+        }
         Ok(())
     }
 
+    /// Make every vCPU flush its EPT translations before it runs again, so the cleared dirty
+    /// flags of the EPT take effect.
+    fn flush_ept_later(&mut self) {
+        let invept_pending = self.invept_pending.get_mut();
+        (0..self.vcpus.capacity()).for_each(|vcpu_id| {
+            invept_pending.insert(vcpu_id);
+        });
+    }
+
+    /// Checks that the vCPU, MMIO device and memory sections of `data` are well-formed and can
+    /// be loaded into this VM. The device state is checked by the devices as they load it.
+    fn check_snapshot(&mut self, data: &[u8]) -> HyperResult {
+        let mut r = SnapshotReader::new(data)?;
+        let mut regions = SavedRegions::default();
+        while let Some((tag, mut section)) = r.next_section()? {
+            match tag {
+                SECTION_VCPU => {
+                    let vcpu_id = section.get_usize()?;
+                    self.vcpus.get_vcpu(vcpu_id)?.read_state(&mut section)?;
+                }
+                SECTION_VCPU_DEVICES => {
+                    let vcpu_id = section.get_usize()?;
+                    vcpu_and_device(&mut self.vcpus, &mut self.vcpu_devices, vcpu_id)?;
+                }
+                SECTION_MMIO_DEVICES => self.mmio_bus.check_state(&mut section)?,
+                SECTION_MEMORY => regions.check(&mut section)?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Bind the specified [`VCpu`] to current physical processor.
+    ///
+    /// The returned guards must be dropped before the vCPU can run.
+    pub fn bind_vcpu(
+        &self,
+        vcpu_id: usize,
+    ) -> HyperResult<(VCpuGuard<'_, VCpu<H>>, VCpuGuard<'_, PD>)> {
+        let mut vcpu_bond = self.vcpu_bond.lock();
+        if vcpu_bond.contains(vcpu_id) {
+            Err(HyperError::InvalidParam)
+        } else {
+            let (vcpu, device) = self.lock_vcpu_and_device(vcpu_id)?;
+            vcpu_bond.insert(vcpu_id);
+            vcpu.bind_to_current_processor()?;
+            Ok((vcpu, device))
+        }
+    }
+
     #[cfg(feature = "type1_5")]
-    #[allow(unreachable_code)]
-    /// Run a specified [`VCpu`] on current logical vcpu.
-    pub fn run_type15_vcpu(&mut self, vcpu_id: usize, linux: &LinuxContext) -> HyperResult {
-        let (vcpu, vcpu_device) = self.vcpus.get_vcpu_and_device(vcpu_id).unwrap();
+    /// Run a specified [`VCpu`] on current logical vcpu. Vm-exits nobody can handle fault
+    /// the guest according to the [`GuestFaultPolicy`], which is the only way this returns
+    /// besides a failure of the hypervisor itself.
+    pub fn run_type15_vcpu(&mut self, vcpu_id: usize, linux: &LinuxContext) -> HyperResult<VmExit> {
+        let (vcpu, vcpu_device) =
+            vcpu_and_device(&mut self.vcpus, &mut self.vcpu_devices, vcpu_id)?;
         loop {
-            if let Some(exit_info) = vcpu.run_type15(linux) {
-                if exit_info.exit_reason == VmxExitReason::VMCALL {
-                    let regs = vcpu.regs();
-                    let id = regs.rax as u32;
-                    let args = (regs.rdi as usize, regs.rsi as usize, regs.rdx as usize);
+            let Some(exit_info) = vcpu.run_type15(linux) else {
+                continue;
+            };
+            let fault = if exit_info.entry_failure {
+                error!("VM entry failed: {:#x?}", exit_info);
+                return Ok(VmExit::InternalError(HyperError::BadState));
+            } else if matches!(
+                exit_info.exit_reason,
+                VmxExitReason::CR_ACCESS | VmxExitReason::XSETBV
+            ) {
+                // refused by `VmxVcpu`
+                Some(GuestFault::GeneralProtection {
+                    pc: exit_info.guest_rip,
+                })
+            } else if exit_info.exit_reason == VmxExitReason::VMCALL {
+                let regs = vcpu.regs();
+                let id = regs.rax as u32;
+                let args = (regs.rdi as usize, regs.rsi as usize, regs.rdx as usize);
 
-                    trace!("{:#x?}", regs);
-                    match vcpu_device.hypercall_handler(vcpu, id, args) {
-                        Ok(result) => vcpu.regs_mut().rax = result as u64,
-                        Err(e) => panic!("Hypercall failed: {e:?}, hypercall id: {id:#x}, args: {args:#x?}, vcpu: {vcpu:#x?}"),
+                trace!("{:#x?}", regs);
+                match vcpu_device.hypercall_handler(vcpu, id, args) {
+                    Ok(result) => {
+                        vcpu.regs_mut().rax = result as u64;
+                        vcpu.advance_rip(VM_EXIT_INSTR_LEN_VMCALL)?;
+                        None
                     }
-
-                    vcpu.advance_rip(VM_EXIT_INSTR_LEN_VMCALL)?;
-                } else if exit_info.exit_reason == VmxExitReason::EXCEPTION_NMI {
-                    match vcpu_device.nmi_handler(vcpu) {
-                        Ok(result) => vcpu.regs_mut().rax = result as u64,
-                        Err(e) => panic!("nmi_handler failed: {e:?}"),
+                    Err(e) => {
+                        warn!("hypercall {id:#x} failed: {e:?}, args: {args:#x?}");
+                        // as if there were no hypervisor to call
+                        Some(GuestFault::IllegalInstruction {
+                            pc: exit_info.guest_rip,
+                        })
                     }
-                } else {
-                    let result = vcpu_device.vmexit_handler(vcpu, &exit_info).or_else(|| {
-                        let guest_rip = exit_info.guest_rip;
-                        let length = exit_info.exit_instruction_length;
-                        let instr = Self::decode_instr(self.ept.clone(), vcpu, guest_rip, length)
-                            .expect("decode instruction failed");
-                        self.device.vmexit_handler(vcpu, &exit_info, Some(instr))
-                    });
-                    debug!("this is result {:?}", result);
-                    match result {
-                        Some(result) => {
-                            if result.is_err() {
-                                panic!(
-                                    "VM failed to handle a vm-exit: {:#x?}, error {:?}, vcpu: {:#x?}",
-                                    exit_info.exit_reason,
-                                    result.unwrap_err(),
-                                    vcpu
-                                );
-                            }
-                        }
-                        None => {
-                            panic!(
-                                "nobody wants to handle this vm-exit: {:#x?}, vcpu: {:#x?}",
-                                exit_info, vcpu
-                            );
-                        }
+                }
+            } else if exit_info.exit_reason == VmxExitReason::EXCEPTION_NMI {
+                match vcpu_device.nmi_handler(vcpu) {
+                    Ok(result) => vcpu.regs_mut().rax = result as u64,
+                    // not caused by the guest, nothing to fault it with
+                    Err(e) => return Ok(VmExit::InternalError(e)),
+                }
+                None
+            } else {
+                let result = vcpu_device.vmexit_handler(vcpu, &exit_info).or_else(|| {
+                    let guest_rip = exit_info.guest_rip;
+                    let length = exit_info.exit_instruction_length;
+                    let instr =
+                        Self::decode_instr(self.ept.clone(), &self.memory, vcpu, guest_rip, length)
+                            .ok();
+                    self.device
+                        .get_mut()
+                        .vmexit_handler(vcpu, &exit_info, instr)
+                });
+                match result {
+                    Some(Ok(())) => None,
+                    Some(Err(e)) => {
+                        warn!(
+                            "VM failed to handle a vm-exit: {:#x?}, error {:?}",
+                            exit_info.exit_reason, e
+                        );
+                        Some(GuestFault::GeneralProtection {
+                            pc: exit_info.guest_rip,
+                        })
                     }
+                    None => {
+                        warn!("nobody wants to handle this vm-exit: {:#x?}", exit_info);
+                        Some(GuestFault::GeneralProtection {
+                            pc: exit_info.guest_rip,
+                        })
+                    }
+                }
+            };
+            if let Some(fault) = fault {
+                if let Some(exit) = handle_guest_fault(self.fault_policy, vcpu, fault)? {
+                    return Ok(exit);
                 }
-                // debug!("test decode instruction");
-                // let guest_rip = exit_info.guest_rip;
-                // let length = exit_info.exit_instruction_length;
-                // let instr = Self::decode_instr(self.ept.clone(), vcpu, guest_rip, length)?;
-                // debug!("this is instr {:?}", instr);
             }
-            // vcpu_device.check_events(vcpu)?;
         }
     }
 
     /// Unbind the specified [`VCpu`] bond by [`VM::<H>::bind_vcpu`].
-    pub fn unbind_vcpu(&mut self, vcpu_id: usize) -> HyperResult {
-        if self.vcpu_bond.contains(vcpu_id) {
-            match self.vcpus.get_vcpu_and_device(vcpu_id) {
-                Ok((vcpu, _)) => {
-                    self.vcpu_bond.remove(vcpu_id);
+    pub fn unbind_vcpu(&self, vcpu_id: usize) -> HyperResult {
+        let mut vcpu_bond = self.vcpu_bond.lock();
+        if vcpu_bond.contains(vcpu_id) {
+            match self.vcpus.lock_vcpu(vcpu_id) {
+                Ok(vcpu) => {
+                    vcpu_bond.remove(vcpu_id);
                     vcpu.unbind_from_current_processor()?;
                     Ok(())
                 }
@@ -244,22 +1001,173 @@ impl<H: HyperCraftHal, PD: PerCpuDevices<H>, VD: PerVmDevices<H>, G: GuestPageTa
 
     /// Get per-vm devices.
     pub fn devices(&mut self) -> &mut VD {
-        &mut self.device
+        self.device.get_mut()
     }
 
     /// Get vcpu and its devices by its id.
     pub fn get_vcpu_and_device(&mut self, vcpu_id: usize) -> HyperResult<(&mut VCpu<H>, &mut PD)> {
-        self.vcpus.get_vcpu_and_device(vcpu_id)
+        vcpu_and_device(&mut self.vcpus, &mut self.vcpu_devices, vcpu_id)
+    }
+
+    /// Take exclusive access to a vcpu and its devices, failing with [`HyperError::BadState`]
+    /// while another physical processor holds them.
+    pub fn lock_vcpu_and_device(
+        &self,
+        vcpu_id: usize,
+    ) -> HyperResult<(VCpuGuard<'_, VCpu<H>>, VCpuGuard<'_, PD>)> {
+        let vcpu = self.vcpus.lock_vcpu(vcpu_id)?;
+        let device = self.vcpu_devices.get(vcpu_id).ok_or(HyperError::NotFound)?;
+        Ok((vcpu, VCpuGuard::try_lock(device)?))
+    }
+
+    /// Translate a [`VmxExitInfo`] into an arch-independent [`VmExit`], remembering where the
+    /// data of a read goes and moving `RIP` past the instruction that caused the exit.
+    ///
+    /// Hypercalls take their number in `RAX` and arguments in `RDI`, `RSI`, `RDX`, `R10`, `R8`
+    /// and `R9`.
+    fn translate_exit(&self, vcpu: &mut VCpu<H>, exit_info: VmxExitInfo) -> HyperResult<VmExit> {
+        let exit = match exit_info.exit_reason {
+            VmxExitReason::VMCALL => {
+                let regs = vcpu.regs();
+                VmExit::Hypercall {
+                    nr: regs.rax as usize,
+                    args: [
+                        regs.rdi as usize,
+                        regs.rsi as usize,
+                        regs.rdx as usize,
+                        regs.r10 as usize,
+                        regs.r8 as usize,
+                        regs.r9 as usize,
+                    ],
+                }
+            }
+            VmxExitReason::HLT => VmExit::Halt,
+            VmxExitReason::TRIPLE_FAULT => return Ok(VmExit::Shutdown),
+            VmxExitReason::IO_INSTRUCTION => {
+                let io_info = vcpu.io_exit_info()?;
+                if io_info.is_string {
+                    return Ok(VmExit::ArchSpecific(exit_info));
+                } else if io_info.is_in {
+                    // `in` writes `AL`, `AX` or `EAX`
+                    vcpu.set_pending_read(PendingRead {
+                        reg_size: io_info.access_size,
+                        merge: io_info.access_size < 4,
+                        ..PendingRead::new(0, io_info.access_size)
+                    });
+                    VmExit::PioIn {
+                        port: io_info.port,
+                        size: io_info.access_size,
+                    }
+                } else {
+                    let mask = u32::MAX >> (32 - 8 * io_info.access_size as u32);
+                    VmExit::PioOut {
+                        port: io_info.port,
+                        size: io_info.access_size,
+                        data: vcpu.regs().rax as u32 & mask,
+                    }
+                }
+            }
+            VmxExitReason::EPT_VIOLATION => {
+                let fault_info = vcpu.nested_page_fault_info()?;
+                let instr = Self::decode_instr(
+                    self.ept.clone(),
+                    &self.memory,
+                    vcpu,
+                    exit_info.guest_rip,
+                    exit_info.exit_instruction_length,
+                )?;
+                let addr = fault_info.fault_guest_paddr;
+                let size = match instr.memory_size().size() {
+                    size @ (1 | 2 | 4 | 8) => size as u8,
+                    _ => return Ok(VmExit::ArchSpecific(exit_info)),
+                };
+                if !fault_info.access_flags.contains(MappingFlags::WRITE) {
+                    match Self::mmio_read_target(&instr, size) {
+                        Some(pending) => vcpu.set_pending_read(pending),
+                        None => return Ok(VmExit::ArchSpecific(exit_info)),
+                    }
+                    VmExit::MmioRead { addr, size }
+                } else {
+                    match Self::mmio_write_data(vcpu, &instr) {
+                        Some(data) => VmExit::MmioWrite { addr, size, data },
+                        None => return Ok(VmExit::ArchSpecific(exit_info)),
+                    }
+                }
+            }
+            _ => return Ok(VmExit::ArchSpecific(exit_info)),
+        };
+        vcpu.advance_rip(exit_info.exit_instruction_length as u8)?;
+        Ok(exit)
+    }
+
+    /// The register a decoded `mov`, `movzx`, `movsx` or `movsxd` loads from memory into. Other
+    /// instructions also compute with or only compare the data, which a read can't express.
+    fn mmio_read_target(instr: &Instruction, size: u8) -> Option<PendingRead> {
+        let is_load = matches!(
+            instr.mnemonic(),
+            Mnemonic::Mov | Mnemonic::Movzx | Mnemonic::Movsx | Mnemonic::Movsxd
+        );
+        if !is_load
+            || instr.op_count() != 2
+            || instr.op0_kind() != OpKind::Register
+            || instr.op1_kind() != OpKind::Memory
+        {
+            return None;
+        }
+        let reg = instr.op0_register();
+        if !reg.is_gpr() {
+            return None;
+        }
+        let reg_size = reg.size() as u8;
+        Some(PendingRead {
+            sign_extend: matches!(instr.mnemonic(), Mnemonic::Movsx | Mnemonic::Movsxd),
+            reg_size,
+            shift: if emulate::is_high_byte(reg) { 8 } else { 0 },
+            // writing a 32-bit register clears the upper half, narrower ones are merged
+            merge: reg_size < 4,
+            ..PendingRead::new(reg.full_register().number(), size)
+        })
+    }
+
+    /// The value a decoded `mov` stores to memory, if it comes from a register or an immediate.
+    fn mmio_write_data(vcpu: &VCpu<H>, instr: &Instruction) -> Option<u64> {
+        if instr.mnemonic() != Mnemonic::Mov
+            || instr.op_count() != 2
+            || instr.op0_kind() != OpKind::Memory
+        {
+            return None;
+        }
+        match instr.op1_kind() {
+            OpKind::Register => {
+                let reg = instr.op1_register();
+                if !reg.is_gpr() {
+                    return None;
+                }
+                let mut value = vcpu.gpr(reg.full_register().number()) as u64;
+                if emulate::is_high_byte(reg) {
+                    value >>= 8;
+                }
+                Some(value & (u64::MAX >> (64 - 8 * reg.size())))
+            }
+            OpKind::Immediate8
+            | OpKind::Immediate16
+            | OpKind::Immediate32
+            | OpKind::Immediate8to32
+            | OpKind::Immediate8to64
+            | OpKind::Immediate32to64 => Some(instr.immediate(1)),
+            _ => None,
+        }
     }
 
     /// decode guest instruction
     pub fn decode_instr(
-        ept: Arc<G>,
+        ept: Arc<Mutex<G>>,
+        memory: &GuestMemory<H>,
         vcpu: &VCpu<H>,
         guest_rip: usize,
         length: u32,
     ) -> HyperResult<Instruction> {
-        let asm = Self::get_gva_content_bytes(ept, guest_rip, length, vcpu)?;
+        let asm = Self::get_gva_content_bytes(ept, memory, guest_rip, length, vcpu)?;
         let asm_slice = asm.as_slice();
         // Only one isntruction
         let mut decoder = Decoder::with_ip(64, asm_slice, guest_rip as u64, DecoderOptions::NONE);
@@ -274,115 +1182,103 @@ impl<H: HyperCraftHal, PD: PerCpuDevices<H>, VD: PerVmDevices<H>, G: GuestPageTa
 
     /// get gva content bytes
     pub fn get_gva_content_bytes(
-        ept: Arc<G>,
+        ept: Arc<Mutex<G>>,
+        memory: &GuestMemory<H>,
         guest_rip: usize,
         length: u32,
         vcpu: &VCpu<H>,
     ) -> HyperResult<Vec<u8>> {
-        // debug!(
-        //     "get_gva_content_bytes: guest_rip: {:#x}, length: {:#x}",
-        //     guest_rip, length
-        // );
         let gva = vcpu.gla2gva(guest_rip);
-        // debug!("get_gva_content_bytes: gva: {:#x}", gva);
-        let gpa = Self::gva2gpa(ept.clone(), vcpu, gva)?;
-        // debug!("get_gva_content_bytes: gpa: {:#x}", gpa);
-        let hva = Self::gpa2hva(ept.clone(), gpa)?;
-        // debug!("get_gva_content_bytes: hva: {:#x}", hva);
-        let mut content = Vec::with_capacity(length as usize);
-        let code_ptr = hva as *const u8;
-        unsafe {
-            for i in 0..length {
-                let value_ptr = code_ptr.offset(i as isize);
-                content.push(value_ptr.read());
-            }
-        }
-        // debug!("get_gva_content_bytes: content: {:?}", content);
+        let mut content = vec![0; length as usize];
+        memory.fetch_gva(&*ept.lock(), &vcpu.get_ptw_info(), gva, &mut content)?;
         Ok(content)
     }
+}
 
-    fn gpa2hva(ept: Arc<G>, gpa: GuestPhysAddr) -> HyperResult<HostVirtAddr> {
-        let hpa = Self::gpa2hpa(ept, gpa)?;
-        let hva = H::phys_to_virt(hpa);
-        Ok(hva as HostVirtAddr)
-    }
+/// The vCPU and guest memory an instruction that accessed a device of the MMIO bus is emulated
+/// against. Accesses to the bus go to its devices, the others to guest memory.
+struct MmioEmulation<'a, H: HyperCraftHal, G: GuestPageTableTrait> {
+    vcpu: &'a mut VCpu<H>,
+    memory: &'a GuestMemory<H>,
+    ept: &'a G,
+    mmio_bus: &'a MmioBus,
+    walk: GuestPageWalkInfo,
+}
 
-    fn gpa2hpa(ept: Arc<G>, gpa: GuestPhysAddr) -> HyperResult<HostPhysAddr> {
-        ept.translate(gpa)
+impl<H: HyperCraftHal, G: GuestPageTableTrait> EmulatorContext for MmioEmulation<'_, H, G> {
+    fn gpr(&self, index: usize) -> u64 {
+        self.vcpu.gpr(index) as u64
     }
 
-    fn gva2gpa(ept: Arc<G>, vcpu: &VCpu<H>, gva: GuestVirtAddr) -> HyperResult<GuestPhysAddr> {
-        let guest_ptw_info = vcpu.get_ptw_info();
-        Self::page_table_walk(ept, guest_ptw_info, gva)
+    fn set_gpr(&mut self, index: usize, value: u64) {
+        self.vcpu.set_gpr(index, value as usize)
     }
 
-    // suppose it is 4-level page table
-    fn page_table_walk(
-        ept: Arc<G>,
-        pw_info: GuestPageWalkInfo,
-        gva: GuestVirtAddr,
-    ) -> HyperResult<GuestPhysAddr> {
-        use x86_64::structures::paging::page_table::PageTableFlags as PTF;
-
-        // debug!("page_table_walk: gva: {:#x}\npw_info:{:#x?}", gva, pw_info);
-        const PHYS_ADDR_MASK: usize = 0x000f_ffff_ffff_f000; // bits 12..52
-        if pw_info.level <= 1 {
-            return Ok(gva as GuestPhysAddr);
-        }
-        let mut addr = pw_info.top_entry;
-        let mut current_level = pw_info.level;
-        let mut shift = 0;
-        let mut page_size = PAGE_SIZE;
-
-        let mut entry = 0;
-
-        while current_level != 0 {
-            current_level -= 1;
-            // get page table base addr
-            addr = addr & PHYS_ADDR_MASK;
-
-            let base = Self::gpa2hva(ept.clone(), addr)?;
-            shift = (current_level * pw_info.width as usize) + 12;
-
-            let index = (gva >> shift) & ((1 << (pw_info.width as usize)) - 1);
-            page_size = 1 << shift;
-
-            // get page table entry pointer
-            let entry_ptr = unsafe { (base as *const usize).offset(index as isize) };
-
-            // next page table addr (gpa)
-            entry = unsafe { *entry_ptr };
-
-            let entry_flags = PTF::from_bits_retain(entry as u64);
+    fn rflags(&self) -> u64 {
+        self.vcpu.rflags() as u64
+    }
 
-            // debug!("next page table entry {:#x} {:?}", entry, entry_flags);
+    fn set_rflags(&mut self, rflags: u64) {
+        self.vcpu
+            .set_guest_rflags(rflags as usize, usize::MAX)
+            .unwrap()
+    }
 
-            /* check if the entry present */
-            if !entry_flags.contains(PTF::PRESENT) {
-                warn!(
-                    "GVA {:#x} l{} entry {:#x} not presented in its NPT",
-                    gva,
-                    current_level + 1,
-                    entry
-                );
-                return Err(HyperError::BadState);
-            }
+    fn segment_base(&self, index: usize) -> u64 {
+        self.vcpu.segment_base(index) as u64
+    }
 
-            // Check hugepage
-            if pw_info.pse && current_level > 0 && entry_flags.contains(PTF::HUGE_PAGE) {
-                break;
-            }
+    fn read_memory(&mut self, gla: u64, size: u8) -> HyperResult<u64> {
+        let gva = gla as GuestVirtAddr;
+        let gpa = self
+            .walk
+            .translate(self.memory, self.ept, gva, GuestAccess::Read)?;
+        if let Some(data) = self.mmio_bus.read(gpa as u64, size) {
+            return data;
+        }
+        let mut buf = [0; 8];
+        self.memory
+            .read_gva(self.ept, &self.walk, gva, &mut buf[..size as usize])?;
+        Ok(u64::from_le_bytes(buf))
+    }
 
-            addr = entry;
+    fn write_memory(&mut self, gla: u64, size: u8, value: u64) -> HyperResult {
+        let gva = gla as GuestVirtAddr;
+        let gpa = self
+            .walk
+            .translate(self.memory, self.ept, gva, GuestAccess::Write)?;
+        if let Some(result) = self.mmio_bus.write(gpa as u64, size, value) {
+            return result;
         }
+        let data = value.to_le_bytes();
+        self.memory
+            .write_gva(self.ept, &self.walk, gva, &data[..size as usize])
+    }
+}
 
-        entry >>= shift;
-        /* shift left 12bit more and back to clear XD/Prot Key/Ignored bits */
-        entry <<= shift + 12;
-        entry >>= 12;
+/// The EPT of a VM, failing with [`HyperError::BadState`] while it is shared.
+fn ept_mut<G: GuestPageTableTrait>(ept: &mut Arc<Mutex<G>>) -> HyperResult<&mut G> {
+    Ok(Arc::get_mut(ept).ok_or(HyperError::BadState)?.get_mut())
+}
 
-        Ok((entry | (gva & (page_size - 1))) as GuestPhysAddr)
-    }
+fn vcpu_and_device<'a, H: HyperCraftHal, PD: PerCpuDevices<H>>(
+    vcpus: &'a mut VmCpus<H>,
+    vcpu_devices: &'a mut [Mutex<Option<PD>>],
+    vcpu_id: usize,
+) -> HyperResult<(&'a mut VCpu<H>, &'a mut PD)> {
+    let vcpu = vcpus.get_vcpu(vcpu_id)?;
+    let device = vcpu_devices
+        .get_mut(vcpu_id)
+        .and_then(|device| device.get_mut().as_mut())
+        .ok_or(HyperError::NotFound)?;
+    Ok((vcpu, device))
+}
+
+fn is_msr_exit(exit_info: &VmxExitInfo) -> bool {
+    matches!(
+        exit_info.exit_reason,
+        VmxExitReason::MSR_READ | VmxExitReason::MSR_WRITE
+    )
 }
 
 /// VM exit information.
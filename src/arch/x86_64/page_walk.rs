@@ -0,0 +1,490 @@
+use x86_64::structures::paging::page_table::PageTableFlags as PTF;
+
+use crate::{
+    GuestAccess, GuestMemory, GuestPageFault, GuestPageTableTrait, GuestPageWalk, GuestPhysAddr,
+    GuestVirtAddr, HyperCraftHal, HyperError, HyperResult,
+};
+
+/// Bits 12..52 of a PAE or 4/5-level paging entry.
+const PHYS_ADDR_MASK: usize = 0x000f_ffff_ffff_f000;
+/// Bits 12..32 of a 32-bit paging entry.
+const PHYS_ADDR_MASK_32: usize = 0xffff_f000;
+/// Bits 5..32 of `CR3` in PAE paging, the address of the page-directory-pointer table.
+const PDPT_ADDR_MASK: usize = 0xffff_ffe0;
+
+/// `#PF` error code bits. (SDM Vol. 3A, Section 4.7)
+const PFEC_PRESENT: u32 = 1 << 0;
+const PFEC_WRITE: u32 = 1 << 1;
+const PFEC_USER: u32 = 1 << 2;
+const PFEC_RSVD: u32 = 1 << 3;
+const PFEC_FETCH: u32 = 1 << 4;
+
+#[derive(Debug)]
+/// The information of guest page walk.
+pub struct GuestPageWalkInfo {
+    /// The guest page table physical address.
+    pub top_entry: usize, // Top level paging structure entry
+    /// Guest page table level.
+    pub level: usize,
+    /// Guest page table width
+    pub width: u32,
+    /// Guest page table user mode
+    pub is_user_mode_access: bool,
+    /// Guest page table write access
+    pub is_write_access: bool,
+    /// Guest page table instruction fetch
+    pub is_inst_fetch: bool,
+    /// CR4.PSE for 32bit paging, true for PAE/4-level paging
+    pub pse: bool,
+    /// CR0.WP
+    pub wp: bool, // CR0.WP
+    /// MSR_IA32_EFER_NXE_BIT
+    pub nxe: bool,
+
+    /// Guest page table Supervisor mode access prevention
+    pub is_smap_on: bool,
+    /// Guest page table Supervisor mode execution protection
+    pub is_smep_on: bool,
+    /// RFLAGS.AC, which lifts SMAP for supervisor data accesses
+    pub ac: bool,
+    /// Set the accessed and dirty bits of the entries used, as the MMU does
+    pub set_accessed_dirty: bool,
+}
+
+impl GuestPageWalkInfo {
+    /// Whether the guest uses PAE paging, whose top level is 4 PDPTEs without permission bits.
+    fn is_pae(&self) -> bool {
+        self.level == 3
+    }
+
+    /// Base error code of a `#PF` caused by `access`.
+    fn error_code(&self, access: GuestAccess) -> u32 {
+        let mut code = 0;
+        if access == GuestAccess::Write {
+            code |= PFEC_WRITE;
+        }
+        if self.is_user_mode_access {
+            code |= PFEC_USER;
+        }
+        // I/D is only reported when fetches can fault on their own
+        if access == GuestAccess::Fetch && (self.nxe || self.is_smep_on) {
+            code |= PFEC_FETCH;
+        }
+        code
+    }
+
+    /// Whether `entry`, found at `level`, sets a bit that must be zero.
+    fn has_reserved_bits(&self, level: usize, entry: usize) -> bool {
+        let flags = PTF::from_bits_truncate(entry as u64);
+        if self.width == 10 {
+            return false;
+        }
+        (!self.nxe && flags.contains(PTF::NO_EXECUTE))
+            || (self.is_pae() && level == 3 && flags.intersects(PTF::HUGE_PAGE | PTF::NO_EXECUTE))
+            || (level >= 4 && flags.contains(PTF::HUGE_PAGE))
+    }
+
+    /// Whether `entry`, found at `level`, maps a page rather than the next table.
+    fn is_leaf(&self, level: usize, entry: usize) -> bool {
+        let huge = PTF::from_bits_truncate(entry as u64).contains(PTF::HUGE_PAGE);
+        match level {
+            1 => true,
+            2 => huge && self.pse,
+            // 1G pages, PS in a PDPTE is reserved in PAE paging
+            3 => huge && !self.is_pae(),
+            _ => false,
+        }
+    }
+
+    /// Whether the accumulated permissions of a translation deny `access`. (SDM Vol. 3A,
+    /// Section 4.6)
+    fn denies(&self, access: GuestAccess, user: bool, writable: bool, executable: bool) -> bool {
+        if self.is_user_mode_access {
+            return match access {
+                GuestAccess::Read => !user,
+                GuestAccess::Write => !user || !writable,
+                GuestAccess::Fetch => !user || !executable,
+            };
+        }
+        let smap = user && self.is_smap_on && !self.ac;
+        match access {
+            GuestAccess::Read => smap,
+            // supervisor writes ignore R/W unless CR0.WP is set
+            GuestAccess::Write => smap || (!writable && self.wp),
+            GuestAccess::Fetch => !executable || (user && self.is_smep_on),
+        }
+    }
+}
+
+impl GuestPageWalk for GuestPageWalkInfo {
+    /// Walks 32-bit, PAE, 4-level or 5-level guest paging structures. A failed walk reports the
+    /// `#PF` error code the CPU would push.
+    fn translate<H: HyperCraftHal, G: GuestPageTableTrait>(
+        &self,
+        memory: &GuestMemory<H>,
+        gpt: &G,
+        gva: GuestVirtAddr,
+        access: GuestAccess,
+    ) -> HyperResult<GuestPhysAddr> {
+        if self.level == 0 {
+            return Ok(gva as GuestPhysAddr);
+        }
+        let fault = |error_code| {
+            HyperError::GuestPageFault(GuestPageFault {
+                addr: gva,
+                physical: false,
+                access,
+                error_code,
+            })
+        };
+        let error_code = self.error_code(access);
+        // linear addresses are 32 bits wide outside of IA-32e mode
+        let va = if self.level <= 3 {
+            gva & 0xffff_ffff
+        } else {
+            gva
+        };
+        let (entry_size, addr_mask) = if self.width == 10 {
+            (4, PHYS_ADDR_MASK_32)
+        } else {
+            (8, PHYS_ADDR_MASK)
+        };
+        let mut table = match self.level {
+            2 => self.top_entry & PHYS_ADDR_MASK_32,
+            3 => self.top_entry & PDPT_ADDR_MASK,
+            _ => self.top_entry & PHYS_ADDR_MASK,
+        };
+        // entries to mark accessed, the PDPTEs of PAE paging have no accessed bit
+        let mut used = [0; 5];
+        let mut num_used = 0;
+        let (mut user, mut writable, mut executable) = (true, true, true);
+        let mut level = self.level;
+        loop {
+            let shift = (level - 1) * self.width as usize + 12;
+            let index = (va >> shift) & ((1 << self.width) - 1);
+            let entry_addr = table + index * entry_size;
+            let entry = if entry_size == 4 {
+                memory.read_obj::<u32, G>(gpt, entry_addr)? as usize
+            } else {
+                memory.read_obj::<u64, G>(gpt, entry_addr)? as usize
+            };
+            let flags = PTF::from_bits_truncate(entry as u64);
+            if !flags.contains(PTF::PRESENT) {
+                return Err(fault(error_code));
+            }
+            if self.has_reserved_bits(level, entry) {
+                return Err(fault(error_code | PFEC_PRESENT | PFEC_RSVD));
+            }
+            if !(self.is_pae() && level == 3) {
+                user &= flags.contains(PTF::USER_ACCESSIBLE);
+                writable &= flags.contains(PTF::WRITABLE);
+                executable &= !flags.contains(PTF::NO_EXECUTE);
+                used[num_used] = entry_addr;
+                num_used += 1;
+            }
+
+            if self.is_leaf(level, entry) {
+                if self.denies(access, user, writable, executable) {
+                    return Err(fault(error_code | PFEC_PRESENT));
+                }
+                if self.set_accessed_dirty {
+                    let (leaf, tables) = used[..num_used].split_last().unwrap();
+                    for &addr in tables {
+                        memory.fetch_or_u32(gpt, addr, PTF::ACCESSED.bits() as u32)?;
+                    }
+                    let mut bits = PTF::ACCESSED;
+                    if access == GuestAccess::Write {
+                        bits |= PTF::DIRTY;
+                    }
+                    memory.fetch_or_u32(gpt, *leaf, bits.bits() as u32)?;
+                }
+                let page_size = 1 << shift;
+                let base = if entry_size == 4 && level == 2 {
+                    // bits 13..21 of a 4M page entry hold bits 32..40 of the address
+                    (entry & 0xffc0_0000) | (((entry >> 13) & 0xff) << 32)
+                } else {
+                    entry & addr_mask & !(page_size - 1)
+                };
+                return Ok(base | (va & (page_size - 1)));
+            }
+            table = entry & addr_mask;
+            level -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::guest_memory::tests::{ram, TestGpt, TestHal};
+
+    const PML4: usize = 0x1000;
+    const PDPT: usize = 0x2000;
+    const PD: usize = 0x3000;
+    const PT: usize = 0x4000;
+    const PAGE: usize = 0x8000;
+
+    const P: usize = PTF::PRESENT.bits() as usize;
+    const W: usize = PTF::WRITABLE.bits() as usize;
+    const U: usize = PTF::USER_ACCESSIBLE.bits() as usize;
+    const A: usize = PTF::ACCESSED.bits() as usize;
+    const D: usize = PTF::DIRTY.bits() as usize;
+    const PS: usize = PTF::HUGE_PAGE.bits() as usize;
+    const NX: usize = PTF::NO_EXECUTE.bits() as usize;
+
+    /// 4-level paging as a 64-bit supervisor sees it with `CR0.WP` and `EFER.NXE` set.
+    fn info() -> GuestPageWalkInfo {
+        GuestPageWalkInfo {
+            top_entry: PML4,
+            level: 4,
+            width: 9,
+            is_user_mode_access: false,
+            is_write_access: false,
+            is_inst_fetch: false,
+            pse: true,
+            wp: true,
+            nxe: true,
+            is_smap_on: false,
+            is_smep_on: false,
+            ac: false,
+            set_accessed_dirty: false,
+        }
+    }
+
+    /// 4-level tables mapping the page at virtual address 0x1000 to [`PAGE`] with `flags`, the
+    /// tables themselves with `P | W | U`.
+    fn tables(flags: usize) -> (GuestMemory<TestHal>, TestGpt) {
+        let (memory, gpt) = ram(0x10000);
+        memory.write_obj(&gpt, PML4, &(PDPT | P | W | U)).unwrap();
+        memory.write_obj(&gpt, PDPT, &(PD | P | W | U)).unwrap();
+        memory.write_obj(&gpt, PD, &(PT | P | W | U)).unwrap();
+        memory.write_obj(&gpt, PT + 8, &(PAGE | flags)).unwrap();
+        (memory, gpt)
+    }
+
+    fn translate(
+        info: &GuestPageWalkInfo,
+        (memory, gpt): &(GuestMemory<TestHal>, TestGpt),
+        gva: GuestVirtAddr,
+        access: GuestAccess,
+    ) -> HyperResult<GuestPhysAddr> {
+        info.translate(memory, gpt, gva, access)
+    }
+
+    fn fault(gva: GuestVirtAddr, access: GuestAccess, error_code: u32) -> HyperResult<usize> {
+        Err(HyperError::GuestPageFault(GuestPageFault {
+            addr: gva,
+            physical: false,
+            access,
+            error_code,
+        }))
+    }
+
+    #[test]
+    fn four_level_translation_sets_accessed_and_dirty() {
+        let mem = tables(P | W);
+        let walk = GuestPageWalkInfo {
+            set_accessed_dirty: true,
+            ..info()
+        };
+        assert_eq!(
+            translate(&walk, &mem, 0x1234, GuestAccess::Read),
+            Ok(0x8234)
+        );
+        let (memory, gpt) = &mem;
+        assert_eq!(
+            memory.read_obj::<usize, _>(gpt, PT + 8),
+            Ok(PAGE | P | W | A)
+        );
+        assert_eq!(
+            translate(&walk, &mem, 0x1ffc, GuestAccess::Write),
+            Ok(0x8ffc)
+        );
+        assert_eq!(
+            memory.read_obj::<usize, _>(gpt, PT + 8),
+            Ok(PAGE | P | W | A | D)
+        );
+        for table in [PML4, PDPT, PD] {
+            assert_ne!(memory.read_obj::<usize, _>(gpt, table).unwrap() & A, 0);
+        }
+        // the walk doesn't touch the entries unless asked to
+        let mem = tables(P | W);
+        assert_eq!(
+            translate(&info(), &mem, 0x1000, GuestAccess::Write),
+            Ok(PAGE)
+        );
+        assert_eq!(mem.0.read_obj::<usize, _>(&mem.1, PT + 8), Ok(PAGE | P | W));
+    }
+
+    #[test]
+    fn error_codes() {
+        use GuestAccess::*;
+        const PF_P: u32 = PFEC_PRESENT;
+        const PF_W: u32 = PFEC_WRITE;
+        const PF_U: u32 = PFEC_USER;
+        const PF_I: u32 = PFEC_FETCH;
+        const PF_RSVD: u32 = PFEC_RSVD;
+        let user = GuestPageWalkInfo {
+            is_user_mode_access: true,
+            ..info()
+        };
+
+        // not present: P clear, W/U/I from the access
+        let mem = tables(0);
+        assert_eq!(
+            translate(&info(), &mem, 0x1000, Read),
+            fault(0x1000, Read, 0)
+        );
+        assert_eq!(
+            translate(&user, &mem, 0x1000, Write),
+            fault(0x1000, Write, PF_W | PF_U)
+        );
+        assert_eq!(
+            translate(&info(), &mem, 0x1000, Fetch),
+            fault(0x1000, Fetch, PF_I)
+        );
+        // I/D is only reported with NX or SMEP
+        let legacy = GuestPageWalkInfo {
+            nxe: false,
+            ..info()
+        };
+        assert_eq!(
+            translate(&legacy, &mem, 0x1000, Fetch),
+            fault(0x1000, Fetch, 0)
+        );
+
+        // protection violations: P set
+        let mem = tables(P);
+        assert_eq!(
+            translate(&user, &mem, 0x1000, Read),
+            fault(0x1000, Read, PF_P | PF_U)
+        );
+        assert_eq!(
+            translate(&info(), &mem, 0x1000, Write),
+            fault(0x1000, Write, PF_P | PF_W)
+        );
+        let mem = tables(P | NX);
+        assert_eq!(
+            translate(&info(), &mem, 0x1000, Fetch),
+            fault(0x1000, Fetch, PF_P | PF_I)
+        );
+
+        // reserved bits: P and RSVD set, even for accesses the entry would allow
+        assert_eq!(
+            translate(&legacy, &mem, 0x1000, Read),
+            fault(0x1000, Read, PF_P | PF_RSVD)
+        );
+        let mem = tables(P | W);
+        mem.0.write_obj(&mem.1, PML4, &(PDPT | P | PS)).unwrap();
+        assert_eq!(
+            translate(&user, &mem, 0x1000, Write),
+            fault(0x1000, Write, PF_P | PF_W | PF_U | PF_RSVD)
+        );
+    }
+
+    #[test]
+    fn large_pages() {
+        let read = GuestAccess::Read;
+        let mem = tables(P);
+        let (memory, gpt) = &mem;
+        // a 2M page at 2M and a 1G page at 1G
+        memory
+            .write_obj(gpt, PD + 8, &(0x4020_0000 | P | PS))
+            .unwrap();
+        memory
+            .write_obj(gpt, PDPT + 8, &(0x8000_0000 | P | PS))
+            .unwrap();
+        assert_eq!(translate(&info(), &mem, 0x2f_1234, read), Ok(0x402f_1234));
+        assert_eq!(translate(&info(), &mem, 0x7654_3210, read), Ok(0xb654_3210));
+
+        // 32-bit paging, with a 4M page at 4M holding bits 32..40 of its address in 13..21
+        let mut legacy = GuestPageWalkInfo {
+            top_entry: PD,
+            level: 2,
+            width: 10,
+            nxe: false,
+            ..info()
+        };
+        memory
+            .write_obj(gpt, PD + 4, &((0x40_6000 | P | PS) as u32))
+            .unwrap();
+        memory.write_obj(gpt, PT, &((PAGE | P) as u32)).unwrap();
+        assert_eq!(translate(&legacy, &mem, 0x40_1234, read), Ok(0x3_0040_1234));
+        // without CR4.PSE, PS is ignored and the PDE points to a page table
+        legacy.pse = false;
+        memory
+            .write_obj(gpt, PD + 4, &((PT | P | PS) as u32))
+            .unwrap();
+        assert_eq!(translate(&legacy, &mem, 0x40_0123, read), Ok(0x8123));
+
+        // PAE paging: PS is reserved in a PDPTE, but maps a 2M page in a PDE
+        let pae = GuestPageWalkInfo {
+            top_entry: PDPT,
+            level: 3,
+            ..info()
+        };
+        memory
+            .write_obj(gpt, PD + 8, &(0x20_0000 | P | PS))
+            .unwrap();
+        assert_eq!(translate(&pae, &mem, 0x21_0000, read), Ok(0x21_0000));
+        memory.write_obj(gpt, PDPT, &(PD | P | PS)).unwrap();
+        assert_eq!(
+            translate(&pae, &mem, 0x21_0000, read),
+            fault(0x21_0000, read, PFEC_PRESENT | PFEC_RSVD)
+        );
+    }
+
+    #[test]
+    fn supervisor_and_user_pages() {
+        use GuestAccess::*;
+        let fault_p = |access, code| fault(0x1000, access, PFEC_PRESENT | code);
+        let mem = tables(P | U);
+        let user = GuestPageWalkInfo {
+            is_user_mode_access: true,
+            ..info()
+        };
+        // user pages are read-only for user accesses, and for the supervisor with CR0.WP
+        assert_eq!(translate(&user, &mem, 0x1000, Read), Ok(PAGE));
+        assert_eq!(
+            translate(&user, &mem, 0x1000, Write),
+            fault_p(Write, PFEC_WRITE | PFEC_USER)
+        );
+        assert_eq!(
+            translate(&info(), &mem, 0x1000, Write),
+            fault_p(Write, PFEC_WRITE)
+        );
+        let no_wp = GuestPageWalkInfo {
+            wp: false,
+            ..info()
+        };
+        assert_eq!(translate(&no_wp, &mem, 0x1000, Write), Ok(PAGE));
+
+        // SMAP stops supervisor data accesses to user pages unless RFLAGS.AC is set, SMEP
+        // stops supervisor fetches from them
+        let mut smap = GuestPageWalkInfo {
+            is_smap_on: true,
+            is_smep_on: true,
+            ..info()
+        };
+        assert_eq!(translate(&smap, &mem, 0x1000, Read), fault_p(Read, 0));
+        assert_eq!(
+            translate(&smap, &mem, 0x1000, Fetch),
+            fault_p(Fetch, PFEC_FETCH)
+        );
+        smap.ac = true;
+        assert_eq!(translate(&smap, &mem, 0x1000, Read), Ok(PAGE));
+        assert_eq!(
+            translate(&smap, &mem, 0x1000, Fetch),
+            fault_p(Fetch, PFEC_FETCH)
+        );
+
+        // a supervisor-only table anywhere in the walk makes the page supervisor-only
+        let (memory, gpt) = &mem;
+        memory.write_obj(gpt, PDPT, &(PD | P | W)).unwrap();
+        assert_eq!(
+            translate(&user, &mem, 0x1000, Read),
+            fault_p(Read, PFEC_USER)
+        );
+        assert_eq!(translate(&smap, &mem, 0x1000, Fetch), Ok(PAGE));
+    }
+}
@@ -44,6 +44,30 @@ impl GeneralRegisters {
             }
         }
     }
+
+    pub fn set_reg_of_index(&mut self, index: u8, value: u64) {
+        match index {
+            0 => self.rax = value,
+            1 => self.rcx = value,
+            2 => self.rdx = value,
+            3 => self.rbx = value,
+            // 4 => self._unused_rsp = value,
+            5 => self.rbp = value,
+            6 => self.rsi = value,
+            7 => self.rdi = value,
+            8 => self.r8 = value,
+            9 => self.r9 = value,
+            10 => self.r10 = value,
+            11 => self.r11 = value,
+            12 => self.r12 = value,
+            13 => self.r13 = value,
+            14 => self.r14 = value,
+            15 => self.r15 = value,
+            _ => {
+                panic!("Illegal index of GeneralRegisters {}", index);
+            }
+        }
+    }
 }
 
 macro_rules! save_regs_to_stack {
@@ -1,5 +1,9 @@
+use bit_field::BitField;
 use raw_cpuid::CpuId;
 
+use super::vmcs::controls::SecondaryControls;
+use crate::arch::msr::Msr;
+
 /// Checks if VT-x (vmx) is supported by our hardware.
 pub fn has_hardware_support() -> bool {
     if let Some(feature) = CpuId::new().get_feature_info() {
@@ -8,3 +12,11 @@ pub fn has_hardware_support() -> bool {
         false
     }
 }
+
+/// Checks if the CPU supports Page-Modification Logging, which also needs the EPT
+/// accessed and dirty flags. (SDM Vol. 3C, Section 28.2.6)
+pub fn has_pml_support() -> bool {
+    let allowed1 = Msr::IA32_VMX_PROCBASED_CTLS2.read() >> 32;
+    let ept_ad = Msr::IA32_VMX_EPT_VPID_CAP.read().get_bit(21);
+    allowed1 & SecondaryControls::ENABLE_PML.bits() as u64 != 0 && ept_ad
+}
@@ -1,19 +1,19 @@
 mod definitions;
 mod detect;
-mod percpu;
-mod region;
-mod vcpu;
-mod vmcs;
 #[cfg(feature = "type1_5")]
 mod linux_context;
+mod percpu;
+mod region;
 #[cfg(feature = "type1_5")]
 mod segmentation;
+mod vcpu;
+mod vmcs;
 
-pub use detect::has_hardware_support;
-pub use percpu::VmxPerCpuState;
-pub use vcpu::VmxVcpu;
 pub use definitions::VmxExitReason;
 pub use definitions::VmxInterruptionType;
-pub use vmcs::VmxExitInfo;
+pub use detect::{has_hardware_support, has_pml_support};
 #[cfg(feature = "type1_5")]
-pub use linux_context::LinuxContext;
\ No newline at end of file
+pub use linux_context::LinuxContext;
+pub use percpu::VmxPerCpuState;
+pub use vcpu::VmxVcpu;
+pub use vmcs::{set_ept_pointer, VmxExitInfo, VmxIoExitInfo};
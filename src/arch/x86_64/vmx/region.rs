@@ -1,5 +1,6 @@
 use bit_field::BitField;
 use core::marker::PhantomData;
+use core::ops::Range;
 
 use crate::arch::memory::PhysFrame;
 use crate::{GuestPhysAddr, HostPhysAddr, HostVirtAddr, HyperCraftHal};
@@ -99,6 +100,13 @@ impl<H: HyperCraftHal> IOBitmap<H> {
             self.set_intercept(port, intercept)
         }
     }
+
+    /// Intercept or pass through every port.
+    pub fn set_intercept_all(&mut self, intercept: bool) {
+        let byte = if intercept { u8::MAX } else { 0 };
+        self.io_bitmap_a_frame.fill(byte);
+        self.io_bitmap_b_frame.fill(byte);
+    }
 }
 
 #[derive(Debug)]
@@ -152,6 +160,22 @@ impl<H: HyperCraftHal> MsrBitmap<H> {
         }
     }
 
+    /// Intercept or pass through every MSR the bitmap covers.
+    pub fn set_intercept_all(&mut self, intercept: bool) {
+        self.frame.fill(if intercept { u8::MAX } else { 0 });
+    }
+
+    /// Intercept or pass through reads and writes of the MSRs of `range`. MSRs outside of
+    /// `0..0x2000` and `0xc000_0000..0xc000_2000` always cause vm-exits and are skipped.
+    pub fn set_intercept_of_range(&mut self, range: Range<u32>, intercept: bool) {
+        for covered in [0..0x2000, 0xc000_0000..0xc000_2000] {
+            for msr in range.start.max(covered.start)..range.end.min(covered.end) {
+                self.set_intercept(msr, false, intercept);
+                self.set_intercept(msr, true, intercept);
+            }
+        }
+    }
+
     pub fn set_read_intercept(&mut self, msr: u32, intercept: bool) {
         self.set_intercept(msr, false, intercept);
     }
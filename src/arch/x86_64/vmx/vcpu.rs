@@ -1,13 +1,15 @@
+use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
 use alloc::collections::VecDeque;
 use core::fmt::{Debug, Formatter, Result};
-use core::{arch::asm, mem::size_of};
+use core::ops::Range;
+use core::{arch::asm, mem::size_of, ptr::NonNull};
 use x86::vmx::vmcs::guest::VMX_PREEMPTION_TIMER_VALUE;
 use x86_64::registers::debug;
 
 use bit_field::BitField;
 use raw_cpuid::CpuId;
 use x86::bits64::vmx;
-use x86::controlregs::{xcr0 as xcr0_read, xcr0_write, Xcr0};
+use x86::controlregs::{cr2, cr2_write, xcr0 as xcr0_read, xcr0_write, Xcr0};
 use x86::dtables::{self, DescriptorTablePointer};
 use x86::segmentation::SegmentSelector;
 use x86_64::registers::control::{Cr0, Cr0Flags, Cr3, Cr4, Cr4Flags, EferFlags};
@@ -24,19 +26,146 @@ use super::vmcs::{
 use super::LinuxContext;
 use super::VmxPerCpuState;
 use crate::arch::{
-    ept::GuestPageWalkInfo, memory::NestedPageFaultInfo, msr::Msr, regs::GeneralRegisters,
+    memory::{NestedPageFaultInfo, PhysFrame},
+    msr::Msr,
+    regs::GeneralRegisters,
+    GuestPageWalkInfo,
 };
+use crate::memory::PAGE_SIZE_4K;
 use crate::{
-    GuestPhysAddr, GuestVirtAddr, HostPhysAddr, HyperCraftHal, HyperError, HyperResult, VmxExitInfo,
+    GuestFault, GuestPhysAddr, GuestVirtAddr, HostPhysAddr, HyperCraftHal, HyperError, HyperResult,
+    PendingRead, SnapshotReader, SnapshotWriter, VCpuTrait, VmxExitInfo,
 };
 
 static mut VMX_PREEMPTION_TIMER_SET_VALUE: u32 = 1000_000;
 
+/// Number of entries of the Page-Modification Log.
+const PML_ENTRIES: u16 = 512;
+/// Vector of the page-fault exception.
+const PF_VECTOR: u8 = 14;
+
+/// Guest registers the VMCS doesn't switch: `XCR0`, `IA32_XSS`, the x87/SSE/AVX state, `CR2`
+/// and the MSRs of [`SWITCHED_MSRS`].
 pub struct XState {
     host_xcr0: u64,
     guest_xcr0: u64,
     host_xss: u64,
     guest_xss: u64,
+    guest_cr2: u64,
+    host_msrs: [u64; SWITCHED_MSRS.len()],
+    guest_msrs: [u64; SWITCHED_MSRS.len()],
+    host_area: XSaveArea,
+    guest_area: XSaveArea,
+}
+
+/// MSRs of the `syscall` instruction and `swapgs`, which the guest and host each set up.
+const SWITCHED_MSRS: [Msr; 4] = [
+    Msr::IA32_STAR,
+    Msr::IA32_LSTAR,
+    Msr::IA32_FMASK,
+    Msr::IA32_KERNEL_GSBASE,
+];
+
+/// Size of the legacy region and header of an XSAVE area.
+const XSAVE_HEADER_END: usize = 576;
+/// Offset of `XSTATE_BV` in an XSAVE area.
+const XSAVE_XSTATE_BV: usize = 512;
+/// Offset of `MXCSR` in an XSAVE area.
+const XSAVE_MXCSR: usize = 24;
+/// `MXCSR` at reset, with every SIMD floating-point exception masked.
+const MXCSR_DEFAULT: u32 = 0x1f80;
+
+/// A standard-format XSAVE area, large enough for every state component of the processor.
+struct XSaveArea {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+// The area is owned like a `Box`.
+unsafe impl Send for XSaveArea {}
+unsafe impl Sync for XSaveArea {}
+
+impl XSaveArea {
+    /// An area holding the initial state of every component.
+    fn new() -> Self {
+        let size = CpuId::new()
+            .get_extended_state_info()
+            .map_or(XSAVE_HEADER_END, |info| {
+                info.xsave_area_size_supported_features() as usize
+            });
+        let layout = Layout::from_size_align(size.max(XSAVE_HEADER_END), 64).unwrap();
+        let ptr = NonNull::new(unsafe { alloc_zeroed(layout) })
+            .unwrap_or_else(|| handle_alloc_error(layout));
+        let mut area = Self { ptr, layout };
+        // a zero header puts the components in their initial state, except `MXCSR`
+        area.bytes_mut()[XSAVE_MXCSR..XSAVE_MXCSR + 4]
+            .copy_from_slice(&MXCSR_DEFAULT.to_le_bytes());
+        area
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+
+    /// Saves the components enabled in `XCR0`.
+    unsafe fn save(&mut self) {
+        asm!(
+            "xsave64 [{}]",
+            in(reg) self.ptr.as_ptr(),
+            in("eax") u32::MAX,
+            in("edx") u32::MAX,
+            options(nostack),
+        );
+    }
+
+    /// Restores the components enabled in `XCR0`, which must be `xcr0`. Those saved while
+    /// `XCR0` held more are dropped, as `xrstor` faults on them.
+    unsafe fn restore(&mut self, xcr0: u64) {
+        let xstate_bv = &mut self.bytes_mut()[XSAVE_XSTATE_BV..XSAVE_XSTATE_BV + 8];
+        let bv = u64::from_le_bytes(xstate_bv.try_into().unwrap()) & xcr0;
+        xstate_bv.copy_from_slice(&bv.to_le_bytes());
+        asm!(
+            "xrstor64 [{}]",
+            in(reg) self.ptr.as_ptr(),
+            in("eax") u32::MAX,
+            in("edx") u32::MAX,
+            options(nostack),
+        );
+    }
+
+    /// Checks that `bytes`, an area saved by another vCPU, can be restored here: no larger
+    /// than this one, in the standard format and with a valid `MXCSR`.
+    fn validate(&self, bytes: &[u8]) -> HyperResult {
+        if bytes.len() < XSAVE_HEADER_END || bytes.len() > self.layout.size() {
+            return Err(HyperError::DecodeError);
+        }
+        let mxcsr = u32::from_le_bytes(bytes[XSAVE_MXCSR..XSAVE_MXCSR + 4].try_into().unwrap());
+        if bytes[XSAVE_XSTATE_BV + 8..XSAVE_HEADER_END]
+            .iter()
+            .any(|&b| b != 0)
+            || mxcsr >> 16 != 0
+        {
+            return Err(HyperError::DecodeError);
+        }
+        Ok(())
+    }
+
+    /// Replaces the area by `bytes`, checked by [`XSaveArea::validate`].
+    fn load(&mut self, bytes: &[u8]) {
+        let area = self.bytes_mut();
+        area[..bytes.len()].copy_from_slice(bytes);
+        area[bytes.len()..].fill(0);
+    }
+}
+
+impl Drop for XSaveArea {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr.as_ptr(), self.layout) };
+    }
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -58,6 +187,63 @@ impl XState {
             guest_xcr0: xcr0,
             host_xss: xss,
             guest_xss: xss,
+            guest_cr2: 0,
+            host_msrs: [0; SWITCHED_MSRS.len()],
+            guest_msrs: [0; SWITCHED_MSRS.len()],
+            host_area: XSaveArea::new(),
+            guest_area: XSaveArea::new(),
+        }
+    }
+
+    /// Like [`XState::new`], but the guest starts with the registers of the current
+    /// processor: a type 1.5 guest is the host itself.
+    fn inherit_current() -> Self {
+        let mut xstate = Self::new();
+        unsafe {
+            xstate.guest_area.save();
+            xstate.guest_cr2 = cr2() as u64;
+        }
+        for (msr, val) in SWITCHED_MSRS.iter().zip(&mut xstate.guest_msrs) {
+            *val = msr.read();
+        }
+        xstate
+    }
+
+    unsafe fn load_guest_xcrs(&self) {
+        xcr0_write(Xcr0::from_bits_unchecked(self.guest_xcr0));
+        Msr::IA32_XSS.write(self.guest_xss);
+    }
+
+    unsafe fn load_host_xcrs(&self) {
+        xcr0_write(Xcr0::from_bits_unchecked(self.host_xcr0));
+        Msr::IA32_XSS.write(self.host_xss);
+    }
+
+    /// Switch to the guest registers before VM entry, saving those of the host.
+    unsafe fn enter_guest(&mut self) {
+        self.host_area.save();
+        for (msr, val) in SWITCHED_MSRS.iter().zip(&mut self.host_msrs) {
+            *val = msr.read();
+        }
+        self.load_guest_xcrs();
+        self.guest_area.restore(self.guest_xcr0);
+        for (msr, &val) in SWITCHED_MSRS.iter().zip(&self.guest_msrs) {
+            msr.write(val);
+        }
+        cr2_write(self.guest_cr2);
+    }
+
+    /// Switch back to the host registers after VM exit, saving those of the guest.
+    unsafe fn exit_guest(&mut self) {
+        self.guest_cr2 = cr2() as u64;
+        for (msr, val) in SWITCHED_MSRS.iter().zip(&mut self.guest_msrs) {
+            *val = msr.read();
+        }
+        self.guest_area.save();
+        self.load_host_xcrs();
+        self.host_area.restore(self.host_xcr0);
+        for (msr, &val) in SWITCHED_MSRS.iter().zip(&self.host_msrs) {
+            msr.write(val);
         }
     }
 
@@ -69,6 +255,103 @@ impl XState {
 const MSR_IA32_EFER_LMA_BIT: u64 = 1 << 10;
 const CR0_PE: usize = 1 << 0;
 
+/// VMCS guest-state fields saved in a snapshot, besides the registers.
+const SNAPSHOT_GUEST16: [VmcsGuest16; 8] = {
+    use VmcsGuest16::*;
+    [
+        ES_SELECTOR,
+        CS_SELECTOR,
+        SS_SELECTOR,
+        DS_SELECTOR,
+        FS_SELECTOR,
+        GS_SELECTOR,
+        LDTR_SELECTOR,
+        TR_SELECTOR,
+    ]
+};
+const SNAPSHOT_GUEST32: [VmcsGuest32; 21] = {
+    use VmcsGuest32::*;
+    [
+        ES_LIMIT,
+        CS_LIMIT,
+        SS_LIMIT,
+        DS_LIMIT,
+        FS_LIMIT,
+        GS_LIMIT,
+        LDTR_LIMIT,
+        TR_LIMIT,
+        GDTR_LIMIT,
+        IDTR_LIMIT,
+        ES_ACCESS_RIGHTS,
+        CS_ACCESS_RIGHTS,
+        SS_ACCESS_RIGHTS,
+        DS_ACCESS_RIGHTS,
+        FS_ACCESS_RIGHTS,
+        GS_ACCESS_RIGHTS,
+        LDTR_ACCESS_RIGHTS,
+        TR_ACCESS_RIGHTS,
+        INTERRUPTIBILITY_STATE,
+        ACTIVITY_STATE,
+        IA32_SYSENTER_CS,
+    ]
+};
+const SNAPSHOT_GUEST64: [VmcsGuest64; 7] = {
+    use VmcsGuest64::*;
+    [
+        IA32_DEBUGCTL,
+        IA32_PAT,
+        IA32_EFER,
+        PDPTE0,
+        PDPTE1,
+        PDPTE2,
+        PDPTE3,
+    ]
+};
+const SNAPSHOT_GUESTNW: [VmcsGuestNW; 19] = {
+    use VmcsGuestNW::*;
+    [
+        CR0,
+        CR3,
+        CR4,
+        ES_BASE,
+        CS_BASE,
+        SS_BASE,
+        DS_BASE,
+        FS_BASE,
+        GS_BASE,
+        LDTR_BASE,
+        TR_BASE,
+        GDTR_BASE,
+        IDTR_BASE,
+        DR7,
+        RIP,
+        RFLAGS,
+        PENDING_DBG_EXCEPTIONS,
+        IA32_SYSENTER_ESP,
+        IA32_SYSENTER_EIP,
+    ]
+};
+/// Control fields that hold guest state: the CR0/CR4 shadows, whether the guest is in IA-32e
+/// mode and the event being injected.
+const SNAPSHOT_CONTROL32: [VmcsControl32; 4] = {
+    use VmcsControl32::*;
+    [
+        VMENTRY_CONTROLS,
+        VMENTRY_INTERRUPTION_INFO_FIELD,
+        VMENTRY_EXCEPTION_ERR_CODE,
+        VMENTRY_INSTRUCTION_LEN,
+    ]
+};
+const SNAPSHOT_CONTROLNW: [VmcsControlNW; 4] = {
+    use VmcsControlNW::*;
+    [
+        CR0_GUEST_HOST_MASK,
+        CR4_GUEST_HOST_MASK,
+        CR0_READ_SHADOW,
+        CR4_READ_SHADOW,
+    ]
+};
+
 /// A virtual CPU within a guest.
 #[repr(C)]
 pub struct VmxVcpu<H: HyperCraftHal> {
@@ -84,6 +367,13 @@ pub struct VmxVcpu<H: HyperCraftHal> {
     pending_events: VecDeque<(u8, Option<u32>)>,
     xstate: XState,
     is_host: bool,
+    pending_read: Option<PendingRead>,
+    entry: GuestPhysAddr,
+    boot_arg: usize,
+    /// Page-Modification Log, while the writes of the guest are logged.
+    pml_buffer: Option<PhysFrame<H>>,
+    /// Faulting address loaded into `CR2` when the queued `#PF` is injected.
+    pending_cr2: Option<usize>,
 }
 
 impl<H: HyperCraftHal> VmxVcpu<H> {
@@ -106,6 +396,11 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
             pending_events: VecDeque::with_capacity(8),
             xstate: XState::new(),
             is_host: false,
+            pending_read: None,
+            entry,
+            boot_arg: 0,
+            pml_buffer: None,
+            pending_cr2: None,
         };
         // Todo: remove these functions.
         vcpu.setup_io_bitmap()?;
@@ -115,11 +410,6 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
         Ok(vcpu)
     }
 
-    /// Get the identifier of this [`VmxVcpu`].
-    pub fn vcpu_id(&self) -> usize {
-        self.vcpu_id
-    }
-
     /// Bind this [`VmxVcpu`] to current logical processor.
     pub fn bind_to_current_processor(&self) -> HyperResult {
         unsafe {
@@ -163,7 +453,7 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
         }
 
         // Run guest
-        self.load_guest_xstate();
+        unsafe { self.xstate.enter_guest() };
         unsafe {
             if self.launched {
                 self.vmx_resume();
@@ -176,29 +466,26 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
                 self.vmx_launch();
             }
         }
-        self.load_host_xstate();
+        unsafe { self.xstate.exit_guest() };
 
         // Handle vm-exits
-        let exit_info = self.exit_info().unwrap();
+        let exit_info = vmcs::exit_info().unwrap();
         trace!("VM exit: {:#x?}", exit_info);
 
         match self.builtin_vmexit_handler(&exit_info) {
-            Some(result) => {
-                if result.is_err() {
-                    panic!("VmxVcpu failed to handle a VM-exit that should be handled by itself: {:?}, error {:?}, vcpu: {:#x?}", exit_info.exit_reason, result.unwrap_err(), self);
-                }
-
-                None
+            Some(Ok(())) => None,
+            Some(Err(e)) => {
+                // e.g. a guest access to a control register that is not allowed
+                warn!(
+                    "VmxVcpu failed to handle a VM-exit that should be handled by itself: {:?}, error {:?}",
+                    exit_info.exit_reason, e
+                );
+                Some(exit_info)
             }
             None => Some(exit_info),
         }
     }
 
-    /// Basic information about VM exits.
-    pub fn exit_info(&self) -> HyperResult<vmcs::VmxExitInfo> {
-        vmcs::exit_info()
-    }
-
     /// Raw information for VM Exits Due to Vectored Events, See SDM 25.9.2
     pub fn raw_interrupt_exit_info(&self) -> HyperResult<u32> {
         vmcs::raw_interrupt_exit_info()
@@ -272,8 +559,11 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
         let is_smep_on = (VmcsGuestNW::CR4.read().unwrap()
             & Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION.bits() as usize)
             != 0;
+        let ac = VmcsGuestNW::RFLAGS.read().unwrap() as u64
+            & x86_64::registers::rflags::RFlags::ALIGNMENT_CHECK.bits()
+            != 0;
         let width: u32;
-        if level == 4 || level == 3 {
+        if (3..=5).contains(&level) {
             width = 9;
         } else if level == 2 {
             width = 10;
@@ -295,6 +585,8 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
             nxe,
             is_smap_on,
             is_smep_on,
+            ac,
+            set_accessed_dirty: false,
         }
     }
 
@@ -303,11 +595,55 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
         VmcsGuestNW::RIP.read().unwrap()
     }
 
+    /// Guest rflags. (`RFLAGS`)
+    pub fn rflags(&self) -> usize {
+        VmcsGuestNW::RFLAGS.read().unwrap()
+    }
+
     /// Guest cs. (`cs`)
     pub fn cs(&self) -> u16 {
         VmcsGuest16::CS_SELECTOR.read().unwrap()
     }
 
+    /// Base address of the segment register `index`, in the order `ES`, `CS`, `SS`, `DS`, `FS`
+    /// and `GS`. Only `FS` and `GS` have one in 64-bit mode.
+    pub fn segment_base(&self, index: usize) -> usize {
+        let base = match index {
+            0 => VmcsGuestNW::ES_BASE,
+            1 => VmcsGuestNW::CS_BASE,
+            2 => VmcsGuestNW::SS_BASE,
+            3 => VmcsGuestNW::DS_BASE,
+            4 => VmcsGuestNW::FS_BASE,
+            5 => VmcsGuestNW::GS_BASE,
+            _ => return 0,
+        };
+        if index < 4 && self.get_cpu_mode() == VmCpuMode::Mode64 {
+            return 0;
+        }
+        base.read().unwrap()
+    }
+
+    /// Default operand size in bits of the code the guest runs, from the mode and `CS.D`.
+    pub fn code_bitness(&self) -> u32 {
+        const CS_ACCESS_RIGHTS_D: u32 = 1 << 14;
+        match self.get_cpu_mode() {
+            VmCpuMode::Mode64 => 64,
+            VmCpuMode::Real => 16,
+            _ if VmcsGuest32::CS_ACCESS_RIGHTS.read().unwrap() & CS_ACCESS_RIGHTS_D != 0 => 32,
+            _ => 16,
+        }
+    }
+
+    /// Remember where the data of an emulated read goes once the VMM supplies it.
+    pub(crate) fn set_pending_read(&mut self, pending: PendingRead) {
+        self.pending_read = Some(pending);
+    }
+
+    /// Whether an emulated read is waiting for [`VCpuTrait::complete_read`].
+    pub(crate) fn has_pending_read(&self) -> bool {
+        self.pending_read.is_some()
+    }
+
     /// Advance guest `RIP` by `instr_len` bytes.
     pub fn advance_rip(&mut self, instr_len: u8) -> HyperResult {
         Ok(VmcsGuestNW::RIP.write(VmcsGuestNW::RIP.read()? + instr_len as usize)?)
@@ -341,12 +677,88 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
         Ok(())
     }
 
+    /// Start or stop Page-Modification Logging, which records the guest-physical address of
+    /// every page the guest dirties. (SDM Vol. 3C, Section 28.2.6)
+    pub(crate) fn set_pml(&mut self, enable: bool) -> HyperResult {
+        let bits = vmcs::controls::SecondaryControls::ENABLE_PML.bits();
+        let ctrl = VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS.read()?;
+        if enable {
+            let buffer = PhysFrame::alloc_zero()?;
+            VmcsControl64::PML_ADDR.write(buffer.start_paddr() as u64)?;
+            VmcsGuest16::PML_INDEX.write(PML_ENTRIES - 1)?;
+            VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS.write(ctrl | bits)?;
+            self.pml_buffer = Some(buffer);
+        } else {
+            VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS.write(ctrl & !bits)?;
+            self.pml_buffer = None;
+        }
+        Ok(())
+    }
+
+    /// Whether Page-Modification Logging is on.
+    pub(crate) fn pml_enabled(&self) -> bool {
+        self.pml_buffer.is_some()
+    }
+
+    /// Pass every guest-physical address logged since the last call to `mark` and empty the
+    /// Page-Modification Log.
+    pub(crate) fn drain_pml(&mut self, mut mark: impl FnMut(GuestPhysAddr)) -> HyperResult {
+        let Some(buffer) = &self.pml_buffer else {
+            return Ok(());
+        };
+        // the CPU fills the log downwards and leaves the index at the next free entry, which
+        // wraps around to 0xffff once the log is full
+        let next = VmcsGuest16::PML_INDEX.read()?;
+        let entries = buffer.as_mut_ptr() as *const u64;
+        for index in next.wrapping_add(1)..PML_ENTRIES {
+            let gpa = unsafe { entries.add(index as usize).read_volatile() } as usize;
+            mark(gpa & !(PAGE_SIZE_4K - 1));
+        }
+        VmcsGuest16::PML_INDEX.write(PML_ENTRIES - 1)?;
+        Ok(())
+    }
+
     /// Set I/O intercept by modifying I/O bitmap.
     pub fn set_io_intercept_of_range(&mut self, port_base: u32, count: u32, intercept: bool) {
         self.io_bitmap
             .set_intercept_of_range(port_base, count, intercept)
     }
 
+    /// Reprogram the I/O bitmap to intercept the ports of `ranges`, along with the ports
+    /// intercepted by default unless `passthrough` is set.
+    pub(crate) fn program_io_bitmap(
+        &mut self,
+        ranges: impl Iterator<Item = Range<u16>>,
+        passthrough: bool,
+    ) -> HyperResult {
+        self.io_bitmap.set_intercept_all(false);
+        if !passthrough {
+            self.setup_io_bitmap()?;
+        }
+        for range in ranges {
+            self.io_bitmap
+                .set_intercept_of_range(range.start as u32, range.len() as u32, true);
+        }
+        Ok(())
+    }
+
+    /// Reprogram the MSR bitmap to intercept reads and writes of the MSRs of `ranges`, along
+    /// with every other MSR if `intercept_all` is set, or with the MSRs intercepted by default.
+    pub(crate) fn program_msr_bitmap(
+        &mut self,
+        ranges: impl Iterator<Item = Range<u32>>,
+        intercept_all: bool,
+    ) -> HyperResult {
+        self.msr_bitmap.set_intercept_all(intercept_all);
+        if !intercept_all {
+            self.setup_msr_bitmap()?;
+        }
+        for range in ranges {
+            self.msr_bitmap.set_intercept_of_range(range, true);
+        }
+        Ok(())
+    }
+
     /// Set msr intercept by modifying msr bitmap.
     /// Todo: distinguish read and write.
     pub fn set_msr_intercept_of_range(&mut self, msr: u32, intercept: bool) {
@@ -355,6 +767,251 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
     }
 }
 
+impl<H: HyperCraftHal> VCpuTrait for VmxVcpu<H> {
+    /// Get the identifier of this [`VmxVcpu`].
+    fn vcpu_id(&self) -> usize {
+        self.vcpu_id
+    }
+
+    /// Set guest `RIP` and pass `boot_arg` in `RSI`, as the Linux 64-bit boot protocol expects.
+    ///
+    /// The vCPU must be bound to the current processor.
+    fn init(&mut self, entry: GuestPhysAddr, boot_arg: usize) -> HyperResult {
+        VmcsGuestNW::RIP.write(entry)?;
+        self.guest_regs.rsi = boot_arg as u64;
+        self.entry = entry;
+        self.boot_arg = boot_arg;
+        Ok(())
+    }
+
+    fn pc(&self) -> GuestVirtAddr {
+        self.rip()
+    }
+
+    fn set_pc(&mut self, pc: GuestVirtAddr) {
+        VmcsGuestNW::RIP.write(pc).unwrap()
+    }
+
+    /// `index` follows the x86 register encoding, so 4 is `RSP`.
+    fn gpr(&self, index: usize) -> usize {
+        match index {
+            4 => self.stack_pointer(),
+            0..=15 => self.guest_regs.get_reg_of_index(index as u8) as usize,
+            _ => 0,
+        }
+    }
+
+    fn set_gpr(&mut self, index: usize, val: usize) {
+        match index {
+            4 => self.set_stack_pointer(val),
+            0..=15 => self.guest_regs.set_reg_of_index(index as u8, val as u64),
+            _ => {}
+        }
+    }
+
+    /// Basic information about VM exits.
+    fn exit_info(&self) -> HyperResult<VmxExitInfo> {
+        vmcs::exit_info()
+    }
+
+    fn complete_read(&mut self, data: u64) -> HyperResult {
+        let pending = self.pending_read.take().ok_or(HyperError::BadState)?;
+        let old = self.gpr(pending.reg);
+        self.set_gpr(pending.reg, pending.apply(old, data));
+        Ok(())
+    }
+
+    /// Queue `#UD`, `#GP(0)` or `#PF`.
+    fn inject_fault(&mut self, fault: GuestFault) -> HyperResult {
+        const UD_VECTOR: u8 = 6;
+        const GP_VECTOR: u8 = 13;
+
+        match fault {
+            GuestFault::IllegalInstruction { .. } => self.queue_event(UD_VECTOR, None),
+            GuestFault::GeneralProtection { .. } | GuestFault::AccessFault { .. } => {
+                self.queue_event(GP_VECTOR, Some(0))
+            }
+            GuestFault::PageFault { fault, .. } => {
+                self.pending_cr2 = Some(fault.addr);
+                self.queue_event(PF_VECTOR, Some(fault.error_code));
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrite the VMCS guest state as [`VmxVcpu::new`] does. The vCPU must be bound to the
+    /// current processor.
+    fn reset(&mut self) -> HyperResult {
+        self.guest_regs = GeneralRegisters::default();
+        self.pending_events.clear();
+        self.pending_cr2 = None;
+        self.pending_read = None;
+        self.xstate = XState::new();
+        VmcsControl32::VMENTRY_INTERRUPTION_INFO_FIELD.write(0)?;
+        self.setup_vmcs_guest(self.entry)?;
+        self.init(self.entry, self.boot_arg)
+    }
+
+    /// Save the general-purpose registers, the VMCS guest state, pending events and the guest
+    /// registers of [`XState`]. The vCPU must be bound to the current processor.
+    fn save_state(&self, w: &mut SnapshotWriter) -> HyperResult {
+        if self.has_pending_read() {
+            return Err(HyperError::BadState);
+        }
+        // `RSP` comes from the VMCS
+        (0..16).for_each(|index| w.put_usize(self.gpr(index)));
+        for field in SNAPSHOT_GUEST16 {
+            w.put_u16(field.read()?);
+        }
+        for field in SNAPSHOT_GUEST32 {
+            w.put_u32(field.read()?);
+        }
+        for field in SNAPSHOT_GUEST64 {
+            w.put_u64(field.read()?);
+        }
+        for field in SNAPSHOT_GUESTNW {
+            w.put_usize(field.read()?);
+        }
+        for field in SNAPSHOT_CONTROL32 {
+            w.put_u32(field.read()?);
+        }
+        for field in SNAPSHOT_CONTROLNW {
+            w.put_usize(field.read()?);
+        }
+        w.put_usize(self.pending_events.len());
+        for &(vector, err_code) in &self.pending_events {
+            w.put_u8(vector);
+            w.put_u8(err_code.is_some() as u8);
+            w.put_u32(err_code.unwrap_or(0));
+        }
+        w.put_u64(self.xstate.guest_xcr0);
+        w.put_u64(self.xstate.guest_xss);
+        w.put_u64(self.xstate.guest_cr2);
+        for &val in &self.xstate.guest_msrs {
+            w.put_u64(val);
+        }
+        w.put_bytes(self.xstate.guest_area.bytes());
+        Ok(())
+    }
+
+    /// The vCPU must be bound to the current processor.
+    fn load_state(&mut self, r: &mut SnapshotReader) -> HyperResult {
+        let state = self.read_state(r)?;
+        for (index, &val) in state.gprs.iter().enumerate() {
+            self.set_gpr(index, val);
+        }
+        for (field, val) in SNAPSHOT_GUEST16.into_iter().zip(state.guest16) {
+            field.write(val)?;
+        }
+        for (field, val) in SNAPSHOT_GUEST32.into_iter().zip(state.guest32) {
+            field.write(val)?;
+        }
+        for (field, val) in SNAPSHOT_GUEST64.into_iter().zip(state.guest64) {
+            field.write(val)?;
+        }
+        for (field, val) in SNAPSHOT_GUESTNW.into_iter().zip(state.guestnw) {
+            field.write(val)?;
+        }
+        for (field, val) in SNAPSHOT_CONTROL32.into_iter().zip(state.control32) {
+            field.write(val)?;
+        }
+        for (field, val) in SNAPSHOT_CONTROLNW.into_iter().zip(state.controlnw) {
+            field.write(val)?;
+        }
+        self.pending_events = state.pending_events;
+        self.xstate.guest_xcr0 = state.xcr0;
+        self.xstate.guest_xss = state.xss;
+        self.xstate.guest_cr2 = state.cr2;
+        self.xstate.guest_msrs = state.msrs;
+        self.xstate.guest_area.load(state.area);
+        self.pending_read = None;
+        Ok(())
+    }
+}
+
+/// The state written by [`VCpuTrait::save_state`], read back but not loaded yet.
+pub(crate) struct SavedState<'a> {
+    gprs: [usize; 16],
+    guest16: [u16; SNAPSHOT_GUEST16.len()],
+    guest32: [u32; SNAPSHOT_GUEST32.len()],
+    guest64: [u64; SNAPSHOT_GUEST64.len()],
+    guestnw: [usize; SNAPSHOT_GUESTNW.len()],
+    control32: [u32; SNAPSHOT_CONTROL32.len()],
+    controlnw: [usize; SNAPSHOT_CONTROLNW.len()],
+    pending_events: VecDeque<(u8, Option<u32>)>,
+    xcr0: u64,
+    xss: u64,
+    cr2: u64,
+    msrs: [u64; SWITCHED_MSRS.len()],
+    area: &'a [u8],
+}
+
+impl<H: HyperCraftHal> VmxVcpu<H> {
+    /// Reads the state written by [`VCpuTrait::save_state`] without loading it, failing if it
+    /// is malformed or can't be loaded on this processor. The vCPU needn't be bound.
+    pub(crate) fn read_state<'a>(&self, r: &mut SnapshotReader<'a>) -> HyperResult<SavedState<'a>> {
+        let mut gprs = [0; 16];
+        for val in &mut gprs {
+            *val = r.get_usize()?;
+        }
+        let mut guest16 = [0; SNAPSHOT_GUEST16.len()];
+        for val in &mut guest16 {
+            *val = r.get_u16()?;
+        }
+        let mut guest32 = [0; SNAPSHOT_GUEST32.len()];
+        for val in &mut guest32 {
+            *val = r.get_u32()?;
+        }
+        let mut guest64 = [0; SNAPSHOT_GUEST64.len()];
+        for val in &mut guest64 {
+            *val = r.get_u64()?;
+        }
+        let mut guestnw = [0; SNAPSHOT_GUESTNW.len()];
+        for val in &mut guestnw {
+            *val = r.get_usize()?;
+        }
+        let mut control32 = [0; SNAPSHOT_CONTROL32.len()];
+        for val in &mut control32 {
+            *val = r.get_u32()?;
+        }
+        let mut controlnw = [0; SNAPSHOT_CONTROLNW.len()];
+        for val in &mut controlnw {
+            *val = r.get_usize()?;
+        }
+        let mut pending_events = VecDeque::new();
+        for _ in 0..r.get_usize()? {
+            let vector = r.get_u8()?;
+            let has_err_code = r.get_u8()? != 0;
+            let err_code = r.get_u32()?;
+            pending_events.push_back((vector, has_err_code.then_some(err_code)));
+        }
+        let xcr0 = r.get_u64()?;
+        let xss = r.get_u64()?;
+        let cr2 = r.get_u64()?;
+        let mut msrs = [0; SWITCHED_MSRS.len()];
+        for val in &mut msrs {
+            *val = r.get_u64()?;
+        }
+        let area = r.get_bytes()?;
+        self.xstate.guest_area.validate(area)?;
+        Ok(SavedState {
+            gprs,
+            guest16,
+            guest32,
+            guest64,
+            guestnw,
+            control32,
+            controlnw,
+            pending_events,
+            xcr0,
+            xss,
+            cr2,
+            msrs,
+            area,
+        })
+    }
+}
+
 // Implementation of private methods
 impl<H: HyperCraftHal> VmxVcpu<H> {
     fn setup_io_bitmap(&mut self) -> HyperResult {
@@ -646,7 +1303,11 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
             if cr4 & Cr4Flags::PHYSICAL_ADDRESS_EXTENSION.bits() as usize != 0 {
                 // is long mode
                 if efer & EferFlags::LONG_MODE_ACTIVE.bits() != 0 {
-                    level = 4;
+                    level = if cr4 & Cr4Flags::L5_PAGING.bits() as usize != 0 {
+                        5
+                    } else {
+                        4
+                    };
                 } else {
                     level = 3;
                 }
@@ -679,8 +1340,13 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
             io_bitmap: IOBitmap::passthrough_all()?,
             msr_bitmap: MsrBitmap::passthrough_all()?,
             pending_events: VecDeque::with_capacity(8),
-            xstate: XState::new(),
+            xstate: XState::inherit_current(),
             is_host: true,
+            pending_read: None,
+            entry: 0,
+            boot_arg: 0,
+            pml_buffer: None,
+            pending_cr2: None,
         };
 
         vcpu.setup_type15_vmcs(ept_root, linux)?;
@@ -705,7 +1371,7 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
         }
 
         // Run guest
-        self.load_guest_xstate();
+        unsafe { self.xstate.enter_guest() };
         // debug!("vcpu set to linux regs: {:#x?}", self.guest_regs);
         unsafe {
             if self.launched {
@@ -720,20 +1386,22 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
                 self.vmx_launch();
             }
         }
-        self.load_host_xstate();
+        unsafe { self.xstate.exit_guest() };
 
         // Handle vm-exits
-        let exit_info = self.exit_info().unwrap();
+        let exit_info = vmcs::exit_info().unwrap();
         trace!("VM exit: {:#x?}", exit_info);
         // debug!("VM exit: {:#x?}", exit_info);
 
         match self.builtin_vmexit_handler(&exit_info) {
-            Some(result) => {
-                if result.is_err() {
-                    panic!("VmxVcpu failed to handle a VM-exit that should be handled by itself: {:?}, error {:?}, vcpu: {:#x?}", exit_info.exit_reason, result.unwrap_err(), self);
-                }
-
-                None
+            Some(Ok(())) => None,
+            Some(Err(e)) => {
+                // e.g. a guest access to a control register that is not allowed
+                warn!(
+                    "VmxVcpu failed to handle a VM-exit that should be handled by itself: {:?}, error {:?}",
+                    exit_info.exit_reason, e
+                );
+                Some(exit_info)
             }
             None => Some(exit_info),
         }
@@ -939,6 +1607,12 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
             // );
             if event.0 < 32 || self.allow_interrupt() {
                 // if it's an exception, or an interrupt that is not blocked, inject it directly.
+                if event.0 == PF_VECTOR {
+                    if let Some(cr2) = self.pending_cr2.take() {
+                        // the guest `CR2` is not part of the VMCS, it is loaded on VM entry
+                        self.xstate.guest_cr2 = cr2 as u64;
+                    }
+                }
                 vmcs::inject_event(event.0, event.1)?;
                 self.pending_events.pop_front();
             } else {
@@ -954,13 +1628,13 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
     /// Return the result or None if the vm-exit was not handled.
     fn builtin_vmexit_handler(&mut self, exit_info: &VmxExitInfo) -> Option<HyperResult> {
         if exit_info.entry_failure {
-            panic!("VM entry failed: {:#x?}", exit_info);
+            return None;
         }
 
         // Following vm-exits are handled here:
         // - interrupt window: turn off interrupt window;
         // - xsetbv: set guest xcr;
-        // - cr access: just panic;
+        // - cr access: move to cr0/cr4, others are refused;
         match exit_info.exit_reason {
             VmxExitReason::INTERRUPT_WINDOW => Some(self.set_interrupt_window(false)),
             VmxExitReason::PREEMPTION_TIMER => Some(self.handle_vmx_preemption_timer()),
@@ -1015,10 +1689,8 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
             _ => {}
         };
 
-        panic!(
-            "Guest's access to cr not allowed: {:#x?}, {:#x?}",
-            self, cr_access_info
-        );
+        warn!("Guest's access to cr not allowed: {:#x?}", cr_access_info);
+        Err(HyperError::NotSupported)
     }
 
     #[cfg(feature = "type1_5")]
@@ -1226,17 +1898,11 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
     }
 
     fn load_guest_xstate(&mut self) {
-        unsafe {
-            xcr0_write(Xcr0::from_bits_unchecked(self.xstate.guest_xcr0));
-            Msr::IA32_XSS.write(self.xstate.guest_xss);
-        }
+        unsafe { self.xstate.load_guest_xcrs() }
     }
 
     fn load_host_xstate(&mut self) {
-        unsafe {
-            xcr0_write(Xcr0::from_bits_unchecked(self.xstate.host_xcr0));
-            Msr::IA32_XSS.write(self.xstate.host_xss);
-        }
+        unsafe { self.xstate.load_host_xcrs() }
     }
 }
 
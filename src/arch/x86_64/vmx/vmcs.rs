@@ -542,6 +542,10 @@ pub struct VmxIoExitInfo {
     pub is_repeat: bool,
     /// Port number. (as specified in DX or in an immediate operand)
     pub port: u16,
+    /// Address size in bytes of a string instruction, 0 otherwise.
+    pub address_size: u8,
+    /// Guest-linear address of the memory operand of a string instruction, 0 otherwise.
+    pub linear_addr: usize,
 }
 
 /// Exit Qualification for Control Register Accesses. (SDM Vol. 3C, Section 28.2.1, Table 28-5)
@@ -721,12 +725,25 @@ pub fn inject_event(vector: u8, err_code: Option<u32>) -> HyperResult {
 pub fn io_exit_info() -> HyperResult<VmxIoExitInfo> {
     // SDM Vol. 3C, Section 27.2.1, Table 27-5
     let qualification = VmcsReadOnlyNW::EXIT_QUALIFICATION.read()?;
+    let is_string = qualification.get_bit(4);
+    let (address_size, linear_addr) = if is_string {
+        // SDM Vol. 3C, Section 27.2.5, Table 27-8: 0 = 16-bit, 1 = 32-bit, 2 = 64-bit
+        let instr_info = VmcsReadOnly32::VMEXIT_INSTRUCTION_INFO.read()?;
+        (
+            (2 << instr_info.get_bits(7..10)) as u8,
+            VmcsReadOnlyNW::GUEST_LINEAR_ADDR.read()?,
+        )
+    } else {
+        (0, 0)
+    };
     Ok(VmxIoExitInfo {
         access_size: qualification.get_bits(0..3) as u8 + 1,
         is_in: qualification.get_bit(3),
-        is_string: qualification.get_bit(4),
+        is_string,
         is_repeat: qualification.get_bit(5),
         port: qualification.get_bits(16..32) as u16,
+        address_size,
+        linear_addr,
     })
 }
 
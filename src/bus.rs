@@ -0,0 +1,386 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::ops::Range;
+use spin::Mutex;
+
+use crate::{HyperError, HyperResult, MmioOps, SnapshotReader, SnapshotWriter, VCpuTrait, VmExit};
+#[cfg(target_arch = "x86_64")]
+use crate::{PioOps, VirtMsrOps};
+
+/// Devices on non-overlapping ranges of an address space, found by address in O(log n).
+pub(crate) struct RangeMap<K, D: ?Sized> {
+    /// Devices by the start of their range, along with its end.
+    ranges: BTreeMap<K, (K, Arc<Mutex<D>>)>,
+}
+
+impl<K: Ord + Copy, D: ?Sized> RangeMap<K, D> {
+    pub fn new() -> Self {
+        Self {
+            ranges: BTreeMap::new(),
+        }
+    }
+
+    /// Adds `device` on `range`, which must not be empty nor overlap another device.
+    pub fn insert(&mut self, range: Range<K>, device: Arc<Mutex<D>>) -> HyperResult {
+        if range.start >= range.end {
+            return Err(HyperError::InvalidParam);
+        }
+        // the last range starting below our end is the only one that can overlap
+        if let Some((_, (end, _))) = self.ranges.range(..range.end).next_back() {
+            if *end > range.start {
+                return Err(HyperError::InvalidParam);
+            }
+        }
+        self.ranges.insert(range.start, (range.end, device));
+        Ok(())
+    }
+
+    /// Removes the device whose range starts at `start`.
+    pub fn remove(&mut self, start: K) -> HyperResult<Arc<Mutex<D>>> {
+        self.ranges
+            .remove(&start)
+            .map(|(_, device)| device)
+            .ok_or(HyperError::NotFound)
+    }
+
+    /// The device whose range holds `addr`.
+    pub fn get(&self, addr: K) -> Option<&Arc<Mutex<D>>> {
+        let (_, (end, device)) = self.ranges.range(..=addr).next_back()?;
+        (addr < *end).then_some(device)
+    }
+
+    /// The ranges of every device, in address order.
+    pub fn ranges(&self) -> impl Iterator<Item = Range<K>> + '_ {
+        self.ranges.iter().map(|(&start, &(end, _))| start..end)
+    }
+
+    /// Every device along with the start of its range, in address order.
+    pub fn iter(&self) -> impl Iterator<Item = (K, &Arc<Mutex<D>>)> + '_ {
+        self.ranges
+            .iter()
+            .map(|(&start, (_, device))| (start, device))
+    }
+
+    /// The device whose range starts at `start`.
+    pub fn get_at(&self, start: K) -> Option<&Arc<Mutex<D>>> {
+        self.ranges.get(&start).map(|(_, device)| device)
+    }
+}
+
+impl<K: Ord + Copy, D: ?Sized> Default for RangeMap<K, D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Dispatches guest MMIO accesses to the [`MmioOps`] device registered for the address.
+#[derive(Default)]
+pub struct MmioBus {
+    devices: RangeMap<u64, dyn MmioOps>,
+}
+
+impl MmioBus {
+    /// Creates an empty bus.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `device` on its [`MmioOps::mmio_range`]. Fails with
+    /// [`HyperError::InvalidParam`] if the range is empty or overlaps a registered device.
+    pub fn register(&mut self, device: Arc<Mutex<dyn MmioOps>>) -> HyperResult {
+        let range = device.lock().mmio_range();
+        self.devices.insert(range, device)
+    }
+
+    /// Unregisters the device whose range starts at `base` and gives it back.
+    pub fn unregister(&mut self, base: u64) -> HyperResult<Arc<Mutex<dyn MmioOps>>> {
+        self.devices.remove(base)
+    }
+
+    /// Whether a device is registered for `addr`.
+    pub fn contains(&self, addr: u64) -> bool {
+        self.devices.get(addr).is_some()
+    }
+
+    /// Reads `size` bytes at `addr` from the device there, if any.
+    pub fn read(&self, addr: u64, size: u8) -> Option<HyperResult<u64>> {
+        let device = self.devices.get(addr)?;
+        Some(device.lock().read(addr, size))
+    }
+
+    /// Writes `size` bytes of `value` at `addr` to the device there, if any.
+    pub fn write(&self, addr: u64, size: u8, value: u64) -> Option<HyperResult> {
+        let device = self.devices.get(addr)?;
+        Some(device.lock().write(addr, size, value))
+    }
+
+    /// Writes the state of every device into a VM snapshot, each one tagged with the start of
+    /// its range and framed so that it can only read back what it wrote.
+    pub(crate) fn save_state(&self, w: &mut SnapshotWriter) -> HyperResult {
+        for (base, device) in self.devices.iter() {
+            let mut state = SnapshotWriter::without_header();
+            device.lock().save_state(&mut state)?;
+            w.put_u64(base);
+            w.put_bytes(&state.into_bytes());
+        }
+        Ok(())
+    }
+
+    /// Loads the state written by [`MmioBus::save_state`]. Every device it holds must be
+    /// registered at the same address, see [`MmioBus::check_state`].
+    pub(crate) fn load_state(&self, r: &mut SnapshotReader) -> HyperResult {
+        while !r.is_empty() {
+            let device = self
+                .devices
+                .get_at(r.get_u64()?)
+                .ok_or(HyperError::NotFound)?;
+            let mut state = SnapshotReader::without_header(r.get_bytes()?);
+            device.lock().load_state(&mut state)?;
+        }
+        Ok(())
+    }
+
+    /// Checks that the state written by [`MmioBus::save_state`] is well-formed and that every
+    /// device it holds is registered at the same address.
+    pub(crate) fn check_state(&self, r: &mut SnapshotReader) -> HyperResult {
+        while !r.is_empty() {
+            self.devices
+                .get_at(r.get_u64()?)
+                .ok_or(HyperError::NotFound)?;
+            r.get_bytes()?;
+        }
+        Ok(())
+    }
+
+    /// Completes an MMIO exit of `vcpu` with the device registered for its address, returning
+    /// the exit to hand to the caller of [`crate::VmTrait::run_vcpu`] if there is none. A
+    /// device that fails turns the exit into [`VmExit::InternalError`].
+    pub(crate) fn dispatch<V: VCpuTrait>(&self, vcpu: &mut V, exit: VmExit) -> Option<VmExit> {
+        let result = match exit {
+            VmExit::MmioRead { addr, size } => self
+                .read(addr as u64, size)?
+                .and_then(|data| vcpu.complete_read(data)),
+            VmExit::MmioWrite { addr, size, data } => self.write(addr as u64, size, data)?,
+            exit => return Some(exit),
+        };
+        result.err().map(VmExit::InternalError)
+    }
+}
+
+/// Dispatches guest port I/O to the [`PioOps`] device registered for the port.
+#[cfg(target_arch = "x86_64")]
+#[derive(Default)]
+pub struct PioBus {
+    devices: RangeMap<u16, dyn PioOps>,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl PioBus {
+    /// Creates an empty bus.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `device` on its [`PioOps::port_range`]. Fails with
+    /// [`HyperError::InvalidParam`] if the range is empty or overlaps a registered device.
+    pub fn register(&mut self, device: Arc<Mutex<dyn PioOps>>) -> HyperResult {
+        let range = device.lock().port_range();
+        self.devices.insert(range, device)
+    }
+
+    /// Unregisters the device whose range starts at `base` and gives it back.
+    pub fn unregister(&mut self, base: u16) -> HyperResult<Arc<Mutex<dyn PioOps>>> {
+        self.devices.remove(base)
+    }
+
+    /// Whether a device is registered for `port`.
+    pub fn contains(&self, port: u16) -> bool {
+        self.devices.get(port).is_some()
+    }
+
+    /// The port ranges of the registered devices.
+    pub fn ranges(&self) -> impl Iterator<Item = Range<u16>> + '_ {
+        self.devices.ranges()
+    }
+
+    /// Reads `size` bytes from `port` of the device there, if any.
+    pub fn read(&self, port: u16, size: u8) -> Option<HyperResult<u32>> {
+        let device = self.devices.get(port)?;
+        Some(device.lock().read(port, size))
+    }
+
+    /// Writes `size` bytes of `value` to `port` of the device there, if any.
+    pub fn write(&self, port: u16, size: u8, value: u32) -> Option<HyperResult> {
+        let device = self.devices.get(port)?;
+        Some(device.lock().write(port, size, value))
+    }
+}
+
+/// What a VM does with an `rdmsr` or `wrmsr` of an MSR that no [`VirtMsrOps`] device handles.
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnhandledMsrPolicy {
+    /// Intercept every MSR and inject `#GP(0)` for the unhandled ones.
+    #[default]
+    InjectGp,
+    /// Intercept every MSR, drop writes to the unhandled ones and read them as 0.
+    IgnoreWrites,
+    /// Let the guest access the unhandled MSRs directly where the MSR bitmap passes them
+    /// through. The others still exit and get `#GP(0)`: the host never runs `rdmsr` or `wrmsr`
+    /// on behalf of the guest.
+    Passthrough,
+}
+
+/// Dispatches guest `rdmsr` and `wrmsr` to the [`VirtMsrOps`] device registered for the MSR.
+#[cfg(target_arch = "x86_64")]
+#[derive(Default)]
+pub struct MsrBus {
+    devices: RangeMap<u32, dyn VirtMsrOps>,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl MsrBus {
+    /// Creates an empty bus.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `device` on its [`VirtMsrOps::msr_range`]. Fails with
+    /// [`HyperError::InvalidParam`] if the range is empty or overlaps a registered device.
+    pub fn register(&mut self, device: Arc<Mutex<dyn VirtMsrOps>>) -> HyperResult {
+        let range = device.lock().msr_range();
+        self.devices.insert(range, device)
+    }
+
+    /// Unregisters the device whose range starts at `base` and gives it back.
+    pub fn unregister(&mut self, base: u32) -> HyperResult<Arc<Mutex<dyn VirtMsrOps>>> {
+        self.devices.remove(base)
+    }
+
+    /// Whether a device is registered for `msr`.
+    pub fn contains(&self, msr: u32) -> bool {
+        self.devices.get(msr).is_some()
+    }
+
+    /// The MSR ranges of the registered devices.
+    pub fn ranges(&self) -> impl Iterator<Item = Range<u32>> + '_ {
+        self.devices.ranges()
+    }
+
+    /// Reads `msr` from the device handling it, if any.
+    pub fn read(&self, msr: u32) -> Option<HyperResult<u64>> {
+        let device = self.devices.get(msr)?;
+        Some(device.lock().read(msr))
+    }
+
+    /// Writes `value` to `msr` of the device handling it, if any.
+    pub fn write(&self, msr: u32, value: u64) -> Option<HyperResult> {
+        let device = self.devices.get(msr)?;
+        Some(device.lock().write(msr, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlapping_ranges_are_refused() {
+        let mut map = RangeMap::<u64, u8>::new();
+        map.insert(0x1000..0x2000, Arc::new(Mutex::new(1))).unwrap();
+        map.insert(0x3000..0x4000, Arc::new(Mutex::new(2))).unwrap();
+        for range in [0x1800..0x1900, 0x0800..0x1001, 0x1fff..0x3001, 0x0..0x5000] {
+            assert_eq!(
+                map.insert(range, Arc::new(Mutex::new(0))),
+                Err(HyperError::InvalidParam)
+            );
+        }
+        assert_eq!(
+            map.insert(0x2000..0x2000, Arc::new(Mutex::new(0))),
+            Err(HyperError::InvalidParam)
+        );
+        map.insert(0x2000..0x3000, Arc::new(Mutex::new(3))).unwrap();
+        assert_eq!(
+            map.ranges().collect::<alloc::vec::Vec<_>>(),
+            [0x1000..0x2000, 0x2000..0x3000, 0x3000..0x4000]
+        );
+    }
+
+    /// A device with one register, saved in snapshots.
+    struct Register(Range<u64>, u64);
+
+    impl MmioOps for Register {
+        fn mmio_range(&self) -> Range<u64> {
+            self.0.clone()
+        }
+
+        fn read(&mut self, _addr: u64, _access_size: u8) -> HyperResult<u64> {
+            Ok(self.1)
+        }
+
+        fn write(&mut self, _addr: u64, _access_size: u8, value: u64) -> HyperResult {
+            self.1 = value;
+            Ok(())
+        }
+
+        fn save_state(&mut self, w: &mut SnapshotWriter) -> HyperResult {
+            w.put_u64(self.1);
+            Ok(())
+        }
+
+        fn load_state(&mut self, r: &mut SnapshotReader) -> HyperResult {
+            self.1 = r.get_u64()?;
+            Ok(())
+        }
+    }
+
+    fn mmio_bus(bases: &[u64]) -> MmioBus {
+        let mut bus = MmioBus::new();
+        for &base in bases {
+            bus.register(Arc::new(Mutex::new(Register(base..base + 8, 0))))
+                .unwrap();
+        }
+        bus
+    }
+
+    #[test]
+    fn mmio_device_state_round_trip() {
+        let bus = mmio_bus(&[0x1000, 0x2000]);
+        bus.write(0x1000, 8, 1).unwrap().unwrap();
+        bus.write(0x2000, 8, 2).unwrap().unwrap();
+        let mut w = SnapshotWriter::without_header();
+        bus.save_state(&mut w).unwrap();
+        let data = w.into_bytes();
+
+        let restored = mmio_bus(&[0x1000, 0x2000]);
+        restored
+            .check_state(&mut SnapshotReader::without_header(&data))
+            .unwrap();
+        restored
+            .load_state(&mut SnapshotReader::without_header(&data))
+            .unwrap();
+        assert_eq!(restored.read(0x1000, 8), Some(Ok(1)));
+        assert_eq!(restored.read(0x2000, 8), Some(Ok(2)));
+        // every device must be registered at the same address
+        let moved = mmio_bus(&[0x1000, 0x2004]);
+        assert_eq!(
+            moved.check_state(&mut SnapshotReader::without_header(&data)),
+            Err(HyperError::NotFound)
+        );
+    }
+
+    #[test]
+    fn lookup_and_unregister() {
+        let mut map = RangeMap::<u16, u8>::new();
+        map.insert(0x60..0x61, Arc::new(Mutex::new(1))).unwrap();
+        map.insert(0x3f8..0x400, Arc::new(Mutex::new(2))).unwrap();
+        assert_eq!(map.get(0x60).map(|dev| *dev.lock()), Some(1));
+        assert_eq!(map.get(0x3ff).map(|dev| *dev.lock()), Some(2));
+        assert!(map.get(0x61).is_none());
+        assert!(map.get(0x400).is_none());
+        assert!(map.get(0x5f).is_none());
+        assert_eq!(map.remove(0x3f9).err(), Some(HyperError::NotFound));
+        assert_eq!(*map.remove(0x3f8).unwrap().lock(), 2);
+        assert!(map.get(0x3f8).is_none());
+    }
+}
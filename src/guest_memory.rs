@@ -0,0 +1,917 @@
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::mem::{size_of, MaybeUninit};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::snapshot::SECTION_MEMORY;
+use crate::{
+    memory::PAGE_SIZE_4K, GuestPageTableTrait, GuestPhysAddr, GuestVirtAddr, HostPhysAddr,
+    HostVirtAddr, HyperCraftHal, HyperError, HyperResult, SnapshotReader, SnapshotWriter,
+};
+use page_table_entry::MappingFlags;
+
+/// A contiguous range of guest RAM backed by host pages the VM allocated itself.
+#[derive(Debug, Clone, Copy)]
+pub struct GuestMemoryRegion {
+    /// Guest physical address of the first byte.
+    pub gpa: GuestPhysAddr,
+    /// Host virtual address of the backing pages.
+    pub hva: HostVirtAddr,
+    /// Size in bytes, a multiple of the page size.
+    pub size: usize,
+    /// Flags the region is mapped with in the nested page table.
+    pub flags: MappingFlags,
+}
+
+impl GuestMemoryRegion {
+    fn num_pages(&self) -> usize {
+        self.size / PAGE_SIZE_4K
+    }
+
+    fn overlaps(&self, gpa: GuestPhysAddr, size: usize) -> bool {
+        gpa < self.gpa + self.size && self.gpa < gpa + size
+    }
+
+    fn contains(&self, gpa: GuestPhysAddr) -> bool {
+        (self.gpa..self.gpa + self.size).contains(&gpa)
+    }
+}
+
+/// Kind of a guest memory access, checked against the permissions of the guest page tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestAccess {
+    /// Data read.
+    Read,
+    /// Data write.
+    Write,
+    /// Instruction fetch.
+    Fetch,
+}
+
+/// A guest memory access that couldn't be done, reported as [`HyperError::GuestPageFault`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuestPageFault {
+    /// The first address that couldn't be accessed.
+    pub addr: usize,
+    /// Whether `addr` is a guest physical address the nested page table doesn't map or doesn't
+    /// allow the access to, rather than a guest virtual address the guest page tables don't
+    /// allow.
+    pub physical: bool,
+    /// The kind of access.
+    pub access: GuestAccess,
+    /// Syndrome to report to the guest: the `#PF` error code on x86_64, the fault status code
+    /// on aarch64. Unused on RISC-V and for physical addresses.
+    pub error_code: u32,
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Plain data that [`GuestMemory::read_obj`] and [`GuestMemory::write_obj`] may copy from and to
+/// guest memory: valid for any bit pattern and without padding. It is implemented for the
+/// integer types and arrays of them, and can't be implemented outside this crate.
+pub trait Pod: Copy + sealed::Sealed {}
+
+macro_rules! impl_pod {
+    ($($ty:ty),*) => {
+        $(
+            impl sealed::Sealed for $ty {}
+            impl Pod for $ty {}
+        )*
+    };
+}
+
+impl_pod!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+impl<T: Pod, const N: usize> sealed::Sealed for [T; N] {}
+impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// Translates guest virtual addresses the way the MMU of a vCPU does.
+pub trait GuestPageWalk {
+    /// Translates `gva` for an access of kind `access`, reading the guest page tables from
+    /// `memory` through `gpt`. Fails with [`HyperError::GuestPageFault`] where the guest would
+    /// take a page fault.
+    fn translate<H: HyperCraftHal, G: GuestPageTableTrait>(
+        &self,
+        memory: &GuestMemory<H>,
+        gpt: &G,
+        gva: GuestVirtAddr,
+        access: GuestAccess,
+    ) -> HyperResult<GuestPhysAddr>;
+}
+
+/// Pages the guest wrote since they were last collected, one bit per page of each region.
+struct DirtyLog {
+    /// Whether pages are mapped read-only until the guest writes them, rather than tracked by
+    /// the dirty flags of the nested page table.
+    write_protect: bool,
+    bitmaps: Vec<Vec<AtomicU64>>,
+}
+
+impl DirtyLog {
+    fn new_bitmap(region: &GuestMemoryRegion) -> Vec<AtomicU64> {
+        let mut bitmap = Vec::new();
+        bitmap.resize_with((region.num_pages() + 63) / 64, || AtomicU64::new(0));
+        bitmap
+    }
+
+    /// The flags a page of `region` is mapped with until the guest writes it.
+    fn armed_flags(&self, region: &GuestMemoryRegion) -> MappingFlags {
+        if self.write_protect {
+            region.flags - MappingFlags::WRITE
+        } else {
+            region.flags
+        }
+    }
+}
+
+/// The guest RAM owned by a VM.
+///
+/// Pages are zeroed when they are handed to the guest and again before they go back to
+/// [`HyperCraftHal::dealloc_pages`], so nothing a guest wrote is visible to whoever reuses them.
+pub struct GuestMemory<H: HyperCraftHal> {
+    regions: Vec<GuestMemoryRegion>,
+    dirty_log: Option<DirtyLog>,
+    marker: PhantomData<H>,
+}
+
+impl<H: HyperCraftHal> GuestMemory<H> {
+    /// Creates an empty set of guest RAM regions.
+    pub fn new() -> Self {
+        Self {
+            regions: Vec::new(),
+            dirty_log: None,
+            marker: PhantomData,
+        }
+    }
+
+    /// The regions allocated so far, in allocation order.
+    pub fn regions(&self) -> &[GuestMemoryRegion] {
+        &self.regions
+    }
+
+    /// Allocates `size` bytes of zeroed RAM and maps it at `gpa` in `gpt`, returning the host
+    /// virtual address of the backing pages.
+    pub fn alloc_region<G: GuestPageTableTrait>(
+        &mut self,
+        gpt: &mut G,
+        gpa: GuestPhysAddr,
+        size: usize,
+        flags: MappingFlags,
+    ) -> HyperResult<HostVirtAddr> {
+        if size == 0 || gpa % PAGE_SIZE_4K != 0 || size % PAGE_SIZE_4K != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        if self.regions.iter().any(|r| r.overlaps(gpa, size)) {
+            return Err(HyperError::InvalidParam);
+        }
+        let region = GuestMemoryRegion {
+            gpa,
+            hva: H::alloc_pages(size / PAGE_SIZE_4K).ok_or(HyperError::NoMemory)?,
+            size,
+            flags,
+        };
+        scrub(&region);
+        let map_flags = match &self.dirty_log {
+            Some(log) => log.armed_flags(&region),
+            None => flags,
+        };
+        if let Err(err) = gpt.map_region(gpa, hva_to_hpa::<H>(region.hva), size, map_flags) {
+            // Drop whatever part of the region did get mapped.
+            unmap(gpt, &region);
+            H::dealloc_pages(region.hva, region.num_pages());
+            return Err(err);
+        }
+        if let Some(log) = &mut self.dirty_log {
+            log.bitmaps.push(DirtyLog::new_bitmap(&region));
+        }
+        self.regions.push(region);
+        Ok(region.hva)
+    }
+
+    /// Starts logging which pages the guest writes.
+    ///
+    /// With `write_protect`, pages are mapped read-only in `gpt` until the guest writes them and
+    /// [`GuestMemory::handle_write_fault`] is called. Otherwise the hardware logs the writes
+    /// through the dirty flags of `gpt`, which remapping a page clears, and the caller reports
+    /// them with [`GuestMemory::mark_dirty`]. Either way, stale translations of `gpt` must be
+    /// flushed before the guest runs again.
+    pub(crate) fn start_dirty_log<G: GuestPageTableTrait>(
+        &mut self,
+        gpt: &mut G,
+        write_protect: bool,
+    ) -> HyperResult {
+        if self.dirty_log.is_some() {
+            return Err(HyperError::BadState);
+        }
+        let log = DirtyLog {
+            write_protect,
+            bitmaps: self.regions.iter().map(DirtyLog::new_bitmap).collect(),
+        };
+        for region in &self.regions {
+            for page in 0..region.num_pages() {
+                Self::remap(gpt, region, page, log.armed_flags(region))?;
+            }
+        }
+        self.dirty_log = Some(log);
+        Ok(())
+    }
+
+    /// Stops logging writes, mapping write-protected pages writable again.
+    pub(crate) fn stop_dirty_log<G: GuestPageTableTrait>(&mut self, gpt: &mut G) -> HyperResult {
+        let log = self.dirty_log.take().ok_or(HyperError::BadState)?;
+        if log.write_protect {
+            for region in &self.regions {
+                for page in 0..region.num_pages() {
+                    Self::remap(gpt, region, page, region.flags)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether writes to guest RAM are being logged.
+    pub(crate) fn is_dirty_logging(&self) -> bool {
+        self.dirty_log.is_some()
+    }
+
+    /// Whether writes are logged by write-protecting guest RAM rather than by the hardware.
+    pub(crate) fn is_write_protecting(&self) -> bool {
+        self.dirty_log.as_ref().is_some_and(|log| log.write_protect)
+    }
+
+    /// Records that the guest wrote the page at `gpa`. Returns `false` if writes aren't logged
+    /// or `gpa` isn't guest RAM.
+    pub(crate) fn mark_dirty(&self, gpa: GuestPhysAddr) -> bool {
+        let Some(log) = &self.dirty_log else {
+            return false;
+        };
+        match self.regions.iter().position(|r| r.contains(gpa)) {
+            Some(index) => {
+                let page = (gpa - self.regions[index].gpa) / PAGE_SIZE_4K;
+                log.bitmaps[index][page / 64].fetch_or(1 << (page % 64), Ordering::AcqRel);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Handles a guest write to a page write-protected for dirty logging: the page is marked
+    /// dirty and mapped writable again in `gpt`, so the write can be retried once the stale
+    /// translation is flushed. Returns `false` if `gpa` isn't such a page.
+    pub(crate) fn handle_write_fault<G: GuestPageTableTrait>(
+        &self,
+        gpt: &mut G,
+        gpa: GuestPhysAddr,
+    ) -> HyperResult<bool> {
+        if !self.is_write_protecting() {
+            return Ok(false);
+        }
+        let Some(region) = self.regions.iter().find(|r| r.contains(gpa)) else {
+            return Ok(false);
+        };
+        if !region.flags.contains(MappingFlags::WRITE) {
+            return Ok(false);
+        }
+        Self::remap(gpt, region, (gpa - region.gpa) / PAGE_SIZE_4K, region.flags)?;
+        self.mark_dirty(gpa);
+        Ok(true)
+    }
+
+    /// Returns a bitmap of the pages in `gpa..gpa + size` the guest wrote since the last call,
+    /// bit `i % 64` of word `i / 64` standing for the page at `gpa + i * 4K`, and clears it.
+    /// The pages are armed again in `gpt`, whose stale translations must be flushed before the
+    /// guest runs again.
+    pub(crate) fn take_dirty_log<G: GuestPageTableTrait>(
+        &mut self,
+        gpt: &mut G,
+        gpa: GuestPhysAddr,
+        size: usize,
+    ) -> HyperResult<Vec<u64>> {
+        if gpa % PAGE_SIZE_4K != 0 || size % PAGE_SIZE_4K != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        let log = self.dirty_log.as_ref().ok_or(HyperError::BadState)?;
+        let mut dirty = vec![0u64; (size / PAGE_SIZE_4K + 63) / 64];
+        for (region, bitmap) in self.regions.iter().zip(&log.bitmaps) {
+            if !region.overlaps(gpa, size) {
+                continue;
+            }
+            let start = gpa.max(region.gpa) - region.gpa;
+            let end = (gpa + size).min(region.gpa + region.size) - region.gpa;
+            for page in start / PAGE_SIZE_4K..end / PAGE_SIZE_4K {
+                let bit = 1 << (page % 64);
+                if bitmap[page / 64].fetch_and(!bit, Ordering::AcqRel) & bit != 0 {
+                    let index = (region.gpa + page * PAGE_SIZE_4K - gpa) / PAGE_SIZE_4K;
+                    dirty[index / 64] |= 1 << (index % 64);
+                    Self::remap(gpt, region, page, log.armed_flags(region))?;
+                }
+            }
+        }
+        Ok(dirty)
+    }
+
+    /// Maps page `page` of `region` again with `flags`, which also gives it clean dirty flags.
+    fn remap<G: GuestPageTableTrait>(
+        gpt: &mut G,
+        region: &GuestMemoryRegion,
+        page: usize,
+        flags: MappingFlags,
+    ) -> HyperResult {
+        let offset = page * PAGE_SIZE_4K;
+        gpt.unmap(region.gpa + offset)?;
+        gpt.map(
+            region.gpa + offset,
+            hva_to_hpa::<H>(region.hva + offset),
+            flags,
+        )
+    }
+
+    /// Appends one [`SECTION_MEMORY`] section per region, holding its layout and contents.
+    pub(crate) fn save(&self, w: &mut SnapshotWriter) -> HyperResult {
+        for region in &self.regions {
+            w.section(SECTION_MEMORY, |w| {
+                w.put_usize(region.gpa);
+                w.put_usize(region.flags.bits());
+                let contents =
+                    unsafe { core::slice::from_raw_parts(region.hva as *const u8, region.size) };
+                w.put_bytes(contents);
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Allocates the region saved in a [`SECTION_MEMORY`] section, maps it in `gpt` and fills
+    /// it with the saved contents.
+    pub(crate) fn load_region<G: GuestPageTableTrait>(
+        &mut self,
+        gpt: &mut G,
+        r: &mut SnapshotReader,
+    ) -> HyperResult {
+        let (gpa, flags, contents) = read_region(r)?;
+        let hva = self.alloc_region(gpt, gpa, contents.len(), flags)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(contents.as_ptr(), hva as *mut u8, contents.len())
+        };
+        Ok(())
+    }
+
+    /// Reads guest physical memory at `gpa` into `buf`, following the mapping of each page in
+    /// `gpt`, which must allow the guest to read it.
+    pub fn read_gpa<G: GuestPageTableTrait>(
+        &self,
+        gpt: &G,
+        gpa: GuestPhysAddr,
+        buf: &mut [u8],
+    ) -> HyperResult {
+        self.read_gpa_as(gpt, gpa, buf, GuestAccess::Read)
+    }
+
+    /// Writes `data` to guest physical memory at `gpa`, following the mapping of each page in
+    /// `gpt`, which must allow the guest to write it. The pages are logged as dirty, including
+    /// those write-protected for dirty logging. Nothing is written to pages past one that
+    /// faults.
+    pub fn write_gpa<G: GuestPageTableTrait>(
+        &self,
+        gpt: &G,
+        gpa: GuestPhysAddr,
+        data: &[u8],
+    ) -> HyperResult {
+        for_each_page(gpa, data.len(), |addr, offset, len| {
+            let hva = self.gpa_to_hva(gpt, addr, GuestAccess::Write)?;
+            let src = &data[offset..offset + len];
+            unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), hva as *mut u8, len) };
+            self.mark_dirty(addr);
+            Ok(())
+        })
+    }
+
+    /// Reads guest virtual memory at `gva` into `buf`, translating each page with `walk` and
+    /// checking that the guest may read it.
+    pub fn read_gva<G: GuestPageTableTrait>(
+        &self,
+        gpt: &G,
+        walk: &impl GuestPageWalk,
+        gva: GuestVirtAddr,
+        buf: &mut [u8],
+    ) -> HyperResult {
+        self.read_gva_as(gpt, walk, gva, buf, GuestAccess::Read)
+    }
+
+    /// Reads instruction bytes at `gva` into `buf`, like [`GuestMemory::read_gva`] but checking
+    /// that the guest may execute them.
+    pub fn fetch_gva<G: GuestPageTableTrait>(
+        &self,
+        gpt: &G,
+        walk: &impl GuestPageWalk,
+        gva: GuestVirtAddr,
+        buf: &mut [u8],
+    ) -> HyperResult {
+        self.read_gva_as(gpt, walk, gva, buf, GuestAccess::Fetch)
+    }
+
+    /// Writes `data` to guest virtual memory at `gva`, translating each page with `walk` and
+    /// checking that the guest may write it. Nothing is written if any page faults.
+    pub fn write_gva<G: GuestPageTableTrait>(
+        &self,
+        gpt: &G,
+        walk: &impl GuestPageWalk,
+        gva: GuestVirtAddr,
+        data: &[u8],
+    ) -> HyperResult {
+        // translate every page down to the host first so that a fault leaves the guest memory
+        // untouched
+        let mut pages = Vec::new();
+        for_each_page(gva, data.len(), |addr, _, _| {
+            let gpa = walk.translate(self, gpt, addr, GuestAccess::Write)?;
+            pages.push((gpa, self.gpa_to_hva(gpt, gpa, GuestAccess::Write)?));
+            Ok(())
+        })?;
+        let mut pages = pages.into_iter();
+        for_each_page(gva, data.len(), |_, offset, len| {
+            // one address per page, as collected above
+            let (gpa, hva) = pages.next().unwrap();
+            let src = &data[offset..offset + len];
+            unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), hva as *mut u8, len) };
+            self.mark_dirty(gpa);
+            Ok(())
+        })
+    }
+
+    /// Reads a `T` from guest physical memory at `gpa`.
+    pub fn read_obj<T: Pod, G: GuestPageTableTrait>(
+        &self,
+        gpt: &G,
+        gpa: GuestPhysAddr,
+    ) -> HyperResult<T> {
+        let mut obj = MaybeUninit::<T>::zeroed();
+        // any bit pattern is a valid `T`
+        let buf =
+            unsafe { core::slice::from_raw_parts_mut(obj.as_mut_ptr() as *mut u8, size_of::<T>()) };
+        self.read_gpa(gpt, gpa, buf)?;
+        Ok(unsafe { obj.assume_init() })
+    }
+
+    /// Writes `obj` to guest physical memory at `gpa`.
+    pub fn write_obj<T: Pod, G: GuestPageTableTrait>(
+        &self,
+        gpt: &G,
+        gpa: GuestPhysAddr,
+        obj: &T,
+    ) -> HyperResult {
+        // `T` has no padding, so every byte is initialized
+        let data =
+            unsafe { core::slice::from_raw_parts(obj as *const T as *const u8, size_of::<T>()) };
+        self.write_gpa(gpt, gpa, data)
+    }
+
+    /// Atomically sets `bits` in the naturally aligned `u32` at guest physical address `gpa`
+    /// and returns its previous value, as a page-table walker setting accessed and dirty bits
+    /// does. The page is logged as dirty if any bit changes.
+    pub fn fetch_or_u32<G: GuestPageTableTrait>(
+        &self,
+        gpt: &G,
+        gpa: GuestPhysAddr,
+        bits: u32,
+    ) -> HyperResult<u32> {
+        if gpa % size_of::<u32>() != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        let hva = self.gpa_to_hva(gpt, gpa, GuestAccess::Write)?;
+        let old = unsafe { &*(hva as *const AtomicU32) }.fetch_or(bits, Ordering::AcqRel);
+        if old & bits != bits {
+            self.mark_dirty(gpa);
+        }
+        Ok(old)
+    }
+
+    fn read_gva_as<G: GuestPageTableTrait>(
+        &self,
+        gpt: &G,
+        walk: &impl GuestPageWalk,
+        gva: GuestVirtAddr,
+        buf: &mut [u8],
+        access: GuestAccess,
+    ) -> HyperResult {
+        for_each_page(gva, buf.len(), |addr, offset, len| {
+            let gpa = walk.translate(self, gpt, addr, access)?;
+            self.read_gpa_as(gpt, gpa, &mut buf[offset..offset + len], access)
+        })
+    }
+
+    fn read_gpa_as<G: GuestPageTableTrait>(
+        &self,
+        gpt: &G,
+        gpa: GuestPhysAddr,
+        buf: &mut [u8],
+        access: GuestAccess,
+    ) -> HyperResult {
+        for_each_page(gpa, buf.len(), |addr, offset, len| {
+            let hva = self.gpa_to_hva(gpt, addr, access)?;
+            let dst = &mut buf[offset..offset + len];
+            unsafe { core::ptr::copy_nonoverlapping(hva as *const u8, dst.as_mut_ptr(), len) };
+            Ok(())
+        })
+    }
+
+    /// Host virtual address backing `gpa` in `gpt`, if its mapping allows `access`. Device
+    /// pages are never accessed, and a write is allowed to a page only write-protected for
+    /// dirty logging, which the caller logs.
+    fn gpa_to_hva<G: GuestPageTableTrait>(
+        &self,
+        gpt: &G,
+        gpa: GuestPhysAddr,
+        access: GuestAccess,
+    ) -> HyperResult<HostVirtAddr> {
+        let fault = || {
+            HyperError::GuestPageFault(GuestPageFault {
+                addr: gpa,
+                physical: true,
+                access,
+                error_code: 0,
+            })
+        };
+        let (hpa, flags) = gpt.query(gpa).map_err(|_| fault())?;
+        let allowed = match access {
+            GuestAccess::Read => flags.contains(MappingFlags::READ),
+            GuestAccess::Write => {
+                flags.contains(MappingFlags::WRITE) || self.is_write_protected(gpa)
+            }
+            GuestAccess::Fetch => flags.contains(MappingFlags::EXECUTE),
+        };
+        if !allowed || flags.contains(MappingFlags::DEVICE) {
+            return Err(fault());
+        }
+        Ok(hpa_to_hva::<H>(hpa))
+    }
+
+    /// Whether `gpa` is writable guest RAM mapped read-only until the guest writes it.
+    fn is_write_protected(&self, gpa: GuestPhysAddr) -> bool {
+        self.is_write_protecting()
+            && self
+                .regions
+                .iter()
+                .any(|r| r.contains(gpa) && r.flags.contains(MappingFlags::WRITE))
+    }
+
+    /// Unmaps every region from `gpt`, scrubs it and gives its pages back to the host.
+    pub fn release<G: GuestPageTableTrait>(&mut self, gpt: &mut G) {
+        if let Some(log) = &mut self.dirty_log {
+            log.bitmaps.clear();
+        }
+        for region in self.regions.drain(..) {
+            unmap(gpt, &region);
+            scrub(&region);
+            H::dealloc_pages(region.hva, region.num_pages());
+        }
+    }
+}
+
+impl<H: HyperCraftHal> Default for GuestMemory<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H: HyperCraftHal> Drop for GuestMemory<H> {
+    fn drop(&mut self) {
+        // The nested page table goes away together with us, no need to unmap.
+        for region in self.regions.drain(..) {
+            scrub(&region);
+            H::dealloc_pages(region.hva, region.num_pages());
+        }
+    }
+}
+
+/// Reads the layout and contents of the region saved in a [`SECTION_MEMORY`] section.
+fn read_region<'a>(
+    r: &mut SnapshotReader<'a>,
+) -> HyperResult<(GuestPhysAddr, MappingFlags, &'a [u8])> {
+    let gpa = r.get_usize()?;
+    let flags = MappingFlags::from_bits(r.get_usize()?).ok_or(HyperError::DecodeError)?;
+    Ok((gpa, flags, r.get_bytes()?))
+}
+
+/// The regions of a snapshot, checked one [`SECTION_MEMORY`] section at a time before the
+/// guest memory is released to restore it.
+#[derive(Default)]
+pub(crate) struct SavedRegions(Vec<(GuestPhysAddr, usize)>);
+
+impl SavedRegions {
+    /// Checks that the region of a [`SECTION_MEMORY`] section is well-formed, page-aligned,
+    /// not empty and doesn't overlap the regions checked before.
+    pub fn check(&mut self, r: &mut SnapshotReader) -> HyperResult {
+        let (gpa, _, contents) = read_region(r)?;
+        let size = contents.len();
+        if size == 0
+            || gpa % PAGE_SIZE_4K != 0
+            || size % PAGE_SIZE_4K != 0
+            || gpa.checked_add(size).is_none()
+            || self
+                .0
+                .iter()
+                .any(|&(start, len)| gpa < start + len && start < gpa + size)
+        {
+            return Err(HyperError::DecodeError);
+        }
+        self.0.push((gpa, size));
+        Ok(())
+    }
+}
+
+/// Calls `f` with the address, the offset from `addr` and the length of each piece of
+/// `addr..addr + len` that lies within one page.
+fn for_each_page(
+    addr: usize,
+    len: usize,
+    mut f: impl FnMut(usize, usize, usize) -> HyperResult,
+) -> HyperResult {
+    if len > 0 && addr.checked_add(len - 1).is_none() {
+        return Err(HyperError::InvalidParam);
+    }
+    let mut offset = 0;
+    while offset < len {
+        let piece = (PAGE_SIZE_4K - (addr + offset) % PAGE_SIZE_4K).min(len - offset);
+        f(addr + offset, offset, piece)?;
+        offset += piece;
+    }
+    Ok(())
+}
+
+fn scrub(region: &GuestMemoryRegion) {
+    unsafe { core::ptr::write_bytes(region.hva as *mut u8, 0, region.size) }
+}
+
+fn unmap<G: GuestPageTableTrait>(gpt: &mut G, region: &GuestMemoryRegion) {
+    for gpa in (region.gpa..region.gpa + region.size).step_by(PAGE_SIZE_4K) {
+        // Pages that were never mapped are fine to skip.
+        let _ = gpt.unmap(gpa);
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn hva_to_hpa<H: HyperCraftHal>(hva: HostVirtAddr) -> HostPhysAddr {
+    H::virt_to_phys(hva)
+}
+
+/// The host runs with an identity mapping on RISC-V and aarch64.
+#[cfg(not(target_arch = "x86_64"))]
+fn hva_to_hpa<H: HyperCraftHal>(hva: HostVirtAddr) -> HostPhysAddr {
+    hva
+}
+
+#[cfg(target_arch = "x86_64")]
+fn hpa_to_hva<H: HyperCraftHal>(hpa: HostPhysAddr) -> HostVirtAddr {
+    H::phys_to_virt(hpa)
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn hpa_to_hva<H: HyperCraftHal>(hpa: HostPhysAddr) -> HostVirtAddr {
+    hpa
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+    use alloc::collections::BTreeMap;
+
+    use super::*;
+
+    /// Pages come from the global allocator, physical addresses are virtual ones.
+    pub(crate) struct TestHal;
+
+    impl TestHal {
+        fn layout(num_pages: usize) -> Layout {
+            Layout::from_size_align(num_pages * PAGE_SIZE_4K, PAGE_SIZE_4K).unwrap()
+        }
+    }
+
+    impl HyperCraftHal for TestHal {
+        fn alloc_pages(num_pages: usize) -> Option<HostVirtAddr> {
+            Some(unsafe { alloc_zeroed(Self::layout(num_pages)) } as HostVirtAddr)
+        }
+
+        fn dealloc_pages(va: HostVirtAddr, num_pages: usize) {
+            unsafe { dealloc(va as *mut u8, Self::layout(num_pages)) }
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        fn phys_to_virt(pa: HostPhysAddr) -> HostVirtAddr {
+            pa
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        fn virt_to_phys(va: HostVirtAddr) -> HostPhysAddr {
+            va
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        fn current_time_nanos() -> u64 {
+            0
+        }
+    }
+
+    /// A nested page table of 4K pages.
+    pub(crate) struct TestGpt(BTreeMap<GuestPhysAddr, (HostPhysAddr, MappingFlags)>);
+
+    impl GuestPageTableTrait for TestGpt {
+        fn new() -> HyperResult<Self> {
+            Ok(Self(BTreeMap::new()))
+        }
+
+        fn map(
+            &mut self,
+            gpa: GuestPhysAddr,
+            hpa: HostPhysAddr,
+            flags: MappingFlags,
+        ) -> HyperResult {
+            self.0.insert(gpa, (hpa, flags));
+            Ok(())
+        }
+
+        fn map_region(
+            &mut self,
+            gpa: GuestPhysAddr,
+            hpa: HostPhysAddr,
+            size: usize,
+            flags: MappingFlags,
+        ) -> HyperResult {
+            for offset in (0..size).step_by(PAGE_SIZE_4K) {
+                self.map(gpa + offset, hpa + offset, flags)?;
+            }
+            Ok(())
+        }
+
+        fn unmap(&mut self, gpa: GuestPhysAddr) -> HyperResult {
+            self.0
+                .remove(&gpa)
+                .map(|_| ())
+                .ok_or(HyperError::InvalidParam)
+        }
+
+        fn translate(&self, gpa: GuestPhysAddr) -> HyperResult<HostPhysAddr> {
+            self.query(gpa).map(|(hpa, _)| hpa)
+        }
+
+        fn query(&self, gpa: GuestPhysAddr) -> HyperResult<(HostPhysAddr, MappingFlags)> {
+            let page = gpa & !(PAGE_SIZE_4K - 1);
+            let &(hpa, flags) = self.0.get(&page).ok_or(HyperError::PageFault)?;
+            Ok((hpa + gpa - page, flags))
+        }
+
+        fn token(&self) -> usize {
+            0
+        }
+    }
+
+    /// Guest virtual addresses are guest physical ones.
+    struct IdentityWalk;
+
+    impl GuestPageWalk for IdentityWalk {
+        fn translate<H: HyperCraftHal, G: GuestPageTableTrait>(
+            &self,
+            _memory: &GuestMemory<H>,
+            _gpt: &G,
+            gva: GuestVirtAddr,
+            _access: GuestAccess,
+        ) -> HyperResult<GuestPhysAddr> {
+            Ok(gva)
+        }
+    }
+
+    const ROM: GuestPhysAddr = 0x10000;
+    const RAM: GuestPhysAddr = 0x20000;
+    const RAM_SIZE: usize = 2 * PAGE_SIZE_4K;
+    const DEVICE: GuestPhysAddr = 0x30000;
+
+    /// `size` bytes of RAM at guest physical address 0, to lay out guest page tables in.
+    pub(crate) fn ram(size: usize) -> (GuestMemory<TestHal>, TestGpt) {
+        let mut memory = GuestMemory::new();
+        let mut gpt = TestGpt::new().unwrap();
+        let flags = MappingFlags::READ | MappingFlags::WRITE;
+        memory.alloc_region(&mut gpt, 0, size, flags).unwrap();
+        (memory, gpt)
+    }
+
+    /// Guest memory with a page of ROM, two pages of RAM and a device page.
+    fn memory() -> (GuestMemory<TestHal>, TestGpt) {
+        let mut memory = GuestMemory::new();
+        let mut gpt = TestGpt::new().unwrap();
+        let rom_flags = MappingFlags::READ | MappingFlags::EXECUTE;
+        memory
+            .alloc_region(&mut gpt, ROM, PAGE_SIZE_4K, rom_flags)
+            .unwrap();
+        let ram_flags = MappingFlags::READ | MappingFlags::WRITE;
+        let ram = memory
+            .alloc_region(&mut gpt, RAM, RAM_SIZE, ram_flags)
+            .unwrap();
+        let device_flags = ram_flags | MappingFlags::DEVICE;
+        gpt.map(DEVICE, hva_to_hpa::<TestHal>(ram), device_flags)
+            .unwrap();
+        (memory, gpt)
+    }
+
+    fn fault(addr: usize, access: GuestAccess) -> HyperResult {
+        Err(HyperError::GuestPageFault(GuestPageFault {
+            addr,
+            physical: true,
+            access,
+            error_code: 0,
+        }))
+    }
+
+    #[test]
+    fn nested_page_table_permissions_are_checked() {
+        let (memory, gpt) = memory();
+        let mut buf = [0u8; 4];
+        assert_eq!(memory.read_gpa(&gpt, ROM, &mut buf), Ok(()));
+        assert_eq!(
+            memory.write_gpa(&gpt, ROM, &buf),
+            fault(ROM, GuestAccess::Write)
+        );
+        assert_eq!(
+            memory.fetch_gva(&gpt, &IdentityWalk, RAM, &mut buf),
+            fault(RAM, GuestAccess::Fetch)
+        );
+        assert_eq!(
+            memory.read_gpa(&gpt, DEVICE, &mut buf),
+            fault(DEVICE, GuestAccess::Read)
+        );
+        // the piece on the unmapped page faults
+        let end = RAM + RAM_SIZE;
+        assert_eq!(
+            memory.write_gpa(&gpt, end - 2, &[1, 2, 3, 4]),
+            fault(end, GuestAccess::Write)
+        );
+        assert_eq!(memory.read_obj::<u16, _>(&gpt, end - 2), Ok(0x201));
+        // while through a guest page walk nothing is written
+        assert_eq!(
+            memory.write_gva(&gpt, &IdentityWalk, end - 2, &[5, 6, 7, 8]),
+            fault(end, GuestAccess::Write)
+        );
+        assert_eq!(memory.read_obj::<u16, _>(&gpt, end - 2), Ok(0x201));
+    }
+
+    #[test]
+    fn writes_to_write_protected_pages_are_logged() {
+        let (mut memory, mut gpt) = memory();
+        memory.start_dirty_log(&mut gpt, true).unwrap();
+        assert!(!gpt.query(RAM).unwrap().1.contains(MappingFlags::WRITE));
+        memory.write_obj(&gpt, RAM + 8, &0x1234_5678u32).unwrap();
+        assert_eq!(memory.read_obj::<u32, _>(&gpt, RAM + 8), Ok(0x1234_5678));
+        assert_eq!(memory.take_dirty_log(&mut gpt, RAM, RAM_SIZE), Ok(vec![1]));
+        // the guest can't write ROM, with or without dirty logging
+        assert_eq!(
+            memory.write_obj(&gpt, ROM, &0u8),
+            fault(ROM, GuestAccess::Write)
+        );
+    }
+
+    #[test]
+    fn saved_regions_are_checked() {
+        let (memory, _gpt) = memory();
+        let mut w = SnapshotWriter::without_header();
+        memory.save(&mut w).unwrap();
+        // the same regions again overlap, as do unaligned or empty ones
+        memory.save(&mut w).unwrap();
+        for (gpa, size) in [(RAM + 1, PAGE_SIZE_4K), (DEVICE, 1), (DEVICE, 0)] {
+            w.section(SECTION_MEMORY, |w| {
+                w.put_usize(gpa);
+                w.put_usize(MappingFlags::READ.bits());
+                w.put_bytes(&vec![0; size]);
+                Ok(())
+            })
+            .unwrap();
+        }
+        let data = w.into_bytes();
+        let mut r = SnapshotReader::without_header(&data);
+        let mut regions = SavedRegions::default();
+        let mut results = Vec::new();
+        while let Some((_, mut section)) = r.next_section().unwrap() {
+            results.push(regions.check(&mut section).is_ok());
+        }
+        assert_eq!(results, [true, true, false, false, false, false, false]);
+    }
+
+    #[test]
+    fn pieces_split_at_page_boundaries() {
+        let mut pieces = Vec::new();
+        for_each_page(0x1ffe, 0x1004, |addr, offset, len| {
+            pieces.push((addr, offset, len));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            pieces,
+            [(0x1ffe, 0, 2), (0x2000, 2, 0x1000), (0x3000, 0x1002, 2)]
+        );
+    }
+
+    #[test]
+    fn wrapping_range_is_refused() {
+        let result = for_each_page(usize::MAX, 2, |_, _, _| Ok(()));
+        assert_eq!(result, Err(HyperError::InvalidParam));
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{
     arch::VCpu, memory::PAGE_SIZE_4K, GuestPageTableTrait, HostPageNum, HostPhysAddr, HostVirtAddr,
-    HyperResult, VmExitInfo,
+    HyperResult, SnapshotReader, SnapshotWriter, VmExitInfo,
 };
 use iced_x86::Instruction;
 
@@ -67,6 +67,14 @@ pub trait PerCpuDevices<H: HyperCraftHal>: Sized {
     fn nmi_handler(&mut self, vcpu: &mut VCpu<H>) -> HyperResult<u32>;
     /// Checks whether there are some new events and injects them.
     fn check_events(&mut self, vcpu: &mut VCpu<H>) -> HyperResult;
+    /// Writes the device state into a VM snapshot. Stateless devices need not implement it.
+    fn save_state(&mut self, _w: &mut SnapshotWriter) -> HyperResult {
+        Ok(())
+    }
+    /// Loads the device state written by [`PerCpuDevices::save_state`].
+    fn load_state(&mut self, _r: &mut SnapshotReader) -> HyperResult {
+        Ok(())
+    }
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -81,6 +89,14 @@ pub trait PerVmDevices<H: HyperCraftHal>: Sized {
         exit_info: &VmExitInfo,
         instr: Option<Instruction>,
     ) -> Option<HyperResult>;
+    /// Writes the device state into a VM snapshot. Stateless devices need not implement it.
+    fn save_state(&mut self, _w: &mut SnapshotWriter) -> HyperResult {
+        Ok(())
+    }
+    /// Loads the device state written by [`PerVmDevices::save_state`].
+    fn load_state(&mut self, _r: &mut SnapshotReader) -> HyperResult {
+        Ok(())
+    }
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -113,6 +129,14 @@ pub trait MmioOps: Send + Sync {
     fn read(&mut self, addr: u64, access_size: u8) -> HyperResult<u64>;
     /// Write operation
     fn write(&mut self, addr: u64, access_size: u8, value: u64) -> HyperResult;
+    /// Writes the device state into a VM snapshot. Stateless devices need not implement it.
+    fn save_state(&mut self, _w: &mut SnapshotWriter) -> HyperResult {
+        Ok(())
+    }
+    /// Loads the device state written by [`MmioOps::save_state`].
+    fn load_state(&mut self, _r: &mut SnapshotReader) -> HyperResult {
+        Ok(())
+    }
 }
 
 /// Read data from Region to argument `data`,
@@ -36,37 +36,78 @@ mod arch;
 #[cfg(target_arch = "x86_64")]
 #[path = "arch/x86_64/mod.rs"]
 mod arch;
+// The RISC-V interrupt controller models, MMIO decoder and page walk don't depend on the ISA,
+// so their tests run on any host.
+#[cfg(all(test, not(target_arch = "riscv64")))]
+#[path = "arch/riscv/devices/mod.rs"]
+mod riscv_devices;
+#[cfg(all(test, not(target_arch = "riscv64")))]
+#[path = "arch/riscv/mmio.rs"]
+mod riscv_mmio;
+#[cfg(all(test, not(target_arch = "riscv64")))]
+#[path = "arch/riscv/page_walk.rs"]
+mod riscv_page_walk;
+// Nor do the aarch64 load/store decoder and page walk.
+#[cfg(all(test, not(target_arch = "aarch64")))]
+#[path = "arch/aarch64/emulate.rs"]
+mod aarch64_emulate;
+#[cfg(all(test, not(target_arch = "aarch64")))]
+#[path = "arch/aarch64/page_walk.rs"]
+mod aarch64_page_walk;
 
+mod bus;
+mod guest_memory;
 mod hal;
 mod memory;
+mod migration;
+mod snapshot;
 mod traits;
 mod vcpus;
+mod vmexit;
+mod vmstate;
 
 /// HyperCraft Result Define.
 pub type HyperResult<T = ()> = Result<T, HyperError>;
 
 #[cfg(not(target_arch = "aarch64"))]
-pub use arch::{init_hv_runtime, GprIndex, HyperCallMsg, VmExitInfo};
+pub use arch::{init_hv_runtime, GprIndex, HyperCallMsg};
 
-pub use arch::{NestedPageTable, PerCpu, VCpu, VM};
+#[cfg(target_arch = "riscv64")]
+pub use arch::{set_host_timer, AiaConfig, HartState, HostImsic};
+pub use arch::{GuestPageWalkInfo, NestedPageTable, PerCpu, VCpu, VmExitInfo, VM};
 
 #[cfg(all(target_arch = "x86_64", feature = "type1_5"))]
 pub use arch::LinuxContext;
-pub use hal::{HyperCraftHal, MmioOps, PioOps, RegionOps, VirtMsrOps};
+pub use bus::MmioBus;
+#[cfg(target_arch = "x86_64")]
+pub use bus::{MsrBus, PioBus, UnhandledMsrPolicy};
+pub use guest_memory::{
+    GuestAccess, GuestMemory, GuestMemoryRegion, GuestPageFault, GuestPageWalk, Pod,
+};
+pub use hal::{HyperCraftHal, MmioOps, RegionOps};
 #[cfg(target_arch = "x86_64")]
-pub use hal::{PerCpuDevices, PerVmDevices};
+pub use hal::{PerCpuDevices, PerVmDevices, PioOps, VirtMsrOps};
 pub use memory::{
     GuestPageNum, GuestPageTableTrait, GuestPhysAddr, GuestVirtAddr, HostPageNum, HostPhysAddr,
     HostVirtAddr,
 };
-pub use vcpus::VmCpus;
+pub use migration::{receive_vm, MigrationConfig, MigrationSender, MigrationStream};
+pub use snapshot::{SnapshotReader, SnapshotWriter, SNAPSHOT_VERSION};
+pub use traits::{VCpuTrait, VmTrait};
+pub use vcpus::{VCpuGuard, VmCpus};
+pub(crate) use vmexit::{handle_guest_fault, PendingRead};
+pub use vmexit::{GuestFault, GuestFaultPolicy, VmExit};
+pub(crate) use vmstate::AtomicVmState;
+pub use vmstate::VmState;
 
 #[cfg(target_arch = "aarch64")]
-pub use arch::lower_aarch64_synchronous;
+pub use arch::{init_el2, lower_aarch64_synchronous};
 
 use alloc::string::String;
 #[cfg(target_arch = "x86_64")]
-pub use arch::{GuestPageWalkInfo, VmxExitInfo, VmxExitReason, VmxInterruptionType};
+pub use arch::{decode_instruction, emulate_instruction, EmulatorContext};
+#[cfg(target_arch = "x86_64")]
+pub use arch::{VmxExitInfo, VmxExitReason, VmxInterruptionType};
 
 /// The error type for hypervisor operation failures.
 #[derive(Debug, PartialEq)]
@@ -91,6 +132,8 @@ pub enum HyperError {
     FetchFault,
     /// Page fault error.
     PageFault,
+    /// A guest memory access faulted at the given address.
+    GuestPageFault(GuestPageFault),
     /// Decode error.
     DecodeError,
     /// Disabled.
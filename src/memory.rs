@@ -1,4 +1,4 @@
-use crate::{HyperCraftHal, HyperResult};
+use crate::{HyperCraftHal, HyperError, HyperResult};
 use page_table_entry::MappingFlags;
 
 /// Guest physical address.
@@ -48,6 +48,14 @@ pub trait GuestPageTableTrait {
     /// `gpa` maps to.
     fn translate(&self, gpa: GuestPhysAddr) -> HyperResult<HostPhysAddr>;
 
+    /// Translate `gpa` like [`GuestPageTableTrait::translate`], also returning the flags the
+    /// guest physical frame is mapped with. Fails with [`HyperError::NotSupported`] unless the
+    /// page table implements it, in which case guest memory can't be accessed through
+    /// [`crate::GuestMemory`].
+    fn query(&self, _gpa: GuestPhysAddr) -> HyperResult<(HostPhysAddr, MappingFlags)> {
+        Err(HyperError::NotSupported)
+    }
+
     /// Get guest page table token.
     fn token(&self) -> usize;
 }
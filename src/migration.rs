@@ -0,0 +1,630 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+
+use crate::snapshot::SNAPSHOT_ARCH;
+use crate::{
+    memory::PAGE_SIZE_4K, GuestMemoryRegion, GuestPageTableTrait, GuestPhysAddr, HyperCraftHal,
+    HyperError, HyperResult, SnapshotReader, SnapshotWriter, VmState, VmTrait,
+};
+use page_table_entry::MappingFlags;
+
+/// First bytes of every migration stream.
+const MIGRATION_MAGIC: [u8; 8] = *b"HCMIGRAT";
+
+/// Version of the migration stream written by this crate.
+const MIGRATION_VERSION: u32 = 1;
+
+/// Guest RAM layout: the number of regions, then the address, size and flags of each.
+const RECORD_LAYOUT: u32 = 1;
+/// A batch of guest pages: the number of pages, then the address and contents of each.
+const RECORD_PAGES: u32 = 2;
+/// Register state of every vCPU, as a snapshot.
+const RECORD_VCPUS: u32 = 3;
+/// State of the emulated devices, as a snapshot.
+const RECORD_DEVICES: u32 = 4;
+/// End of the stream, the VM can be resumed on the receiving side.
+const RECORD_END: u32 = 5;
+
+/// Largest record a receiver accepts, so a corrupted length can't exhaust the heap.
+const MAX_RECORD_LEN: usize = 16 << 20;
+/// Largest number of pages in one batch, which keeps page records below [`MAX_RECORD_LEN`].
+const MAX_BATCH_PAGES: usize = 1024;
+
+/// Byte stream the migration data goes through, e.g. a network connection.
+pub trait MigrationStream {
+    /// Writes all of `buf`.
+    fn write_all(&mut self, buf: &[u8]) -> HyperResult;
+
+    /// Fills `buf`, failing if the stream ends first.
+    fn read_exact(&mut self, buf: &mut [u8]) -> HyperResult;
+}
+
+/// An in-memory pipe: writes go to the back, reads come from the front.
+impl MigrationStream for VecDeque<u8> {
+    fn write_all(&mut self, buf: &[u8]) -> HyperResult {
+        self.extend(buf);
+        Ok(())
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> HyperResult {
+        if self.len() < buf.len() {
+            return Err(HyperError::DecodeError);
+        }
+        let len = buf.len();
+        for (dst, src) in buf.iter_mut().zip(self.drain(..len)) {
+            *dst = src;
+        }
+        Ok(())
+    }
+}
+
+/// When pre-copy stops and the VM is stopped for the final copy.
+#[derive(Debug, Clone, Copy)]
+pub struct MigrationConfig {
+    /// A round that leaves at most this many dirty pages ends pre-copy.
+    pub converge_pages: usize,
+    /// Pre-copy ends after this many rounds even if the guest keeps dirtying more pages.
+    pub max_rounds: usize,
+    /// Pages sent per record, at most 1024.
+    pub batch_pages: usize,
+}
+
+impl Default for MigrationConfig {
+    fn default() -> Self {
+        Self {
+            converge_pages: 256,
+            max_rounds: 30,
+            batch_pages: 64,
+        }
+    }
+}
+
+/// Sends a VM with pre-copy migration.
+///
+/// [`MigrationSender::start`] sends all of guest RAM while the guest keeps running, then each
+/// [`MigrationSender::iterate`] resends the pages it wrote meanwhile until few enough are left.
+/// The VM is then paused and [`MigrationSender::finish`] sends the last dirty pages along with
+/// the vCPU and device state. The vCPUs must be out of the guest during each of these calls
+/// and may run in between.
+pub struct MigrationSender<H: HyperCraftHal, G: GuestPageTableTrait> {
+    config: MigrationConfig,
+    rounds: usize,
+    pages_sent: usize,
+    marker: PhantomData<(H, G)>,
+}
+
+impl<H: HyperCraftHal, G: GuestPageTableTrait> MigrationSender<H, G> {
+    /// Creates a sender that stops pre-copy as set by `config`.
+    pub fn new(config: MigrationConfig) -> Self {
+        Self {
+            config,
+            rounds: 0,
+            pages_sent: 0,
+            marker: PhantomData,
+        }
+    }
+
+    /// Number of pre-copy rounds done after the first full copy.
+    pub fn rounds(&self) -> usize {
+        self.rounds
+    }
+
+    /// Number of pages sent so far, counting the ones sent again.
+    pub fn pages_sent(&self) -> usize {
+        self.pages_sent
+    }
+
+    /// Starts logging guest writes and sends the stream header, the memory layout and all of
+    /// guest RAM.
+    pub fn start<V: VmTrait<H, G>>(
+        &mut self,
+        vm: &mut V,
+        stream: &mut impl MigrationStream,
+    ) -> HyperResult {
+        let mut w = SnapshotWriter::without_header();
+        w.put_u32(MIGRATION_VERSION);
+        w.put_u32(SNAPSHOT_ARCH);
+        stream.write_all(&MIGRATION_MAGIC)?;
+        stream.write_all(&w.into_bytes())?;
+
+        let mut w = SnapshotWriter::without_header();
+        w.put_usize(vm.memory_regions().len());
+        for region in vm.memory_regions() {
+            w.put_usize(region.gpa);
+            w.put_usize(region.size);
+            w.put_usize(region.flags.bits());
+        }
+        write_record(stream, RECORD_LAYOUT, &w.into_bytes())?;
+
+        // pages written from here on are sent again
+        vm.enable_dirty_log()?;
+        let pages: Vec<GuestPhysAddr> = vm
+            .memory_regions()
+            .iter()
+            .flat_map(|r| (r.gpa..r.gpa + r.size).step_by(PAGE_SIZE_4K))
+            .collect();
+        self.send_pages(vm, stream, &pages)
+    }
+
+    /// Sends the pages the guest wrote since the previous round. Returns whether pre-copy is
+    /// over and the VM should be paused for [`MigrationSender::finish`].
+    pub fn iterate<V: VmTrait<H, G>>(
+        &mut self,
+        vm: &mut V,
+        stream: &mut impl MigrationStream,
+    ) -> HyperResult<bool> {
+        let pages = take_dirty_pages(vm)?;
+        self.send_pages(vm, stream, &pages)?;
+        self.rounds += 1;
+        Ok(pages.len() <= self.config.converge_pages || self.rounds >= self.config.max_rounds)
+    }
+
+    /// Stop-and-copy: sends the last dirty pages, the vCPU and device state and the end of the
+    /// stream, then stops logging guest writes. The VM must be paused; it stays so and can be
+    /// destroyed once the receiver has taken over, or resumed if the migration failed.
+    pub fn finish<V: VmTrait<H, G>>(
+        &mut self,
+        vm: &mut V,
+        stream: &mut impl MigrationStream,
+    ) -> HyperResult {
+        if vm.state() != VmState::Paused {
+            return Err(HyperError::BadState);
+        }
+        let pages = take_dirty_pages(vm)?;
+        self.send_pages(vm, stream, &pages)?;
+
+        let mut w = SnapshotWriter::new();
+        vm.save_vcpu_state(&mut w)?;
+        write_record(stream, RECORD_VCPUS, &w.into_bytes())?;
+        let mut w = SnapshotWriter::new();
+        vm.save_device_state(&mut w)?;
+        write_record(stream, RECORD_DEVICES, &w.into_bytes())?;
+        write_record(stream, RECORD_END, &[])?;
+        vm.disable_dirty_log()
+    }
+
+    fn send_pages<V: VmTrait<H, G>>(
+        &mut self,
+        vm: &V,
+        stream: &mut impl MigrationStream,
+        pages: &[GuestPhysAddr],
+    ) -> HyperResult {
+        let regions = vm.memory_regions();
+        for batch in pages.chunks(self.config.batch_pages.clamp(1, MAX_BATCH_PAGES)) {
+            let mut w = SnapshotWriter::without_header();
+            w.put_usize(batch.len());
+            for &gpa in batch {
+                let region = regions
+                    .iter()
+                    .find(|r| (r.gpa..r.gpa + r.size).contains(&gpa))
+                    .ok_or(HyperError::NotFound)?;
+                let hva = region.hva + (gpa - region.gpa);
+                let page = unsafe { core::slice::from_raw_parts(hva as *const u8, PAGE_SIZE_4K) };
+                w.put_usize(gpa);
+                w.put_bytes(page);
+            }
+            write_record(stream, RECORD_PAGES, &w.into_bytes())?;
+            self.pages_sent += batch.len();
+        }
+        Ok(())
+    }
+}
+
+/// Receives a VM sent by a [`MigrationSender`] into `vm`, which must have the same vCPUs and
+/// no guest RAM where the sender had some. Returns once the whole stream is received, with the
+/// VM paused and ready to be resumed.
+pub fn receive_vm<H: HyperCraftHal, G: GuestPageTableTrait, V: VmTrait<H, G>>(
+    vm: &mut V,
+    stream: &mut impl MigrationStream,
+) -> HyperResult {
+    let mut header = [0; MIGRATION_MAGIC.len() + 8];
+    stream.read_exact(&mut header)?;
+    if header[..MIGRATION_MAGIC.len()] != MIGRATION_MAGIC {
+        return Err(HyperError::DecodeError);
+    }
+    let mut r = SnapshotReader::without_header(&header[MIGRATION_MAGIC.len()..]);
+    if r.get_u32()? > MIGRATION_VERSION || r.get_u32()? != SNAPSHOT_ARCH {
+        return Err(HyperError::NotSupported);
+    }
+
+    let mut regions = Vec::new();
+    loop {
+        let (tag, payload) = read_record(stream)?;
+        let mut r = SnapshotReader::without_header(&payload);
+        match tag {
+            RECORD_LAYOUT => {
+                for _ in 0..r.get_usize()? {
+                    let gpa = r.get_usize()?;
+                    let size = r.get_usize()?;
+                    let flags =
+                        MappingFlags::from_bits(r.get_usize()?).ok_or(HyperError::DecodeError)?;
+                    let hva = vm.alloc_memory(gpa, size, flags)?;
+                    regions.push(GuestMemoryRegion {
+                        gpa,
+                        hva,
+                        size,
+                        flags,
+                    });
+                }
+            }
+            RECORD_PAGES => {
+                for _ in 0..r.get_usize()? {
+                    let gpa = r.get_usize()?;
+                    let data = r.get_bytes()?;
+                    let region = regions
+                        .iter()
+                        .find(|r| (r.gpa..r.gpa + r.size).contains(&gpa))
+                        .filter(|_| gpa % PAGE_SIZE_4K == 0 && data.len() == PAGE_SIZE_4K)
+                        .ok_or(HyperError::DecodeError)?;
+                    let hva = region.hva + (gpa - region.gpa);
+                    unsafe {
+                        core::ptr::copy_nonoverlapping(data.as_ptr(), hva as *mut u8, data.len())
+                    };
+                }
+            }
+            RECORD_VCPUS | RECORD_DEVICES => vm.load_state(&payload)?,
+            RECORD_END => return Ok(()),
+            // written by a later version, skip it
+            _ => {}
+        }
+    }
+}
+
+fn write_record(stream: &mut impl MigrationStream, tag: u32, payload: &[u8]) -> HyperResult {
+    let mut w = SnapshotWriter::without_header();
+    w.put_u32(tag);
+    w.put_usize(payload.len());
+    stream.write_all(&w.into_bytes())?;
+    stream.write_all(payload)
+}
+
+fn read_record(stream: &mut impl MigrationStream) -> HyperResult<(u32, Vec<u8>)> {
+    let mut header = [0; 12];
+    stream.read_exact(&mut header)?;
+    let mut r = SnapshotReader::without_header(&header);
+    let tag = r.get_u32()?;
+    let len = r.get_usize()?;
+    if len > MAX_RECORD_LEN {
+        return Err(HyperError::DecodeError);
+    }
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload)?;
+    Ok((tag, payload))
+}
+
+/// Collects the pages of guest RAM written since the last call, clearing the dirty log.
+fn take_dirty_pages<H: HyperCraftHal, G: GuestPageTableTrait, V: VmTrait<H, G>>(
+    vm: &mut V,
+) -> HyperResult<Vec<GuestPhysAddr>> {
+    let ranges: Vec<(GuestPhysAddr, usize)> = vm
+        .memory_regions()
+        .iter()
+        .map(|r| (r.gpa, r.size))
+        .collect();
+    let mut pages = Vec::new();
+    for (gpa, size) in ranges {
+        let bitmap = vm.get_dirty_log(gpa, size)?;
+        for page in 0..size / PAGE_SIZE_4K {
+            if bitmap[page / 64] & (1 << (page % 64)) != 0 {
+                pages.push(gpa + page * PAGE_SIZE_4K);
+            }
+        }
+    }
+    Ok(pages)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+    use spin::Mutex;
+
+    use super::*;
+    use crate::arch::VCpu;
+    use crate::guest_memory::tests::{TestGpt, TestHal};
+    use crate::snapshot::{SECTION_VCPU, SECTION_VM_DEVICES};
+    use crate::vmstate::AtomicVmState;
+    use crate::{GuestFaultPolicy, GuestMemory, HostVirtAddr, MmioOps, VCpuGuard, VmCpus, VmExit};
+
+    /// A VM without vCPUs to run: its guest writes are made with [`TestVm::guest_write`], its
+    /// register and device state are one number each.
+    struct TestVm {
+        memory: GuestMemory<TestHal>,
+        gpt: TestGpt,
+        state: AtomicVmState,
+        regs: u64,
+        devices: u64,
+    }
+
+    impl TestVm {
+        fn guest_write(&self, gpa: GuestPhysAddr, val: u8) {
+            self.memory.write_gpa(&self.gpt, gpa, &[val; 16]).unwrap();
+        }
+
+        fn page(&self, gpa: GuestPhysAddr) -> Vec<u8> {
+            let mut page = vec![0; PAGE_SIZE_4K];
+            self.memory.read_gpa(&self.gpt, gpa, &mut page).unwrap();
+            page
+        }
+    }
+
+    impl VmTrait<TestHal, TestGpt> for TestVm {
+        fn new(_vcpus: VmCpus<TestHal>, gpt: TestGpt, _vm_id: usize) -> HyperResult<Self> {
+            Ok(Self {
+                memory: GuestMemory::new(),
+                gpt,
+                state: AtomicVmState::new(VmState::Created),
+                regs: 0,
+                devices: 0,
+            })
+        }
+
+        fn vm_id(&self) -> usize {
+            0
+        }
+
+        fn init_vcpu(&self, _vcpu_id: usize, _entry: GuestPhysAddr, _arg: usize) -> HyperResult {
+            Err(HyperError::NotSupported)
+        }
+
+        fn run_vcpu(&self, _vcpu_id: usize) -> HyperResult<VmExit> {
+            Err(HyperError::NotSupported)
+        }
+
+        fn vcpu(&mut self, _vcpu_id: usize) -> HyperResult<&mut VCpu<TestHal>> {
+            Err(HyperError::NotFound)
+        }
+
+        fn lock_vcpu(&self, _vcpu_id: usize) -> HyperResult<VCpuGuard<'_, VCpu<TestHal>>> {
+            Err(HyperError::NotFound)
+        }
+
+        fn add_vcpu(&mut self, _vcpu: VCpu<TestHal>) -> HyperResult {
+            Err(HyperError::NotSupported)
+        }
+
+        fn remove_vcpu(&mut self, _vcpu_id: usize) -> HyperResult<VCpu<TestHal>> {
+            Err(HyperError::NotFound)
+        }
+
+        fn register_mmio_device(&mut self, _device: Arc<Mutex<dyn MmioOps>>) -> HyperResult {
+            Err(HyperError::NotSupported)
+        }
+
+        fn unregister_mmio_device(&mut self, _base: u64) -> HyperResult<Arc<Mutex<dyn MmioOps>>> {
+            Err(HyperError::NotFound)
+        }
+
+        fn set_fault_policy(&mut self, _policy: GuestFaultPolicy) {}
+
+        fn state(&self) -> VmState {
+            self.state.load()
+        }
+
+        fn pause(&self) -> HyperResult {
+            self.state.pause()
+        }
+
+        fn resume(&self) -> HyperResult {
+            self.state.resume()
+        }
+
+        fn reset(&mut self) -> HyperResult {
+            self.state.reset()
+        }
+
+        fn destroy(&mut self) -> HyperResult {
+            self.memory.release(&mut self.gpt);
+            self.state.store(VmState::Destroyed);
+            Ok(())
+        }
+
+        fn save_snapshot(&mut self) -> HyperResult<Vec<u8>> {
+            Err(HyperError::NotSupported)
+        }
+
+        fn restore_snapshot(&mut self, _data: &[u8]) -> HyperResult {
+            Err(HyperError::NotSupported)
+        }
+
+        fn save_vcpu_state(&mut self, w: &mut SnapshotWriter) -> HyperResult {
+            w.section(SECTION_VCPU, |w| {
+                w.put_u64(self.regs);
+                Ok(())
+            })
+        }
+
+        fn save_device_state(&mut self, w: &mut SnapshotWriter) -> HyperResult {
+            w.section(SECTION_VM_DEVICES, |w| {
+                w.put_u64(self.devices);
+                Ok(())
+            })
+        }
+
+        fn load_state(&mut self, data: &[u8]) -> HyperResult {
+            let mut r = SnapshotReader::new(data)?;
+            while let Some((tag, mut section)) = r.next_section()? {
+                match tag {
+                    SECTION_VCPU => self.regs = section.get_u64()?,
+                    SECTION_VM_DEVICES => self.devices = section.get_u64()?,
+                    _ => return Err(HyperError::DecodeError),
+                }
+            }
+            self.state.store(VmState::Paused);
+            Ok(())
+        }
+
+        fn memory_regions(&self) -> &[GuestMemoryRegion] {
+            self.memory.regions()
+        }
+
+        fn enable_dirty_log(&mut self) -> HyperResult {
+            self.memory.start_dirty_log(&mut self.gpt, true)
+        }
+
+        fn disable_dirty_log(&mut self) -> HyperResult {
+            self.memory.stop_dirty_log(&mut self.gpt)
+        }
+
+        fn get_dirty_log(&mut self, gpa: GuestPhysAddr, size: usize) -> HyperResult<Vec<u64>> {
+            self.memory.take_dirty_log(&mut self.gpt, gpa, size)
+        }
+
+        fn alloc_memory(
+            &mut self,
+            gpa: GuestPhysAddr,
+            size: usize,
+            flags: MappingFlags,
+        ) -> HyperResult<HostVirtAddr> {
+            self.memory.alloc_region(&mut self.gpt, gpa, size, flags)
+        }
+    }
+
+    fn vm() -> TestVm {
+        TestVm::new(VmCpus::new(1), TestGpt::new().unwrap(), 0).unwrap()
+    }
+
+    /// A running VM with 8 pages of RAM at 0 and 4 at 0x10_0000, each filled with a byte of
+    /// its own.
+    fn source_vm() -> TestVm {
+        let mut vm = vm();
+        let flags = MappingFlags::READ | MappingFlags::WRITE;
+        vm.alloc_memory(0, 8 * PAGE_SIZE_4K, flags).unwrap();
+        vm.alloc_memory(0x10_0000, 4 * PAGE_SIZE_4K, flags).unwrap();
+        for (i, gpa) in pages(&vm).into_iter().enumerate() {
+            let page = vec![i as u8 + 1; PAGE_SIZE_4K];
+            vm.memory.write_gpa(&vm.gpt, gpa, &page).unwrap();
+        }
+        vm.regs = 0x1234;
+        vm.devices = 0x5678;
+        vm.state.enter_guest().unwrap();
+        vm
+    }
+
+    fn pages(vm: &TestVm) -> Vec<GuestPhysAddr> {
+        vm.memory_regions()
+            .iter()
+            .flat_map(|r| (r.gpa..r.gpa + r.size).step_by(PAGE_SIZE_4K))
+            .collect()
+    }
+
+    #[test]
+    fn pre_copy_migration() {
+        let mut src = source_vm();
+        let mut pipe = VecDeque::new();
+        let config = MigrationConfig {
+            converge_pages: 1,
+            max_rounds: 30,
+            batch_pages: 5,
+        };
+        let mut sender = MigrationSender::new(config);
+        sender.start(&mut src, &mut pipe).unwrap();
+        assert_eq!(sender.pages_sent(), 12);
+
+        // the guest keeps writing while its RAM is copied
+        src.guest_write(0x1000, 0xaa);
+        src.guest_write(0x3000, 0xbb);
+        src.guest_write(0x10_2000, 0xcc);
+        assert!(!sender.iterate(&mut src, &mut pipe).unwrap());
+        src.guest_write(0x3010, 0xdd);
+        assert!(sender.iterate(&mut src, &mut pipe).unwrap());
+        assert_eq!((sender.rounds(), sender.pages_sent()), (2, 16));
+
+        // stop-and-copy, which needs the VM paused, sends the pages written since
+        src.guest_write(0x7000, 0xee);
+        src.regs = 0x4321;
+        assert_eq!(
+            sender.finish(&mut src, &mut pipe),
+            Err(HyperError::BadState)
+        );
+        src.pause().unwrap();
+        sender.finish(&mut src, &mut pipe).unwrap();
+        assert_eq!(sender.pages_sent(), 17);
+        assert!(!src.memory.is_dirty_logging());
+
+        let mut dst = vm();
+        receive_vm(&mut dst, &mut pipe).unwrap();
+        assert!(pipe.is_empty());
+        assert_eq!(dst.memory_regions().len(), 2);
+        for gpa in pages(&src) {
+            assert_eq!(dst.page(gpa), src.page(gpa), "page {gpa:#x}");
+        }
+        assert_eq!(dst.page(0x3000)[0x10], 0xdd);
+        assert_eq!((dst.regs, dst.devices), (0x4321, 0x5678));
+        assert_eq!(dst.state(), VmState::Paused);
+    }
+
+    #[test]
+    fn pre_copy_stops_after_max_rounds() {
+        let mut src = source_vm();
+        let mut pipe = VecDeque::new();
+        let config = MigrationConfig {
+            converge_pages: 0,
+            max_rounds: 3,
+            ..Default::default()
+        };
+        let mut sender = MigrationSender::new(config);
+        sender.start(&mut src, &mut pipe).unwrap();
+        // a guest that never stops writing
+        for round in 1..=3 {
+            src.guest_write(0x2000, round as u8);
+            assert_eq!(sender.iterate(&mut src, &mut pipe).unwrap(), round == 3);
+        }
+        // a round without writes converges at once
+        let mut src = source_vm();
+        let mut sender = MigrationSender::new(config);
+        sender.start(&mut src, &mut pipe).unwrap();
+        assert!(sender.iterate(&mut src, &mut pipe).unwrap());
+        assert_eq!(sender.pages_sent(), 12);
+    }
+
+    #[test]
+    fn stream_from_another_arch() {
+        let mut w = SnapshotWriter::without_header();
+        w.put_u32(MIGRATION_VERSION);
+        w.put_u32(SNAPSHOT_ARCH + 1);
+        let mut pipe: VecDeque<u8> = MIGRATION_MAGIC.iter().copied().collect();
+        pipe.extend(w.into_bytes());
+        assert_eq!(
+            receive_vm(&mut vm(), &mut pipe),
+            Err(HyperError::NotSupported)
+        );
+    }
+
+    #[test]
+    fn records_through_pipe() {
+        let mut pipe = VecDeque::new();
+        write_record(&mut pipe, RECORD_PAGES, &[1, 2, 3]).unwrap();
+        write_record(&mut pipe, RECORD_END, &[]).unwrap();
+
+        assert_eq!(
+            read_record(&mut pipe).unwrap(),
+            (RECORD_PAGES, vec![1, 2, 3])
+        );
+        assert_eq!(read_record(&mut pipe).unwrap(), (RECORD_END, vec![]));
+        assert!(pipe.is_empty());
+    }
+
+    #[test]
+    fn truncated_record() {
+        let mut pipe = VecDeque::new();
+        write_record(&mut pipe, RECORD_PAGES, &[0; 16]).unwrap();
+        pipe.truncate(pipe.len() - 1);
+        assert!(matches!(
+            read_record(&mut pipe),
+            Err(HyperError::DecodeError)
+        ));
+    }
+
+    #[test]
+    fn oversized_record() {
+        let mut w = SnapshotWriter::without_header();
+        w.put_u32(RECORD_PAGES);
+        w.put_usize(MAX_RECORD_LEN + 1);
+        let mut pipe: VecDeque<u8> = w.into_bytes().into_iter().collect();
+        assert!(matches!(
+            read_record(&mut pipe),
+            Err(HyperError::DecodeError)
+        ));
+    }
+}
@@ -0,0 +1,331 @@
+use alloc::vec::Vec;
+
+use crate::{HyperError, HyperResult};
+
+/// First bytes of every snapshot.
+const SNAPSHOT_MAGIC: [u8; 8] = *b"HCSNAPSH";
+
+/// Version of the snapshot format written by this crate. Snapshots of a newer version are
+/// refused, older ones are read as far as their sections are still understood.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) const SNAPSHOT_ARCH: u32 = 1;
+#[cfg(target_arch = "riscv64")]
+pub(crate) const SNAPSHOT_ARCH: u32 = 2;
+#[cfg(target_arch = "aarch64")]
+pub(crate) const SNAPSHOT_ARCH: u32 = 3;
+
+/// Register state of one vCPU: its ID followed by the arch state.
+pub(crate) const SECTION_VCPU: u32 = 1;
+/// State of the per-cpu devices of one vCPU: the vCPU ID followed by the device state.
+pub(crate) const SECTION_VCPU_DEVICES: u32 = 2;
+/// State of the per-vm devices.
+pub(crate) const SECTION_VM_DEVICES: u32 = 3;
+/// One guest RAM region: its layout followed by its contents.
+pub(crate) const SECTION_MEMORY: u32 = 4;
+/// State of the interrupt controller emulated by the hypervisor.
+pub(crate) const SECTION_IRQCHIP: u32 = 5;
+/// State of the MSI interrupt controllers emulated by the hypervisor.
+pub(crate) const SECTION_MSI_IRQCHIP: u32 = 6;
+/// State of the timers emulated by the hypervisor.
+pub(crate) const SECTION_TIMER: u32 = 7;
+/// State of the devices on the MMIO bus, each tagged with the start of its range.
+pub(crate) const SECTION_MMIO_DEVICES: u32 = 8;
+
+/// Builds a snapshot.
+///
+/// A snapshot is a header (magic, format version and architecture) followed by tagged
+/// sections, each carrying its length so a reader can skip the ones it doesn't know. All
+/// integers are little-endian.
+pub struct SnapshotWriter {
+    buf: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    /// Creates a snapshot holding only the header.
+    pub fn new() -> Self {
+        let mut writer = Self { buf: Vec::new() };
+        writer.buf.extend_from_slice(&SNAPSHOT_MAGIC);
+        writer.put_u32(SNAPSHOT_VERSION);
+        writer.put_u32(SNAPSHOT_ARCH);
+        writer
+    }
+
+    /// Creates an empty buffer, for data framed by some other format.
+    pub(crate) fn without_header() -> Self {
+        Self { buf: Vec::new() }
+    }
+
+    /// Appends a byte.
+    pub fn put_u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+
+    /// Appends a 16-bit integer.
+    pub fn put_u16(&mut self, val: u16) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    /// Appends a 32-bit integer.
+    pub fn put_u32(&mut self, val: u32) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    /// Appends a 64-bit integer.
+    pub fn put_u64(&mut self, val: u64) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    /// Appends a `usize`, always stored as 64 bits.
+    pub fn put_usize(&mut self, val: usize) {
+        self.put_u64(val as u64);
+    }
+
+    /// Appends a byte string, prefixed with its length.
+    pub fn put_bytes(&mut self, bytes: &[u8]) {
+        self.put_usize(bytes.len());
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Appends a section tagged `tag` whose payload is written by `f`.
+    pub(crate) fn section(
+        &mut self,
+        tag: u32,
+        f: impl FnOnce(&mut Self) -> HyperResult,
+    ) -> HyperResult {
+        self.put_u32(tag);
+        let len_pos = self.buf.len();
+        self.put_u64(0);
+        f(self)?;
+        let len = (self.buf.len() - len_pos - 8) as u64;
+        self.buf[len_pos..len_pos + 8].copy_from_slice(&len.to_le_bytes());
+        Ok(())
+    }
+
+    /// Gives back the finished snapshot.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+impl Default for SnapshotWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads back what a [`SnapshotWriter`] wrote, failing with [`HyperError::DecodeError`] on
+/// truncated or malformed data.
+pub struct SnapshotReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> SnapshotReader<'a> {
+    /// Checks the header of `data` and returns a reader positioned at the first section.
+    ///
+    /// Snapshots of a newer format version or of another architecture fail with
+    /// [`HyperError::NotSupported`].
+    pub fn new(data: &'a [u8]) -> HyperResult<Self> {
+        let mut reader = Self { data, pos: 0 };
+        if reader.take(SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC {
+            return Err(HyperError::DecodeError);
+        }
+        if reader.get_u32()? > SNAPSHOT_VERSION || reader.get_u32()? != SNAPSHOT_ARCH {
+            return Err(HyperError::NotSupported);
+        }
+        Ok(reader)
+    }
+
+    /// Returns a reader of data without a snapshot header, framed by some other format.
+    pub(crate) fn without_header(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> HyperResult<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.data.len())
+            .ok_or(HyperError::DecodeError)?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> HyperResult<[u8; N]> {
+        // `take` returned exactly `N` bytes
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    /// Whether everything was read.
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    /// Reads a byte.
+    pub fn get_u8(&mut self) -> HyperResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Reads a 16-bit integer.
+    pub fn get_u16(&mut self) -> HyperResult<u16> {
+        Ok(u16::from_le_bytes(self.take_array()?))
+    }
+
+    /// Reads a 32-bit integer.
+    pub fn get_u32(&mut self) -> HyperResult<u32> {
+        Ok(u32::from_le_bytes(self.take_array()?))
+    }
+
+    /// Reads a 64-bit integer.
+    pub fn get_u64(&mut self) -> HyperResult<u64> {
+        Ok(u64::from_le_bytes(self.take_array()?))
+    }
+
+    /// Reads a `usize` written by [`SnapshotWriter::put_usize`].
+    pub fn get_usize(&mut self) -> HyperResult<usize> {
+        usize::try_from(self.get_u64()?).map_err(|_| HyperError::DecodeError)
+    }
+
+    /// Reads a byte string written by [`SnapshotWriter::put_bytes`].
+    pub fn get_bytes(&mut self) -> HyperResult<&'a [u8]> {
+        let len = self.get_usize()?;
+        self.take(len)
+    }
+
+    /// Reads the tag of the next section and returns a reader limited to its payload, or
+    /// `None` at the end of the snapshot.
+    pub(crate) fn next_section(&mut self) -> HyperResult<Option<(u32, SnapshotReader<'a>)>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let tag = self.get_u32()?;
+        let len = self.get_usize()?;
+        let payload = SnapshotReader {
+            data: self.take(len)?,
+            pos: 0,
+        };
+        Ok(Some((tag, payload)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A snapshot with a vCPU section holding every integer width and a byte string, and an
+    /// empty memory section.
+    fn snapshot() -> Vec<u8> {
+        let mut w = SnapshotWriter::new();
+        w.section(SECTION_VCPU, |w| {
+            w.put_u8(0x12);
+            w.put_u16(0x3456);
+            w.put_u32(0x789a_bcde);
+            w.put_u64(u64::MAX - 1);
+            w.put_usize(7);
+            w.put_bytes(b"regs");
+            Ok(())
+        })
+        .unwrap();
+        w.section(SECTION_MEMORY, |_| Ok(())).unwrap();
+        w.into_bytes()
+    }
+
+    /// A header like the one of [`SnapshotWriter::new`] with the given version and arch.
+    fn header(version: u32, arch: u32) -> Vec<u8> {
+        let mut w = SnapshotWriter::without_header();
+        SNAPSHOT_MAGIC.iter().for_each(|&b| w.put_u8(b));
+        w.put_u32(version);
+        w.put_u32(arch);
+        w.into_bytes()
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = snapshot();
+        let mut r = SnapshotReader::new(&data).unwrap();
+        let (tag, mut vcpu) = r.next_section().unwrap().unwrap();
+        assert_eq!(tag, SECTION_VCPU);
+        assert_eq!(vcpu.get_u8().unwrap(), 0x12);
+        assert_eq!(vcpu.get_u16().unwrap(), 0x3456);
+        assert_eq!(vcpu.get_u32().unwrap(), 0x789a_bcde);
+        assert_eq!(vcpu.get_u64().unwrap(), u64::MAX - 1);
+        assert_eq!(vcpu.get_usize().unwrap(), 7);
+        assert_eq!(vcpu.get_bytes().unwrap(), b"regs");
+        // a section reader ends with its payload
+        assert_eq!(vcpu.get_u8(), Err(HyperError::DecodeError));
+        let (tag, mut memory) = r.next_section().unwrap().unwrap();
+        assert_eq!(tag, SECTION_MEMORY);
+        assert!(memory.next_section().unwrap().is_none());
+        assert!(r.next_section().unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_input_fails_to_decode() {
+        let data = snapshot();
+        // cut anywhere but after the header or after the vCPU section, which is followed by
+        // the 12 bytes of the empty memory section
+        let complete = [SNAPSHOT_MAGIC.len() + 8, data.len() - 12];
+        for len in (0..data.len()).filter(|len| !complete.contains(len)) {
+            let result = SnapshotReader::new(&data[..len]).and_then(|mut r| {
+                while let Some((_, mut section)) = r.next_section()? {
+                    while section.get_u8().is_ok() {}
+                }
+                Ok(())
+            });
+            assert_eq!(result, Err(HyperError::DecodeError), "{len} bytes");
+        }
+        // a byte string longer than what is left
+        let mut w = SnapshotWriter::without_header();
+        w.put_usize(usize::MAX);
+        assert!(SnapshotReader::without_header(&w.into_bytes())
+            .get_bytes()
+            .is_err());
+    }
+
+    #[test]
+    fn other_versions_and_arches() {
+        let mut data = header(SNAPSHOT_VERSION + 1, SNAPSHOT_ARCH);
+        assert_eq!(
+            SnapshotReader::new(&data).err(),
+            Some(HyperError::NotSupported)
+        );
+        data = header(SNAPSHOT_VERSION, SNAPSHOT_ARCH + 1);
+        assert_eq!(
+            SnapshotReader::new(&data).err(),
+            Some(HyperError::NotSupported)
+        );
+        // older versions are read
+        data = header(SNAPSHOT_VERSION - 1, SNAPSHOT_ARCH);
+        assert!(SnapshotReader::new(&data).is_ok());
+        data[0] = b'X';
+        assert_eq!(
+            SnapshotReader::new(&data).err(),
+            Some(HyperError::DecodeError)
+        );
+    }
+
+    #[test]
+    fn unknown_sections_are_skipped() {
+        let mut w = SnapshotWriter::new();
+        w.section(0xdead, |w| {
+            w.put_bytes(&[0xff; 100]);
+            Ok(())
+        })
+        .unwrap();
+        w.section(SECTION_TIMER, |w| {
+            w.put_u64(42);
+            Ok(())
+        })
+        .unwrap();
+        let data = w.into_bytes();
+        let mut r = SnapshotReader::new(&data).unwrap();
+        // the unknown section is left unread
+        assert_eq!(r.next_section().unwrap().unwrap().0, 0xdead);
+        let (tag, mut timer) = r.next_section().unwrap().unwrap();
+        assert_eq!((tag, timer.get_u64().unwrap()), (SECTION_TIMER, 42));
+        assert!(r.next_section().unwrap().is_none());
+    }
+}
@@ -1,28 +1,60 @@
-#[cfg(target_arch = "riscv64")]
-use crate::{GprIndex, VmExitInfo,};
-
 use crate::arch::VCpu;
 use crate::{
-    GuestPageTableTrait, GuestPhysAddr, HyperCraftHal, HyperResult, VmCpus,
+    GuestFault, GuestFaultPolicy, GuestMemoryRegion, GuestPageTableTrait, GuestPhysAddr,
+    GuestVirtAddr, HostVirtAddr, HyperCraftHal, HyperResult, MmioOps, SnapshotReader,
+    SnapshotWriter, VCpuGuard, VmCpus, VmExit, VmExitInfo, VmState,
 };
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use page_table_entry::MappingFlags;
+use spin::Mutex;
 
-#[cfg(target_arch = "riscv64")]
 /// Trait for VCpu struct.
 pub trait VCpuTrait {
-    /// Create a new vCPU
-    fn new(vcpu_id: usize, entry: GuestPhysAddr) -> Self;
+    /// Gets the vCPU's id.
+    fn vcpu_id(&self) -> usize;
 
-    /// Runs this vCPU until traps.
-    fn run(&mut self) -> VmExitInfo;
+    /// Sets the guest entry point and the boot argument passed to the guest.
+    ///
+    /// The boot argument is placed where the guest boot protocol expects it: `a1` (the device
+    /// tree) on RISC-V, `x0` (the device tree) on aarch64 and `rsi` (boot params) on x86_64.
+    fn init(&mut self, entry: GuestPhysAddr, boot_arg: usize) -> HyperResult;
 
-    /// Gets one of the vCPU's general purpose registers.
-    fn get_gpr(&self, index: GprIndex);
+    /// Gets the guest program counter.
+    fn pc(&self) -> GuestVirtAddr;
 
-    /// Set one of the vCPU's general purpose register.
-    fn set_gpr(&mut self, index: GprIndex, val: usize);
+    /// Sets the guest program counter.
+    fn set_pc(&mut self, pc: GuestVirtAddr);
 
-    /// Gets the vCPU's id.
-    fn vcpu_id(&self) -> usize;
+    /// Gets one of the vCPU's general purpose registers, indexed by its architectural number.
+    /// Registers that don't exist read as 0, as do `x0` on RISC-V and index 31 (`xzr`) on
+    /// aarch64.
+    fn gpr(&self, index: usize) -> usize;
+
+    /// Sets one of the vCPU's general purpose registers, indexed by its architectural number.
+    /// Writes to registers that don't exist, `x0` on RISC-V and index 31 on aarch64 are ignored.
+    fn set_gpr(&mut self, index: usize, val: usize);
+
+    /// Information about the most recent vm-exit of this vCPU.
+    fn exit_info(&self) -> HyperResult<VmExitInfo>;
+
+    /// Completes a [`VmExit::MmioRead`] or [`VmExit::PioIn`] returned by
+    /// [`VmTrait::run_vcpu`], writing `data` to the register the guest instruction reads into.
+    fn complete_read(&mut self, data: u64) -> HyperResult;
+
+    /// Injects the architectural exception for `fault`, delivered the next time the vCPU runs.
+    fn inject_fault(&mut self, fault: GuestFault) -> HyperResult;
+
+    /// Puts the vCPU back into the state the last [`VCpuTrait::init`] left it in, dropping
+    /// pending reads and events, as a guest-visible reset would.
+    fn reset(&mut self) -> HyperResult;
+
+    /// Writes the guest-visible register state of the vCPU, including events waiting to be
+    /// injected. Fails with [`crate::HyperError::BadState`] while a read is pending.
+    fn save_state(&self, w: &mut SnapshotWriter) -> HyperResult;
+
+    /// Loads the register state written by [`VCpuTrait::save_state`].
+    fn load_state(&mut self, r: &mut SnapshotReader) -> HyperResult;
 }
 
 /// Trait for PerCpu struct.
@@ -41,19 +73,121 @@ pub trait PerCpuTrait<H: HyperCraftHal> {
     fn this_cpu() -> &'static mut Self;
 }
 
-#[cfg(not(target_arch = "x86_64"))]
 /// Trait for VM struct.
-pub trait VmTrait<H: HyperCraftHal, G: GuestPageTableTrait> {
-    /// Create a new VM with `vcpus` vCPUs and `gpt` as the guest page table.
-    fn new(vcpus: VmCpus<H>, gpt: G) -> HyperResult<Self>
-    where
-        Self: Sized;
+///
+/// A VM can be shared between physical CPUs, e.g. in an `Arc`: methods taking `&self` may be
+/// called concurrently, each physical CPU running a different vCPU, while methods taking
+/// `&mut self` need every vCPU to be out of the guest.
+pub trait VmTrait<H: HyperCraftHal, G: GuestPageTableTrait>: Sized {
+    /// Create a new VM with id `vm_id`, `vcpus` vCPUs and `gpt` as the guest page table.
+    fn new(vcpus: VmCpus<H>, gpt: G, vm_id: usize) -> HyperResult<Self>;
+
+    /// Gets the VM's id.
+    fn vm_id(&self) -> usize;
+
+    /// Initialize `VCpu` by `vcpu_id`, making it start at `entry` with `boot_arg` as its boot
+    /// argument, and attach it to the guest page table. Call it on the physical CPU that is going
+    /// to run the vCPU.
+    fn init_vcpu(&self, vcpu_id: usize, entry: GuestPhysAddr, boot_arg: usize) -> HyperResult;
+
+    /// Run the vCPU with ID `vcpu_id` on the current physical CPU until a vm-exit the hypervisor
+    /// core can't handle by itself, and return it to the caller.
+    ///
+    /// Unless the exit is [`VmExit::ArchSpecific`] or [`VmExit::GuestFault`], the guest pc is
+    /// already past the instruction that caused it. A read must be completed with
+    /// [`VCpuTrait::complete_read`] before the vCPU runs again. If another physical CPU pauses
+    /// or shuts down the VM meanwhile, the vCPU returns [`VmExit::Paused`] or
+    /// [`VmExit::Shutdown`] at its next vm-exit.
+    fn run_vcpu(&self, vcpu_id: usize) -> HyperResult<VmExit>;
+
+    /// Gets the vCPU with ID `vcpu_id`.
+    fn vcpu(&mut self, vcpu_id: usize) -> HyperResult<&mut VCpu<H>>;
+
+    /// Takes exclusive access to the vCPU with ID `vcpu_id`, e.g. to complete a read, failing with
+    /// [`crate::HyperError::BadState`] while it runs on another physical CPU.
+    fn lock_vcpu(&self, vcpu_id: usize) -> HyperResult<VCpuGuard<'_, VCpu<H>>>;
+
+    /// Adds `vcpu` to the VM. Its ID must be below the capacity of the [`VmCpus`] the VM was
+    /// created with and not be in use.
+    fn add_vcpu(&mut self, vcpu: VCpu<H>) -> HyperResult;
+
+    /// Removes the vCPU with ID `vcpu_id` from the VM and gives it back.
+    fn remove_vcpu(&mut self, vcpu_id: usize) -> HyperResult<VCpu<H>>;
+
+    /// Registers an emulated MMIO device. Guest accesses to its range are then handled by the
+    /// device instead of being returned as [`VmExit::MmioRead`] and [`VmExit::MmioWrite`]. Fails
+    /// with [`crate::HyperError::InvalidParam`] if the range is empty or overlaps another device.
+    fn register_mmio_device(&mut self, device: Arc<Mutex<dyn MmioOps>>) -> HyperResult;
+
+    /// Unregisters the MMIO device whose range starts at `base` and gives it back.
+    fn unregister_mmio_device(&mut self, base: u64) -> HyperResult<Arc<Mutex<dyn MmioOps>>>;
+
+    /// Sets how the VM reacts to guest-triggered faults, [`GuestFaultPolicy::Inject`] by default.
+    fn set_fault_policy(&mut self, policy: GuestFaultPolicy);
+
+    /// Gets the lifecycle state of the VM.
+    fn state(&self) -> VmState;
+
+    /// Pauses the VM: [`VmTrait::run_vcpu`] refuses to enter the guest until it is resumed.
+    fn pause(&self) -> HyperResult;
+
+    /// Resumes a paused VM.
+    fn resume(&self) -> HyperResult;
+
+    /// Resets every vCPU to the entry point and boot argument it was initialized with, leaving
+    /// guest memory alone. The VM goes back to [`VmState::Created`].
+    fn reset(&mut self) -> HyperResult;
+
+    /// Tears the VM down: drops all vCPUs, which frees their arch state, and unmaps, scrubs and
+    /// frees the guest memory. The nested page table itself is freed when the VM is dropped.
+    fn destroy(&mut self) -> HyperResult;
+
+    /// Serializes the VM: the registers of every vCPU, the device state and the layout and
+    /// contents of the guest memory. The VM must not be running, pause it first.
+    fn save_snapshot(&mut self) -> HyperResult<Vec<u8>>;
+
+    /// Restores a snapshot taken by [`VmTrait::save_snapshot`] on the same host, replacing the
+    /// guest memory. The VM must have the vCPUs the snapshot holds and must not be running; it
+    /// is left paused. If this fails halfway, the VM should be reset or destroyed.
+    fn restore_snapshot(&mut self, data: &[u8]) -> HyperResult;
+
+    /// Appends the register state of every vCPU to `w`, as [`VmTrait::save_snapshot`] would.
+    /// The VM must not be running.
+    fn save_vcpu_state(&mut self, w: &mut SnapshotWriter) -> HyperResult;
+
+    /// Appends the state of the emulated devices to `w`, as [`VmTrait::save_snapshot`] would.
+    /// The VM must not be running.
+    fn save_device_state(&mut self, w: &mut SnapshotWriter) -> HyperResult;
+
+    /// Loads the sections of a snapshot, which may hold only part of the VM, on top of its
+    /// current state. Unlike [`VmTrait::restore_snapshot`], the guest memory is kept, so memory
+    /// sections must not overlap it. The VM must not be running; it is left paused.
+    fn load_state(&mut self, data: &[u8]) -> HyperResult;
+
+    /// The guest RAM owned by the VM.
+    fn memory_regions(&self) -> &[GuestMemoryRegion];
+
+    /// Starts tracking which pages of guest RAM the guest writes. Fails with
+    /// [`crate::HyperError::NotSupported`] if the CPU can't log writes, and with
+    /// [`crate::HyperError::BadState`] if they are already tracked.
+    fn enable_dirty_log(&mut self) -> HyperResult;
+
+    /// Stops tracking guest writes.
+    fn disable_dirty_log(&mut self) -> HyperResult;
 
-    /// Initialize `VCpu` by `vcpu_id`.
-    fn init_vcpu(&mut self, vcpu_id: usize);
+    /// Returns which pages in `gpa..gpa + size` the guest wrote since dirty logging was enabled
+    /// or this was last called for them, and clears that record. Bit `i % 64` of word `i / 64`
+    /// stands for the page at `gpa + i * 4K`; both `gpa` and `size` must be page-aligned.
+    fn get_dirty_log(&mut self, gpa: GuestPhysAddr, size: usize) -> HyperResult<Vec<u64>>;
 
-    /// Run the host VM's vCPU with ID `vcpu_id`. Does not return.
-    fn run(&mut self, vcpu_id: usize);
+    /// Allocates `size` bytes of zeroed guest RAM at `gpa`, owned by the VM and freed when it is
+    /// destroyed. Returns the host virtual address of the backing memory.
+    fn alloc_memory(
+        &mut self,
+        gpa: GuestPhysAddr,
+        size: usize,
+        flags: MappingFlags,
+    ) -> HyperResult<HostVirtAddr>;
 }
 
 /// Trait for NestedPageTable struct.
@@ -1,72 +1,110 @@
-use alloc::boxed::Box;
-use arrayvec::ArrayVec;
-use spin::Once;
+use alloc::vec::Vec;
+use core::ops::{Deref, DerefMut};
+use spin::{Mutex, MutexGuard};
 
-use crate::arch::{VCpu, VM};
-use crate::hal::PerCpuDevices;
-use crate::{GuestPageTableTrait, HyperCraftHal, HyperError, HyperResult,};
-
-
-/// The maximum number of CPUs we can support.
-pub const MAX_CPUS: usize = 8;
-
-pub const VM_CPUS_MAX: usize = MAX_CPUS;
+use crate::arch::VCpu;
+use crate::traits::VCpuTrait;
+use crate::{HyperCraftHal, HyperError, HyperResult};
 
 /// The set of vCPUs in a VM.
-#[derive(Default)]
-pub struct VmCpus<H: HyperCraftHal, PD: PerCpuDevices<H>> {
-    inner: [Once<VCpu<H>>; VM_CPUS_MAX],
-    device: [Once<PD>; VM_CPUS_MAX],
+///
+/// vCPU IDs range from 0 to the capacity given at creation, and each slot can be emptied and
+/// filled again, e.g. for CPU hotplug. Every vCPU sits behind its own lock, so different
+/// physical CPUs can run different vCPUs of the same VM at the same time.
+pub struct VmCpus<H: HyperCraftHal> {
+    inner: Vec<Mutex<Option<VCpu<H>>>>,
 }
 
-impl<H: HyperCraftHal, PD: PerCpuDevices<H>> VmCpus<H, PD> {
-    /// Creates a new vCPU tracking structure.
-    pub fn new() -> Self {
-        Self {
-            inner: [Once::INIT; VM_CPUS_MAX],
-            device: [Once::INIT; VM_CPUS_MAX],
-        }
+impl<H: HyperCraftHal> VmCpus<H> {
+    /// Creates a new vCPU tracking structure with room for `capacity` vCPUs.
+    pub fn new(capacity: usize) -> Self {
+        let mut inner = Vec::with_capacity(capacity);
+        inner.resize_with(capacity, || Mutex::new(None));
+        Self { inner }
+    }
+
+    /// The number of vCPUs the VM can have, which bounds vCPU IDs.
+    pub fn capacity(&self) -> usize {
+        self.inner.len()
     }
 
     /// Adds the given vCPU to the set of vCPUs.
     pub fn add_vcpu(&mut self, vcpu: VCpu<H>) -> HyperResult<()> {
         let vcpu_id = vcpu.vcpu_id();
-        let once_entry = self.inner.get(vcpu_id).ok_or(HyperError::BadState)?;
-
-        let real_vcpu = once_entry.call_once(|| vcpu);
-        let device_once_entry = self.device.get(vcpu_id).ok_or(HyperError::BadState)?;
-
-        device_once_entry.call_once(|| PD::new(real_vcpu).unwrap());
-
+        let slot = self
+            .inner
+            .get_mut(vcpu_id)
+            .ok_or(HyperError::BadState)?
+            .get_mut();
+        if slot.is_some() {
+            return Err(HyperError::BadState);
+        }
+        *slot = Some(vcpu);
         Ok(())
     }
 
-    /// Returns a reference to the vCPU with `vcpu_id` if it exists.
-    pub fn get_vcpu_and_device(&mut self, vcpu_id: usize) -> HyperResult<(&mut VCpu<H>, &mut PD)> {
-        let vcpu = self
-            .inner
-            .get_mut(vcpu_id)
-            .and_then(|once| once.get_mut())
-            .ok_or(HyperError::NotFound)?;
-        let device = self
-            .device
+    /// Removes the vCPU with `vcpu_id` and returns it, freeing its ID for a later
+    /// [`VmCpus::add_vcpu`].
+    pub fn remove_vcpu(&mut self, vcpu_id: usize) -> HyperResult<VCpu<H>> {
+        self.inner
             .get_mut(vcpu_id)
-            .and_then(|once| once.get_mut())
-            .ok_or(HyperError::NotFound)?;
-        Ok((vcpu, device))
+            .and_then(|slot| slot.get_mut().take())
+            .ok_or(HyperError::NotFound)
     }
-    
+
     /// Returns a reference to the vCPU with `vcpu_id` if it exists.
     pub fn get_vcpu(&mut self, vcpu_id: usize) -> HyperResult<&mut VCpu<H>> {
         let vcpu = self
             .inner
             .get_mut(vcpu_id)
-            .and_then(|once| once.get_mut())
+            .and_then(|slot| slot.get_mut().as_mut())
             .ok_or(HyperError::NotFound)?;
         Ok(vcpu)
     }
+
+    /// Takes exclusive access to the vCPU with `vcpu_id` through a shared reference, failing with
+    /// [`HyperError::BadState`] if another physical CPU holds it.
+    pub fn lock_vcpu(&self, vcpu_id: usize) -> HyperResult<VCpuGuard<'_, VCpu<H>>> {
+        VCpuGuard::try_lock(self.inner.get(vcpu_id).ok_or(HyperError::NotFound)?)
+    }
+
+    /// Drops every vCPU, freeing their arch state.
+    pub(crate) fn clear(&mut self) {
+        self.inner
+            .iter_mut()
+            .for_each(|slot| *slot.get_mut() = None);
+    }
+}
+
+/// Exclusive access to a vCPU, or to the state that belongs to it, held by the physical CPU
+/// running it.
+pub struct VCpuGuard<'a, T>(MutexGuard<'a, Option<T>>);
+
+impl<'a, T> VCpuGuard<'a, T> {
+    pub(crate) fn try_lock(slot: &'a Mutex<Option<T>>) -> HyperResult<Self> {
+        let guard = slot.try_lock().ok_or(HyperError::BadState)?;
+        if guard.is_none() {
+            return Err(HyperError::NotFound);
+        }
+        Ok(Self(guard))
+    }
+}
+
+impl<T> Deref for VCpuGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // checked in `try_lock`
+        self.0.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for VCpuGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.0.as_mut().unwrap()
+    }
 }
 
-// Safety: Each VCpu is wrapped with a Mutex to provide safe concurrent access to VCpu.
-unsafe impl<H: HyperCraftHal, PD: PerCpuDevices<H>> Sync for VmCpus<H, PD> {}
-unsafe impl<H: HyperCraftHal, PD: PerCpuDevices<H>> Send for VmCpus<H, PD> {}
+// Safety: a vCPU is only reached through its own `Mutex`, or through `&mut VmCpus`.
+unsafe impl<H: HyperCraftHal> Sync for VmCpus<H> {}
+unsafe impl<H: HyperCraftHal> Send for VmCpus<H> {}
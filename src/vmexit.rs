@@ -0,0 +1,191 @@
+use crate::{
+    GuestAccess, GuestPageFault, GuestPhysAddr, GuestVirtAddr, HyperError, HyperResult, VCpuTrait,
+    VmExitInfo,
+};
+
+/// An arch-independent description of a vm-exit, translated by each backend from its native
+/// exit information.
+#[derive(Debug)]
+pub enum VmExit {
+    /// The guest read from an unmapped (emulated) guest physical address.
+    MmioRead {
+        /// Guest physical address of the access.
+        addr: GuestPhysAddr,
+        /// Access width in bytes.
+        size: u8,
+    },
+    /// The guest wrote to an unmapped (emulated) guest physical address.
+    MmioWrite {
+        /// Guest physical address of the access.
+        addr: GuestPhysAddr,
+        /// Access width in bytes.
+        size: u8,
+        /// The value written, zero-extended.
+        data: u64,
+    },
+    /// The guest read from an I/O port.
+    PioIn {
+        /// Port number.
+        port: u16,
+        /// Access width in bytes.
+        size: u8,
+    },
+    /// The guest wrote to an I/O port.
+    PioOut {
+        /// Port number.
+        port: u16,
+        /// Access width in bytes.
+        size: u8,
+        /// The value written, zero-extended.
+        data: u32,
+    },
+    /// The guest issued a hypercall the hypervisor core does not implement itself.
+    Hypercall {
+        /// Hypercall number.
+        nr: usize,
+        /// Hypercall arguments.
+        args: [usize; 6],
+    },
+    /// The vCPU halted until the next interrupt.
+    Halt,
+    /// The guest started another vCPU through firmware, which the caller should now run too.
+    VCpuStarted {
+        /// The vCPU started.
+        vcpu_id: usize,
+    },
+    /// The guest asked to power off the machine.
+    Shutdown,
+    /// The guest asked to reset the machine.
+    Reset,
+    /// The VM was paused while the vCPU was running, so it did not re-enter the guest.
+    Paused,
+    /// Some other machine-level event raised through firmware, e.g. a reset because of a
+    /// system failure. `event` and `data` are the firmware function and its argument.
+    SystemEvent {
+        /// Firmware specific event type.
+        event: u32,
+        /// Firmware specific event data.
+        data: u64,
+    },
+    /// The guest triggered a fault the hypervisor core can't emulate, and the VM's
+    /// [`GuestFaultPolicy`] is [`GuestFaultPolicy::Terminate`].
+    GuestFault(GuestFault),
+    /// The hypervisor core failed to handle the vm-exit.
+    InternalError(HyperError),
+    /// A vm-exit without an arch-independent representation.
+    ArchSpecific(VmExitInfo),
+}
+
+/// A guest-triggered condition the hypervisor core can't emulate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestFault {
+    /// The guest executed an instruction that can't be executed on its behalf. Injected as
+    /// `#UD` on x86_64, an illegal instruction on RISC-V and an undefined instruction on aarch64.
+    IllegalInstruction {
+        /// Guest pc of the instruction.
+        pc: GuestVirtAddr,
+    },
+    /// The guest accessed a register in a way the hypervisor doesn't allow. Injected as `#GP(0)`
+    /// on x86_64 and as an illegal (undefined) instruction elsewhere.
+    GeneralProtection {
+        /// Guest pc of the instruction.
+        pc: GuestVirtAddr,
+    },
+    /// The guest accessed a guest physical address that is neither memory nor an emulated
+    /// device. Injected as `#GP(0)` on x86_64, a load, store or instruction access fault on
+    /// RISC-V and a synchronous external data or instruction abort on aarch64.
+    AccessFault {
+        /// Guest pc of the instruction.
+        pc: GuestVirtAddr,
+        /// Guest physical address of the access.
+        addr: GuestPhysAddr,
+        /// The kind of access.
+        access: GuestAccess,
+    },
+    /// An access the guest page tables don't allow, found by walking them in software. Injected
+    /// as `#PF` on x86_64, a load, store or instruction page fault on RISC-V and a data or
+    /// instruction abort on aarch64, reporting the faulting guest virtual address.
+    PageFault {
+        /// Guest pc of the instruction.
+        pc: GuestVirtAddr,
+        /// The fault reported by the walk.
+        fault: GuestPageFault,
+    },
+}
+
+/// How a VM reacts to a [`GuestFault`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GuestFaultPolicy {
+    /// Inject the architectural fault into the guest and keep running it.
+    #[default]
+    Inject,
+    /// Stop running the vCPU and return [`VmExit::GuestFault`], so that the caller can
+    /// terminate the VM.
+    Terminate,
+}
+
+/// Injects `fault` into `vcpu`, or returns the exit to hand to the caller, according to `policy`.
+pub(crate) fn handle_guest_fault<V: VCpuTrait>(
+    policy: GuestFaultPolicy,
+    vcpu: &mut V,
+    fault: GuestFault,
+) -> HyperResult<Option<VmExit>> {
+    warn!("vcpu {} guest fault: {:?}", vcpu.vcpu_id(), fault);
+    match policy {
+        GuestFaultPolicy::Inject => {
+            vcpu.inject_fault(fault)?;
+            Ok(None)
+        }
+        GuestFaultPolicy::Terminate => Ok(Some(VmExit::GuestFault(fault))),
+    }
+}
+
+/// The destination of an emulated read, remembered until the VMM supplies the data through
+/// [`VCpuTrait::complete_read`](crate::VCpuTrait::complete_read).
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PendingRead {
+    /// Index of the destination general purpose register.
+    pub reg: usize,
+    /// Access width in bytes.
+    pub size: u8,
+    /// Whether the value is sign-extended rather than zero-extended.
+    pub sign_extend: bool,
+    /// Width in bytes of the (sub-)register that receives the value.
+    pub reg_size: u8,
+    /// Bit offset of the sub-register, e.g. 8 for `AH` on x86.
+    pub shift: u8,
+    /// Whether the register bits outside the sub-register are preserved.
+    pub merge: bool,
+}
+
+impl PendingRead {
+    /// A read of `size` bytes zero-extended into the whole register `reg`.
+    pub fn new(reg: usize, size: u8) -> Self {
+        Self {
+            reg,
+            size,
+            sign_extend: false,
+            reg_size: 8,
+            shift: 0,
+            merge: false,
+        }
+    }
+
+    /// Computes the new value of the destination register from its old value and the data read.
+    pub fn apply(&self, old: usize, data: u64) -> usize {
+        let mask = |bytes: u8| u64::MAX >> (64 - 8 * bytes as u32);
+        let bits = 64 - 8 * self.size as u32;
+        let value = if self.sign_extend {
+            (((data << bits) as i64) >> bits) as u64
+        } else {
+            data & mask(self.size)
+        };
+        let reg_mask = mask(self.reg_size) << self.shift;
+        let value = (value << self.shift) & reg_mask;
+        if self.merge {
+            ((old as u64 & !reg_mask) | value) as usize
+        } else {
+            value as usize
+        }
+    }
+}
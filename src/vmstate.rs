@@ -0,0 +1,108 @@
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::{HyperError, HyperResult, VmExit};
+
+/// Lifecycle state of a VM.
+///
+/// A VM starts out `Created` and becomes `Running` when a vCPU first enters the guest. It can
+/// then be paused and resumed, ends up `Shutdown` when the guest powers off, and goes back to
+/// `Created` on reset. Destroying it is final.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum VmState {
+    /// Created or reset, no vCPU has entered the guest yet.
+    #[default]
+    Created,
+    /// vCPUs may enter the guest.
+    Running,
+    /// vCPUs are kept out of the guest until the VM is resumed.
+    Paused,
+    /// The guest powered itself off.
+    Shutdown,
+    /// vCPUs and guest memory have been freed, the VM can't be used anymore.
+    Destroyed,
+}
+
+impl VmState {
+    /// Whether vCPUs of a VM in this state may enter the guest.
+    pub fn is_runnable(self) -> bool {
+        matches!(self, Self::Created | Self::Running)
+    }
+
+    fn from_u8(val: u8) -> Self {
+        match val {
+            0 => Self::Created,
+            1 => Self::Running,
+            2 => Self::Paused,
+            3 => Self::Shutdown,
+            _ => Self::Destroyed,
+        }
+    }
+}
+
+/// A [`VmState`] shared by the physical CPUs running the vCPUs of a VM.
+pub(crate) struct AtomicVmState(AtomicU8);
+
+impl AtomicVmState {
+    pub(crate) const fn new(state: VmState) -> Self {
+        Self(AtomicU8::new(state as u8))
+    }
+
+    pub(crate) fn load(&self) -> VmState {
+        VmState::from_u8(self.0.load(Ordering::Acquire))
+    }
+
+    pub(crate) fn store(&self, state: VmState) {
+        self.0.store(state as u8, Ordering::Release)
+    }
+
+    /// Moves to `to` if the current state passes `allowed`.
+    fn transition(&self, allowed: impl Fn(VmState) -> bool, to: VmState) -> HyperResult {
+        self.0
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |val| {
+                allowed(VmState::from_u8(val)).then_some(to as u8)
+            })
+            .map(|_| ())
+            .map_err(|_| HyperError::BadState)
+    }
+
+    /// Moves to [`VmState::Running`] when a vCPU is about to enter the guest.
+    pub(crate) fn enter_guest(&self) -> HyperResult {
+        self.transition(VmState::is_runnable, VmState::Running)
+    }
+
+    /// The exit a running vCPU returns instead of re-entering the guest, if another one
+    /// stopped the VM in the meantime.
+    pub(crate) fn stop_exit(&self) -> Option<VmExit> {
+        match self.load() {
+            VmState::Paused => Some(VmExit::Paused),
+            VmState::Shutdown => Some(VmExit::Shutdown),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn pause(&self) -> HyperResult {
+        self.transition(VmState::is_runnable, VmState::Paused)
+    }
+
+    pub(crate) fn resume(&self) -> HyperResult {
+        self.transition(|state| state == VmState::Paused, VmState::Running)
+    }
+
+    pub(crate) fn reset(&self) -> HyperResult {
+        self.transition(|state| state != VmState::Destroyed, VmState::Created)
+    }
+
+    /// Fails unless the VM is stopped, i.e. no vCPU is meant to be in the guest, as saving or
+    /// restoring a snapshot requires.
+    pub(crate) fn ensure_stopped(&self) -> HyperResult {
+        match self.load() {
+            VmState::Created | VmState::Paused | VmState::Shutdown => Ok(()),
+            VmState::Running | VmState::Destroyed => Err(HyperError::BadState),
+        }
+    }
+
+    pub(crate) fn is_destroyed(&self) -> bool {
+        self.load() == VmState::Destroyed
+    }
+}